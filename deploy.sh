@@ -8,10 +8,13 @@ set -e
 
 SERVER_ADDR="remarkable"
 SERVER_DIR="/home/root"
+# reMarkable 1 and 2: armv7-unknown-linux-gnueabihf
+# reMarkable Paper Pro: aarch64-unknown-linux-gnu
+TARGET="${TARGET:-armv7-unknown-linux-gnueabihf}"
 
-cross build --target=armv7-unknown-linux-gnueabihf --release
+cross build --target=$TARGET --release
 rsync -vh --progress \
-  target/armv7-unknown-linux-gnueabihf/release/book-safe \
+  target/$TARGET/release/book-safe \
   $SERVER_ADDR:/tmp/
 
 cmds="
@@ -0,0 +1,110 @@
+//! What happened to every profile, kept for the weekly digest. Each
+//! notification event is appended whether or not notifications are
+//! configured, as are pauses. Entries are dropped once a digest covered
+//! them and they are a few weeks old.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// unix timestamp
+    pub at: i64,
+    pub profile: String,
+    /// `lock`, `unlock`, `pause` or one of the other notification events
+    pub event: String,
+    pub message: String,
+}
+
+fn path() -> PathBuf {
+    crate::state_dir().join("activity.jsonl")
+}
+
+/// Appends an entry, best effort: a failure is logged
+pub fn record(profile: &Profile, event: &str, message: &str) {
+    let entry = Entry {
+        at: OffsetDateTime::now_utc().unix_timestamp(),
+        profile: profile.to_string(),
+        event: event.to_owned(),
+        message: message.to_owned(),
+    };
+    if let Err(e) = append(&entry) {
+        log::warn!("could not record activity: {e:#}");
+    }
+}
+
+fn append(entry: &Entry) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let line = serde_json::to_string(entry)?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path())
+        .and_then(|mut file| writeln!(file, "{line}"))
+        .wrap_err("Could not write activity log")
+}
+
+fn load() -> Result<Vec<Entry>> {
+    let text = match fs::read_to_string(path()) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).wrap_err("Could not read activity log"),
+    };
+    // a line cut short by a power loss should not hide the rest
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// The entries after `since`, oldest first
+pub fn since(since: OffsetDateTime) -> Result<Vec<Entry>> {
+    let since = since.unix_timestamp();
+    Ok(load()?.into_iter().filter(|e| e.at > since).collect())
+}
+
+/// Drops the entries from before `before`
+pub fn prune(before: OffsetDateTime) -> Result<()> {
+    let before = before.unix_timestamp();
+    let kept: String = load()?
+        .into_iter()
+        .filter(|e| e.at >= before)
+        .map(|e| serde_json::to_string(&e).map(|line| line + "\n"))
+        .collect::<Result<_, _>>()?;
+    fs::write(path(), kept).wrap_err("Could not write activity log")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_and_prunes() {
+        let root = crate::device::simulate_in_temp_dir("activity");
+        let profile = Profile::new("kids").unwrap();
+        let start = OffsetDateTime::now_utc() - time::Duration::minutes(1);
+        record(&profile, "lock", "locked 3 documents");
+        record(&profile, "pause", "paused for 1 hours");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path())
+            .unwrap()
+            .write_all(b"{\"at\": 12")
+            .unwrap();
+
+        let entries = since(start).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].event, "pause");
+        assert_eq!(entries[0].profile, "kids");
+        prune(OffsetDateTime::now_utc() + time::Duration::minutes(1)).unwrap();
+        assert!(since(start).unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
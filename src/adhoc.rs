@@ -0,0 +1,47 @@
+//! Locks started with `lock-now`. These last a set time independent of
+//! the schedule, runs of the service in the meantime keep them locked.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+use crate::util::AcceptErr;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// unix timestamp of when the lock ends
+    until: i64,
+}
+
+pub fn save(profile: &Profile, until: OffsetDateTime) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let record = Record {
+        until: until.unix_timestamp(),
+    };
+    let json = serde_json::to_string_pretty(&record)?;
+    fs::write(profile.lock_now_record(), json).wrap_err("Could not write lock-now record")
+}
+
+/// When the current lock-now lock ends, if there is one that has not
+/// ended yet
+pub fn until(profile: &Profile, now: OffsetDateTime) -> Result<Option<OffsetDateTime>> {
+    let json = match fs::read_to_string(profile.lock_now_record()) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err("Could not read lock-now record"),
+    };
+    let record: Record = serde_json::from_str(&json).wrap_err("Could not parse lock-now record")?;
+    let until = OffsetDateTime::from_unix_timestamp(record.until)
+        .wrap_err("Invalid end in lock-now record")?;
+    Ok(Some(until).filter(|until| *until > now))
+}
+
+pub fn clear(profile: &Profile) -> Result<()> {
+    fs::remove_file(profile.lock_now_record())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove lock-now record")
+}
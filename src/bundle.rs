@@ -0,0 +1,129 @@
+//! A whole setup in one file: the config and how every profile was
+//! installed. Importing it sets up a sibling's device the same way, or
+//! restores a device after a factory reset.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::profile::Profile;
+use crate::systemd::Installed;
+
+/// Changed when a bundle can no longer be read by older versions
+const FORMAT: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bundle {
+    format: u32,
+    /// the book-safe version that exported it, for support
+    exported_by: String,
+    /// the config file as written, defaults are not filled in
+    pub config: serde_json::Value,
+    /// install record of every profile, by name
+    pub profiles: BTreeMap<String, Installed>,
+}
+
+impl Bundle {
+    /// The config and install records on this device
+    pub fn collect() -> Result<Self> {
+        let mut profiles = BTreeMap::new();
+        for profile in Profile::installed()? {
+            if let Some(installed) = Installed::load(&profile)? {
+                profiles.insert(profile.to_string(), installed);
+            }
+        }
+        Ok(Self {
+            format: FORMAT,
+            exported_by: env!("CARGO_PKG_VERSION").to_owned(),
+            config: Config::load_json()?,
+            profiles,
+        })
+    }
+
+    /// Just `profile` installed with `installed`, the config on this
+    /// device gets `timezone`
+    pub fn single(profile: &Profile, installed: Installed, timezone: &str) -> Result<Self> {
+        let mut config = Config::load_json()?;
+        if let Some(fields) = config.as_object_mut() {
+            fields.insert("timezone".to_owned(), timezone.into());
+        }
+        Ok(Self {
+            format: FORMAT,
+            exported_by: env!("CARGO_PKG_VERSION").to_owned(),
+            config,
+            profiles: BTreeMap::from([(profile.to_string(), installed)]),
+        })
+    }
+
+    /// Only readable by its owner, it can hold the api token and passwords
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(json.as_bytes()))
+            .wrap_err_with(|| format!("Could not write: {}", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read: {}", path.display()))?;
+        let bundle: Self = serde_json::from_str(&json).wrap_err("Could not parse the bundle")?;
+        if bundle.format > FORMAT {
+            return Err(eyre!(
+                "The bundle was exported by book-safe {}, it is too new to import",
+                bundle.exported_by
+            ))
+            .suggestion("update book-safe");
+        }
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let root = crate::device::simulate_in_temp_dir("bundle");
+        let config = serde_json::json!({"timezone": "Europe/Amsterdam", "api_token": "secret"});
+        Config::save_json(&config).unwrap();
+        let args = vec!["install".to_owned(), "--path=Books".to_owned()];
+        let kids = Profile::new("kids").unwrap();
+        Installed {
+            args: args.clone(),
+            scheduler: crate::scheduler::Kind::Cron,
+            folders: BTreeMap::new(),
+        }
+        .save(&kids)
+        .unwrap();
+
+        let path = root.join("bundle.json");
+        Bundle::collect().unwrap().write(&path).unwrap();
+        let bundle = Bundle::read(&path).unwrap();
+        assert_eq!(bundle.config, config);
+        assert_eq!(bundle.profiles.len(), 1);
+        assert_eq!(bundle.profiles["kids"].args, args);
+        assert_eq!(
+            bundle.profiles["kids"].scheduler,
+            crate::scheduler::Kind::Cron
+        );
+
+        assert!(Config::save_json(&serde_json::json!({"timezon": "UTC"})).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,381 @@
+//! Which device we are running on, decided at runtime so one code path
+//! works for the reMarkable 1 and 2 (armv7) and the Paper Pro (aarch64).
+//! The firmware version decides which files make up a document.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Rm1,
+    Rm2,
+    PaperPro,
+    /// Not a reMarkable, used while developing. Everything is
+    /// kept in `data/` and the system is left alone.
+    Desktop,
+}
+
+impl Model {
+    fn detect() -> Self {
+        for source in ["/sys/devices/soc0/machine", "/proc/device-tree/model"] {
+            let Ok(name) = fs::read_to_string(source) else {
+                continue;
+            };
+            if let Some(model) = Self::parse(&name) {
+                log::debug!("detected device: {model:?} from {source}");
+                return model;
+            }
+        }
+        Model::Desktop
+    }
+
+    fn parse(machine: &str) -> Option<Self> {
+        let machine = machine.trim_end_matches(['\0', '\n']);
+        if !machine.starts_with("reMarkable") {
+            return None;
+        }
+        Some(
+            if machine.contains("Ferrari") || machine.contains("Paper Pro") {
+                Model::PaperPro
+            } else if machine.contains('2') {
+                Model::Rm2
+            } else if machine.contains('1') || machine.contains("Prototype") {
+                Model::Rm1
+            } else {
+                log::warn!("unknown reMarkable: {machine}, treating it as a reMarkable 2");
+                Model::Rm2
+            },
+        )
+    }
+}
+
+/// Version of the firmware, only the parts that matter to us: 3.5.2.1807
+/// is major 3 minor 5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl Version {
+    fn detect() -> Option<Self> {
+        let sources = [
+            ("/etc/os-release", "IMG_VERSION"),
+            (
+                "/usr/share/remarkable/update.conf",
+                "REMARKABLE_RELEASE_VERSION",
+            ),
+        ];
+        for (source, key) in sources {
+            let Ok(text) = fs::read_to_string(source) else {
+                continue;
+            };
+            if let Some(version) = Self::find(&text, key) {
+                log::debug!("detected firmware: {version} from {source}");
+                return Some(version);
+            }
+        }
+        None
+    }
+
+    /// The version in the `key=value` line for `key` of `text`
+    fn find(text: &str, key: &str) -> Option<Self> {
+        let (_, value) = text
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(k, _)| k.trim() == key)?;
+        let mut parts = value.trim().trim_matches('"').split('.');
+        Some(Self {
+            major: parts.next()?.parse().ok()?,
+            minor: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+/// What xochitl keeps where, this changes between firmware versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Files of a document only some versions have, moved along with
+    /// the document when hiding it
+    pub extra_files: &'static [&'static str],
+    /// Whether `lastOpenedPage` in the metadata is kept up to date, 3.x
+    /// keeps the last page in the `.content` instead
+    pub opened_page: bool,
+    /// Whether sync deletes a document from the cloud once it finds a
+    /// `.tombstone`, before 3.x it looks for `deleted` in the metadata
+    pub tombstones: bool,
+}
+
+impl Layout {
+    pub const V2: Self = Self {
+        extra_files: &["local"],
+        opened_page: true,
+        tombstones: false,
+    };
+    pub const V3: Self = Self {
+        extra_files: &["tombstone"],
+        opened_page: false,
+        tombstones: true,
+    };
+    /// For versions we do not know, moves every file we know of
+    pub const UNKNOWN: Self = Self {
+        extra_files: &["local", "tombstone"],
+        opened_page: false,
+        tombstones: true,
+    };
+
+    fn for_version(version: Option<Version>) -> Self {
+        match version {
+            Some(Version { major: 2, .. }) => Self::V2,
+            Some(Version { major: 3, .. }) => Self::V3,
+            Some(version) => {
+                log::warn!(
+                    "firmware {version} is unknown to book-safe, it might miss files of \
+                    documents. Please open an issue mentioning the version"
+                );
+                Self::UNKNOWN
+            }
+            None => {
+                log::warn!(
+                    "could not find the firmware version, book-safe might miss files \
+                    of documents. Please open an issue"
+                );
+                Self::UNKNOWN
+            }
+        }
+    }
+}
+
+/// Where the documents and book-safe's own files are kept
+#[derive(Debug, Clone)]
+pub struct Paths {
+    /// where xochitl keeps the documents
+    pub documents: PathBuf,
+    /// where locked documents are moved to
+    pub safe_dir: PathBuf,
+    /// where older versions kept the locked documents, they are moved
+    /// to `safe_dir` on first use
+    pub legacy_safe_dir: PathBuf,
+    /// where book-safe keeps its own data, such as the route cache
+    pub state_dir: PathBuf,
+    pub config: PathBuf,
+    /// xochitl's settings, with the cloud tokens if it is paired
+    pub xochitl_conf: PathBuf,
+}
+
+impl Paths {
+    fn remarkable() -> Self {
+        Self {
+            documents: "/home/root/.local/share/remarkable/xochitl".into(),
+            safe_dir: "/home/root/.book-safe/vault".into(),
+            legacy_safe_dir: "/home/root/locked_books".into(),
+            state_dir: "/home/root/.local/share/book-safe".into(),
+            config: "/home/root/.config/book-safe/config.json".into(),
+            xochitl_conf: "/home/root/.config/remarkable/xochitl.conf".into(),
+        }
+    }
+
+    /// Everything in `root`, while developing that is `data/`
+    pub fn in_dir(root: &Path) -> Self {
+        Self {
+            documents: root.join("xochitl"),
+            safe_dir: root.join(".book-safe/vault"),
+            legacy_safe_dir: root.join("locked_books"),
+            state_dir: root.join("state"),
+            config: root.join("config.json"),
+            xochitl_conf: root.join("xochitl.conf"),
+        }
+    }
+}
+
+/// Paths and service names that differ between devices
+#[derive(Debug)]
+pub struct Profile {
+    pub model: Model,
+    paths: Paths,
+    /// the service running the ui when no launcher is used
+    pub ui_service: &'static str,
+    /// None if unknown or not on a reMarkable
+    pub firmware: Option<Version>,
+    pub layout: Layout,
+}
+
+impl Profile {
+    fn for_model(model: Model) -> Self {
+        let mut device = match model {
+            Model::Rm1 | Model::Rm2 | Model::PaperPro => {
+                let firmware = Version::detect();
+                Self {
+                    firmware,
+                    layout: Layout::for_version(firmware),
+                    ..Self::new(model, Paths::remarkable())
+                }
+            }
+            Model::Desktop => Self::new(model, Paths::in_dir(Path::new("data"))),
+        };
+        if let Some(safe_dir) = crate::config::safe_dir_at(device.config()) {
+            device.paths.safe_dir = safe_dir;
+        }
+        device
+    }
+
+    /// Without a firmware version, laid out like the newest firmware
+    fn new(model: Model, paths: Paths) -> Self {
+        Self {
+            model,
+            paths,
+            ui_service: "xochitl.service",
+            firmware: None,
+            layout: Layout::V3,
+        }
+    }
+
+    /// A reMarkable 2 with everything in `root`, for tests that need a
+    /// device other than the one `simulate_in_temp_dir` sets up
+    #[cfg(test)]
+    pub fn remarkable_in(root: &Path) -> Self {
+        Self::new(Model::Rm2, Paths::in_dir(root))
+    }
+
+    /// false while developing on a desktop, then we must not touch
+    /// the network, services or wifi
+    pub fn is_remarkable(&self) -> bool {
+        self.model != Model::Desktop
+    }
+
+    pub fn documents(&self) -> &Path {
+        &self.paths.documents
+    }
+
+    pub fn safe_dir(&self) -> &Path {
+        &self.paths.safe_dir
+    }
+
+    pub fn legacy_safe_dir(&self) -> &Path {
+        &self.paths.legacy_safe_dir
+    }
+
+    pub fn state_dir(&self) -> &Path {
+        &self.paths.state_dir
+    }
+
+    pub fn config(&self) -> &Path {
+        &self.paths.config
+    }
+
+    /// Whether the device is paired with a cloud account, xochitl then
+    /// syncs unless book-safe blocks it
+    pub fn cloud_paired(&self) -> bool {
+        fs::read_to_string(&self.paths.xochitl_conf).is_ok_and(|conf| paired(&conf))
+    }
+}
+
+/// Pairing stores a device token in xochitl.conf, unpairing empties it
+fn paired(xochitl_conf: &str) -> bool {
+    xochitl_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("devicetoken="))
+        .any(|token| !token.trim().is_empty())
+}
+
+static CURRENT: OnceLock<Profile> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    static TEST_DEVICE: std::cell::Cell<Option<&'static Profile>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// The profile of the device we are running on, detected on first use
+pub fn current() -> &'static Profile {
+    #[cfg(test)]
+    if let Some(device) = TEST_DEVICE.get() {
+        return device;
+    }
+    CURRENT.get_or_init(|| Profile::for_model(Model::detect()))
+}
+
+/// Act as if we are not on a reMarkable, even if we are.
+/// Must be called before anything uses the device.
+pub fn simulate() {
+    CURRENT
+        .set(Profile::for_model(Model::Desktop))
+        .expect("simulate is called before the device is detected");
+}
+
+/// Act as if not on a reMarkable using the documents in `documents`, a
+/// copy of the xochitl folder. The rest is kept in a temporary dir.
+/// Must be called before anything uses the device.
+pub fn use_copy(documents: &Path) -> std::io::Result<()> {
+    let root = std::env::temp_dir().join("book-safe-plan");
+    fs::create_dir_all(&root)?;
+    let paths = Paths {
+        documents: documents.to_path_buf(),
+        ..Paths::in_dir(&root)
+    };
+    CURRENT
+        .set(Profile::new(Model::Desktop, paths))
+        .expect("use_copy is called before the device is detected");
+    Ok(())
+}
+
+/// Simulates with everything kept in a new, empty, temporary dir. Only
+/// affects the current thread so tests can run in parallel.
+#[cfg(test)]
+pub fn simulate_in_temp_dir(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("book-safe-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let device = Profile::new(Model::Desktop, Paths::in_dir(&root));
+    TEST_DEVICE.set(Some(Box::leak(Box::new(device))));
+    root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn machine_names() {
+        assert_eq!(Model::parse("reMarkable 1.0\n"), Some(Model::Rm1));
+        assert_eq!(Model::parse("reMarkable 2.0\0"), Some(Model::Rm2));
+        assert_eq!(Model::parse("reMarkable Ferrari\n"), Some(Model::PaperPro));
+        assert_eq!(Model::parse("Raspberry Pi 4 Model B"), None);
+    }
+
+    #[test]
+    fn cloud_pairing() {
+        let conf = "[General]\nwifion=true\ndevicetoken=eyJhbGciOiJIUzI1NiJ9.e30.x\n";
+        assert!(paired(conf));
+        assert!(!paired("[General]\ndevicetoken=\nusertoken=\n"));
+        assert!(!paired("[General]\nwifion=true\n"));
+    }
+
+    #[test]
+    fn firmware_versions() {
+        let os_release = "NAME=\"Codex Linux\"\nIMG_VERSION=\"3.5.2.1807\"\n";
+        let v3 = Version::find(os_release, "IMG_VERSION").unwrap();
+        assert_eq!(v3, Version { major: 3, minor: 5 });
+        assert_eq!(Layout::for_version(Some(v3)), Layout::V3);
+        let update_conf = "[General]\nREMARKABLE_RELEASE_VERSION=2.15.1.1189\n";
+        let v2 = Version::find(update_conf, "REMARKABLE_RELEASE_VERSION").unwrap();
+        assert_eq!(v2.to_string(), "2.15");
+        assert_eq!(Layout::for_version(Some(v2)), Layout::V2);
+        assert_eq!(Version::find("IMG_VERSION=\"\"", "IMG_VERSION"), None);
+        let unknown = Layout::for_version(Some(Version { major: 4, minor: 0 }));
+        for layout in [Layout::V2, Layout::V3] {
+            assert!(layout
+                .extra_files
+                .iter()
+                .all(|f| unknown.extra_files.contains(f)));
+        }
+    }
+}
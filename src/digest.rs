@@ -0,0 +1,262 @@
+//! A weekly summary of the locks, unlocks, pauses and tamper events of
+//! every profile, mailed or posted to a webhook so parents see it without
+//! logging in to the device. Runs send it once its time has passed, the
+//! timers have an extra edge for that.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime, Time};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use crate::activity::{self, Entry};
+use crate::config::{self, Config};
+use crate::notify;
+use crate::smtp;
+use crate::util::time::{self as util_time, ParseHourMinute};
+
+/// Activity is kept this long after it was sent
+const KEEP: Duration = Duration::weeks(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// unix timestamp of when the last digest was sent
+    sent: i64,
+}
+
+fn record_path() -> PathBuf {
+    crate::state_dir().join("digest.json")
+}
+
+fn last_sent() -> Result<Option<OffsetDateTime>> {
+    let json = match fs::read_to_string(record_path()) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err("Could not read digest record"),
+    };
+    let record: Record = serde_json::from_str(&json).wrap_err("Could not parse digest record")?;
+    OffsetDateTime::from_unix_timestamp(record.sent)
+        .map(Some)
+        .wrap_err("Invalid time in digest record")
+}
+
+fn save(sent: OffsetDateTime) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let record = Record {
+        sent: sent.unix_timestamp(),
+    };
+    let json = serde_json::to_string_pretty(&record)?;
+    fs::write(record_path(), json).wrap_err("Could not write digest record")
+}
+
+/// When the digest was last due, on `day` at `at` on the clock of `tz`
+fn last_due(day: time::Weekday, at: Time, now: OffsetDateTime, tz: &Tz) -> OffsetDateTime {
+    let today = now.to_timezone(tz).date();
+    let back = (7 + today.weekday().number_days_from_monday() - day.number_days_from_monday()) % 7;
+    let date = today - Duration::days(back.into());
+    let due = util_time::resolve(date, at, tz);
+    if due > now {
+        util_time::resolve(date - Duration::weeks(1), at, tz)
+    } else {
+        due
+    }
+}
+
+/// When the digest is due every week, for the timers. None if there is
+/// no digest configured.
+pub fn due_at(config: &Config) -> Result<Option<(time::Weekday, Time)>> {
+    let Some(digest) = &config.digest else {
+        return Ok(None);
+    };
+    let at = Time::try_parse(&digest.at).wrap_err("Invalid digest time")?;
+    Ok(Some((digest.day.into(), at)))
+}
+
+/// Sends the digest if it became due since the last one, a failure is
+/// logged and does not stop the run
+pub fn send_if_due(config: &Config, now: OffsetDateTime) {
+    let Some(digest) = &config.digest else {
+        return;
+    };
+    if let Err(e) = try_send_if_due(digest, config, now) {
+        log::warn!("could not send the digest: {e:#}");
+    }
+}
+
+fn try_send_if_due(digest: &config::Digest, config: &Config, now: OffsetDateTime) -> Result<()> {
+    let tz = util_time::zone(config.timezone.as_deref())?;
+    let at = Time::try_parse(&digest.at).wrap_err("Invalid digest time")?;
+    let Some(sent) = last_sent()? else {
+        // the first digest covers a whole week
+        return save(now);
+    };
+    if sent >= last_due(digest.day.into(), at, now, tz) {
+        return Ok(());
+    }
+    send(digest, sent, now, tz)
+}
+
+/// Sends the digest now, or prints it, covering the activity since the
+/// last one
+pub fn command(print: bool, config: &Config) -> Result<()> {
+    let tz = util_time::zone(config.timezone.as_deref())?;
+    let now = OffsetDateTime::now_utc();
+    let since = last_sent()?.unwrap_or(now - Duration::weeks(1));
+    if print {
+        print!("{}", compose(&activity::since(since)?, since, now, tz));
+        return Ok(());
+    }
+    let digest = config
+        .digest
+        .as_ref()
+        .ok_or_else(|| eyre!("Nowhere to send the digest to"))
+        .suggestion("set digest in the config, or pass --print")?;
+    send(digest, since, now, tz)
+}
+
+fn send(
+    digest: &config::Digest,
+    since: OffsetDateTime,
+    now: OffsetDateTime,
+    tz: &Tz,
+) -> Result<()> {
+    let body = compose(&activity::since(since)?, since, now, tz);
+    let subject = format!(
+        "book-safe digest of the week up to {}",
+        now.to_timezone(tz).date()
+    );
+    if digest.url.starts_with("http") {
+        notify::post(&digest.url, "text/plain; charset=utf-8", &body)
+    } else {
+        let to = digest
+            .to
+            .as_deref()
+            .ok_or_else(|| eyre!("No address to mail the digest to"))
+            .suggestion("set `to` under digest in the config")?;
+        let mail = smtp::Mail {
+            from: &digest.from,
+            to,
+            subject: &subject,
+            body: &body,
+        };
+        smtp::send(&digest.url, &mail)
+    }
+    .wrap_err("Could not send the digest")?;
+    log::info!("sent the digest");
+    save(now)?;
+    activity::prune(now - KEEP)
+}
+
+/// Per profile how often each event happened, and when the events that
+/// are not part of the schedule happened
+fn compose(entries: &[Entry], since: OffsetDateTime, now: OffsetDateTime, tz: &Tz) -> String {
+    let local = |at: OffsetDateTime| {
+        let at = at.to_timezone(tz);
+        format!(
+            "{} {} {}:{:02}",
+            at.weekday(),
+            at.date(),
+            at.hour(),
+            at.minute()
+        )
+    };
+    let mut text = format!(
+        "Book-safe activity from {} to {}\n",
+        local(since),
+        local(now)
+    );
+    let mut profiles: BTreeMap<&str, Vec<&Entry>> = BTreeMap::new();
+    for entry in entries.iter().filter(|e| e.event != "upcoming") {
+        profiles.entry(&entry.profile).or_default().push(entry);
+    }
+    if profiles.is_empty() {
+        text.push_str("\nNothing happened.\n");
+    }
+    for (profile, entries) in profiles {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for entry in &entries {
+            *counts.entry(&entry.event).or_default() += 1;
+        }
+        let counts: Vec<_> = counts
+            .into_iter()
+            .map(|(event, n)| format!("{event}: {n}"))
+            .collect();
+        let _ = writeln!(text, "\n{profile}: {}", counts.join(", "));
+        for entry in entries {
+            if matches!(entry.event.as_str(), "lock" | "unlock") {
+                continue;
+            }
+            let at = OffsetDateTime::from_unix_timestamp(entry.at).unwrap_or(now);
+            let _ = writeln!(text, "  {} {}: {}", local(at), entry.event, entry.message);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `h:m` UTC on `day` June 2024
+    fn june(day: u8, h: u8, m: u8) -> OffsetDateTime {
+        time::Date::from_calendar_date(2024, time::Month::June, day)
+            .unwrap()
+            .with_hms(h, m, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    #[test]
+    fn due_weekly() {
+        let tz = util_time::find_timezone("Europe/Amsterdam").unwrap();
+        let at = Time::from_hms(18, 0, 0).unwrap();
+        // a wednesday
+        let now = june(12, 12, 0);
+        let due = last_due(time::Weekday::Sunday, at, now, tz);
+        assert_eq!(due, june(9, 16, 0));
+        // on the day itself, before and after the time
+        let sunday = june(16, 15, 0);
+        assert_eq!(last_due(time::Weekday::Sunday, at, sunday, tz), due);
+        let sunday = june(16, 17, 0);
+        assert_eq!(
+            last_due(time::Weekday::Sunday, at, sunday, tz),
+            june(16, 16, 0)
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let tz = util_time::find_timezone("UTC").unwrap();
+        let since = june(9, 18, 0);
+        let entry = |at: OffsetDateTime, event: &str, message: &str| Entry {
+            at: at.unix_timestamp(),
+            profile: "kids".to_owned(),
+            event: event.to_owned(),
+            message: message.to_owned(),
+        };
+        let entries = [
+            entry(june(10, 21, 0), "lock", "locked 3 documents"),
+            entry(june(11, 7, 0), "unlock", "unlocked folders"),
+            entry(june(11, 21, 5), "tamper", "paused by hand"),
+            entry(june(11, 21, 5), "pause", "paused for 1 hours"),
+        ];
+        let text = compose(&entries, since, since + Duration::weeks(1), tz);
+        assert_eq!(
+            text,
+            "Book-safe activity from Sunday 2024-06-09 18:00 to Sunday 2024-06-16 18:00\n\
+            \n\
+            kids: lock: 1, pause: 1, tamper: 1, unlock: 1\n  \
+            Tuesday 2024-06-11 21:05 tamper: paused by hand\n  \
+            Tuesday 2024-06-11 21:05 pause: paused for 1 hours\n"
+        );
+        assert!(compose(&[], since, since, tz).ends_with("Nothing happened.\n"));
+    }
+}
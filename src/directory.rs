@@ -1,17 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::Display;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use color_eyre::{eyre::WrapErr, Result};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
 use indextree::{Arena, NodeId};
 use regex::Regex;
+use time::OffsetDateTime;
 
-#[cfg(target_arch = "arm")]
-pub const DIR: &str = "/home/root/.local/share/remarkable/xochitl";
-#[cfg(not(target_arch = "arm"))]
-pub const DIR: &str = "data/xochitl";
+use crate::device::Layout;
 
 fn extract_field<'a>(metadata: &'a str, field: &str) -> Option<&'a str> {
     let pattern = format!("\"{field}\": ?(?:\"(.*?)\"|.*?)(?:,|\n|}})");
@@ -22,6 +23,14 @@ fn extract_field<'a>(metadata: &'a str, field: &str) -> Option<&'a str> {
     Some(value)
 }
 
+/// Like `extract_field` but for values that are not quoted, such as numbers
+fn extract_number<T: std::str::FromStr>(metadata: &str, field: &str) -> Option<T> {
+    let pattern = format!("\"{field}\": ?\"?([0-9]+)");
+    let re = Regex::new(&pattern)
+        .unwrap_or_else(|_| panic!("Unable to parse pattern {pattern} to Regex object"));
+    re.captures(metadata)?.get(1)?.as_str().parse().ok()
+}
+
 fn parent(metadata: &str) -> Option<&str> {
     extract_field(metadata, "parent")
 }
@@ -30,39 +39,221 @@ fn name(metadata: &str) -> Option<&str> {
     extract_field(metadata, "visibleName")
 }
 
-fn is_folder(metadata: &str) -> bool {
-    let doc_type = extract_field(metadata, "type").unwrap();
+/// When and on which page a document was last opened
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Opened {
+    pub at: Option<OffsetDateTime>,
+    pub page: Option<u32>,
+}
+
+fn opened(metadata: &str, layout: Layout) -> Opened {
+    // xochitl stores milliseconds since the epoch, or an empty string
+    // if the document was never opened
+    let at = extract_number::<i128>(metadata, "lastOpened")
+        .filter(|ms| *ms > 0)
+        .and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000).ok());
+    Opened {
+        at,
+        page: layout
+            .opened_page
+            .then(|| extract_number(metadata, "lastOpenedPage"))
+            .flatten(),
+    }
+}
+
+/// Whether the document is starred, the ui lists it under favorites
+fn pinned(metadata: &str) -> bool {
+    Regex::new(r#""pinned": ?true"#)
+        .expect("valid regex")
+        .is_match(metadata)
+}
+
+/// Kind of document, as in `fileType` in its `.content` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileType {
+    Pdf,
+    Epub,
+    /// Handwritten, made on the device
+    Notebook,
+}
+
+impl FileType {
+    /// None if the `.content` file can not be read or has an unknown type
+    pub fn of(uuid: Uuid) -> Option<Self> {
+        let path = crate::device::current()
+            .documents()
+            .join(uuid.to_string())
+            .with_extension("content");
+        let content = fs::read_to_string(path).ok()?;
+        match extract_field(&content, "fileType")? {
+            "pdf" => Some(Self::Pdf),
+            "epub" => Some(Self::Epub),
+            // older firmware leaves it empty for notebooks
+            "notebook" | "" => Some(Self::Notebook),
+            _ => None,
+        }
+    }
+}
+
+/// Which documents to lock, by their type, size and age. `only_types`
+/// and `skip_types` are not both set.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub only_types: Vec<FileType>,
+    pub skip_types: Vec<FileType>,
+    /// in bytes, of the document and its pages
+    pub min_size: Option<u64>,
+    /// last modified after this
+    pub added_after: Option<OffsetDateTime>,
+}
+
+impl Filter {
+    /// Documents whose type or age is unknown are locked
+    pub fn locks(&self, uuid: Uuid) -> bool {
+        self.type_matches(uuid) && self.size_matches(uuid) && self.age_matches(uuid)
+    }
+
+    fn type_matches(&self, uuid: Uuid) -> bool {
+        if self.only_types.is_empty() && self.skip_types.is_empty() {
+            return true;
+        }
+        let Some(kind) = FileType::of(uuid) else {
+            return true;
+        };
+        (self.only_types.is_empty() || self.only_types.contains(&kind))
+            && !self.skip_types.contains(&kind)
+    }
+
+    fn size_matches(&self, uuid: Uuid) -> bool {
+        let Some(min_size) = self.min_size else {
+            return true;
+        };
+        doc_size(uuid) >= min_size
+    }
+
+    fn age_matches(&self, uuid: Uuid) -> bool {
+        let Some(added_after) = self.added_after else {
+            return true;
+        };
+        let path = crate::device::current()
+            .documents()
+            .join(uuid.to_string())
+            .with_extension("metadata");
+        let Ok(metadata) = fs::read_to_string(path) else {
+            return true;
+        };
+        extract_number::<i128>(&metadata, "lastModified")
+            .and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000).ok())
+            .is_none_or(|modified| modified > added_after)
+    }
+}
+
+/// On disk size of every document in `dir`, summing all files and
+/// folders named after its uuid
+pub fn sizes(dir: &Path) -> HashMap<Uuid, u64> {
+    let mut sizes = HashMap::new();
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let name = entry.file_name();
+        let Some(uuid) = name
+            .to_str()
+            .map(|name| name.split_once('.').map_or(name, |(uuid, _)| uuid))
+            .and_then(|uuid| Uuid::parse(uuid).ok())
+        else {
+            continue;
+        };
+        *sizes.entry(uuid).or_default() += crate::util::progress::size(&entry.path());
+    }
+    sizes
+}
+
+/// Size in bytes of a document: its pdf or epub and its pages
+pub fn doc_size(uuid: Uuid) -> u64 {
+    let path = crate::device::current().documents().join(uuid.to_string());
+    ["", "pdf", "epub"]
+        .into_iter()
+        .map(|ext| crate::util::progress::size(&path.with_extension(ext)))
+        .sum()
+}
+
+fn is_folder(metadata: &str) -> Result<bool> {
+    let doc_type = extract_field(metadata, "type").ok_or_else(|| eyre!("No type"))?;
     match doc_type {
-        "DocumentType" => false,
-        "CollectionType" => true,
-        other => panic!("unexpected document type: {other}"),
+        "DocumentType" => Ok(false),
+        "CollectionType" => Ok(true),
+        other => Err(eyre!("Unexpected document type: {other}")),
     }
 }
 
-#[derive(Eq, PartialEq, Hash, Debug, Clone)]
-pub struct Uuid(String);
+/// Id of a document or folder, xochitl names its files after it
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
+pub struct Uuid(uuid::Uuid);
 
-impl AsRef<Path> for Uuid {
-    fn as_ref(&self) -> &Path {
-        Path::new(&self.0)
+impl Uuid {
+    /// Only accepts the lowercase hyphenated form xochitl uses, anything
+    /// else would not match the file names
+    pub fn parse(s: &str) -> Result<Self> {
+        let uuid = uuid::Uuid::try_parse(s).wrap_err_with(|| format!("Not a uuid: {s:?}"))?;
+        if uuid.hyphenated().to_string() != s {
+            return Err(eyre!("Not a uuid in the form xochitl uses: {s:?}"));
+        }
+        Ok(Self(uuid))
     }
 }
 
 impl std::fmt::Display for Uuid {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+/// Where a document or folder is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parent {
+    Root,
+    Trash,
+    Folder(Uuid),
+}
+
+impl Parent {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "" => Ok(Self::Root),
+            "trash" => Ok(Self::Trash),
+            uuid => Uuid::parse(uuid).map(Self::Folder),
+        }
     }
 }
 
-impl std::convert::From<&str> for Uuid {
-    fn from(s: &str) -> Self {
-        Uuid(s.to_owned())
+/// As in the metadata files
+impl Display for Parent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Parent::Root => Ok(()),
+            Parent::Trash => f.write_str("trash"),
+            Parent::Folder(uuid) => uuid.fmt(f),
+        }
     }
 }
 
 pub struct File {
     uuid: Uuid,
     name: String,
+    opened: Opened,
+}
+
+/// A document and the folders it is in
+pub struct Doc {
+    pub path: PathBuf,
+    pub opened: Opened,
+}
+
+/// A folder or document, see `Tree::items`
+pub struct Item {
+    pub uuid: Uuid,
+    /// as shown in the ui, starting with `trash` for the trash
+    pub path: PathBuf,
+    pub folder: bool,
+    pub opened: Opened,
 }
 
 impl Display for File {
@@ -71,17 +262,27 @@ impl Display for File {
     }
 }
 
+/// Holds the folders whose metadata is gone, such as a folder deleted
+/// on another device while this one was offline
+pub const ORPHANED: &str = "(orphaned)";
+
 pub struct Tree {
     arena: Arena<()>,
+    root: NodeId,
+    trash: NodeId,
+    orphaned: NodeId,
     node: HashMap<Uuid, NodeId>,
     name: HashMap<NodeId, String>,
     files: HashMap<NodeId, Vec<File>>,
+    pinned: HashSet<Uuid>,
+    /// On disk size of every document, only if `measure` was called
+    sizes: Option<HashMap<Uuid, u64>>,
 }
 
 impl Display for Tree {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let node_id = self.root();
-        self.print_recurse(*node_id, 0, f)?;
+        self.print_recurse(*node_id, 0, &[], f)?;
         Ok(())
     }
 }
@@ -90,36 +291,61 @@ pub struct SubTree<'a> {
     tree: &'a Tree,
     pub path: PathBuf,
     root: NodeId,
+    skip: Vec<NodeId>,
 }
 
-impl<'a> Display for SubTree<'a> {
+impl SubTree<'_> {
+    /// Leave the folders `skip` out when printing
+    pub fn without(mut self, skip: &[NodeId]) -> Self {
+        self.skip.extend_from_slice(skip);
+        self
+    }
+}
+
+impl Display for SubTree<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.tree.print_recurse(self.root, 0, f)?;
+        self.tree.print_recurse(self.root, 0, &self.skip, f)?;
         Ok(())
     }
 }
 
 impl Tree {
-    fn add_root(&mut self, uuid: Uuid, name: impl Into<String>) {
-        let node_id = self.arena.new_node(());
-        self.name.insert(node_id, name.into());
-        self.node.insert(uuid, node_id);
-    }
-
     pub fn new() -> Self {
-        let mut tree = Self {
-            arena: Arena::new(),
+        let mut arena = Arena::new();
+        let trash = arena.new_node(());
+        let root = arena.new_node(());
+        let orphaned = arena.new_node(());
+        let name = HashMap::from([
+            (trash, "trash".to_owned()),
+            (root, String::new()),
+            (orphaned, ORPHANED.to_owned()),
+        ]);
+        Self {
+            arena,
+            root,
+            trash,
+            orphaned,
             node: HashMap::new(),
-            name: HashMap::new(),
+            name,
             files: HashMap::new(),
-        };
+            pinned: HashSet::new(),
+            sizes: None,
+        }
+    }
 
-        tree.add_root(Uuid("trash".to_owned()), "trash");
-        tree.add_root(Uuid(String::new()), "");
-        tree
+    /// The node of `parent`, created if we did not see that folder yet
+    fn parent_node(&mut self, parent: Parent) -> NodeId {
+        match parent {
+            Parent::Root => self.root,
+            Parent::Trash => self.trash,
+            Parent::Folder(uuid) => *self
+                .node
+                .entry(uuid)
+                .or_insert_with(|| self.arena.new_node(())),
+        }
     }
 
-    fn path(&self, node: &NodeId) -> PathBuf {
+    pub fn path(&self, node: &NodeId) -> PathBuf {
         node.ancestors(&self.arena)
             .collect::<Vec<_>>()
             .into_iter()
@@ -129,11 +355,12 @@ impl Tree {
             .collect()
     }
 
-    pub fn subtree(&self, node: NodeId) -> SubTree {
+    pub fn subtree(&self, node: NodeId) -> SubTree<'_> {
         SubTree {
             tree: self,
             path: self.path(&node),
             root: node,
+            skip: Vec::new(),
         }
     }
 
@@ -141,10 +368,17 @@ impl Tree {
         &self,
         node: NodeId,
         indent: usize,
+        skip: &[NodeId],
         f: &mut std::fmt::Formatter,
     ) -> std::fmt::Result {
         let ident_str: String = std::iter::once(' ').cycle().take(indent * 4).collect();
-        let node_name = self.name.get(&node).unwrap();
+        let name = self.name.get(&node).unwrap();
+        let node_name = match self.folder_size(node) {
+            Some(size) => format!("{name} ({})", crate::util::progress::mb(size))
+                .trim_start()
+                .to_owned(),
+            None => name.clone(),
+        };
         match indent {
             0 => writeln!(f, "{node_name}")?,
             _ => writeln!(f, "{ident_str}|-- {node_name}")?,
@@ -158,14 +392,29 @@ impl Tree {
         }
 
         for child in node.children(&self.arena) {
-            self.print_recurse(child, indent + 1, f)?;
+            if !skip.contains(&child) {
+                self.print_recurse(child, indent + 1, skip, f)?;
+            }
         }
         Ok(())
     }
 
+    /// Reads the size of every document in `dir`, from then on folders
+    /// are printed with the size of everything in them
+    pub fn measure(&mut self, dir: &Path) {
+        self.sizes = Some(sizes(dir));
+    }
+
+    /// Size of the documents in `folder` and its subfolders, None if
+    /// the tree was not measured
+    pub fn folder_size(&self, folder: NodeId) -> Option<u64> {
+        let sizes = self.sizes.as_ref()?;
+        let files = self.descendant_files(folder);
+        Some(files.iter().filter_map(|uuid| sizes.get(uuid)).sum())
+    }
+
     pub fn root(&self) -> &NodeId {
-        let uuid = Uuid(String::new());
-        self.node.get(&uuid).unwrap()
+        &self.root
     }
 
     pub fn node_for(&self, path: &str) -> std::result::Result<NodeId, String> {
@@ -185,25 +434,135 @@ impl Tree {
         Ok(node)
     }
 
+    /// Like `node_for` but ignoring case and surrounding whitespace in
+    /// every folder name. An exact match wins, a name matching several
+    /// folders only loosely matches none. Returns the exact path.
+    pub fn fuzzy_path(&self, path: &str) -> Option<String> {
+        let loose = |name: &str| name.trim().to_lowercase();
+        let mut node = *self.root();
+        let mut exact = Vec::new();
+        for comp in path.split('/') {
+            let name = |n: &NodeId| self.name.get(n).unwrap().as_str();
+            let children: Vec<_> = node.children(&self.arena).collect();
+            let found = if let Some(child) = children.iter().find(|n| name(n) == comp) {
+                *child
+            } else {
+                let mut matching = children.iter().filter(|n| loose(name(n)) == loose(comp));
+                match (matching.next(), matching.next()) {
+                    (Some(child), None) => *child,
+                    _ => return None,
+                }
+            };
+            exact.push(name(&found).to_owned());
+            node = found;
+        }
+        Some(exact.join("/"))
+    }
+
+    /// Id of the folder at `path`, None for the root or if not found
+    pub fn folder_id(&self, path: &str) -> Option<Uuid> {
+        let node = self.node_for(path).ok()?;
+        self.folder_uuid(node)
+    }
+
+    /// Id of the folder `node`, None for the root, trash and orphaned
+    pub fn folder_uuid(&self, node: NodeId) -> Option<Uuid> {
+        self.node
+            .iter()
+            .find(|(_, n)| **n == node)
+            .map(|(uuid, _)| *uuid)
+    }
+
+    /// Where folder `uuid` is now in the form `node_for` takes, None if
+    /// it is gone or in the trash
+    pub fn folder_path(&self, uuid: Uuid) -> Option<String> {
+        let node = self.node.get(&uuid)?;
+        if !node.ancestors(&self.arena).any(|n| n == self.root) {
+            return None;
+        }
+        self.path(node).to_str().map(str::to_owned)
+    }
+
     pub fn descendant_files(&self, subroot: NodeId) -> Vec<Uuid> {
         let mut files = Vec::new();
         for folder in subroot.descendants(&self.arena) {
             if let Some(content) = self.files.get(&folder) {
-                files.extend(content.iter().map(|f| f.uuid.clone()));
+                files.extend(content.iter().map(|f| f.uuid));
             }
         }
         files
     }
 
-    pub fn add_file(&mut self, uuid: Uuid, parent_uuid: Uuid, name: String) {
-        let parent_node = if let Some(n) = self.node.get(&parent_uuid) {
-            *n
-        } else {
-            let parent_node = self.arena.new_node(());
-            self.node.insert(parent_uuid, parent_node);
-            parent_node
-        };
-        let file = File { uuid, name };
+    /// All documents in `subroot` and its subfolders
+    pub fn descendant_docs(&self, subroot: NodeId) -> Vec<Doc> {
+        let mut docs = Vec::new();
+        for folder in subroot.descendants(&self.arena) {
+            let path = self.path(&folder);
+            for file in self.files.get(&folder).into_iter().flatten() {
+                docs.push(Doc {
+                    path: path.join(&file.name),
+                    opened: file.opened,
+                });
+            }
+        }
+        docs
+    }
+
+    /// Where every document is, as shown in the ui
+    pub fn doc_paths(&self) -> HashMap<Uuid, PathBuf> {
+        self.files
+            .iter()
+            .flat_map(|(folder, files)| {
+                let path = self.path(folder);
+                files
+                    .iter()
+                    .map(move |file| (file.uuid, path.join(&file.name)))
+            })
+            .collect()
+    }
+
+    /// Every folder and document, including those in the trash and
+    /// those in folders that are gone. Folders come before their content.
+    pub fn items(&self) -> Vec<Item> {
+        let uuids: HashMap<NodeId, Uuid> = self.node.iter().map(|(u, n)| (*n, *u)).collect();
+        let mut items = Vec::new();
+        for top in [self.root, self.trash, self.orphaned] {
+            for folder in top.descendants(&self.arena) {
+                let path = self.path(&folder);
+                if let Some(uuid) = uuids.get(&folder).filter(|_| folder != top) {
+                    items.push(Item {
+                        uuid: *uuid,
+                        path: path.clone(),
+                        folder: true,
+                        opened: Opened::default(),
+                    });
+                }
+                for file in self.files.get(&folder).into_iter().flatten() {
+                    items.push(Item {
+                        uuid: file.uuid,
+                        path: path.join(&file.name),
+                        folder: false,
+                        opened: file.opened,
+                    });
+                }
+            }
+        }
+        items
+    }
+
+    /// Documents last opened after `since`, such as the one being read
+    pub fn opened_since(&self, since: OffsetDateTime) -> HashSet<Uuid> {
+        self.files
+            .values()
+            .flatten()
+            .filter(|file| file.opened.at.is_some_and(|at| at > since))
+            .map(|file| file.uuid)
+            .collect()
+    }
+
+    pub fn add_file(&mut self, uuid: Uuid, parent: Parent, name: String, opened: Opened) {
+        let parent_node = self.parent_node(parent);
+        let file = File { uuid, name, opened };
         match self.files.get_mut(&parent_node) {
             Some(list) => list.push(file),
             None => {
@@ -212,7 +571,19 @@ impl Tree {
         }
     }
 
-    pub fn add_folder(&mut self, uuid: Uuid, parent_uuid: Uuid, name: String) {
+    pub fn pin(&mut self, uuid: Uuid) {
+        self.pinned.insert(uuid);
+    }
+
+    /// The pinned documents, leaving out those in the trash
+    pub fn pinned(&self) -> Vec<Uuid> {
+        self.descendant_files(self.root)
+            .into_iter()
+            .filter(|uuid| self.pinned.contains(uuid))
+            .collect()
+    }
+
+    pub fn add_folder(&mut self, uuid: Uuid, parent: Parent, name: String) {
         let node_id = if let Some(node) = self.node.get(&uuid) {
             *node
         } else {
@@ -223,51 +594,208 @@ impl Tree {
 
         self.name.insert(node_id, name);
 
-        let parent_node_id = if let Some(p) = self.node.get(&parent_uuid) {
-            *p
-        } else {
-            let parent_node_id = self.arena.new_node(());
-            self.node.insert(parent_uuid, parent_node_id);
-            parent_node_id
+        let parent_node_id = self.parent_node(parent);
+        if parent_node_id
+            .checked_append(node_id, &mut self.arena)
+            .is_err()
+        {
+            log::warn!("folder {uuid} is inside itself, treating it as orphaned");
+            self.orphaned.append(node_id, &mut self.arena);
+        }
+    }
+
+    /// Moves folders we only know of as a parent, their metadata is gone,
+    /// to [`ORPHANED`]. They are named after their uuid. Returns the number
+    /// of documents that ended up there.
+    fn adopt_orphans(&mut self) -> usize {
+        let missing: Vec<_> = self
+            .node
+            .iter()
+            .filter(|(_, node)| !self.name.contains_key(node))
+            .map(|(uuid, node)| (*uuid, *node))
+            .collect();
+        for (uuid, node) in missing {
+            self.name.insert(node, uuid.to_string());
+            self.orphaned.append(node, &mut self.arena);
+        }
+        self.descendant_files(self.orphaned).len()
+    }
+
+    /// Where every document and folder is, except for orphaned folders
+    pub fn parents(&self) -> HashMap<Uuid, Parent> {
+        let uuids: HashMap<NodeId, Uuid> = self.node.iter().map(|(u, n)| (*n, *u)).collect();
+        let parent_of = |node: NodeId| match node {
+            node if node == self.root => Some(Parent::Root),
+            node if node == self.trash => Some(Parent::Trash),
+            node => uuids.get(&node).copied().map(Parent::Folder),
         };
-        parent_node_id.append(node_id, &mut self.arena);
+        let mut parents = HashMap::new();
+        for (node, files) in &self.files {
+            if let Some(parent) = parent_of(*node) {
+                parents.extend(files.iter().map(|file| (file.uuid, parent)));
+            }
+        }
+        for (uuid, node) in &self.node {
+            let parent = self.arena[*node].parent().and_then(parent_of);
+            if let Some(parent) = parent {
+                parents.insert(*uuid, parent);
+            }
+        }
+        parents
+    }
+
+    /// The documents in the trash, directly or in a trashed folder, that
+    /// were in one of `roots` before. Where they were is looked up in
+    /// `was_in` as the trash does not keep it.
+    pub fn trashed_from(&self, roots: &[NodeId], was_in: &HashMap<Uuid, Parent>) -> Vec<Uuid> {
+        let came_from_roots = |uuid: &Uuid| {
+            let node = match was_in.get(uuid) {
+                Some(Parent::Root) => self.root,
+                Some(Parent::Folder(folder)) => match self.node.get(folder) {
+                    Some(node) => *node,
+                    None => return false,
+                },
+                Some(Parent::Trash) | None => return false,
+            };
+            node.ancestors(&self.arena).any(|n| roots.contains(&n))
+        };
+
+        let mut docs: Vec<Uuid> = self
+            .files
+            .get(&self.trash)
+            .into_iter()
+            .flatten()
+            .map(|file| file.uuid)
+            .filter(came_from_roots)
+            .collect();
+        for (uuid, node) in &self.node {
+            let trashed = self.arena[*node].parent() == Some(self.trash);
+            if trashed && came_from_roots(uuid) {
+                docs.extend(self.descendant_files(*node));
+            }
+        }
+        docs
+    }
+
+    pub fn trash(&self) -> SubTree<'_> {
+        self.subtree(self.trash)
+    }
+
+    /// The documents in folders that no longer exist, if there are any
+    pub fn orphaned(&self) -> Option<SubTree<'_>> {
+        self.orphaned
+            .children(&self.arena)
+            .next()
+            .is_some()
+            .then(|| self.subtree(self.orphaned))
     }
 }
 
 pub fn map() -> Result<(Tree, HashMap<String, Uuid>)> {
+    map_dirs(&[crate::device::current().documents()])
+}
+
+/// Maps the documents and folders in all of `dirs`, used to include the
+/// documents moved to a safe
+pub fn map_dirs(dirs: &[&Path]) -> Result<(Tree, HashMap<String, Uuid>)> {
     let mut tree = Tree::new();
     let mut index = HashMap::new();
 
-    for entry in fs::read_dir(DIR).wrap_err("remarkable data directory not found")? {
-        let path = entry.unwrap().path();
+    let mut entries = Vec::new();
+    for dir in dirs {
+        let dir_entries = fs::read_dir(dir)
+            .wrap_err_with(|| format!("data directory not found: {}", dir.display()))?;
+        entries.extend(dir_entries);
+    }
+    // one broken file should not stop us from locking the rest
+    let mut skipped = 0;
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                log::warn!("skipping unreadable dir entry: {e}");
+                skipped += 1;
+                continue;
+            }
+        };
         let ext = path.extension().and_then(OsStr::to_str);
         match ext {
-            Some(e) if e == "metadata" => (),
+            Some("metadata") => (),
             Some(_) | None => continue,
         }
 
-        let uuid = Uuid(path.file_stem().unwrap().to_str().unwrap().to_owned());
-        let metadata = fs::read_to_string(path).unwrap();
-        let parent_uuid = Uuid(parent(&metadata).unwrap().to_owned());
-        let name = name(&metadata).unwrap().to_owned();
-        index.insert(name.clone(), uuid.clone());
-
-        if is_folder(&metadata) {
-            tree.add_folder(uuid, parent_uuid, name);
-        } else {
-            tree.add_file(uuid, parent_uuid, name);
+        let entry = match parse_entry(&path) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("skipping {}: {e:#}", path.display());
+                skipped += 1;
+                continue;
+            }
+        };
+        index.insert(entry.name.clone(), entry.uuid);
+        if entry.pinned {
+            tree.pin(entry.uuid);
         }
+        match entry.opened {
+            None => tree.add_folder(entry.uuid, entry.parent, entry.name),
+            Some(opened) => tree.add_file(entry.uuid, entry.parent, entry.name, opened),
+        }
+    }
+    let orphans = tree.adopt_orphans();
+    if orphans > 0 {
+        log::warn!(
+            "{orphans} documents are in folders that no longer exist, \
+            they can not be locked and are listed under {ORPHANED}"
+        );
+    }
+    if skipped > 0 {
+        log::warn!(
+            "skipped {skipped} documents or folders that could not be read, \
+            they are left alone and not in the report"
+        );
     }
     Ok((tree, index))
 }
 
+/// What we need from a `.metadata` file
+struct Entry {
+    uuid: Uuid,
+    parent: Parent,
+    name: String,
+    /// None for folders
+    opened: Option<Opened>,
+    pinned: bool,
+}
+
+fn parse_entry(path: &Path) -> Result<Entry> {
+    // such as backups made by an editor, xochitl ignores them too
+    let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let uuid = Uuid::parse(stem).wrap_err("Not named after a uuid")?;
+    let metadata = fs::read_to_string(path).wrap_err("Could not read file")?;
+    let parent = parent(&metadata).ok_or_else(|| eyre!("No parent"))?;
+    let parent = Parent::parse(parent).wrap_err("Invalid parent")?;
+    let name = name(&metadata).ok_or_else(|| eyre!("No visibleName"))?;
+    let opened = if is_folder(&metadata)? {
+        None
+    } else {
+        Some(opened(&metadata, crate::device::current().layout))
+    };
+    Ok(Entry {
+        uuid,
+        parent,
+        name: name.to_owned(),
+        opened,
+        pinned: pinned(&metadata),
+    })
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
 
     #[test]
     fn extract_parent_id() {
-        let metadata = r###"
+        let metadata = r#"
 {
     "deleted": false,
     "lastModified": "1633603894527",
@@ -282,12 +810,25 @@ pub mod test {
     "version": 1,
     "visibleName": "Paper selection"
 }
-"###;
+"#;
 
         assert_eq!(
             Some("95318cc7-f844-416f-963a-cf277c83f10c"),
             parent(metadata)
-        )
+        );
+    }
+
+    #[test]
+    fn extract_last_opened() {
+        let metadata = r#"{"visibleName":"Dune","type":"DocumentType","parent":"","lastOpened":"1654958754102","lastOpenedPage":12,"version":0}"#;
+        let dune = opened(metadata, Layout::V2);
+        assert_eq!(dune.at.unwrap().unix_timestamp(), 1_654_958_754);
+        assert_eq!(dune.page, Some(12));
+        // 3.x no longer updates the page in the metadata
+        assert_eq!(opened(metadata, Layout::V3).page, None);
+
+        let never = r#"{"visibleName":"CMS","type":"CollectionType","parent":"","lastOpened":"","version":0}"#;
+        assert_eq!(opened(never, Layout::V2), Opened::default());
     }
 
     #[test]
@@ -296,7 +837,7 @@ pub mod test {
         assert_eq!(
             Some("0b7d1978-dc97-4433-8e31-ad6ff7fe1cf7"),
             parent(metadata)
-        )
+        );
     }
 
     #[test]
@@ -322,7 +863,7 @@ pub mod test {
     #[test]
     fn extract_type_with_spaces() {
         let metadata = "{\n    \"deleted\": false,\n    \"lastModified\": \"1643992474183\",\n    \"lastOpened\": \"1643992259259\",\n    \"lastOpenedPage\": 0,\n    \"metadatamodified\": false,\n    \"modified\": false,\n    \"parent\": \"3055805b-54c9-4950-9492-ff97ee603764\",\n    \"pinned\": false,\n    \"synced\": true,\n    \"type\": \"DocumentType\",\n    \"version\": 2,\n    \"visibleName\": \"Book recs\"\n}\n";
-        assert!(!is_folder(metadata));
+        assert!(!is_folder(metadata).unwrap());
     }
 
     #[test]
@@ -331,7 +872,129 @@ pub mod test {
         assert_eq!(Some("Missing semester"), name(metadata));
     }
 
-    #[cfg(test)]
+    /// A uuid made from a short name so tests stay readable
+    pub fn id(name: &str) -> Uuid {
+        let mut bytes = [0u8; 16];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Uuid(uuid::Uuid::from_bytes(bytes))
+    }
+
+    #[test]
+    fn uuids() {
+        let uuid = "95318cc7-f844-416f-963a-cf277c83f10c";
+        assert_eq!(Uuid::parse(uuid).unwrap().to_string(), uuid);
+        assert!(Uuid::parse(&uuid.to_uppercase()).is_err());
+        assert!(Uuid::parse("95318cc7f844416f963acf277c83f10c").is_err());
+        assert!(Uuid::parse(".hidden").is_err());
+        assert!(Uuid::parse("").is_err());
+        assert_eq!(Parent::parse("trash").unwrap(), Parent::Trash);
+        assert_eq!(Parent::parse("").unwrap(), Parent::Root);
+        let folder = Parent::parse(uuid).unwrap();
+        assert_eq!(Parent::parse(&folder.to_string()).unwrap(), folder);
+    }
+
+    #[test]
+    fn skips_broken_files() {
+        let root = crate::device::simulate_in_temp_dir("directory");
+        let metadata = r#"{"parent": "", "type": "DocumentType", "visibleName": "Dune"}"#;
+        let uuid = "95318cc7-f844-416f-963a-cf277c83f10c";
+        for file in [uuid, "backup.of.dune", ".hidden"] {
+            fs::write(root.join(file).with_extension("metadata"), metadata).unwrap();
+        }
+        fs::write(root.join("dune.metadata~"), metadata).unwrap();
+
+        let broken = [
+            r#"{"parent": "", "type": "TemplateType", "visibleName": "Grid"}"#,
+            r#"{"type": "DocumentType", "visibleName": "Orphan"}"#,
+            r#"{"parent": "", "type": "DocumentType"}"#,
+            r#"{"parent": "not a folder", "type": "DocumentType", "visibleName": "Lost"}"#,
+            "",
+        ];
+        for (i, metadata) in broken.into_iter().enumerate() {
+            let file = format!("00000000-0000-4000-8000-00000000000{i}.metadata");
+            fs::write(root.join(file), metadata).unwrap();
+        }
+        fs::write(
+            root.join("00000000-0000-4000-8000-000000000009.metadata"),
+            [0xff, 0xfe],
+        )
+        .unwrap();
+
+        let (tree, index) = map_dirs(&[&root]).unwrap();
+        assert_eq!(
+            tree.descendant_files(*tree.root()),
+            [Uuid::parse(uuid).unwrap()]
+        );
+        assert_eq!(index.len(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn orphans() {
+        let mut tree = test_tree();
+        let opened = Opened::default();
+        // the metadata of G0 and H0 is gone
+        tree.add_file(id("g1"), Parent::Folder(id("G0")), "g1".into(), opened);
+        tree.add_folder(id("H1"), Parent::Folder(id("H0")), "H1".into());
+        tree.add_file(id("h2"), Parent::Folder(id("H1")), "h2".into(), opened);
+        // corrupt metadata putting folders inside each other
+        tree.add_folder(id("L0"), Parent::Folder(id("L1")), "L0".into());
+        tree.add_folder(id("L1"), Parent::Folder(id("L0")), "L1".into());
+        tree.add_file(id("l2"), Parent::Folder(id("L1")), "l2".into(), opened);
+
+        assert_eq!(tree.adopt_orphans(), 3);
+        let print = format!("{tree}");
+        assert!(!print.contains("g1") && !print.contains("h2") && !print.contains("L1"));
+        let orphaned = tree.orphaned().unwrap();
+        assert_eq!(orphaned.path, Path::new(ORPHANED));
+        let print = format!("{orphaned}");
+        assert!(print.contains(&id("G0").to_string()));
+        assert!(print.contains("|-- h2") && print.contains("|-- l2"));
+        // locking everything does not reach them
+        assert_eq!(tree.descendant_files(*tree.root()).len(), 5);
+    }
+
+    #[test]
+    fn trashed() {
+        let mut tree = test_tree();
+        let opened = Opened::default();
+        tree.add_file(id("t0"), Parent::Trash, "t0".into(), opened);
+        tree.add_file(id("t1"), Parent::Trash, "t1".into(), opened);
+        tree.add_folder(id("T0"), Parent::Trash, "T0".into());
+        tree.add_file(id("t2"), Parent::Folder(id("T0")), "t2".into(), opened);
+
+        let parents = tree.parents();
+        assert_eq!(parents[&id("a2")], Parent::Folder(id("A1")));
+        assert_eq!(parents[&id("A0")], Parent::Root);
+        assert_eq!(parents[&id("T0")], Parent::Trash);
+        assert_eq!(parents[&id("t0")], Parent::Trash);
+
+        // t0 was in A1, T0 in A0, nothing is known about t1
+        let was_in = HashMap::from([
+            (id("t0"), Parent::Folder(id("A1"))),
+            (id("T0"), Parent::Folder(id("A0"))),
+        ]);
+        let a0 = tree.node_for("A0").unwrap();
+        assert_eq!(tree.trashed_from(&[a0], &was_in), [id("t0"), id("t2")]);
+        let b0 = tree.node_for("B0").unwrap();
+        assert!(tree.trashed_from(&[b0], &was_in).is_empty());
+        assert!(format!("{}", tree.trash()).contains("|-- t1"));
+    }
+
+    #[test]
+    fn recently_opened() {
+        let mut tree = test_tree();
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let opened = |minutes_ago| Opened {
+            at: Some(now - time::Duration::minutes(minutes_ago)),
+            page: None,
+        };
+        tree.add_file(id("r0"), Parent::Root, "r0".into(), opened(2));
+        tree.add_file(id("r1"), Parent::Folder(id("A1")), "r1".into(), opened(30));
+        let since = now - time::Duration::minutes(5);
+        assert_eq!(tree.opened_since(since), HashSet::from([id("r0")]));
+    }
+
     pub fn test_tree() -> Tree {
         let node_parent_pairs = [
             ("a0", ""),
@@ -347,10 +1010,15 @@ pub mod test {
 
         let mut tree = Tree::new();
         for (name, parent) in node_parent_pairs {
+            let parent = match parent {
+                "" => Parent::Root,
+                folder => Parent::Folder(id(folder)),
+            };
             if name.chars().next().unwrap().is_uppercase() {
-                tree.add_folder(name.into(), Uuid(parent.to_owned()), name.into());
+                tree.add_folder(id(name), parent, name.into());
             } else {
-                tree.add_file(name.into(), Uuid(parent.to_owned()), name.to_owned());
+                let opened = Opened::default();
+                tree.add_file(id(name), parent, name.to_owned(), opened);
             }
         }
         tree
@@ -377,7 +1045,7 @@ pub mod test {
         assert_eq!("", root_name);
 
         let print = format!("{tree}");
-        let correct = r###"
+        let correct = r"
     |-- a0
     |-- b0
     |-- A0
@@ -387,16 +1055,182 @@ pub mod test {
     |-- B0
         |-- b1
         |-- B1
-"###;
+";
+        assert_eq!(print, correct);
+    }
+
+    #[test]
+    fn items() {
+        let mut tree = test_tree();
+        tree.add_file(id("t0"), Parent::Trash, "t0".to_owned(), Opened::default());
+        let items = tree.items();
+        let paths: Vec<_> = items
+            .iter()
+            .map(|e| (e.path.to_str().unwrap(), e.folder))
+            .collect();
+        assert_eq!(paths.len(), 10);
+        let position = |path| paths.iter().position(|(p, _)| *p == path).unwrap();
+        assert!(position("A0") < position("A0/A1") && position("A0/A1") < position("A0/A1/a2"));
+        assert!(paths.contains(&("B0/B1", true)) && paths.contains(&("b0", false)));
+        assert!(paths.contains(&("trash/t0", false)));
+    }
+
+    #[test]
+    fn print_without() {
+        let tree = test_tree();
+        let a1 = tree.node_for("A0/A1").unwrap();
+        let b0 = tree.node_for("B0").unwrap();
+        let print = format!("{}", tree.subtree(*tree.root()).without(&[a1, b0]));
+        let correct = r"
+    |-- a0
+    |-- b0
+    |-- A0
+        |-- a1
+";
         assert_eq!(print, correct);
     }
 
+    #[test]
+    fn print_sizes() {
+        let root = crate::device::simulate_in_temp_dir("sizes");
+        let documents = crate::device::current().documents();
+        fs::create_dir_all(documents.join(id("a1").to_string())).unwrap();
+        let file =
+            |name: String, len: usize| fs::write(documents.join(name), vec![0; len]).unwrap();
+        file(format!("{}.pdf", id("a1")), 300_000);
+        file(format!("{}/page.rm", id("a1")), 200_000);
+        file(format!("{}.epub", id("a2")), 1_000_000);
+        file("not-a-uuid.pdf".to_owned(), 5_000_000);
+
+        let mut tree = test_tree();
+        assert_eq!(tree.folder_size(*tree.root()), None);
+        tree.measure(documents);
+        let a0 = tree.node_for("A0").unwrap();
+        assert_eq!(tree.folder_size(a0), Some(1_500_000));
+        let print = format!("{}", tree.subtree(a0));
+        assert_eq!(
+            print,
+            "A0 (1.5 MB)\n    |-- a1\n    |-- A1 (1.0 MB)\n        |-- a2\n"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn folders_by_id() {
+        let mut tree = test_tree();
+        assert_eq!(tree.folder_id("A0/A1"), Some(id("A1")));
+        assert_eq!(tree.folder_id(""), None);
+        assert_eq!(tree.folder_id("A0/a1"), None);
+        // renamed and moved into B0
+        tree.add_folder(id("A1"), Parent::Folder(id("B0")), "A2".into());
+        assert_eq!(tree.folder_path(id("A1")).as_deref(), Some("B0/A2"));
+        tree.add_folder(id("A1"), Parent::Trash, "A2".into());
+        assert_eq!(tree.folder_path(id("A1")), None);
+    }
+
+    #[test]
+    fn doc_paths() {
+        let tree = test_tree();
+        let paths = tree.doc_paths();
+        assert_eq!(paths.len(), 5);
+        assert_eq!(paths[&id("a2")], Path::new("A0/A1/a2"));
+        assert_eq!(paths[&id("b0")], Path::new("b0"));
+    }
+
+    #[test]
+    fn pinned_docs() {
+        assert!(pinned(
+            r#"{"parent": "", "pinned": true, "type": "DocumentType"}"#
+        ));
+        assert!(pinned("{\n    \"pinned\":true\n}"));
+        assert!(!pinned(r#"{"pinned": false, "type": "DocumentType"}"#));
+
+        let mut tree = test_tree();
+        tree.add_file(id("t0"), Parent::Trash, "t0".into(), Opened::default());
+        for doc in ["a2", "b0", "t0"] {
+            tree.pin(id(doc));
+        }
+        let mut found = tree.pinned();
+        found.sort_by_key(ToString::to_string);
+        let mut expected = [id("a2"), id("b0")];
+        expected.sort_by_key(ToString::to_string);
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn file_types() {
+        let root = crate::device::simulate_in_temp_dir("file-types");
+        let documents = crate::device::current().documents();
+        fs::create_dir(documents).unwrap();
+        let [book, notes, old, unknown] = ["book", "notes", "old", "unknown"].map(id);
+        for (uuid, kind) in [(book, "epub"), (notes, "notebook"), (old, "")] {
+            let content = format!("{{\n    \"fileType\": \"{kind}\",\n    \"pageCount\": 3\n}}");
+            fs::write(documents.join(format!("{uuid}.content")), content).unwrap();
+        }
+        assert_eq!(FileType::of(book), Some(FileType::Epub));
+        assert_eq!(FileType::of(old), Some(FileType::Notebook));
+        assert_eq!(FileType::of(unknown), None);
+
+        let skip = Filter {
+            skip_types: vec![FileType::Notebook],
+            ..Filter::default()
+        };
+        assert!(skip.locks(book) && !skip.locks(notes) && skip.locks(unknown));
+        let only = Filter {
+            only_types: vec![FileType::Pdf, FileType::Epub],
+            ..Filter::default()
+        };
+        assert!(only.locks(book) && !only.locks(old) && only.locks(unknown));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn size_and_age() {
+        let root = crate::device::simulate_in_temp_dir("size-and-age");
+        let documents = crate::device::current().documents();
+        fs::create_dir(documents).unwrap();
+        let [big, small, unknown] = ["big", "small", "unknown"].map(id);
+        let now = OffsetDateTime::now_utc();
+        for (uuid, bytes, days) in [(big, 2000, 1), (small, 20, 60)] {
+            let path = documents.join(uuid.to_string());
+            fs::write(path.with_extension("pdf"), vec![0; bytes]).unwrap();
+            let modified = (now - time::Duration::days(days)).unix_timestamp() * 1000;
+            let metadata = format!(r#"{{"lastModified": "{modified}", "type": "DocumentType"}}"#);
+            fs::write(path.with_extension("metadata"), metadata).unwrap();
+        }
+
+        let large = Filter {
+            min_size: Some(1000),
+            ..Filter::default()
+        };
+        assert!(large.locks(big) && !large.locks(small) && !large.locks(unknown));
+        let recent = Filter {
+            added_after: Some(now - time::Duration::days(30)),
+            ..Filter::default()
+        };
+        assert!(recent.locks(big) && !recent.locks(small) && recent.locks(unknown));
+        fs::remove_dir_all(root).unwrap();
+    }
+
     #[test]
     fn children() {
         let tree = test_tree();
         let node = tree.node_for("A0").unwrap();
         let files = tree.descendant_files(node);
-        assert_eq!(files, vec!("a1".into(), "a2".into()));
+        assert_eq!(files, vec!(id("a1"), id("a2")));
+    }
+
+    #[test]
+    fn fuzzy_paths() {
+        let mut tree = test_tree();
+        tree.add_folder(id("Comics"), Parent::Root, "Comics ".into());
+        tree.add_folder(id("C0"), Parent::Root, "Manga".into());
+        tree.add_folder(id("C1"), Parent::Root, "manga".into());
+        assert_eq!(tree.fuzzy_path("a0/a1").as_deref(), Some("A0/A1"));
+        assert_eq!(tree.fuzzy_path("comics").as_deref(), Some("Comics "));
+        assert_eq!(tree.fuzzy_path("manga"), Some("manga".to_owned()));
+        assert_eq!(tree.fuzzy_path("MANGA"), None);
+        assert_eq!(tree.fuzzy_path("A0/A2"), None);
     }
 
     #[test]
@@ -404,15 +1238,6 @@ pub mod test {
         let tree = test_tree();
         let node = tree.node_for("").unwrap();
         let files = tree.descendant_files(node);
-        assert_eq!(
-            files,
-            vec!(
-                "a0".into(),
-                "b0".into(),
-                "a1".into(),
-                "a2".into(),
-                "b1".into()
-            )
-        );
+        assert_eq!(files, ["a0", "b0", "a1", "a2", "b1"].map(id));
     }
 }
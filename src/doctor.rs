@@ -0,0 +1,242 @@
+//! Checks the environment book-safe needs, most support issues come down
+//! to one of these.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use color_eyre::{eyre::eyre, Result};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+use crate::util::cmd::{self, SysCmd};
+use crate::{device, scheduler, sync, systemd, util};
+
+/// Free space needed for the report and the state, moving documents
+/// into the safe does not take space
+pub const MIN_FREE_KB: u64 = 10 * 1024;
+
+struct Problem {
+    reason: String,
+    suggestion: &'static str,
+}
+
+impl Problem {
+    fn new(reason: String, suggestion: &'static str) -> Self {
+        Self { reason, suggestion }
+    }
+}
+
+type Outcome = std::result::Result<String, Problem>;
+
+pub fn run() -> Result<()> {
+    let mut checks: Vec<(&str, Outcome)> = vec![
+        ("document dir", documents()),
+        ("firmware", firmware()),
+        ("running as root", root()),
+        ("systemd", systemd()),
+        ("timedatectl", timedatectl()),
+        ("routing table", routes()),
+        ("free space", free_space()),
+        ("clock", clock()),
+        ("route cache", cache()),
+    ];
+    checks.extend(installed());
+
+    let mut failed = 0;
+    for (name, outcome) in checks {
+        match outcome {
+            Ok(detail) => println!("[ ok ] {name}: {detail}"),
+            Err(problem) => {
+                failed += 1;
+                println!("[FAIL] {name}: {}", problem.reason);
+                println!("       suggestion: {}", problem.suggestion);
+            }
+        }
+    }
+
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(eyre!("{failed} check(s) failed"))
+    }
+}
+
+fn documents() -> Outcome {
+    let dir = device::current().documents();
+    let entries = fs::read_dir(dir).map_err(|e| {
+        Problem::new(
+            format!("{}: {e}", dir.display()),
+            "is xochitl's data stored elsewhere on this firmware? Please open an issue",
+        )
+    })?;
+    Ok(format!("{} ({} entries)", dir.display(), entries.count()))
+}
+
+fn firmware() -> Outcome {
+    let device = device::current();
+    if !device.is_remarkable() {
+        return Ok("not on a remarkable".to_owned());
+    }
+    match device.firmware {
+        Some(version) if device.layout != device::Layout::UNKNOWN => Ok(version.to_string()),
+        Some(version) => Err(Problem::new(
+            format!("{version} is unknown, files of documents might be missed"),
+            "please open an issue mentioning the firmware version",
+        )),
+        None => Err(Problem::new(
+            "could not find the version".to_owned(),
+            "please open an issue mentioning the firmware version",
+        )),
+    }
+}
+
+fn root() -> Outcome {
+    let uid = fs::metadata("/proc/self")
+        .map_err(|e| Problem::new(e.to_string(), "is /proc mounted?"))?
+        .uid();
+    if uid == 0 {
+        Ok("yes".to_owned())
+    } else {
+        Err(Problem::new(
+            format!("running as uid {uid}"),
+            "run book-safe as root, it needs to move files, change routes and manage services",
+        ))
+    }
+}
+
+fn systemd() -> Outcome {
+    systemd::version().map_err(|e| {
+        Problem::new(
+            format!("{e:#}"),
+            "book-safe talks to systemd over the system bus, is dbus running?",
+        )
+    })
+}
+
+fn timedatectl() -> Outcome {
+    let output = cmd::System
+        .output("timedatectl", &["--version"])
+        .map_err(|e| {
+            Problem::new(
+                e.to_string(),
+                "timedatectl is needed to check the clock is synchronized",
+            )
+        })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or_default().to_owned())
+}
+
+fn routes() -> Outcome {
+    let n = sync::blocked_routes().map_err(|e| {
+        Problem::new(
+            format!("{e:#}"),
+            "`route` and `ip` are needed to block sync, or use --sync-blocker wifi",
+        )
+    })?;
+    Ok(format!("readable, {n} reject routes"))
+}
+
+fn free_space() -> Outcome {
+    let dir = device::current().documents();
+    let available = available_kb(&cmd::System, dir)
+        .map_err(|e| Problem::new(format!("{e:#}"), "check `df` is installed"))?;
+    if available >= MIN_FREE_KB {
+        Ok(format!("{} MB available", available / 1024))
+    } else {
+        Err(Problem::new(
+            format!("only {available} kB available"),
+            "free up space, book-safe needs some to write its report",
+        ))
+    }
+}
+
+pub fn available_kb(cmd: &impl SysCmd, dir: &Path) -> Result<u64> {
+    let output = cmd.output("df", &["-Pk", &dir.to_string_lossy()])?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_df(&stdout).ok_or_else(|| eyre!("could not parse df output: {stdout}"))
+}
+
+/// Parses the available column of `df -P` output
+fn parse_df(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+fn clock() -> Outcome {
+    let now = OffsetDateTime::now_utc();
+    util::time::ntp_synchronized()
+        .and_then(|synced| util::time::check_clock(now, synced))
+        .map(|()| format!("{now}"))
+        .map_err(|e| {
+            Problem::new(
+                format!("{e}"),
+                "connect to wifi so the clock is set, locking depends on the time",
+            )
+        })
+}
+
+fn cache() -> Outcome {
+    let n = sync::cached_ips().map_err(|e| {
+        Problem::new(
+            format!("{e:#}"),
+            "remove the route cache with `book-safe cache clear` while unlocked",
+        )
+    })?;
+    Ok(format!("{n} ips cached"))
+}
+
+fn installed() -> Vec<(&'static str, Outcome)> {
+    let profiles = match Profile::installed() {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            let problem = Problem::new(format!("{e:#}"), "check the state dir is readable");
+            return vec![("installed profiles", Err(problem))];
+        }
+    };
+    if profiles.is_empty() {
+        return vec![("installed profiles", Ok("none".to_owned()))];
+    }
+
+    profiles
+        .iter()
+        .map(|profile| {
+            let kind = systemd::Installed::load(profile)
+                .ok()
+                .flatten()
+                .map(|installed| installed.scheduler)
+                .unwrap_or_default();
+            let scheduler = scheduler::new(kind);
+            let name = scheduler.name();
+            let outcome = match scheduler.state(profile) {
+                Ok(state) if state == "active" => Ok(format!("{profile}: {name} active")),
+                Ok(state) => Err(Problem::new(
+                    format!("{profile}: {name} is {state}"),
+                    "run `book-safe unlock` to restore the units, or install again",
+                )),
+                Err(e) => Err(Problem::new(
+                    format!("{profile}: {e:#}"),
+                    "run `book-safe unlock` to restore the units, or install again",
+                )),
+            };
+            ("installed profile", outcome)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn df_output() {
+        let output = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n\
+            /dev/mmcblk2p4     6722476 1393928   4965236      22% /home\n";
+        assert_eq!(parse_df(output), Some(4_965_236));
+    }
+}
@@ -0,0 +1,109 @@
+//! Prints the settings book-safe runs with: the config file merged with
+//! the defaults and, for every profile, the arguments it was installed
+//! with and the folders those resolve to.
+
+use color_eyre::{eyre::WrapErr, Result};
+use itertools::Itertools;
+use serde_json::Value;
+
+use crate::config::{self, Config};
+use crate::directory::{self, Tree, Uuid};
+use crate::profile::Profile;
+use crate::systemd::Installed;
+use crate::util::cmd::quote;
+
+pub fn show(config: &Config) -> Result<()> {
+    let path = config::path();
+    let found = if path.exists() { "" } else { ", not found" };
+    println!("config file: {}{found}", path.display());
+    let file = Config::load_json()?;
+    let effective = serde_json::to_value(config)?;
+    for line in settings(&effective, &file) {
+        println!("  {line}");
+    }
+
+    let profiles = Profile::installed()?;
+    if profiles.is_empty() {
+        println!("no profiles installed");
+        return Ok(());
+    }
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    for profile in profiles {
+        let Some(installed) = Installed::load(&profile)? else {
+            continue;
+        };
+        let args = crate::recorded_args(&installed)?;
+        let recorded = installed.args.iter().map(|arg| quote(arg)).join(" ");
+        println!("\nprofile {profile}, installed with: {recorded}");
+        let timezone = match (&args.timezone, &config.timezone) {
+            (Some(tz), _) => format!("{tz} (--timezone)"),
+            (None, Some(tz)) => format!("{tz} (config file)"),
+            (None, None) => "os timezone (default)".to_owned(),
+        };
+        println!("  timezone: {timezone}");
+        println!("  safe: {}", profile.safe_dir().display());
+        if args.lock_all {
+            println!("  folders: all");
+        }
+        for path in args.all_paths().iter().chain(&args.except) {
+            if !path.is_empty() {
+                println!("  {path}: {}", folder(&tree, &installed, path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every setting with its value and whether it is set in the config file
+/// or is the default
+fn settings(effective: &Value, file: &Value) -> Vec<String> {
+    let Some(effective) = effective.as_object() else {
+        return Vec::new();
+    };
+    effective
+        .iter()
+        .map(|(key, value)| {
+            let source = if file.get(key).is_some() {
+                "config file"
+            } else {
+                "default"
+            };
+            format!("{key}: {value} ({source})")
+        })
+        .collect()
+}
+
+/// The uuid `path` was recorded with at install and where that folder
+/// is now
+fn folder(tree: &Tree, installed: &Installed, path: &str) -> String {
+    let Some(recorded) = installed.folders.get(path) else {
+        return match tree.folder_id(path) {
+            Some(uuid) => format!("{uuid} (by name, not recorded at install)"),
+            None => "not found".to_owned(),
+        };
+    };
+    let now = Uuid::parse(recorded)
+        .ok()
+        .and_then(|uuid| tree.folder_path(uuid));
+    match now {
+        Some(now) if now == path => recorded.clone(),
+        Some(now) => format!("{recorded}, moved to {now}"),
+        None => format!("{recorded}, gone: locking {path} by its name"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        let file = serde_json::json!({"timezone": "Europe/Amsterdam"});
+        let config: Config = serde_json::from_value(file.clone()).unwrap();
+        let effective = serde_json::to_value(&config).unwrap();
+        let lines = settings(&effective, &file);
+        assert!(lines.contains(&r#"timezone: "Europe/Amsterdam" (config file)"#.to_owned()));
+        assert!(lines.contains(&"sync_refresh_minutes: 30 (default)".to_owned()));
+        assert!(lines.contains(&"notify_url: null (default)".to_owned()));
+    }
+}
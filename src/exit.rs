@@ -0,0 +1,99 @@
+//! Exit codes so scripts and the service can tell failures apart. Errors
+//! that need their own code carry a [`Failure`] as context, everything
+//! else exits with 1. Clap exits with 2 on invalid arguments.
+
+use std::fmt;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use color_eyre::Report;
+
+/// Set when folders went missing while locking, the rest was locked
+static PARTIAL: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The config file could not be read or is invalid
+    Config,
+    /// The ui or the documents of the device could not be found
+    Device,
+    /// Sync could not be blocked
+    Block,
+    /// Sync could not be unblocked
+    Unblock,
+    /// Folders to lock do not exist, or went missing while locking
+    Missing,
+    /// Found nothing to lock and the config makes that a failure
+    Empty,
+    /// Refused as it would get around the lock, or the approval token
+    /// was wrong
+    Refused,
+    /// Stopped by a signal, the work done was undone
+    Interrupted,
+}
+
+impl Failure {
+    pub const fn code(self) -> u8 {
+        match self {
+            Failure::Config => 3,
+            Failure::Device => 4,
+            Failure::Block | Failure::Unblock => 5,
+            Failure::Missing => 6,
+            Failure::Refused => 7,
+            Failure::Empty => 8,
+            Failure::Interrupted => 130,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Config => "Could not load config",
+            Failure::Device => "Could not find the ui service",
+            Failure::Block => "Could not block sync",
+            Failure::Unblock => "Could not unblock sync",
+            Failure::Missing => "Could not find folders",
+            Failure::Refused => "Not allowed",
+            Failure::Empty => "Found nothing to lock",
+            Failure::Interrupted => "Interrupted",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Exit with [`Failure::Missing`] even if nothing else went wrong
+pub fn partial() {
+    PARTIAL.store(true, Ordering::SeqCst);
+}
+
+pub fn code(res: &Result<(), Report>) -> ExitCode {
+    let code = match res {
+        Ok(()) if PARTIAL.load(Ordering::SeqCst) => Failure::Missing.code(),
+        Ok(()) => 0,
+        Err(report) => report.downcast_ref::<Failure>().map_or(1, |f| f.code()),
+    };
+    ExitCode::from(code)
+}
+
+#[cfg(test)]
+mod test {
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn codes_survive_context() {
+        let err: Result<(), Report> = Err(eyre!("no route to host"))
+            .wrap_err(Failure::Block)
+            .wrap_err("Could not lock");
+        assert_eq!(err.unwrap_err().downcast_ref(), Some(&Failure::Block));
+
+        let err: Result<(), Report> = Err(Report::new(Failure::Interrupted)).wrap_err("Locking");
+        assert_eq!(err.unwrap_err().downcast_ref(), Some(&Failure::Interrupted));
+
+        let untagged = eyre!("Something else");
+        assert_eq!(untagged.downcast_ref::<Failure>(), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Fills the documents dir used by `--simulate` with a made up library:
+//! nested folders holding pdfs, epubs and notebooks, some opened recently
+//! and one in the trash. Lets contributors try locking, unlocking and the
+//! report without a reMarkable. The same number of documents always gives
+//! the same library.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::{Help, Result};
+
+use crate::device;
+
+/// Folders as seen in the ui, parents come before their children
+pub const FOLDERS: &[&str] = &[
+    "Books",
+    "Books/Fantasy",
+    "Books/Science fiction",
+    "Books/Science fiction/Classics",
+    "Comics",
+    "Comics/Manga",
+    "Articles",
+    "Articles/hobby",
+    "Notes",
+];
+
+const ADJECTIVES: &[&str] = &[
+    "Silent", "Hidden", "Last", "Burning", "Distant", "Broken", "Golden", "Quiet", "Lost",
+    "Endless", "Northern", "Crimson",
+];
+const NOUNS: &[&str] = &[
+    "Harbor", "Kingdom", "Garden", "Machine", "River", "Empire", "Library", "Signal", "Orchard",
+    "Station", "Voyage", "Archive", "Mountain",
+];
+
+/// May 2024 in milliseconds, documents were last opened before this
+const NOW_MS: u64 = 1_715_000_000_000;
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Pdf,
+    Epub,
+    Notebook,
+}
+
+/// Xorshift, good enough to make the library look varied
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        usize::try_from(self.next() % n as u64).expect("n is a usize")
+    }
+
+    fn uuid(&mut self) -> String {
+        let hex = format!("{:016x}{:016x}", self.next(), self.next());
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+/// Writes `docs` documents spread over [`FOLDERS`] and the root
+pub fn create(docs: usize) -> Result<()> {
+    let device = device::current();
+    if device.is_remarkable() {
+        return Err(eyre!(
+            "Will not add made up documents to the library of a reMarkable"
+        ))
+        .suggestion("pass --simulate to fill data/xochitl instead");
+    }
+    let dir = device.documents();
+    let not_empty = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+    if not_empty {
+        return Err(eyre!("{} is not empty", dir.display()))
+            .suggestion("remove it first, the fixture does not add to an existing library");
+    }
+    fs::create_dir_all(dir).wrap_err("Could not create documents dir")?;
+    write(dir, docs)?;
+    log::info!(
+        "created {docs} documents in {} folders in {}",
+        FOLDERS.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+fn write(dir: &Path, docs: usize) -> Result<()> {
+    let mut rng = Rng(0x5eed_b00c_5afe_0001);
+    let mut folders: Vec<(&str, String)> = Vec::new();
+    for path in FOLDERS {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => {
+                let (_, uuid) = folders.iter().find(|(p, _)| *p == parent).expect("sorted");
+                (uuid.clone(), name)
+            }
+            None => (String::new(), *path),
+        };
+        let uuid = rng.uuid();
+        let metadata = metadata(name, &parent, "CollectionType", None, 0);
+        write_file(dir, &uuid, "metadata", &metadata)?;
+        write_file(dir, &uuid, "content", "{}\n")?;
+        folders.push((path, uuid));
+    }
+
+    let mut names = HashSet::new();
+    for i in 0..docs {
+        let uuid = rng.uuid();
+        // the first document is in the trash, the rest in a folder or the root
+        let parent = match rng.below(folders.len() + 1) {
+            _ if i == 0 => "trash".to_owned(),
+            n if n == folders.len() => String::new(),
+            n => folders[n].1.clone(),
+        };
+        let kind = [Kind::Pdf, Kind::Epub, Kind::Notebook][rng.below(3)];
+        let mut name = format!(
+            "{} {}",
+            ADJECTIVES[rng.below(ADJECTIVES.len())],
+            NOUNS[rng.below(NOUNS.len())]
+        );
+        if !names.insert(name.clone()) {
+            name = format!("{name} {i}");
+        }
+        // a third was never opened
+        let opened = (rng.below(3) != 0).then(|| NOW_MS - rng.next() % (90 * DAY_MS));
+        let pages = 1 + rng.below(400);
+        let page = opened.map_or(0, |_| rng.below(pages));
+
+        let metadata = metadata(&name, &parent, "DocumentType", opened, page);
+        write_file(dir, &uuid, "metadata", &metadata)?;
+        write_document(dir, &uuid, kind, pages)?;
+    }
+    Ok(())
+}
+
+fn metadata(name: &str, parent: &str, kind: &str, opened: Option<u64>, page: usize) -> String {
+    let opened = opened.map(|ms| ms.to_string()).unwrap_or_default();
+    let json = serde_json::json!({
+        "deleted": false,
+        "lastModified": opened,
+        "lastOpened": opened,
+        "lastOpenedPage": page,
+        "metadatamodified": false,
+        "modified": false,
+        "parent": parent,
+        "pinned": false,
+        "synced": true,
+        "type": kind,
+        "version": 1,
+        "visibleName": name,
+    });
+    serde_json::to_string_pretty(&json).expect("json values serialize")
+}
+
+fn write_document(dir: &Path, uuid: &str, kind: Kind, pages: usize) -> Result<()> {
+    let file_type = match kind {
+        Kind::Pdf => "pdf",
+        Kind::Epub => "epub",
+        Kind::Notebook => "notebook",
+    };
+    let content = serde_json::json!({
+        "fileType": file_type,
+        "orientation": "portrait",
+        "pageCount": pages,
+    });
+    write_file(dir, uuid, "content", &content.to_string())?;
+    write_file(dir, uuid, "pagedata", &"Blank\n".repeat(pages))?;
+
+    match kind {
+        Kind::Pdf => write_file(dir, uuid, "pdf", "%PDF-1.4\n% stub\n%%EOF\n")?,
+        Kind::Epub => {
+            write_file(dir, uuid, "epub", "PK stub")?;
+            write_file(dir, uuid, "epubindex", "stub")?;
+        }
+        Kind::Notebook => (),
+    }
+    // annotations and notebook pages live in a dir named after the document
+    let pages_dir = dir.join(uuid);
+    fs::create_dir(&pages_dir).wrap_err("Could not create pages dir")?;
+    fs::write(pages_dir.join("0.rm"), "reMarkable .lines file, version=5")
+        .wrap_err("Could not write page")
+}
+
+fn write_file(dir: &Path, uuid: &str, ext: &str, content: &str) -> Result<()> {
+    let path = dir.join(uuid).with_extension(ext);
+    fs::write(&path, content).wrap_err_with(|| format!("Could not write: {}", path.display()))
+}
@@ -0,0 +1,44 @@
+//! Documents opened in the last few minutes before a lock are left
+//! alone for a short while so the lock does not take away a book being
+//! read. A transient timer runs again after the grace period, that run
+//! locks them without granting another one.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+use crate::util::AcceptErr;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// unix timestamp of when the postponed documents are locked
+    until: i64,
+}
+
+pub fn save(profile: &Profile, until: OffsetDateTime) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let record = Record {
+        until: until.unix_timestamp(),
+    };
+    let json = serde_json::to_string_pretty(&record)?;
+    fs::write(profile.grace_record(), json).wrap_err("Could not write grace record")
+}
+
+/// Whether the last lock postponed some documents
+pub fn postponed(profile: &Profile) -> Result<bool> {
+    match fs::metadata(profile.grace_record()) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).wrap_err("Could not read grace record"),
+    }
+}
+
+pub fn clear(profile: &Profile) -> Result<()> {
+    fs::remove_file(profile.grace_record())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove grace record")
+}
@@ -0,0 +1,219 @@
+//! Folders locked together and unlocked at the same time. Folders passed
+//! with `--unlock-at` form their own groups, unlocking before the rest.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+use serde::{Deserialize, Serialize};
+use time::Time;
+
+use crate::directory::Uuid;
+use crate::profile::Profile;
+use crate::util::time::clock;
+use crate::util::time::ParseHourMinute;
+use crate::util::AcceptErr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub paths: Vec<String>,
+    /// Folders inside `paths` that are not locked
+    pub except: Vec<String>,
+    /// Also lock the pinned documents, wherever they are
+    pub pinned: bool,
+    pub unlock_at: Time,
+}
+
+/// Parses an `--unlock-at` value: `TIME=PATH`
+pub fn parse_tier(tier: &str) -> Result<(Time, &str)> {
+    let (at, path) = tier
+        .split_once('=')
+        .ok_or_else(|| eyre!("Invalid --unlock-at: {tier}"))
+        .suggestion("use TIME=PATH, for example: 7:00=Light reading")?;
+    let at = Time::try_parse(at).wrap_err("Invalid --unlock-at time")?;
+    Ok((at, path))
+}
+
+/// The groups for a lock from `start` to `end`, the `tiers` first so
+/// their folders are not claimed by a `paths` folder containing them.
+/// Only the `paths` group leaves out `except` and locks the `pinned`
+/// documents.
+pub fn from_tiers(
+    tiers: &[String],
+    paths: &[String],
+    except: &[String],
+    pinned: bool,
+    start: Time,
+    end: Time,
+) -> Result<Vec<Group>> {
+    let mut groups: Vec<Group> = Vec::new();
+    for tier in tiers {
+        let (at, path) = parse_tier(tier)?;
+        if !within(at, start, end) {
+            return Err(eyre!("--unlock-at {tier} is not between the start and end"));
+        }
+        match groups.iter_mut().find(|group| group.unlock_at == at) {
+            Some(group) => group.paths.push(path.to_owned()),
+            None => groups.push(Group {
+                paths: vec![path.to_owned()],
+                except: Vec::new(),
+                pinned: false,
+                unlock_at: at,
+            }),
+        }
+    }
+    groups.push(Group {
+        paths: paths.to_vec(),
+        except: except.to_vec(),
+        pinned,
+        unlock_at: end,
+    });
+    Ok(groups)
+}
+
+fn within(at: Time, start: Time, end: Time) -> bool {
+    if start <= end {
+        start <= at && at <= end
+    } else {
+        at >= start || at <= end
+    }
+}
+
+/// Which documents are locked in which group, needed to unlock a group
+/// while the others stay locked
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Locked {
+    groups: Vec<LockedGroup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockedGroup {
+    /// format: 23:59
+    unlock_at: String,
+    docs: Vec<String>,
+}
+
+impl Locked {
+    pub fn add(&mut self, unlock_at: Time, docs: &[Uuid]) {
+        let docs = docs.iter().map(ToString::to_string);
+        let unlock_at = clock(unlock_at);
+        match self.groups.iter_mut().find(|g| g.unlock_at == unlock_at) {
+            Some(group) => group.docs.extend(docs),
+            None => self.groups.push(LockedGroup {
+                unlock_at,
+                docs: docs.collect(),
+            }),
+        }
+    }
+
+    /// Every locked document with when it unlocks
+    pub fn unlock_times(&self) -> Vec<(Uuid, Time)> {
+        let mut docs = Vec::new();
+        for group in &self.groups {
+            let Ok(unlock_at) = Time::try_parse(&group.unlock_at) else {
+                log::warn!(
+                    "ignoring group with invalid unlock time: {}",
+                    group.unlock_at
+                );
+                continue;
+            };
+            docs.extend(
+                group
+                    .docs
+                    .iter()
+                    .filter_map(|uuid| Uuid::parse(uuid).ok())
+                    .map(|uuid| (uuid, unlock_at)),
+            );
+        }
+        docs
+    }
+
+    /// Removes the groups that do not unlock at one of the `due` times
+    /// and returns their documents
+    pub fn take_ended(&mut self, due: &[Time]) -> Vec<Uuid> {
+        let due: Vec<_> = due.iter().copied().map(clock).collect();
+        let (ended, locked) = self
+            .groups
+            .drain(..)
+            .partition(|group| !due.contains(&group.unlock_at));
+        self.groups = locked;
+        ended
+            .into_iter()
+            .flat_map(|group: LockedGroup| group.docs)
+            .filter_map(|uuid| match Uuid::parse(&uuid) {
+                Ok(uuid) => Some(uuid),
+                Err(e) => {
+                    log::warn!("ignoring locked document in groups record: {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn load(profile: &Profile) -> Result<Option<Self>> {
+        let json = match fs::read_to_string(profile.groups_record()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).wrap_err("Could not read locked groups record"),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .wrap_err("Could not parse locked groups record")
+    }
+
+    pub fn save(&self, profile: &Profile) -> Result<()> {
+        fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(profile.groups_record(), json).wrap_err("Could not write locked groups record")
+    }
+
+    pub fn remove(profile: &Profile) -> Result<()> {
+        fs::remove_file(profile.groups_record())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not remove locked groups record")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hm(h: u8, m: u8) -> Time {
+        Time::from_hms(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn tiers() {
+        let tiers = ["7:00=Light reading".to_owned(), "7:00=News".to_owned()];
+        let paths = ["Books".to_owned()];
+        let except = ["Books/School".to_owned()];
+        let groups = from_tiers(&tiers, &paths, &except, true, hm(23, 0), hm(8, 0)).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].paths, ["Light reading", "News"]);
+        assert!(groups[0].except.is_empty());
+        assert_eq!(groups[1].unlock_at, hm(8, 0));
+        assert_eq!(groups[1].except, except);
+        assert!(!groups[0].pinned && groups[1].pinned);
+
+        let late = ["9:00=Comics".to_owned()];
+        assert!(from_tiers(&late, &paths, &[], false, hm(23, 0), hm(8, 0)).is_err());
+        assert!(parse_tier("Comics").is_err());
+    }
+
+    #[test]
+    fn take_ended() {
+        let [a, b, c] = ["a", "b", "c"].map(crate::directory::test::id);
+        let mut locked = Locked::default();
+        locked.add(hm(7, 0), &[a]);
+        locked.add(hm(8, 0), &[b]);
+        locked.add(hm(7, 0), &[c]);
+
+        assert!(locked.take_ended(&[hm(7, 0), hm(8, 0)]).is_empty());
+        let ended = locked.take_ended(&[hm(8, 0)]);
+        assert_eq!(ended, [a, c]);
+        assert_eq!(locked.groups.len(), 1);
+    }
+}
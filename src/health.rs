@@ -0,0 +1,165 @@
+//! A small json file rewritten after every scheduled run so other tools,
+//! for example an app reading it over sftp, can check book-safe is alive
+//! without parsing the log. It holds the last run of every profile.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::metrics::{self, Outcome};
+use crate::profile::Profile;
+
+/// The timers start a run at least once a day, a profile that did not
+/// run for longer has a broken or missing service
+pub const STALE: Duration = Duration::hours(25);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Locked,
+    Unlocked,
+    StayedLocked,
+    StayedUnlocked,
+}
+
+impl Action {
+    fn of(outcome: &Outcome) -> Self {
+        match (outcome.was_locked, outcome.is_locked) {
+            (false, true) => Action::Locked,
+            (true, false) => Action::Unlocked,
+            (true, true) => Action::StayedLocked,
+            (false, false) => Action::StayedUnlocked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    /// unix timestamp of when the run finished
+    pub at: i64,
+    pub action: Action,
+    pub ok: bool,
+    /// why the run failed
+    pub error: Option<String>,
+    pub took_secs: f64,
+    pub locked_documents: usize,
+}
+
+impl Run {
+    pub fn stale(&self, now: OffsetDateTime) -> bool {
+        now.unix_timestamp() - self.at > STALE.whole_seconds()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Health {
+    /// the book-safe version that last wrote it
+    version: String,
+    /// last run by profile name
+    profiles: BTreeMap<String, Run>,
+}
+
+/// Always in the state dir so tools know where to look
+pub fn path() -> PathBuf {
+    crate::state_dir().join("health.json")
+}
+
+fn load() -> Result<Health> {
+    match fs::read_to_string(path()) {
+        Ok(json) => serde_json::from_str(&json).wrap_err("Could not parse health file"),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Health::default()),
+        Err(e) => Err(e).wrap_err("Could not read health file"),
+    }
+}
+
+/// The last run of `profile`, if any
+pub fn last_run(profile: &Profile) -> Result<Option<Run>> {
+    Ok(load()?.profiles.remove(&profile.to_string()))
+}
+
+/// Records the run of `profile` that ended with `outcome`, replacing the
+/// file in one go so a reader never sees half of it
+pub fn record(profile: &Profile, outcome: &Outcome, error: Option<String>) -> Result<()> {
+    let run = Run {
+        at: OffsetDateTime::now_utc().unix_timestamp(),
+        action: Action::of(outcome),
+        ok: !outcome.failed,
+        error,
+        took_secs: outcome.run_took.unwrap_or_default().as_secs_f64(),
+        locked_documents: metrics::locked_documents(profile)?,
+    };
+    // a damaged file is replaced
+    let mut profiles = load().map(|h| h.profiles).unwrap_or_default();
+    profiles.insert(profile.to_string(), run);
+    let health = Health {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        profiles,
+    };
+
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let json = serde_json::to_string_pretty(&health)?;
+    let tmp = path().with_extension("json.tmp");
+    fs::write(&tmp, json).wrap_err("Could not write health file")?;
+    fs::rename(&tmp, path()).wrap_err("Could not replace health file")
+}
+
+/// Forgets `profile`, for uninstall
+pub fn clear(profile: &Profile) -> Result<()> {
+    let mut health = load()?;
+    if health.profiles.remove(&profile.to_string()).is_none() {
+        return Ok(());
+    }
+    let json = serde_json::to_string_pretty(&health)?;
+    fs::write(path(), json).wrap_err("Could not write health file")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_runs() {
+        let root = crate::device::simulate_in_temp_dir("health");
+        let kids = Profile::new("kids").unwrap();
+        assert!(last_run(&kids).unwrap().is_none());
+
+        let outcome = Outcome {
+            was_locked: false,
+            is_locked: true,
+            failed: false,
+            run_took: Some(std::time::Duration::from_millis(1500)),
+        };
+        record(&kids, &outcome, None).unwrap();
+        let failed = Outcome {
+            failed: true,
+            ..outcome
+        };
+        record(
+            &Profile::new(crate::profile::DEFAULT).unwrap(),
+            &failed,
+            Some("no room".to_owned()),
+        )
+        .unwrap();
+
+        let run = last_run(&kids).unwrap().unwrap();
+        assert_eq!(run.action, Action::Locked);
+        assert!(run.ok);
+        assert!((run.took_secs - 1.5).abs() < f64::EPSILON);
+        assert!(!run.stale(OffsetDateTime::now_utc()));
+        assert!(run.stale(OffsetDateTime::now_utc() + Duration::days(2)));
+        let json = fs::read_to_string(path()).unwrap();
+        assert!(json.contains(r#""error": "no room""#));
+
+        clear(&kids).unwrap();
+        assert!(last_run(&kids).unwrap().is_none());
+        assert!(last_run(&Profile::new(crate::profile::DEFAULT).unwrap())
+            .unwrap()
+            .is_some());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
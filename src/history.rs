@@ -0,0 +1,224 @@
+//! Every lock session of a profile: when it started and ended, which
+//! folders it locked and how many documents. Kept for a year so the
+//! `history` command and the lock report can show how consistently the
+//! schedule ran.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::{Date, Duration, OffsetDateTime, Time};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use crate::profile::Profile;
+use crate::util::time as util_time;
+use crate::util::AcceptErr;
+
+/// Sessions that ended longer ago are dropped
+const KEEP: Duration = Duration::days(365);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// unix timestamp
+    pub start: i64,
+    /// unix timestamp, None while still locked
+    pub end: Option<i64>,
+    pub folders: Vec<String>,
+    pub documents: usize,
+}
+
+impl Session {
+    fn end_or(&self, now: OffsetDateTime) -> i64 {
+        self.end.unwrap_or(now.unix_timestamp())
+    }
+}
+
+pub fn load(profile: &Profile) -> Result<Vec<Session>> {
+    match fs::read_to_string(profile.history_record()) {
+        Ok(json) => serde_json::from_str(&json).wrap_err("Could not parse lock history"),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).wrap_err("Could not read lock history"),
+    }
+}
+
+fn save(profile: &Profile, sessions: &[Session]) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let json = serde_json::to_string_pretty(sessions)?;
+    fs::write(profile.history_record(), json).wrap_err("Could not write lock history")
+}
+
+/// Records a lock of `documents` in `folders`. Locking again while the
+/// last session is open, as happens when postponed documents are locked,
+/// updates that session.
+pub fn started(
+    profile: &Profile,
+    folders: Vec<String>,
+    documents: usize,
+    now: OffsetDateTime,
+) -> Result<()> {
+    let mut sessions = load(profile)?;
+    let oldest = (now - KEEP).unix_timestamp();
+    sessions.retain(|session| session.end.is_none_or(|end| end >= oldest));
+    match sessions.last_mut() {
+        Some(last) if last.end.is_none() => {
+            last.folders = folders;
+            last.documents = documents;
+        }
+        _ => sessions.push(Session {
+            start: now.unix_timestamp(),
+            end: None,
+            folders,
+            documents,
+        }),
+    }
+    save(profile, &sessions)
+}
+
+/// Ends the open session, if there is one
+pub fn ended(profile: &Profile, now: OffsetDateTime) -> Result<()> {
+    let mut sessions = load(profile)?;
+    let Some(last) = sessions.last_mut().filter(|session| session.end.is_none()) else {
+        return Ok(());
+    };
+    last.end = Some(now.unix_timestamp());
+    save(profile, &sessions)
+}
+
+pub fn clear(profile: &Profile) -> Result<()> {
+    fs::remove_file(profile.history_record())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove lock history")
+}
+
+/// How long was locked on each of the last `days` days up to `now` on
+/// the clock of `tz`, oldest first
+pub fn per_day(
+    sessions: &[Session],
+    days: u16,
+    now: OffsetDateTime,
+    tz: &Tz,
+) -> Vec<(Date, Duration)> {
+    let today = now.to_timezone(tz).date();
+    (0..days)
+        .rev()
+        .map(|back| today - Duration::days(back.into()))
+        .map(|date| {
+            let from = util_time::resolve(date, Time::MIDNIGHT, tz).unix_timestamp();
+            let to = date
+                .next_day()
+                .map_or(i64::MAX, |next| {
+                    util_time::resolve(next, Time::MIDNIGHT, tz).unix_timestamp()
+                })
+                .min(now.unix_timestamp());
+            let locked: i64 = sessions
+                .iter()
+                .map(|session| session.end_or(now).min(to) - session.start.max(from))
+                .filter(|secs| *secs > 0)
+                .sum();
+            (date, Duration::seconds(locked))
+        })
+        .collect()
+}
+
+fn hours_minutes(duration: Duration) -> String {
+    let minutes = duration.whole_minutes();
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Prints the sessions of the last `days` days and on how many of them
+/// there was a lock
+pub fn print(profile: &Profile, days: u16, tz: &Tz) -> Result<()> {
+    let sessions = load(profile)?;
+    let now = OffsetDateTime::now_utc();
+    let since = (now - Duration::days(days.into())).unix_timestamp();
+    let local = |at: i64| {
+        let at = OffsetDateTime::from_unix_timestamp(at)
+            .unwrap_or(now)
+            .to_timezone(tz);
+        format!(
+            "{} {} {}:{:02}",
+            at.weekday(),
+            at.date(),
+            at.hour(),
+            at.minute()
+        )
+    };
+    let recent: Vec<_> = sessions
+        .iter()
+        .filter(|session| session.end_or(now) >= since)
+        .collect();
+    if recent.is_empty() {
+        println!("{profile} was not locked in the last {days} days");
+        return Ok(());
+    }
+    for session in &recent {
+        let end = match session.end {
+            Some(end) => local(end),
+            None => "still locked".to_owned(),
+        };
+        let took = Duration::seconds(session.end_or(now) - session.start);
+        println!(
+            "{} to {end} ({}), {} documents in {}",
+            local(session.start),
+            hours_minutes(took),
+            session.documents,
+            session.folders.join(", ")
+        );
+    }
+    let per_day = per_day(&sessions, days, now, tz);
+    let locked_days = per_day
+        .iter()
+        .filter(|(_, locked)| locked.is_positive())
+        .count();
+    let total = per_day.iter().map(|(_, locked)| *locked).sum();
+    println!(
+        "\nlocked on {locked_days} of the last {days} days, {} in total",
+        hours_minutes(total)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sessions() {
+        let root = crate::device::simulate_in_temp_dir("history");
+        let profile = Profile::new("kids").unwrap();
+        let tz = util_time::find_timezone("UTC").unwrap();
+        let evening = time::Date::from_calendar_date(2024, time::Month::June, 10)
+            .unwrap()
+            .with_hms(22, 0, 0)
+            .unwrap()
+            .assume_utc();
+
+        started(&profile, vec!["Books".to_owned()], 3, evening).unwrap();
+        // the postponed documents are locked a bit later
+        let later = evening + Duration::minutes(10);
+        started(&profile, vec!["Books".to_owned()], 5, later).unwrap();
+        ended(&profile, evening + Duration::hours(9)).unwrap();
+        ended(&profile, evening + Duration::hours(10)).unwrap();
+
+        let sessions = load(&profile).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].documents, 5);
+        assert_eq!(
+            sessions[0].end,
+            Some((evening + Duration::hours(9)).unix_timestamp())
+        );
+
+        let now = evening + Duration::days(1);
+        let days = per_day(&sessions, 3, now, tz);
+        let locked: Vec<_> = days.iter().map(|(_, d)| d.whole_hours()).collect();
+        assert_eq!(locked, [0, 2, 7]);
+
+        started(&profile, Vec::new(), 1, now).unwrap();
+        let days = per_day(&load(&profile).unwrap(), 1, now + Duration::hours(1), tz);
+        assert_eq!(days[0].1, Duration::hours(7) + Duration::hours(1));
+        clear(&profile).unwrap();
+        assert!(load(&profile).unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
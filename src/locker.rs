@@ -0,0 +1,304 @@
+//! How documents are locked. Every mode moves files of the document to
+//! the safe of the profile, they differ in which files. Unlocking moves
+//! everything in the safe back, whichever mode was used.
+
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+use time::OffsetDateTime;
+
+use crate::directory::Uuid;
+use crate::util::cmd::{self, SysCmd};
+use crate::util::{interrupt, progress};
+use crate::{device, doctor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Mode {
+    /// Hide the documents from the ui
+    #[default]
+    Hide,
+    /// Keep the documents in the ui but move away what is needed to open
+    /// them. Chmod would not do, xochitl runs as root.
+    Readonly,
+    /// Hide the documents and put a page of the same name in the place
+    /// of each, saying when it unlocks
+    Stub,
+}
+
+impl Mode {
+    /// Extensions of the files moved to the safe, the empty one is the dir
+    /// with the pages and annotations
+    fn moved(self) -> &'static [&'static str] {
+        match self {
+            Mode::Hide | Mode::Stub => &[
+                "",
+                "bookm",
+                "content",
+                "epub",
+                "epubindex",
+                "metadata",
+                "pagedata",
+                "pdf",
+            ],
+            Mode::Readonly => &["", "epub", "epubindex", "pdf"],
+        }
+    }
+
+    /// Whether files with extension `ext` are moved, including those only
+    /// the firmware we run on has
+    fn moves(self, ext: &str) -> bool {
+        self.moved().contains(&ext)
+            || (self != Mode::Readonly && device::current().layout.extra_files.contains(&ext))
+    }
+}
+
+/// Checks that moving documents to `safe_dir` does not need space, and
+/// that there is room for the report and state. Across file systems a
+/// move would be a copy, we refuse rather than fill the disk halfway.
+pub fn check_room(safe_dir: &Path) -> Result<()> {
+    check_room_with(&cmd::System, safe_dir)
+}
+
+fn check_room_with(cmd: &impl SysCmd, safe_dir: &Path) -> Result<()> {
+    let documents = device::current().documents();
+    let device_of = |dir: &Path| {
+        fs::metadata(dir)
+            .map(|meta| meta.dev())
+            .wrap_err_with(|| format!("Could not read: {}", dir.display()))
+    };
+    if device_of(documents)? != device_of(safe_dir)? {
+        return Err(eyre!(
+            "The safe ({}) is on a different file system than the documents",
+            safe_dir.display()
+        ))
+        .suggestion("the safe needs to be on the same file system, check for mounts");
+    }
+
+    match doctor::available_kb(cmd, documents) {
+        Ok(kb) if kb < doctor::MIN_FREE_KB => {
+            Err(eyre!("Only {kb} kB free, not enough for the lock report"))
+                .suggestion("free up space by removing some documents")
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::warn!("could not check free space: {e:#}");
+            Ok(())
+        }
+    }
+}
+
+/// Most devices have two cores, more threads than this does not help
+const THREADS: usize = 4;
+
+/// Moves `docs` to `safe_dir` spread over a few threads, `moved` is
+/// called with the bytes of each document moved. Stops at the first error
+/// or interrupt. Every rename stands on its own, unlocking moves back
+/// whatever made it to the safe, so the order does not matter.
+pub fn move_docs(
+    mode: Mode,
+    safe_dir: &Path,
+    docs: &[Uuid],
+    mut moved: impl FnMut(u64),
+) -> Result<()> {
+    let threads = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(THREADS);
+    let documents = device::current().documents();
+    let docs = files_of(mode, documents, docs)?;
+    let chunk = docs.len().div_ceil(threads).max(1);
+    let failed = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+    // the scope ends once every thread stopped, nothing moves after we
+    // return and the caller starts rolling back
+    thread::scope(|s| {
+        let handles: Vec<_> = docs
+            .chunks(chunk)
+            .map(|chunk| {
+                let tx = tx.clone();
+                let failed = &failed;
+                s.spawn(move || -> Result<()> {
+                    for (uuid, files) in chunk {
+                        if failed.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let res = interrupt::check().and_then(|()| {
+                            move_doc(documents, safe_dir, files)
+                                .wrap_err_with(|| format!("Could not move document: {uuid}"))
+                        });
+                        match res {
+                            Ok(bytes) => tx.send(bytes).expect("receiver outlives threads"),
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        drop(tx);
+        for bytes in rx {
+            moved(bytes);
+        }
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("moving documents does not panic"))
+    })
+}
+
+/// The files `mode` moves for each of `docs`. Listing the dir once saves
+/// trying every extension for every document, most do not exist.
+fn files_of(mode: Mode, dir: &Path, docs: &[Uuid]) -> Result<Vec<(Uuid, Vec<String>)>> {
+    let mut files: HashMap<Uuid, Vec<String>> =
+        docs.iter().map(|uuid| (*uuid, Vec::new())).collect();
+    for entry in fs::read_dir(dir).wrap_err("Could not list documents")? {
+        let name = entry.wrap_err("Could not list documents")?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let (stem, ext) = name.split_once('.').unwrap_or((name, ""));
+        if !mode.moves(ext) {
+            continue;
+        }
+        if let Some(files) = Uuid::parse(stem).ok().and_then(|u| files.get_mut(&u)) {
+            files.push(name.to_owned());
+        }
+    }
+    Ok(docs
+        .iter()
+        .map(|uuid| (*uuid, files.remove(uuid).unwrap_or_default()))
+        .collect())
+}
+
+/// Returns the number of bytes moved
+fn move_doc(dir: &Path, safe_dir: &Path, files: &[String]) -> Result<u64> {
+    let mut bytes = 0;
+    for file in files {
+        let source = dir.join(file);
+        bytes += progress::size(&source);
+        fs::rename(source, safe_dir.join(file))
+            .wrap_err_with(|| format!("Could not move: {file}"))?;
+    }
+    Ok(bytes)
+}
+
+/// Moves a file or dir from the safe back to `dest`. While locked
+/// readonly the ui can create the pages dir of a notebook again, that is
+/// moved to `made-while-locked` in the state dir rather then overwritten.
+pub fn move_back(source: &Path, dest: &Path) -> Result<()> {
+    if fs::symlink_metadata(dest).is_ok() {
+        let aside = crate::state_dir().join("made-while-locked");
+        fs::create_dir_all(&aside).wrap_err("Could not create made-while-locked dir")?;
+        let name = dest
+            .file_name()
+            .expect("dest is a document")
+            .to_string_lossy();
+        let ts = OffsetDateTime::now_utc().unix_timestamp();
+        let aside = aside.join(format!("{name}-{ts}"));
+        log::warn!(
+            "{name} was created while locked, moving it to {}",
+            aside.display()
+        );
+        fs::rename(dest, &aside).wrap_err("Could not move aside what was made while locked")?;
+    }
+    fs::rename(source, dest).wrap_err_with(|| format!("Could not move back: {}", dest.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::cmd::Scripted;
+
+    #[test]
+    fn refuses_without_room() {
+        let root = device::simulate_in_temp_dir("check-room");
+        let documents = device::current().documents();
+        let safe_dir = root.join("safe");
+        fs::create_dir(documents).unwrap();
+        fs::create_dir(&safe_dir).unwrap();
+        let df = |available: u64| {
+            let output = format!(
+                "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                /dev/mmcblk2p4 6722476 1393928 {available} 99% /home\n"
+            );
+            Scripted::default().expect(&format!("df -Pk {}", documents.display()), 0, &output, "")
+        };
+
+        let err = check_room_with(&df(512), &safe_dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Only 512 kB free, not enough for the lock report"
+        );
+        check_room_with(&df(doctor::MIN_FREE_KB), &safe_dir).unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn readonly_keeps_listing() {
+        let moved = Mode::Readonly.moved();
+        assert!(!moved.contains(&"metadata") && !moved.contains(&"content"));
+        assert!(moved.iter().all(|ext| Mode::Hide.moved().contains(ext)));
+    }
+
+    #[test]
+    fn lists_files_to_move() {
+        let root = device::simulate_in_temp_dir("locker-list");
+        let [dune, emma] = ["dune", "emma"].map(crate::directory::test::id);
+        for file in [
+            dune.to_string(),
+            format!("{dune}.metadata"),
+            format!("{dune}.pdf"),
+            format!("{dune}.thumbnails"),
+            format!("{dune}.tombstone"),
+            format!("{emma}.metadata"),
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let mut files = files_of(Mode::Readonly, &root, &[dune]).unwrap();
+        files[0].1.sort();
+        assert_eq!(
+            files,
+            [(dune, vec![dune.to_string(), format!("{dune}.pdf")])]
+        );
+        // the simulated device is laid out like 3.x
+        let files = files_of(Mode::Hide, &root, &[dune, emma]).unwrap();
+        assert_eq!(files[0].1.len(), 4);
+        assert_eq!(files[1].1, [format!("{emma}.metadata")]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sets_aside_what_was_made_while_locked() {
+        let root = device::simulate_in_temp_dir("locker");
+        let safe = root.join("safe");
+        let documents = root.join("documents");
+        fs::create_dir_all(safe.join("notebook")).unwrap();
+        fs::write(safe.join("notebook/0.rm"), "locked").unwrap();
+        fs::create_dir_all(documents.join("notebook")).unwrap();
+        fs::write(documents.join("notebook/0.rm"), "new").unwrap();
+
+        move_back(&safe.join("notebook"), &documents.join("notebook")).unwrap();
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(&documents.join("notebook/0.rm")), "locked");
+        let aside = fs::read_dir(crate::state_dir().join("made-while-locked"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert_eq!(read(&aside.join("0.rm")), "new");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
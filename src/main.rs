@@ -1,71 +1,685 @@
 // enable all lints except those in the restriction group
-#![warn(clippy::pedantic, clippy::cargo)] 
+#![warn(clippy::pedantic, clippy::cargo)]
+// our dependencies pull in multiple versions of some crates, nothing we can do about that
+#![allow(clippy::multiple_crate_versions)]
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
 
-use clap::{ArgAction, Parser, Subcommand};
-use color_eyre::eyre;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use color_eyre::{eyre, Help};
 use eyre::{Result, WrapErr};
 use itertools::Itertools;
 use log::warn;
 use simplelog::ConfigBuilder;
 use time::{OffsetDateTime, Time};
+use time_tz::OffsetDateTimeExt;
 
+use config::Config;
 use directory::Uuid;
-use util::AcceptErr;
+use profile::Profile;
+use util::{interrupt, logfile, progress::Progress, AcceptErr};
 
-use crate::util::time::{set_os_timezone, should_lock, ParseHourMinute};
+use crate::util::time::{
+    ensure_os_timezone, set_os_timezone, should_lock, ParseHourMinute, Schedule,
+};
 
+mod activity;
+mod adhoc;
+mod bundle;
+mod config;
+mod device;
+mod digest;
 mod directory;
+mod doctor;
+mod effective;
+mod exit;
+#[cfg(feature = "dev-fixture")]
+mod fixture;
+mod grace;
+mod group;
+mod health;
+mod history;
+mod locker;
+mod metrics;
+mod mqtt;
+mod notify;
+mod pause;
+mod placeholder;
+mod profile;
+mod quota;
+mod remote;
 mod report;
+mod request;
+mod scheduler;
+mod serve;
+mod smtp;
+mod stats;
+mod sync;
 mod systemd;
+mod trash;
+mod tree_export;
 mod util;
-mod sync;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)] // they are command line flags
 pub struct Args {
     /// Path of a folder to be locked (as seen in the ui),
     /// pass multiple times to block multiple folders
-    #[clap(short, long, required = true)]
+    #[clap(
+        short,
+        long,
+        value_parser = folder_arg,
+        required_unless_present_any = ["unlock_at", "lock_all", "lock_pinned"]
+    )]
     path: Vec<String>,
 
-    /// When to hide folders, format: 23:59
-    #[clap(short, long)]
-    start: String,
+    /// Lock every document, not just those in some folders. Folders
+    /// added later are locked too.
+    #[clap(long, action = ArgAction::SetTrue, conflicts_with = "path")]
+    lock_all: bool,
+
+    /// Folder not to lock with --lock-all, pass multiple times to keep
+    /// multiple folders available
+    #[clap(long, value_name = "PATH", value_parser = folder_arg, requires = "lock_all")]
+    except: Vec<String>,
+
+    /// Also lock the pinned documents, those starred as favorites,
+    /// wherever they are
+    #[clap(long, action = ArgAction::SetTrue, conflicts_with = "lock_all")]
+    lock_pinned: bool,
+
+    /// Only lock documents of these types, for example: epub,pdf
+    #[clap(long, value_enum, value_name = "TYPES", value_delimiter = ',')]
+    only_types: Vec<directory::FileType>,
+
+    /// Do not lock documents of these types, for example notebook to
+    /// keep handwritten notes available
+    #[clap(
+        long,
+        value_enum,
+        value_name = "TYPES",
+        value_delimiter = ',',
+        conflicts_with = "only_types"
+    )]
+    skip_types: Vec<directory::FileType>,
+
+    /// Only lock documents at least this large, including their pages,
+    /// for example: 5MB
+    #[clap(long, value_name = "SIZE")]
+    min_size: Option<String>,
 
-    /// When to release folders, format: 23:59
-    #[clap(short, long)]
-    end: String,
+    /// Only lock documents added or changed this recently, in minutes (m),
+    /// hours (h), days (d) or weeks (w), for example: 30d
+    #[clap(long, value_name = "AGE")]
+    added_within: Option<String>,
 
-    /// Timezone, needed as remarkable resets the device's
-    /// timezone to UTC on every update
+    /// Folder to lock that unlocks before the end, format: 7:00=Comics.
+    /// Pass multiple times for multiple folders. Also works for a folder
+    /// inside one passed with --path.
+    #[clap(
+        long,
+        value_name = "TIME=PATH",
+        value_parser = tier_arg,
+        conflicts_with = "quota"
+    )]
+    unlock_at: Vec<String>,
+
+    /// Send a notification this many minutes before the lock starts,
+    /// to wherever the config sends notifications
+    #[clap(long, value_name = "MINUTES", conflicts_with = "quota")]
+    warn_minutes: Option<u32>,
+
+    /// Leave documents opened in the last this many minutes unlocked
+    /// for that long when the lock starts, so a book being read is not
+    /// taken away mid-sentence. Needs the profile to be installed.
+    #[clap(long, value_name = "MINUTES")]
+    grace_minutes: Option<u32>,
+
+    /// When to hide folders, format: 23:59, 23:59:30 or 11pm. Install
+    /// also takes an offset from the end like -9h, or from the sun like
+    /// sunset+30m.
+    #[clap(short, long, required_unless_present = "quota")]
+    start: Option<String>,
+
+    /// When to release folders, format: 23:59, 23:59:30 or 11pm. Install
+    /// also takes an offset from the start like +9h, or from the sun like
+    /// sunrise+1h.
+    #[clap(short, long, required_unless_present = "quota")]
+    end: Option<String>,
+
+    /// Instead of a start and end, allow the folders to be unlocked this
+    /// long every day, format: 2:30. Once used up they are locked until
+    /// midnight.
+    #[clap(long, value_name = "HOURS:MINUTES", conflicts_with_all = ["start", "end"])]
+    quota: Option<String>,
+
+    /// Timezone the start and end are in, the device's own timezone
+    /// is reset to UTC on every update. Install stores it in the
+    /// config, by default the os timezone is stored.
     #[clap(short('z'), long)]
-    timezone: String,
+    timezone: Option<String>,
 
     /// Do not block sync when locking books, the sync will
     /// delete and re-upload books when locking and unlocking!
     #[clap(long, action = ArgAction::SetTrue)]
     allow_sync: bool,
+
+    /// Extra domain to block while locked, pass multiple times to block
+    /// multiple domains. More can be added in the config file.
+    #[clap(long, value_name = "DOMAIN")]
+    block_domain: Vec<String>,
+
+    /// What to block while locked
+    #[clap(long, value_enum, default_value_t)]
+    block_scope: sync::Scope,
+
+    /// How to block sync while locked
+    #[clap(long, value_enum, default_value_t)]
+    sync_blocker: sync::Backend,
+
+    /// What to do when sync can not be blocked, `best-effort` still
+    /// locks the documents
+    #[clap(long, value_enum, default_value_t)]
+    sync_block_policy: sync::Policy,
+
+    /// Do not wait for the sync domains to be resolved if recently
+    /// resolved ips are cached. Makes locking fast when offline.
+    #[clap(long, action = ArgAction::SetTrue)]
+    offline_ok: bool,
+
+    /// Lock even if the clock looks wrong or was not synchronized over
+    /// the network. Without this book-safe unlocks when it can not trust
+    /// the time, a lock in effect is kept as long as the date is plausible.
+    #[clap(long, action = ArgAction::SetTrue)]
+    force_time: bool,
+
+    /// How to lock the documents
+    #[clap(long, value_enum, default_value_t)]
+    mode: locker::Mode,
+
+    /// Also lock documents and folders moved to the trash from a locked
+    /// folder. Only works for those book-safe saw outside the trash
+    /// during an earlier lock.
+    #[clap(long, action = ArgAction::SetTrue)]
+    include_trash: bool,
+
+    /// Match folder names ignoring case and surrounding whitespace, so
+    /// "comics" finds "Comics ". An exact match is used if there is one,
+    /// a name that loosely matches several folders matches none.
+    #[clap(long, action = ArgAction::SetTrue)]
+    fuzzy_paths: bool,
+}
+
+/// A folder as `Tree::node_for` takes it: without leading, trailing or
+/// double slashes. The root is only locked with --lock-all, it holds
+/// every document including the lock report.
+fn folder_arg(path: &str) -> std::result::Result<String, String> {
+    if path.trim().is_empty() {
+        return Err("the path is empty, pass the folder as seen in the ui".to_owned());
+    }
+    let folder = path.split('/').filter(|part| !part.is_empty()).join("/");
+    if folder.is_empty() {
+        return Err("that is the whole library, use --lock-all to lock everything".to_owned());
+    }
+    Ok(folder)
+}
+
+/// An `--unlock-at` value with its path checked like `--path`
+fn tier_arg(tier: &str) -> std::result::Result<String, String> {
+    match tier.split_once('=') {
+        Some((at, path)) => Ok(format!("{at}={}", folder_arg(path)?)),
+        // reported with a suggestion by `group::parse_tier`
+        None => Ok(tier.to_owned()),
+    }
+}
+
+impl Args {
+    /// Canonical arguments for `install`, as kept in the install record.
+    /// Built from what was parsed rather then copied from our own command
+    /// line. Values are attached with `=` so they are never mistaken for
+    /// flags. The timezone is left out, it is kept in the config.
+    fn to_install_args(&self, profile: &Profile, log: simplelog::Level) -> Vec<String> {
+        fn value<T: clap::ValueEnum>(v: &T) -> String {
+            v.to_possible_value()
+                .expect("no skipped variants")
+                .get_name()
+                .to_owned()
+        }
+
+        let mut args = vec![format!("--log={}", log.as_str().to_lowercase())];
+        if !profile.is_default() {
+            args.push(format!("--profile={profile}"));
+        }
+        args.push("install".to_owned());
+        args.extend(self.path.iter().map(|path| format!("--path={path}")));
+        if self.lock_all {
+            args.push("--lock-all".to_owned());
+        }
+        args.extend(self.except.iter().map(|path| format!("--except={path}")));
+        if self.lock_pinned {
+            args.push("--lock-pinned".to_owned());
+        }
+        if !self.only_types.is_empty() {
+            let types = self.only_types.iter().map(value).join(",");
+            args.push(format!("--only-types={types}"));
+        }
+        if !self.skip_types.is_empty() {
+            let types = self.skip_types.iter().map(value).join(",");
+            args.push(format!("--skip-types={types}"));
+        }
+        if let Some(size) = &self.min_size {
+            args.push(format!("--min-size={size}"));
+        }
+        if let Some(age) = &self.added_within {
+            args.push(format!("--added-within={age}"));
+        }
+        args.extend(
+            self.unlock_at
+                .iter()
+                .map(|tier| format!("--unlock-at={tier}")),
+        );
+        if let Some(minutes) = self.warn_minutes {
+            args.push(format!("--warn-minutes={minutes}"));
+        }
+        if let Some(minutes) = self.grace_minutes {
+            args.push(format!("--grace-minutes={minutes}"));
+        }
+        if let Some(start) = &self.start {
+            args.push(format!("--start={start}"));
+        }
+        if let Some(end) = &self.end {
+            args.push(format!("--end={end}"));
+        }
+        if let Some(quota) = &self.quota {
+            args.push(format!("--quota={quota}"));
+        }
+        if self.allow_sync {
+            args.push("--allow-sync".to_owned());
+        }
+        args.extend(
+            self.block_domain
+                .iter()
+                .map(|d| format!("--block-domain={d}")),
+        );
+        args.push(format!("--block-scope={}", value(&self.block_scope)));
+        args.push(format!("--sync-blocker={}", value(&self.sync_blocker)));
+        args.push(format!(
+            "--sync-block-policy={}",
+            value(&self.sync_block_policy)
+        ));
+        args.push(format!("--mode={}", value(&self.mode)));
+        if self.offline_ok {
+            args.push("--offline-ok".to_owned());
+        }
+        if self.force_time {
+            args.push("--force-time".to_owned());
+        }
+        if self.include_trash {
+            args.push("--include-trash".to_owned());
+        }
+        if self.fuzzy_paths {
+            args.push("--fuzzy-paths".to_owned());
+        }
+        args
+    }
+
+    fn schedule(&self) -> Result<Schedule> {
+        if let Some(quota) = &self.quota {
+            let quota = Time::try_parse(quota).wrap_err("Invalid quota")?;
+            let (hours, minutes, _) = quota.as_hms();
+            return Ok(Schedule::Quota(
+                time::Duration::hours(hours.into()) + time::Duration::minutes(minutes.into()),
+            ));
+        }
+
+        let start = self
+            .start
+            .as_deref()
+            .ok_or_else(|| eyre::eyre!("No start time"))?;
+        let end = self
+            .end
+            .as_deref()
+            .ok_or_else(|| eyre::eyre!("No end time"))?;
+        let clock = |s: &str| match util::time::Expr::parse(s)? {
+            util::time::Expr::At(at) => Ok(at),
+            _ => Err(eyre::eyre!("{s} is relative"))
+                .suggestion("install works out relative times, use it or pass a time"),
+        };
+        Ok(Schedule::Window {
+            start: clock(start).wrap_err("Invalid start time")?,
+            end: clock(end).wrap_err("Invalid end time")?,
+        })
+    }
+
+    /// Replaces a start or end relative to the other or to the sun, like
+    /// `+9h` or `sunrise+1h`, with the time it stands for today
+    fn resolve_times(&mut self, config: &Config) -> Result<()> {
+        use util::time::Expr;
+        let (Some(start), Some(end)) = (&self.start, &self.end) else {
+            return Ok(());
+        };
+        let start = Expr::parse(start).wrap_err("Invalid start time")?;
+        let end = Expr::parse(end).wrap_err("Invalid end time")?;
+        if matches!((start, end), (Expr::At(_), Expr::At(_))) {
+            return Ok(());
+        }
+        let timezone = install_timezone(self, config)?;
+        let tz = util::time::find_timezone(&timezone)?;
+        let today = OffsetDateTime::now_utc().to_timezone(tz).date();
+        let (start, end) =
+            util::time::resolve_window(start, end, today, tz, config.location.as_ref())?;
+        let (start, end) = (util::time::clock(start), util::time::clock(end));
+        log::info!("locking from {start} to {end}");
+        self.start = Some(start);
+        self.end = Some(end);
+        Ok(())
+    }
+
+    /// The folders passed with --path, or the root with --lock-all
+    fn paths(&self) -> Vec<String> {
+        if self.lock_all {
+            vec![String::new()]
+        } else {
+            self.path.clone()
+        }
+    }
+
+    /// Every folder to lock, including those that unlock early
+    fn all_paths(&self) -> Vec<String> {
+        let tiers = self
+            .unlock_at
+            .iter()
+            .filter_map(|tier| tier.split_once('='))
+            .map(|(_, path)| path.to_owned());
+        self.paths().into_iter().chain(tiers).collect()
+    }
+
+    /// All folders as one group, unlocking at `unlock_at`
+    fn single_group(&self, unlock_at: Time) -> Vec<group::Group> {
+        vec![group::Group {
+            paths: self.all_paths(),
+            except: self.except.clone(),
+            pinned: self.lock_pinned,
+            unlock_at,
+        }]
+    }
+
+    fn filter(&self) -> Result<directory::Filter> {
+        let added_within = self
+            .added_within
+            .as_deref()
+            .map(util::time::parse_age)
+            .transpose()
+            .wrap_err("Invalid --added-within")?;
+        Ok(directory::Filter {
+            only_types: self.only_types.clone(),
+            skip_types: self.skip_types.clone(),
+            min_size: self
+                .min_size
+                .as_deref()
+                .map(util::parse_size)
+                .transpose()
+                .wrap_err("Invalid --min-size")?,
+            added_after: added_within.map(|age| OffsetDateTime::now_utc() - age),
+        })
+    }
+
+    /// The timezone passed on the command line or else the one in the config
+    fn timezone<'a>(&'a self, config: &'a Config) -> Option<&'a str> {
+        self.timezone.as_deref().or(config.timezone.as_deref())
+    }
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Lock or unlock right now depending on the time
-    Run(Args),
+    /// Lock or unlock right now depending on the time, as the profile
+    /// was installed
+    Run {
+        /// Print the documents a lock would hide and change nothing
+        #[clap(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+        /// Schedule passed by services from before it was kept in the
+        /// install record, ignored
+        #[clap(hide = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        legacy: Vec<String>,
+    },
     /// Create and enable book-safe system service, locking and unlocking
     /// at those times.
     /// This command requires additional arguments, call
     /// it with --help to see them
-    Install(Args),
-    /// Remove book-safe service and unlock all files. This command
-    /// requires additional arguments, call it with --help to see them
-    Uninstall,
+    Install {
+        #[clap(flatten)]
+        args: Box<Args>,
+        /// Print the units and install record that would be written
+        /// without changing anything
+        #[clap(long, action = ArgAction::SetTrue, conflicts_with = "dry_run")]
+        print: bool,
+        /// Print the documents a lock would hide and change nothing
+        #[clap(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+        /// Write a file for `import` with this install instead of
+        /// installing, for example to plan on a desktop
+        #[clap(long, value_name = "FILE", conflicts_with_all = ["print", "dry_run"])]
+        bundle: Option<PathBuf>,
+        #[clap(flatten)]
+        confirmed: Confirmed,
+        /// What starts the runs, cron for devices with a cron daemon
+        /// instead of systemd timers
+        #[clap(long, value_enum, default_value_t)]
+        scheduler: scheduler::Kind,
+    },
+    /// Change how the profile is installed, keeping the settings that
+    /// are not passed. For example: `book-safe reconfigure --end 8:00`
+    Reconfigure(Changes),
+    /// Lock this folder too, with --lock-all stop leaving it unlocked.
+    /// If the profile is locked the folder is locked right away
+    AddFolder {
+        #[clap(value_parser = folder_arg)]
+        path: String,
+    },
+    /// Stop locking this folder, with --lock-all leave it unlocked. If
+    /// the profile is locked the folder is unlocked right away
+    RemoveFolder {
+        #[clap(value_parser = folder_arg)]
+        path: String,
+    },
+    /// Remove book-safe service and unlock all files
+    Uninstall {
+        /// Leave the current lock in place until its end time. The unlock
+        /// is scheduled with a transient timer, which does not survive a
+        /// reboot
+        #[clap(long, action = ArgAction::SetTrue, conflicts_with = "purge")]
+        keep_locked: bool,
+        /// Also remove the route cache, the state and the config file.
+        /// Shared files are kept while other profiles are installed
+        #[clap(long, action = ArgAction::SetTrue)]
+        purge: bool,
+        /// Do not ask before undoing a lock that is in effect
+        #[clap(short, long, action = ArgAction::SetTrue)]
+        yes: bool,
+    },
+    /// Lock the folders of the installed profile for a number of hours
+    /// from now, regardless of the schedule. Only `unlock` ends it early
+    LockNow { hours: f32 },
+    /// Unlock the folders for a number of hours, after which the schedule
+    /// applies again. Ends a lock-now lock
+    Pause { hours: f32 },
+    /// Ask for the folders to be unlocked. Sends a notification with a
+    /// token to approve it and waits for the approval
+    RequestUnlock {
+        /// Why, shown in the notification
+        reason: String,
+    },
+    /// Approve an unlock request using the token from its notification
+    Approve {
+        token: String,
+        /// Approve through `serve` on the device at this url instead of
+        /// on this device, for example `http://remarkable.local:8421`
+        #[clap(long)]
+        device: Option<String>,
+    },
     /// Unlock all files
-    Unlock,
+    Unlock {
+        /// Do not ask before undoing a lock that is in effect
+        #[clap(short, long, action = ArgAction::SetTrue)]
+        yes: bool,
+    },
+    /// Serve an http api to get the status and lock, unlock or pause.
+    /// Requests need the `api_token` from the config
+    Serve {
+        /// Address to listen on, reachable from other devices only if not
+        /// on localhost. Use an ssh tunnel to reach the default
+        #[clap(long, default_value = "127.0.0.1:8421")]
+        listen: SocketAddr,
+    },
+    /// List the installed profiles, their schedule and if they are locked
+    Status,
+    /// Show when the documents in the locked folders were last opened
+    Stats,
+    /// Show when the folders were locked and for how long
+    History {
+        /// Number of days to show
+        #[clap(long, default_value_t = 30)]
+        days: u16,
+    },
+    /// Print the folders and documents that are not locked, including
+    /// the trash
+    Tree,
+    /// Print every folder and document, with its uuid, type, size and
+    /// when it was last opened, for spreadsheets or backup tools
+    ExportTree {
+        #[clap(long, value_enum, default_value_t)]
+        format: tree_export::Format,
+    },
+    /// Send the summary of the lock activity since the last one now,
+    /// see `digest` in the config
+    Digest {
+        /// Print it instead of sending it
+        #[clap(long, action = ArgAction::SetTrue)]
+        print: bool,
+    },
+    /// Print where the config file is
+    Config {
+        /// Print every setting and whether it comes from the config file
+        /// or is a default, then the arguments each profile was installed
+        /// with and the folders those resolve to
+        #[clap(long, action = ArgAction::SetTrue)]
+        show: bool,
+    },
+    /// Check the environment book-safe needs, such as permissions,
+    /// systemd and free space
+    Doctor,
     /// List supported timezones
     ListTz { search: Option<String> },
+    /// Check that notifications arrive or that a short lock and unlock
+    /// work, before relying on book-safe overnight
+    Test {
+        #[clap(subcommand)]
+        action: TestAction,
+    },
+    /// Inspect or clear the cache of ips used by sync
+    Cache {
+        #[clap(subcommand)]
+        action: CacheAction,
+    },
+    /// Inspect and change the routes blocking sync
+    Routes {
+        #[clap(subcommand)]
+        action: RoutesAction,
+    },
+    /// Write the config and how every profile is installed to a file, to
+    /// set up another device the same way or restore one after a factory
+    /// reset. Keep it private, it holds the api token and passwords
+    Export { path: PathBuf },
+    /// Replace the config with the one in a file written by `export` and
+    /// install the profiles in it
+    Import {
+        path: PathBuf,
+        /// Do not ask before installing a profile with --lock-all, or with
+        /// --allow-sync on a device paired with the cloud
+        #[clap(short, long, action = ArgAction::SetTrue)]
+        yes: bool,
+    },
+    /// Run book-safe on one or more devices over ssh, from a desktop. For
+    /// example: `book-safe remote --host root@10.11.99.1 --host root@tablet2
+    /// status`
+    Remote {
+        /// Device to run on, as passed to ssh. Pass multiple times for
+        /// multiple devices
+        #[clap(long, required = true)]
+        host: Vec<String>,
+        /// Book-safe built for the devices, copied over if a device does
+        /// not have this version
+        #[clap(long)]
+        binary: Option<PathBuf>,
+        /// Where book-safe is on the devices
+        #[clap(long, default_value = "/home/root/book-safe")]
+        remote_path: String,
+        /// The command to run and its arguments
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Print a completion script for a shell, for example:
+    /// `book-safe completions bash > /etc/bash_completion.d/book-safe`
+    Completions { shell: clap_complete::Shell },
+    /// Print the man page, for example: `book-safe manpage > book-safe.1`
+    Manpage {
+        /// Write a page for book-safe and one for every subcommand to
+        /// this dir instead
+        #[clap(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Fill `data/xochitl` with a made up library to try book-safe
+    /// with `--simulate`
+    #[cfg(feature = "dev-fixture")]
+    DevFixture {
+        /// Number of documents to create
+        #[clap(default_value_t = 100)]
+        docs: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TestAction {
+    /// Send a test message to the `notify_url` and mqtt broker in the config
+    Notify,
+    /// Lock the installed folders for a short while, then unlock them.
+    /// Goes through every step of a real lock: stopping the ui, moving the
+    /// documents, the report and blocking sync
+    Lock {
+        /// How long to stay locked, in minutes (`m`) or hours (`h`)
+        #[clap(long = "for", value_name = "DURATION", default_value = "2m")]
+        length: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// Print the cached ips and when they were last resolved
+    Show,
+    /// Remove all cached ips, refuses while files are locked
+    /// as the cache is needed to unblock sync
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+enum RoutesAction {
+    /// Print the cached ips and the ips the routing table rejects
+    List,
+    /// Remove every reject route and empty the cache, refuses while
+    /// files are locked
+    Clear,
+    /// Block this ip while locked, right away if already locked
+    Add { ip: IpAddr },
+    /// Stop blocking this ip
+    Remove { ip: IpAddr },
 }
 
 #[derive(Parser, Debug)]
@@ -82,201 +696,2875 @@ struct Cli {
     /// Options: trace, debug, info, warn, error
     #[clap(short, long, default_value = "info")]
     log: simplelog::Level,
+    /// Also log to this file, it is rotated once it grows large. Defaults
+    /// to `log/book-safe.log` in the state dir.
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// Act as if not on a reMarkable, for development. Uses the files
+    /// in `data/` and leaves the network, services and wifi alone.
+    #[clap(long, global = true, action = ArgAction::SetTrue)]
+    simulate: bool,
+    /// Use this copy of the xochitl folder, for example from a backup, to
+    /// plan on a desktop. Only works with tree and install with
+    /// --dry-run, --print or --bundle.
+    #[clap(long, global = true, value_name = "DIR", conflicts_with = "simulate")]
+    data_dir: Option<PathBuf>,
+    /// Name of the schedule to use. Profiles are independent: each has its
+    /// own folders, times and service
+    #[clap(long, global = true, default_value = profile::DEFAULT)]
+    profile: String,
 }
 
-fn move_doc(uuid: &Uuid) -> Result<()> {
-    let dir = Path::new(directory::DIR);
-
-    let source = dir.join(uuid);
-    let dest = safe_dir().join(uuid);
-    fs::rename(source, dest)
-        .accept_fn(|e| e.kind() == ErrorKind::NotFound) // there isnt always content and/or pdf file
-        .wrap_err_with(|| format!("Could not move directory for document: {uuid}"))?;
-
-    for ext in [
-        "bookm",
-        "content",
-        "epub",
-        "epubindex",
-        "metadata",
-        "pagedata",
-        "pdf",
-    ] {
-        let source = dir.join(uuid).with_extension(ext);
-        let dest = safe_dir().join(uuid).with_extension(ext);
-        fs::rename(source, dest)
-            .accept_fn(|e| e.kind() == ErrorKind::NotFound) // there isnt always content and/or pdf file
-            .wrap_err_with(|| format!("Could not move file with ext: {ext:?}"))?;
-    }
-    Ok(())
+/// Where book-safe keeps its own data, such as the route cache
+fn state_dir() -> &'static Path {
+    device::current().state_dir()
 }
 
-fn safe_dir() -> &'static Path {
-    if cfg!(target_arch = "arm") {
-        Path::new("/home/root/locked_books")
-    } else {
-        Path::new("data/locked_books")
-    }
+/// Creates the safe only accessible to root, and moves in what an older
+/// version left in the legacy safe
+fn ensure_safe_dir(profile: &Profile) -> Result<()> {
+    let safe_dir = profile.safe_dir();
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&safe_dir)
+        .wrap_err("Could not create books safe")?;
+    fs::set_permissions(&safe_dir, fs::Permissions::from_mode(0o700))
+        .wrap_err("Could not restrict access to the books safe")?;
+    migrate_legacy_safe(&profile.legacy_safe_dir(), &safe_dir)
 }
 
-fn ensure_safe_dir() -> Result<()> {
-    fs::create_dir(safe_dir())
-        .accept_fn(|e| e.kind() == ErrorKind::AlreadyExists && safe_dir().is_dir())
-        .wrap_err("Could not create books safe")
+fn migrate_legacy_safe(legacy: &Path, safe_dir: &Path) -> Result<()> {
+    if legacy == safe_dir || !legacy.is_dir() {
+        return Ok(());
+    }
+    let entries = fs::read_dir(legacy)
+        .and_then(Iterator::collect::<std::io::Result<Vec<_>>>)
+        .wrap_err_with(|| format!("Could not read the legacy safe: {}", legacy.display()))?;
+    for entry in &entries {
+        fs::rename(entry.path(), safe_dir.join(entry.file_name()))
+            .wrap_err_with(|| format!("Could not move {} to the safe", entry.path().display()))
+            .suggestion("the safe must be on the same file system as the documents")?;
+    }
+    fs::remove_dir(legacy).wrap_err("Could not remove the legacy safe")?;
+    log::info!(
+        "moved {} locked files from {} to {}",
+        entries.len(),
+        legacy.display(),
+        safe_dir.display()
+    );
+    Ok(())
 }
 
-fn move_docs(to_lock: &[Uuid]) -> Result<()> {
-    for uuid in to_lock {
-        move_doc(uuid).wrap_err("Could not move document")?;
-    }
+fn move_docs(profile: &Profile, to_lock: &[Uuid], mode: locker::Mode) -> Result<()> {
+    let safe_dir = profile.safe_dir();
+    let mut progress = Progress::new("documents", to_lock.len());
+    locker::move_docs(mode, &safe_dir, to_lock, |bytes| progress.add(bytes))?;
+    progress.finish();
     Ok(())
 }
 
-fn unlock_files() -> Result<()> {
-    let dir = Path::new(directory::DIR);
-    for entry in fs::read_dir(safe_dir())? {
-        let entry = entry?;
+fn unlock_files(profile: &Profile) -> Result<()> {
+    let dir = device::current().documents();
+    let entries: Vec<_> = fs::read_dir(profile.safe_dir())?.collect::<Result<_, _>>()?;
+    let mut progress = Progress::new("files", entries.len());
+    for entry in entries {
         let source = entry.path();
         let dest = dir.join(source.file_name().unwrap());
-        fs::rename(source, dest)?;
+        let bytes = util::progress::size(&source);
+        locker::move_back(&source, &dest)?;
+        progress.add(bytes);
+    }
+    progress.finish();
+    group::Locked::remove(profile)
+}
+
+/// Moves only the files of `docs` out of the safe
+fn unlock_docs(profile: &Profile, docs: &[Uuid]) -> Result<()> {
+    let dir = device::current().documents();
+    let docs: HashSet<String> = docs.iter().map(ToString::to_string).collect();
+    for entry in fs::read_dir(profile.safe_dir())? {
+        let source = entry?.path();
+        let Some(name) = source.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let uuid = name.split_once('.').map_or(name, |(uuid, _)| uuid);
+        if docs.contains(uuid) {
+            locker::move_back(&source, &dir.join(name))?;
+        }
     }
     Ok(())
 }
 
-fn locked_files() -> Result<bool> {
-    Ok(fs::read_dir(safe_dir())?.next().is_some())
+fn locked_files(profile: &Profile) -> Result<bool> {
+    match fs::read_dir(profile.safe_dir()) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).wrap_err("Could not read books safe"),
+    }
+}
+
+/// Whether any profile other then `profile` has files locked
+fn others_locked(profile: &Profile) -> Result<bool> {
+    for other in Profile::installed()? {
+        if other != *profile && locked_files(&other)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
 
-fn try_unlock() -> Result<()> {
-    unlock_files()?;
-    report::remove().wrap_err("Could not remove locked files report")
+fn try_unlock(profile: &Profile) -> Result<()> {
+    unlock_files(profile)?;
+    placeholder::remove(profile)?;
+    report::remove(profile).wrap_err("Could not remove locked files report")?;
+    report::set_sync_allowed(profile, false)
 }
 
-fn unlock() -> Result<()> {
-    if locked_files()? {
-        systemd::ui_action("stop").wrap_err("Could not stop gui")?;
-        if let Err(e) = try_unlock() {
+fn unlock(profile: &Profile, config: &Config) -> Result<()> {
+    adhoc::clear(profile)?;
+    grace::clear(profile)?;
+    let mut unlocked = false;
+    if locked_files(profile)? {
+        let ui = systemd::Ui::detect(config).wrap_err(exit::Failure::Device)?;
+        ui.stop().wrap_err("Could not stop gui")?;
+        if let Err(e) = try_unlock(profile) {
             log::error!("{e}");
+            notify::send(config, profile, notify::Event::Failure, &format!("{e:#}"));
+        } else {
+            unlocked = true;
         }
-        systemd::reset_failed()?;
-        systemd::ui_action("start").wrap_err("Could not start gui")?;
+        ui.reset_failed()?;
+        ui.start().wrap_err("Could not start gui")?;
     } else {
         log::info!("no files to unlock");
     }
 
-    sync::unblock().wrap_err("Could not unblock sync")
+    if others_locked(profile)? {
+        log::info!("other profiles still have files locked, keeping sync blocked");
+    } else {
+        sync::unblock(config).wrap_err(exit::Failure::Unblock)?;
+    }
+    // after unblocking, the wifi sync blocker keeps us offline until then
+    if unlocked {
+        notify::send(config, profile, notify::Event::Unlock, "unlocked folders");
+    }
+    Ok(())
+}
+
+/// Tracks the steps of a lock, if it is dropped without being committed
+/// the steps are undone so a failure leaves the device as it was.
+struct Transaction<'a> {
+    profile: &'a Profile,
+    report_saved: bool,
+    blocked: Option<&'a dyn sync::SyncBlocker>,
+    pending: Option<sync::Pending>,
+    moving: bool,
+    committed: bool,
 }
 
-fn try_lock(mut forbidden: Vec<String>, unlock_at: Time, allow_sync: bool) -> Result<()> {
-    unlock_files().wrap_err("could not unlock files")?; // ensure nothing is in locked folder
+impl<'a> Transaction<'a> {
+    fn new(profile: &'a Profile) -> Self {
+        Self {
+            profile,
+            report_saved: false,
+            blocked: None,
+            pending: None,
+            moving: false,
+            committed: false,
+        }
+    }
+
+    fn save_report(&mut self, pdf: report::Doc) -> Result<()> {
+        // a failed save can leave part of the report behind
+        self.report_saved = true;
+        report::save(self.profile, pdf)
+    }
 
-    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    fn block(&mut self, blocker: &'a dyn sync::SyncBlocker) -> Result<()> {
+        self.blocked = Some(blocker);
+        self.pending = Some(blocker.block()?);
+        Ok(())
+    }
+
+    fn move_docs(&mut self, to_lock: &[Uuid], mode: locker::Mode) -> Result<()> {
+        self.moving = true;
+        move_docs(self.profile, to_lock, mode)
+    }
+
+    fn commit(mut self) -> Option<sync::Pending> {
+        self.committed = true;
+        self.pending.take()
+    }
+
+    /// Undo in reverse order, keeps going if a step fails
+    fn rollback(&mut self) {
+        log::info!("undoing lock work");
+        if self.moving {
+            if let Err(e) = unlock_files(self.profile) {
+                log::error!("Could not move back locked documents: {e:?}");
+            }
+        }
+        if let Some(blocker) = self.blocked {
+            // background blocking must finish before we can undo it
+            if let Some(Err(e)) = self.pending.take().map(sync::Pending::wait) {
+                log::warn!("blocking sync failed: {e:?}");
+            }
+            if let Err(e) = blocker.unblock() {
+                log::error!("Could not unblock sync: {e:?}");
+            }
+        }
+        if self.report_saved {
+            // placeholders are only added after the report
+            if let Err(e) = placeholder::remove(self.profile) {
+                log::error!("Could not remove placeholders: {e:?}");
+            }
+            if let Err(e) = report::remove(self.profile) {
+                log::error!("Could not remove locked files report: {e:?}");
+            }
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.rollback();
+        }
+    }
+}
+
+/// How to lock, the same for every group
+struct LockOptions<'a> {
+    unlock_at: Time,
+    quota: Option<time::Duration>,
+    block: Option<&'a dyn sync::SyncBlocker>,
+    /// Sync runs while locked, what we add to the documents may be uploaded
+    allow_sync: bool,
+    block_policy: sync::Policy,
+    include_trash: bool,
+    mode: locker::Mode,
+    /// Skip documents opened within this long
+    grace: Option<time::Duration>,
+    filter: directory::Filter,
+    /// For what to do when nothing is found to lock
+    config: &'a Config,
+}
+
+/// What a lock hides, see `select`
+struct Selection {
+    to_lock: Vec<Uuid>,
+    roots: Vec<(indextree::NodeId, Time)>,
+    except: Vec<indextree::NodeId>,
+    /// pinned documents locked outside the `roots`
+    pinned: Vec<Uuid>,
+    missing: Vec<String>,
+    locked: group::Locked,
+    /// documents left out as they are in `recent`
+    postponed: usize,
+}
+
+/// The documents in `groups` and those trashed from them, leaving out
+/// those in `recent`, those `filter` leaves out and the lock reports
+fn select(
+    tree: &directory::Tree,
+    groups: Vec<group::Group>,
+    was_in: &HashMap<Uuid, directory::Parent>,
+    recent: &HashSet<Uuid>,
+    filter: &directory::Filter,
+) -> Selection {
     let mut to_lock = Vec::new();
+    let mut roots = Vec::new();
+    let mut except = Vec::new();
+    let mut pinned = Vec::new();
+    let mut missing = Vec::new();
+    let mut locked = group::Locked::default();
+    let mut postponed = 0;
+
+    // a document in two groups is locked with the first
+    let mut claimed = HashSet::new();
+    for group in groups {
+        let (group_roots, group_missing): (Vec<_>, Vec<_>) = util::without_overlapping(group.paths)
+            .iter()
+            .map(|p| tree.node_for(p))
+            .partition_result();
+        let (group_except, except_missing): (Vec<_>, Vec<_>) = group
+            .except
+            .iter()
+            .map(|p| tree.node_for(p))
+            .partition_result();
+        for path in except_missing {
+            warn!("could not find folder not to lock: {path}");
+        }
+        let keep: HashSet<_> = group_except
+            .iter()
+            .flat_map(|node| tree.descendant_files(*node))
+            .collect();
+        let in_roots: Vec<_> = group_roots
+            .iter()
+            .flat_map(|node| tree.descendant_files(*node))
+            .chain(tree.trashed_from(&group_roots, was_in))
+            .collect();
+        let group_pinned: Vec<_> = if group.pinned {
+            tree.pinned()
+                .into_iter()
+                .filter(|uuid| !in_roots.contains(uuid))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let (docs, open): (Vec<_>, Vec<_>) = in_roots
+            .into_iter()
+            .chain(group_pinned.iter().copied())
+            .filter(|uuid| !keep.contains(uuid) && !report::is_report(uuid))
+            .filter(|uuid| !placeholder::is_placeholder(uuid))
+            .filter(|uuid| filter.locks(*uuid))
+            .filter(|uuid| claimed.insert(*uuid))
+            .partition(|uuid| !recent.contains(uuid));
+        postponed += open.len();
+        pinned.extend(group_pinned.into_iter().filter(|uuid| docs.contains(uuid)));
+        except.extend(group_except);
+        locked.add(group.unlock_at, &docs);
+        to_lock.extend(docs);
+        roots.extend(group_roots.into_iter().map(|node| (node, group.unlock_at)));
+        missing.extend(group_missing);
+    }
+    Selection {
+        to_lock,
+        roots,
+        except,
+        pinned,
+        missing,
+        locked,
+        postponed,
+    }
+}
+
+fn try_lock(
+    profile: &Profile,
+    groups: Vec<group::Group>,
+    options: &LockOptions,
+) -> Result<(Option<sync::Pending>, usize)> {
+    unlock_files(profile).wrap_err("could not unlock files")?; // ensure nothing is in locked folder
+    placeholder::remove(profile)?;
 
-    let (roots, missing): (Vec<_>, Vec<_>) = forbidden
-        .drain(..)
-        .map(|p| tree.node_for(&p))
-        .partition_result();
-    for node in &roots {
-        let mut files = tree.descendant_files(*node);
-        to_lock.append(&mut files);
+    interrupt::check()?;
+    let (mut tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let was_in = if options.include_trash {
+        trash::remembered()?
+    } else {
+        HashMap::new()
+    };
+    if let Err(e) = trash::remember(&tree) {
+        warn!("could not remember where documents are: {e:#}");
     }
+    let recent = match options.grace {
+        Some(grace) => tree.opened_since(OffsetDateTime::now_utc() - grace),
+        None => HashSet::new(),
+    };
+    let Selection {
+        to_lock,
+        roots,
+        except,
+        pinned,
+        missing,
+        locked,
+        postponed,
+    } = select(&tree, groups, &was_in, &recent, &options.filter);
     for path in &missing {
         warn!("could not find: {path}, if it was not deleted or renamed this is a bug");
     }
+    if !missing.is_empty() {
+        exit::partial();
+    }
 
+    if postponed > 0 {
+        log::info!("postponing {postponed} recently opened documents");
+    }
     if to_lock.is_empty() {
-        warn!("Found nothing to lock, is folder empty?");
-        return Ok(());
+        if postponed == 0 {
+            nothing_locked(profile, &tree, &roots, options.config)?;
+        }
+        return Ok((None, postponed));
+    }
+
+    locker::check_room(&profile.safe_dir())?;
+    interrupt::check()?;
+    let mut transaction = Transaction::new(profile);
+    let folders = root_names(&tree, &roots);
+    let root_ids: Vec<_> = roots
+        .iter()
+        .filter_map(|(node, at)| tree.folder_uuid(*node).map(|uuid| (uuid, *at)))
+        .collect();
+    if options.config.report_sizes {
+        tree.measure(device::current().documents());
+    }
+    let mut pdf = report::build(
+        &tree,
+        roots,
+        &except,
+        &pinned,
+        missing,
+        options.unlock_at,
+        options.quota,
+    );
+    if options.config.report_history {
+        add_history_page(profile, &mut pdf, options.config);
+    }
+    report::set_sync_allowed(profile, options.allow_sync)?;
+    transaction
+        .save_report(pdf)
+        .wrap_err("Could not save locked files report")?;
+    if options.config.placeholders {
+        placeholder::add_folders(profile, &root_ids).wrap_err("Could not add placeholders")?;
+    }
+    if options.mode == locker::Mode::Stub {
+        placeholder::add_stubs(profile, &tree, &locked.unlock_times())
+            .wrap_err("Could not add stubs")?;
+    }
+    if let Some(blocker) = options.block {
+        interrupt::check()?;
+        if let Err(e) = transaction.block(blocker) {
+            block_failed(profile, options.config, options.block_policy, e)?;
+        }
     }
+    locked.save(profile)?;
+    transaction
+        .move_docs(&to_lock, options.mode)
+        .wrap_err("Could not move book data")?;
+    let pending = transaction.commit();
+    if let Err(e) = history::started(profile, folders, to_lock.len(), OffsetDateTime::now_utc()) {
+        warn!("Could not record the lock in the history: {e:?}");
+    }
+    Ok((pending, postponed))
+}
+
+/// The days in the report chart
+const REPORT_DAYS: u16 = 30;
+
+/// Adds a chart of the time locked in the last days to the report, best
+/// effort as it is only informative
+fn add_history_page(profile: &Profile, pdf: &mut report::Doc, config: &Config) {
+    let tz = match util::time::zone(config.timezone.as_deref()) {
+        Ok(tz) => tz,
+        Err(e) => {
+            warn!("Could not add the history to the report: {e:#}");
+            return;
+        }
+    };
+    match history::load(profile) {
+        Ok(sessions) => {
+            let now = OffsetDateTime::now_utc();
+            pdf.add_history(&history::per_day(&sessions, REPORT_DAYS, now, tz));
+        }
+        Err(e) => warn!("Could not add the history to the report: {e:#}"),
+    }
+}
 
-    let pdf = report::build(&tree, roots, missing, unlock_at);
-    report::save(pdf).wrap_err("Could not save locked files report")?;
-    if !allow_sync {
-        sync::block().wrap_err("Could not block sync")?;
+/// The folders locked from `roots`, as shown to the user
+fn root_names(tree: &directory::Tree, roots: &[(indextree::NodeId, Time)]) -> Vec<String> {
+    roots
+        .iter()
+        .map(|(node, _)| tree.path(node))
+        .map(|path| match path.to_string_lossy() {
+            path if path.is_empty() => "the whole library".to_owned(),
+            path => path.into_owned(),
+        })
+        .collect()
+}
+
+/// Reports the folders a lock found nothing in, as `nothing_locked` in
+/// the config says
+fn nothing_locked(
+    profile: &Profile,
+    tree: &directory::Tree,
+    roots: &[(indextree::NodeId, Time)],
+    config: &Config,
+) -> Result<()> {
+    let folders = root_names(tree, roots).join(", ");
+    let message = if folders.is_empty() {
+        "found nothing to lock".to_owned()
+    } else {
+        format!("found nothing to lock in: {folders}")
+    };
+    match config.nothing_locked {
+        config::NothingLocked::Warn => warn!("{message}, are the folders empty?"),
+        config::NothingLocked::Notify => {
+            warn!("{message}, are the folders empty?");
+            notify::send(config, profile, notify::Event::Failure, &message);
+        }
+        config::NothingLocked::Fail => {
+            return Err(eyre::eyre!(message))
+                .wrap_err(exit::Failure::Empty)
+                .suggestion("check the folders are not empty and the filters leave something")
+        }
     }
-    move_docs(&to_lock).wrap_err("Could not move book data")
+    Ok(())
 }
 
-fn lock(forbidden: Vec<String>, unlock_at: Time, allow_sync: bool) -> Result<()> {
+fn lock(
+    profile: &Profile,
+    ui: &systemd::Ui,
+    groups: Vec<group::Group>,
+    options: &LockOptions,
+) -> Result<usize> {
     // if we did not lock the ui before building the file tree the ui could
     // modify the tree while or after we are building it.
-    systemd::ui_action("stop").wrap_err("Could not stop gui")?;
-    if let Err(e) = try_lock(forbidden, unlock_at, allow_sync) {
-        log::error!("{e:?}");
-        log::info!("undoing lock work");
+    ui.stop().wrap_err("Could not stop gui")?;
+    // on failure the transaction in try_lock has already undone its work
+    let res = try_lock(profile, groups, options);
+    ui.reset_failed()?;
+    ui.start().wrap_err("Could not start gui")?;
+
+    let (pending, postponed) = res?;
+    if let Some(Err(e)) = pending.map(sync::Pending::wait) {
+        block_failed(profile, options.config, options.block_policy, e)?;
+    }
+    Ok(postponed)
+}
+
+/// Fails with `e` unless `policy` allows locking without blocking sync
+fn block_failed(
+    profile: &Profile,
+    config: &Config,
+    policy: sync::Policy,
+    e: eyre::Report,
+) -> Result<()> {
+    match policy {
+        sync::Policy::Required => Err(e).wrap_err(exit::Failure::Block),
+        sync::Policy::BestEffort => {
+            warn!("could not block sync, locking anyway: {e:?}");
+            let message = "could not block sync, documents may sync while locked";
+            notify::send(config, profile, notify::Event::Failure, message);
+            Ok(())
+        }
     }
-    report::remove()?;
-    systemd::reset_failed()?;
-    systemd::ui_action("start").wrap_err("Could not start gui")
 }
 
 // TODO commands: Run, Install, Uninstall. Last one does not need current args
 // Install creates a systemd unit file and loads it
 // Uninstall removes a systemd unit file and unloads it
-fn main() -> Result<()> {
-    use simplelog::{ColorChoice, TermLogger, TerminalMode};
+fn main() -> ExitCode {
+    let res = try_main();
+    if let Err(e) = &res {
+        eprintln!("Error: {e:?}");
+    }
+    exit::code(&res)
+}
 
+fn try_main() -> Result<()> {
     color_eyre::install()?;
     let cli = Cli::parse();
+    // these only print and should work anywhere, even without a device
+    match cli.command {
+        Commands::Completions { shell } => {
+            print_completions(shell);
+            return Ok(());
+        }
+        Commands::Manpage { dir } => return write_manpages(dir.as_deref()),
+        _ => (),
+    }
 
-    let config = ConfigBuilder::new()
-        .add_filter_ignore_str("trust_dns_resolver")
-        .add_filter_ignore_str("trust_dns_proto")
-        .build();
-    TermLogger::init(
-        cli.log.to_level_filter(),
-        config,
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )
-    .unwrap();
+    use_device(&cli)?;
+    init_logging(&cli);
+    // runs on a desktop, the device is on the other end of ssh
+    if matches!(cli.command, Commands::Remote { .. }) {
+        return remote(&cli);
+    }
+    if cli.simulate {
+        log::info!("simulating, files in data/ are used and the system is left alone");
+    }
+    interrupt::install_handler()?;
+    // before the safe dir is created, the fixture also creates `data/`
+    #[cfg(feature = "dev-fixture")]
+    if let Commands::DevFixture { docs } = cli.command {
+        return fixture::create(docs);
+    }
 
-    ensure_safe_dir()?;
-    match cli.command {
-        Commands::Run(args) => run(args).wrap_err("Error while running"),
-        Commands::Install(args) => install(args).wrap_err("Error while installing"),
-        Commands::Uninstall => remove().wrap_err("Error while removing"),
-        Commands::Unlock => unlock().wrap_err("Error unlocking files"),
-        Commands::ListTz { search } => util::time::list_tz(search),
+    let profile = Profile::new(&cli.profile)?;
+    ensure_safe_dir(&profile)?;
+    let config = Config::load().wrap_err(exit::Failure::Config)?;
+    if matches!(cli.command, Commands::Run { .. } | Commands::Unlock { .. }) {
+        if let Err(e) = ensure_installed(&config) {
+            log::error!("Could not restore the service: {e:?}");
+        }
+    }
+    undo_by_hand(&cli.command, &profile, &config)?;
+    let res = match cli.command {
+        Commands::Run { dry_run: true, .. } => installed_args(&profile).and_then(|a| dry_run(&a)),
+        Commands::Run { legacy, .. } => tracked_run(&profile, &legacy, &config),
+        Commands::Install {
+            args,
+            print,
+            dry_run,
+            bundle,
+            confirmed,
+            scheduler,
+        } => {
+            let preview = Preview::new(print, dry_run, bundle.as_deref());
+            install(
+                &profile, cli.log, *args, preview, confirmed, scheduler, &config,
+            )
+        }
+        .wrap_err("Error while installing"),
+        Commands::Reconfigure(changes) => {
+            reconfigure(&profile, &changes, &config).wrap_err("Error while reconfiguring")
+        }
+        Commands::AddFolder { path } => add_folder(&profile, &path, &config),
+        Commands::RemoveFolder { path } => remove_folder(&profile, &path, &config),
+        Commands::Uninstall {
+            keep_locked, purge, ..
+        } => remove(&profile, keep_locked, purge, &config).wrap_err("Error while removing"),
+        Commands::LockNow { hours } => tracked(&profile, &config, false, || {
+            lock_now(&profile, hours, &config).wrap_err("Error locking files")
+        }),
+        Commands::Pause { hours } => tracked(&profile, &config, false, || {
+            pause(&profile, hours, &config).wrap_err("Error pausing")
+        }),
+        Commands::Unlock { .. } => tracked(&profile, &config, false, || {
+            unlock(&profile, &config).wrap_err("Error unlocking files")
+        }),
+        Commands::RequestUnlock { reason } => request_unlock(&profile, reason, &config),
+        Commands::Approve { token, device } => approve_on(&profile, &token, device, &config),
+        Commands::Serve { listen } => serve(&profile, listen, &config),
+        Commands::Status => status(&config),
+        Commands::Stats => stats(&profile, &config),
+        Commands::History { days } => show_history(&profile, days, &config),
+        Commands::Tree => print_tree(),
+        Commands::ExportTree { format } => tree_export::print(format),
+        Commands::Digest { print } => digest::command(print, &config),
+        Commands::Config { show } => show_config(show, &config),
+        Commands::Doctor => doctor::run(),
+        Commands::ListTz { search } => {
+            util::time::list_tz(search);
+            Ok(())
+        }
+        Commands::Test { action } => self_test(&profile, &action, &config),
+        Commands::Cache { action } => cache(&profile, &action),
+        Commands::Routes { action } => routes(&profile, &action, &config),
+        Commands::Export { path } => export(&path),
+        Commands::Import { path, yes } => import(&path, yes).wrap_err("Error importing"),
+        Commands::Completions { .. } | Commands::Manpage { .. } | Commands::Remote { .. } => {
+            unreachable!("handled above")
+        }
+        #[cfg(feature = "dev-fixture")]
+        Commands::DevFixture { .. } => unreachable!("handled above"),
+    };
+    // the terminal gets the full report when we return
+    if let Err(e) = &res {
+        log::error!(target: logfile::FILE_ONLY, "{e:#}");
     }
+    res
 }
 
-fn run(args: Args) -> Result<()> {
-    set_os_timezone(&args.timezone).wrap_err("Could not change os time zone")?;
-    let start = Time::try_parse(&args.start).wrap_err("Invalid start time")?;
-    let end = Time::try_parse(&args.end).wrap_err("Invalid end time")?;
-    let now = OffsetDateTime::now_local()
-        .wrap_err("Could not get time")?
-        .time();
-    log::info!("system time: {now}");
+/// Asks before an unlock or uninstall undoes a lock in effect, then
+/// notifies if the schedule is being undone by hand
+fn undo_by_hand(command: &Commands, profile: &Profile, config: &Config) -> Result<()> {
+    let (yes, done) = match command {
+        Commands::Unlock { yes } => (*yes, "unlocked"),
+        Commands::Uninstall {
+            keep_locked: false,
+            yes,
+            ..
+        } => (*yes, "uninstalled"),
+        _ => (true, ""),
+    };
+    if !yes && lock_in_effect(profile, config)? {
+        print_undo(profile)?;
+        if !ask("Undo the lock?", "pass --yes to go ahead without asking")? {
+            return Err(eyre::eyre!(
+                "Not {done}, undoing the lock was not confirmed"
+            ));
+        }
+    }
+    report_tampering(command, profile, config);
+    Ok(())
+}
 
-    let forbidden = util::without_overlapping(args.path);
-    util::check_folders(&forbidden).wrap_err("Could not find folders")?;
+/// Whether `profile` has documents locked that a lock-now or its
+/// schedule keeps locked right now
+fn lock_in_effect(profile: &Profile, config: &Config) -> Result<bool> {
+    if !locked_files(profile)? {
+        return Ok(false);
+    }
+    let now = OffsetDateTime::now_utc();
+    if adhoc::until(profile, now)?.is_some() {
+        return Ok(true);
+    }
+    let Some(installed) = systemd::Installed::load(profile)? else {
+        return Ok(true);
+    };
+    let args = recorded_args(&installed)?;
+    Ok(match args.schedule()? {
+        Schedule::Window { start, end } => {
+            let tz = util::time::zone(args.timezone(config))?;
+            should_lock(now, start, end, tz)
+        }
+        Schedule::Quota(_) => true,
+    })
+}
 
-    if should_lock(now, start, end) {
-        log::info!("locking folders");
-        lock(forbidden, end, args.allow_sync).wrap_err("Could not lock forbidden folders")?;
+/// What undoing the lock of `profile` restores
+fn print_undo(profile: &Profile) -> Result<()> {
+    let documents = metrics::locked_documents(profile)?;
+    println!("{profile} is locked right now, undoing that restores {documents} documents");
+    if let Some(installed) = systemd::Installed::load(profile)? {
+        let args = recorded_args(&installed)?;
+        if args.lock_all {
+            println!("  in all folders");
+        }
+        for path in args.all_paths().iter().filter(|path| !path.is_empty()) {
+            println!("  in {path}");
+        }
+    }
+    if others_locked(profile)? {
+        println!("sync stays blocked, other profiles are locked");
     } else {
-        log::info!("unlocking everything");
-        unlock().wrap_err("Could not unlock all files")?;
+        println!("and unblocks sync");
+    }
+    Ok(())
+}
+
+/// Asks `question` on the terminal, errors if there is none
+fn ask(question: &str, suggestion: &'static str) -> Result<bool> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        return Err(eyre::eyre!("Can not ask to confirm, not a terminal")).suggestion(suggestion);
+    }
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Notifies if the schedule is being undone by hand rather than by a
+/// service or timer
+fn report_tampering(command: &Commands, profile: &Profile, config: &Config) {
+    if !notify::started_by_hand() {
+        return;
     }
+    let tampering = match command {
+        Commands::Unlock { .. } => Some("unlocked by hand"),
+        Commands::Uninstall { .. } => Some("uninstalled by hand"),
+        Commands::Pause { .. } => Some("paused by hand"),
+        Commands::RemoveFolder { .. } => Some("stopped locking a folder by hand"),
+        _ => None,
+    };
+    if let Some(message) = tampering {
+        notify::send(config, profile, notify::Event::Tamper, message);
+    }
+}
 
+/// Picks the device to act on, before anything uses it
+fn use_device(cli: &Cli) -> Result<()> {
+    if let Some(dir) = &cli.data_dir {
+        let works_on_copy = matches!(
+            cli.command,
+            Commands::Tree
+                | Commands::ListTz { .. }
+                | Commands::Install { dry_run: true, .. }
+                | Commands::Install { print: true, .. }
+                | Commands::Install {
+                    bundle: Some(_),
+                    ..
+                }
+        );
+        if !works_on_copy {
+            return Err(eyre::eyre!("This command does not work with --data-dir"))
+                .suggestion("use tree or install with --dry-run, --print or --bundle");
+        }
+        device::use_copy(dir).wrap_err("Could not create a dir to plan in")?;
+    } else if cli.simulate {
+        device::simulate();
+    }
     Ok(())
 }
 
-fn install(args: Args) -> Result<()> {
-    set_os_timezone(&args.timezone).wrap_err("Could not change os time zone")?;
-    let forbidden = util::without_overlapping(args.path.clone());
-    util::check_folders(&forbidden).wrap_err("Could not find folders")?;
-    systemd::write_service().wrap_err("Error creating service")?;
-    systemd::write_timer(&args).wrap_err("Error creating timer")?;
-    systemd::enable().wrap_err("Error enabling service timer")?;
-    run(args).wrap_err("Failed first run after install")
+fn remote(cli: &Cli) -> Result<()> {
+    let Commands::Remote {
+        host,
+        binary,
+        remote_path,
+        args,
+    } = &cli.command
+    else {
+        unreachable!("only called for remote")
+    };
+    let install = remote::Install {
+        path: remote_path,
+        binary: binary.as_deref(),
+    };
+    // --profile before `remote` is meant for the devices
+    let profile = (cli.profile != profile::DEFAULT).then(|| format!("--profile={}", cli.profile));
+    let args: Vec<_> = profile.into_iter().chain(args.iter().cloned()).collect();
+    remote::run(&util::cmd::System, host, &install, &args)
+}
+
+fn print_completions(shell: clap_complete::Shell) {
+    let mut cli = Cli::command();
+    let name = cli.get_name().to_owned();
+    clap_complete::generate(shell, &mut cli, name, &mut std::io::stdout());
+}
+
+/// Prints the main page, or writes all pages to `dir`
+fn write_manpages(dir: Option<&Path>) -> Result<()> {
+    let cli = Cli::command();
+    let Some(dir) = dir else {
+        return clap_mangen::Man::new(cli)
+            .render(&mut std::io::stdout())
+            .wrap_err("Could not print man page");
+    };
+    fs::create_dir_all(dir).wrap_err("Could not create man page dir")?;
+    clap_mangen::generate_to(cli, dir)
+        .wrap_err_with(|| format!("Could not write man pages to: {}", dir.display()))
+}
+
+/// Logs to the terminal and, if it can be opened, the log file
+fn init_logging(cli: &Cli) {
+    use simplelog::{
+        ColorChoice, CombinedLogger, SharedLogger, TermLogger, TerminalMode, WriteLogger,
+    };
+
+    let config = || {
+        ConfigBuilder::new()
+            .add_filter_ignore_str("trust_dns_resolver")
+            .add_filter_ignore_str("trust_dns_proto")
+            .clone()
+    };
+    let level = cli.log.to_level_filter();
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        level,
+        config().add_filter_ignore_str(logfile::FILE_ONLY).build(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )];
+
+    let path = cli.log_file.clone().unwrap_or_else(logfile::default_path);
+    let file = logfile::Rotating::open(&path, logfile::MAX_SIZE);
+    let problem = match file {
+        Ok(file) => {
+            let config = config().set_time_format_rfc3339().build();
+            loggers.push(WriteLogger::new(level, config, file));
+            None
+        }
+        Err(e) => Some(e),
+    };
+    CombinedLogger::init(loggers).unwrap();
+    if let Some(e) = problem {
+        warn!("Not logging to file: {e:#}");
+    }
+}
+
+/// Runs `command` and records its outcome in the metrics, `scheduled`
+/// commands also have their duration recorded and update the health file
+fn tracked(
+    profile: &Profile,
+    config: &Config,
+    scheduled: bool,
+    command: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let was_locked = locked_files(profile)?;
+    let started = Instant::now();
+    let res = command();
+
+    let outcome = metrics::Outcome {
+        was_locked,
+        is_locked: locked_files(profile).unwrap_or(was_locked),
+        failed: res.is_err(),
+        run_took: scheduled.then(|| started.elapsed()),
+    };
+    if let Err(e) = export_metrics(profile, &outcome, config) {
+        warn!("Could not export metrics: {e:?}");
+    }
+    if outcome.was_locked && !outcome.is_locked {
+        if let Err(e) = history::ended(profile, OffsetDateTime::now_utc()) {
+            warn!("Could not record the end of the lock: {e:?}");
+        }
+    }
+    if scheduled {
+        let error = res.as_ref().err().map(|e| format!("{e:#}"));
+        if let Err(e) = health::record(profile, &outcome, error) {
+            warn!("Could not write health file: {e:?}");
+        }
+    }
+    res
+}
+
+/// A `run` as the service starts it: tracked, and a failure is notified
+fn tracked_run(profile: &Profile, legacy: &[String], config: &Config) -> Result<()> {
+    if !legacy.is_empty() {
+        log::debug!("ignoring arguments passed by an outdated service: {legacy:?}");
+    }
+    tracked(profile, config, true, || {
+        installed_args(profile)
+            .and_then(|args| run(profile, &args, config))
+            .wrap_err("Error while running")
+            .inspect_err(|e| {
+                notify::send(config, profile, notify::Event::Failure, &format!("{e:#}"));
+            })
+    })
+}
+
+fn export_metrics(profile: &Profile, outcome: &metrics::Outcome, config: &Config) -> Result<()> {
+    metrics::record(profile, outcome)?;
+    let Some(path) = &config.metrics_file else {
+        return Ok(());
+    };
+    let mut profiles = Profile::installed()?;
+    if !profiles.contains(profile) {
+        profiles.push(profile.clone());
+    }
+    // the routing table is only ours to read on the device
+    let blocked_routes = if device::current().is_remarkable() {
+        Some(sync::blocked_routes()?)
+    } else {
+        None
+    };
+    metrics::write(path, &profiles, blocked_routes)
 }
 
-fn remove() -> Result<()> {
-    systemd::disable().wrap_err("Error disabling service")?;
-    systemd::remove_units().wrap_err("Error removing service files")?;
-    unlock().wrap_err("Error unlocking any locked documents")
+/// Locks or unlocks as the schedule says right now. A failed lock is
+/// undone, so running again after a failure, as systemd does, gives
+/// the same result as a run that succeeded the first time.
+fn run(profile: &Profile, args: &Args, config: &Config) -> Result<()> {
+    let timezone = args.timezone(config);
+    match timezone {
+        Some(timezone) if config.set_os_timezone => {
+            ensure_os_timezone(timezone).wrap_err("Could not change os time zone")?;
+        }
+        Some(_) => (),
+        None => log::warn!("no timezone configured, using the os timezone"),
+    }
+    let schedule = args.schedule()?;
+    let tz = util::time::zone(timezone).wrap_err("Could not get timezone")?;
+    let now = OffsetDateTime::now_utc().to_timezone(tz);
+    log::info!("local time: {now}");
+    if !args.force_time {
+        let keeping_lock = locked_files(profile)? || adhoc::until(profile, now)?.is_some();
+        let clock = check_clock(
+            now,
+            keeping_lock,
+            device::current(),
+            util::time::ntp_synchronized,
+        );
+        if let Err(e) = clock {
+            log::error!("not trusting the clock, unlocking instead: {e:?}");
+            return unlock(profile, config).wrap_err("Could not unlock all files");
+        }
+    }
+    digest::send_if_due(config, now.to_offset(time::UtcOffset::UTC));
+
+    let args = &guard_folders(profile, args, config)?;
+
+    if let Some(until) = adhoc::until(profile, now)? {
+        let until = until.to_timezone(tz);
+        log::info!("locked by lock-now until {until}");
+        let groups = args.single_group(until.time());
+        let session = Session::new(args, config)?;
+        return ensure_locked(profile, &session, args, groups, until.time(), None, config);
+    }
+    if let Some(until) = pause::until(profile, now)? {
+        log::info!("paused until {}", until.to_timezone(tz));
+        return unlock(profile, config).wrap_err("Could not unlock all files");
+    }
+
+    let (due, unlock_at, quota) = match schedule {
+        Schedule::Window { start, end } => {
+            let groups = group::from_tiers(
+                &args.unlock_at,
+                &args.paths(),
+                &args.except,
+                args.lock_pinned,
+                start,
+                end,
+            )?;
+            let due: Vec<_> = groups
+                .into_iter()
+                .filter(|group| should_lock(now, start, group.unlock_at, tz))
+                .collect();
+            if let Some(minutes) = args.warn_minutes.filter(|_| due.is_empty()) {
+                let within = time::Duration::minutes(minutes.into());
+                if util::time::starts_within(now, within, start, end, tz) {
+                    let message = format!("locking at {}", util::time::clock(start));
+                    log::info!("{message}");
+                    notify::send(config, profile, notify::Event::Upcoming, &message);
+                }
+            }
+            (due, end, None)
+        }
+        Schedule::Quota(quota) => {
+            let unlocked = !locked_files(profile)?;
+            let used = quota::update(profile, now, unlocked)?;
+            log::info!("unlocked {} of {} today", hm(used), hm(quota));
+            let due = if used >= quota {
+                args.single_group(Time::MIDNIGHT)
+            } else {
+                Vec::new()
+            };
+            (due, Time::MIDNIGHT, Some(quota))
+        }
+    };
+    if due.is_empty() {
+        // the sync refresh also runs outside the window, then there is
+        // usually nothing to undo
+        let blocking = sync::blocked()? && !others_locked(profile)?;
+        if !locked_files(profile)? && !grace::postponed(profile)? && !blocking {
+            log::info!("nothing locked or blocked, nothing to unlock");
+            return Ok(());
+        }
+        log::info!("unlocking everything");
+        return unlock(profile, config).wrap_err("Could not unlock all files");
+    }
+    // unlocking the tiers that ended and locking the rest stop the ui once
+    let session = Session::new(args, config)?;
+    session.ui.batch(|| {
+        if quota.is_none() {
+            unlock_ended(profile, &session.ui, &due, config)?;
+        }
+        ensure_locked(profile, &session, args, due, unlock_at, quota, config)
+    })
+}
+
+/// Errors if `now` can not be trusted to decide if we should lock. Keeping
+/// a lock only needs a plausible date, the clock is not synchronized after
+/// a reboot without network, for example with `--sync-blocker wifi`. Nor
+/// is it on a device that is not paired and maybe never online.
+fn check_clock(
+    now: OffsetDateTime,
+    keeping_lock: bool,
+    device: &device::Profile,
+    ntp_synchronized: impl FnOnce() -> Result<bool>,
+) -> Result<()> {
+    if keeping_lock || !device.cloud_paired() {
+        return util::time::check_plausible(now);
+    }
+    let synchronized =
+        ntp_synchronized().wrap_err("Could not check if the clock is synchronized")?;
+    util::time::check_clock(now, synchronized)
+}
+
+/// `args` following folders renamed or moved since install. Folders
+/// that are gone are handled as `missing_folder` in the config says.
+fn guard_folders(profile: &Profile, args: &Args, config: &Config) -> Result<Args> {
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let mut args = args.clone();
+    if let Some(mut installed) = systemd::Installed::load(profile)? {
+        if resolve_folders(&mut args, &mut installed.folders, &tree) {
+            installed.save(profile)?;
+        }
+    }
+    match_loosely(&mut args, &tree);
+    let missing: Vec<_> = args
+        .all_paths()
+        .into_iter()
+        .filter(|path| tree.node_for(path).is_err())
+        .collect();
+    if missing.is_empty() {
+        return Ok(args);
+    }
+    let missing = missing.join(", ");
+    let message = match config.missing_folder {
+        config::MissingFolder::Fail => {
+            // reports the missing folders with suggestions
+            util::check_folders(&args.all_paths()).wrap_err(exit::Failure::Missing)?;
+            return Ok(args);
+        }
+        config::MissingFolder::Notify => format!("could not find {missing}, locking the rest"),
+        config::MissingFolder::LockAll => {
+            args.lock_all = true;
+            args.path.clear();
+            args.unlock_at.clear();
+            format!("could not find {missing}, locking everything")
+        }
+    };
+    warn!("{message}");
+    notify::send(config, profile, notify::Event::Tamper, &message);
+    Ok(args)
+}
+
+/// With --fuzzy-paths points the paths in `args` that are not found to
+/// the folder they loosely match
+fn match_loosely(args: &mut Args, tree: &directory::Tree) {
+    if !args.fuzzy_paths {
+        return;
+    }
+    let matching = |path: &str| {
+        if tree.node_for(path).is_ok() {
+            return None;
+        }
+        let found = tree.fuzzy_path(path)?;
+        log::info!("{path} matches folder {found}");
+        Some(found)
+    };
+    for path in args.path.iter_mut().chain(args.except.iter_mut()) {
+        if let Some(found) = matching(path) {
+            *path = found;
+        }
+    }
+    for tier in &mut args.unlock_at {
+        let Some((at, path)) = tier.split_once('=') else {
+            continue;
+        };
+        if let Some(found) = matching(path) {
+            *tier = format!("{at}={found}");
+        }
+    }
+}
+
+/// Points the paths in `args` to where the folder with the uuid recorded
+/// for them in `folders` is now. Renames and moves are followed, and a
+/// new folder given the old name is not locked in its place. If the
+/// recorded folder is gone the path is locked and recorded instead.
+/// Returns whether `folders` changed.
+fn resolve_folders(
+    args: &mut Args,
+    folders: &mut BTreeMap<String, String>,
+    tree: &directory::Tree,
+) -> bool {
+    let mut changed = false;
+    let mut resolve = |path: &str| {
+        let recorded = folders.get(path)?;
+        let now = Uuid::parse(recorded)
+            .ok()
+            .and_then(|uuid| tree.folder_path(uuid));
+        match now {
+            Some(now) if now == path => None,
+            Some(now) => {
+                log::info!("{path} was renamed or moved to {now}, following it");
+                Some(now)
+            }
+            None => {
+                warn!("the folder installed as {path} is gone, locking {path} by its name");
+                if let Some(uuid) = tree.folder_id(path) {
+                    folders.insert(path.to_owned(), uuid.to_string());
+                    changed = true;
+                }
+                None
+            }
+        }
+    };
+    for path in args.path.iter_mut().chain(args.except.iter_mut()) {
+        if let Some(now) = resolve(path) {
+            *path = now;
+        }
+    }
+    for tier in &mut args.unlock_at {
+        let Some((at, path)) = tier.split_once('=') else {
+            continue;
+        };
+        if let Some(now) = resolve(path) {
+            *tier = format!("{at}={now}");
+        }
+    }
+    changed
+}
+
+/// Unlocks the locked groups that are not `due` while keeping the
+/// others locked
+fn unlock_ended(
+    profile: &Profile,
+    ui: &systemd::Ui,
+    due: &[group::Group],
+    config: &Config,
+) -> Result<()> {
+    let Some(mut locked) = group::Locked::load(profile)? else {
+        return Ok(());
+    };
+    let due: Vec<_> = due.iter().map(|group| group.unlock_at).collect();
+    let ended = locked.take_ended(&due);
+    if ended.is_empty() {
+        return Ok(());
+    }
+
+    log::info!("unlocking {} documents that unlock early", ended.len());
+    ui.stop().wrap_err("Could not stop gui")?;
+    let res = unlock_docs(profile, &ended)
+        .and_then(|()| locked.save(profile))
+        .and_then(|()| placeholder::remove_ended(profile, &due));
+    ui.reset_failed()?;
+    ui.start().wrap_err("Could not start gui")?;
+    res.wrap_err("Could not unlock documents that unlock early")?;
+    notify::send(
+        config,
+        profile,
+        notify::Event::Unlock,
+        "unlocked folders that unlock early",
+    );
+    Ok(())
+}
+
+/// Formats a duration as hours and minutes: 2:05
+fn hm(duration: time::Duration) -> String {
+    let minutes = duration.whole_minutes();
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// The ui and sync blocker shared by the (un)locks of one command
+struct Session {
+    ui: systemd::Ui,
+    block: Option<Box<dyn sync::SyncBlocker>>,
+}
+
+impl Session {
+    /// Must be created while xochitl is running, that is needed to detect
+    /// the ui and to discover what to block
+    fn new(args: &Args, config: &Config) -> Result<Self> {
+        let ui = systemd::Ui::detect(config).wrap_err(exit::Failure::Device)?;
+        let block: Option<Box<dyn sync::SyncBlocker>> = match args.sync_blocker {
+            _ if args.allow_sync => None,
+            sync::Backend::Routes => Some(Box::new(sync::Blocklist::new(
+                config,
+                &args.block_domain,
+                args.block_scope,
+                args.offline_ok,
+            ))),
+            sync::Backend::Wifi => Some(Box::new(sync::Wifi::new(&config.wifi_interface))),
+        };
+        let block = block.map(|inner| -> Box<dyn sync::SyncBlocker> {
+            if config.stop_sync_services {
+                Box::new(sync::StopServices::new(inner, config))
+            } else {
+                inner
+            }
+        });
+        Ok(Self { ui, block })
+    }
+}
+
+/// Locks `groups`, or if already locked refreshes the sync block
+fn ensure_locked(
+    profile: &Profile,
+    session: &Session,
+    args: &Args,
+    groups: Vec<group::Group>,
+    unlock_at: Time,
+    quota: Option<time::Duration>,
+    config: &Config,
+) -> Result<()> {
+    let postponed = grace::postponed(profile)?;
+    if locked_files(profile)? && !postponed {
+        // the sync ips might have changed since we locked
+        log::info!("already locked, refreshing sync block");
+        if let Some(blocker) = &session.block {
+            if let Err(e) = blocker.block().and_then(sync::Pending::wait) {
+                block_failed(profile, config, args.sync_block_policy, e)?;
+            }
+        }
+    } else {
+        log::info!("locking folders");
+        // the grace period is granted once, this run may be the one after it
+        grace::clear(profile)?;
+        let grace = if postponed {
+            None
+        } else {
+            grace_period(profile, args)?
+        };
+        let options = LockOptions {
+            unlock_at,
+            quota,
+            block: session.block.as_deref(),
+            allow_sync: args.allow_sync,
+            block_policy: args.sync_block_policy,
+            include_trash: args.include_trash,
+            mode: args.mode,
+            grace: grace.as_ref().map(|(_, grace)| *grace),
+            filter: args.filter()?,
+            config,
+        };
+        let postponed = lock(profile, &session.ui, groups, &options)
+            .wrap_err("Could not lock forbidden folders")?;
+        if let Some((installed, grace)) = grace.filter(|_| postponed > 0) {
+            grace::save(profile, OffsetDateTime::now_utc() + grace)?;
+            systemd::schedule_run_after(profile, &installed, grace)
+                .wrap_err("Could not schedule locking the recently opened documents")?;
+        }
+        let message = format!("locked folders until {unlock_at}");
+        notify::send(config, profile, notify::Event::Lock, &message);
+    }
+    Ok(())
+}
+
+/// How long to leave recently opened documents unlocked, with the record
+/// needed to run again once that is over
+fn grace_period(
+    profile: &Profile,
+    args: &Args,
+) -> Result<Option<(systemd::Installed, time::Duration)>> {
+    let Some(minutes) = args.grace_minutes else {
+        return Ok(None);
+    };
+    let Some(installed) = systemd::Installed::load(profile)? else {
+        log::warn!("{profile} is not installed, locking recently opened documents right away");
+        return Ok(None);
+    };
+    Ok(Some((installed, time::Duration::minutes(minutes.into()))))
+}
+
+/// Locks the folders `profile` was installed with for `hours`, an
+/// earlier lock-now that lasts longer is kept
+fn lock_now(profile: &Profile, hours: f32, config: &Config) -> Result<()> {
+    lock_for(profile, hours_duration(hours)?, config)
+}
+
+/// Locks the installed folders for `duration` from now, with an unlock
+/// scheduled at the end
+fn lock_for(profile: &Profile, duration: time::Duration, config: &Config) -> Result<()> {
+    let installed = systemd::Installed::load(profile)?
+        .ok_or_else(|| eyre::eyre!("Profile {profile} is not installed"))
+        .suggestion("lock-now locks the folders passed to install, install first")?;
+    let mut args = recorded_args(&installed)?;
+    if args.fuzzy_paths {
+        let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+        match_loosely(&mut args, &tree);
+    }
+    util::check_folders(&args.all_paths()).wrap_err(exit::Failure::Missing)?;
+
+    let tz = util::time::zone(args.timezone(config)).wrap_err("Could not get timezone")?;
+    let now = OffsetDateTime::now_utc();
+    let mut until = now
+        .checked_add(duration)
+        .ok_or_else(|| eyre::eyre!("Lock would last too long: {duration}"))?;
+    if let Some(earlier) = adhoc::until(profile, now)? {
+        until = until.max(earlier);
+    }
+    log::info!("locking until {}", until.to_timezone(tz));
+
+    pause::clear(profile)?;
+    adhoc::save(profile, until)?;
+    let res = systemd::schedule_unlock_after(profile, until - now)
+        .wrap_err("Could not schedule unlock")
+        .and_then(|()| {
+            let unlock_at = until.to_timezone(tz).time();
+            let groups = args.single_group(unlock_at);
+            let session = Session::new(&args, config)?;
+            ensure_locked(profile, &session, &args, groups, unlock_at, None, config)
+        });
+    if res.is_err() {
+        adhoc::clear(profile)?;
+    }
+    res
+}
+
+fn hours_duration(hours: f32) -> Result<time::Duration> {
+    Some(hours)
+        .filter(|hours| *hours > 0.0)
+        .and_then(|hours| time::Duration::checked_seconds_f32(hours * 3600.0))
+        .ok_or_else(|| eyre::eyre!("Invalid number of hours: {hours}"))
+}
+
+/// Unlocks `profile` for `hours`, once the pause ends the service is
+/// started so the schedule applies again. A longer earlier pause is kept
+fn pause(profile: &Profile, hours: f32, config: &Config) -> Result<()> {
+    let duration = hours_duration(hours)?;
+    let installed = systemd::Installed::load(profile)?
+        .ok_or_else(|| eyre::eyre!("Profile {profile} is not installed"))
+        .suggestion("there is no schedule to pause, install first")?;
+
+    let now = OffsetDateTime::now_utc();
+    let mut until = now
+        .checked_add(duration)
+        .ok_or_else(|| eyre::eyre!("Lock would last too long: {duration}"))?;
+    if let Some(earlier) = pause::until(profile, now)? {
+        until = until.max(earlier);
+    }
+    log::info!("pausing until {until}");
+
+    pause::save(profile, until)?;
+    let res = systemd::schedule_run_after(profile, &installed, until - now)
+        .wrap_err("Could not schedule the end of the pause")
+        .and_then(|()| unlock(profile, config));
+    if res.is_err() {
+        pause::clear(profile)?;
+    } else {
+        activity::record(profile, "pause", &format!("paused for {hours} hours"));
+    }
+    res
+}
+
+/// Asks the parent to unlock through a notification and waits until they
+/// approve or the request expires
+fn request_unlock(profile: &Profile, reason: String, config: &Config) -> Result<()> {
+    if systemd::Installed::load(profile)?.is_none() {
+        return Err(eyre::eyre!("Profile {profile} is not installed"));
+    }
+    if !notify::configured(config) {
+        return Err(eyre::eyre!("Nowhere to send the request to"))
+            .suggestion("set notify_url or mqtt in the config");
+    }
+    if !locked_files(profile)? {
+        return Err(eyre::eyre!("Nothing is locked"));
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let request = request::Request::new(reason, config.request_hours, now)?;
+    request.save(profile)?;
+    let token = request.token();
+    let link = config
+        .approve_url
+        .as_deref()
+        .map(|url| url.trim_end_matches('/'))
+        .map(|url| format!(" or open {url}/approve?token={token}&profile={profile}"))
+        .unwrap_or_default();
+    let message = format!(
+        "unlock for {} hours requested: {}. Approve with `book-safe approve {token}`{link}",
+        request.hours, request.reason
+    );
+    notify::send(config, profile, notify::Event::Request, &message);
+
+    let minutes = request::TTL.whole_minutes();
+    println!(
+        "asked to unlock for {} hours, waiting up to {minutes} minutes for approval",
+        request.hours
+    );
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        interrupt::check()?;
+        let Some(current) = request::Request::load(profile)? else {
+            return Err(eyre::eyre!("The request was withdrawn"));
+        };
+        if current.token() != token {
+            return Err(eyre::eyre!("A newer request replaced this one"));
+        }
+        if current.state == request::State::Approved {
+            println!("approved, unlocked for {} hours", current.hours);
+            return request::Request::clear(profile);
+        }
+        if current.expired(OffsetDateTime::now_utc()) {
+            request::Request::clear(profile)?;
+            return Err(eyre::eyre!("No approval within {minutes} minutes"));
+        }
+    }
+}
+
+/// Approves here, or with `device` on that device over its http api
+fn approve_on(
+    profile: &Profile,
+    token: &str,
+    device: Option<String>,
+    config: &Config,
+) -> Result<()> {
+    match device {
+        Some(url) => approve_remote(profile, token, &url),
+        None => tracked(profile, config, false, || {
+            approve(profile, token, config).wrap_err("Error approving")
+        }),
+    }
+}
+
+/// Approves the pending unlock request, pausing the lock for as long
+/// as was asked
+fn approve(profile: &Profile, token: &str, config: &Config) -> Result<()> {
+    let mut request = request::Request::load(profile)?
+        .ok_or_else(|| eyre::eyre!("There is no unlock request for profile {profile}"))?;
+    request
+        .approve(token, OffsetDateTime::now_utc())
+        .wrap_err(exit::Failure::Refused)?;
+    log::info!("approved unlock request: {}", request.reason);
+    pause(profile, request.hours, config)?;
+    request.save(profile)
+}
+
+fn approve_remote(profile: &Profile, token: &str, device: &str) -> Result<()> {
+    let url = format!(
+        "{}/approve?token={token}&profile={profile}",
+        device.trim_end_matches('/')
+    );
+    notify::post(&url, "text/plain", "").wrap_err("Could not approve on the device")?;
+    println!("approved");
+    Ok(())
+}
+
+fn serve(profile: &Profile, listen: SocketAddr, config: &Config) -> Result<()> {
+    let token = config
+        .api_token
+        .as_deref()
+        .filter(|token| !token.trim().is_empty());
+    let mqtt = config.mqtt.as_ref().filter(|mqtt| mqtt.commands);
+    if token.is_none() && mqtt.is_none() {
+        return Err(eyre::eyre!("Nothing to serve"))
+            .suggestion("add a long random api_token to the config file, or enable mqtt commands")
+            .with_note(|| {
+                format!(
+                    "the config file is located at: {}",
+                    config::path().display()
+                )
+            });
+    }
+    let commands = mqtt.map(|mqtt| mqtt::listen(mqtt.clone()));
+
+    let http = token.map(|token| (listen, token));
+    serve::run(http, commands.as_ref(), profile, |profile, action| {
+        let done = serde_json::json!({ "ok": true });
+        match action {
+            serve::Action::Status => api_status(),
+            serve::Action::Lock { hours } => {
+                tracked(profile, config, false, || lock_now(profile, hours, config)).map(|()| done)
+            }
+            serve::Action::Unlock => {
+                tracked(profile, config, false, || unlock(profile, config)).map(|()| done)
+            }
+            serve::Action::Pause { hours } => {
+                tracked(profile, config, false, || pause(profile, hours, config)).map(|()| done)
+            }
+            serve::Action::Approve { token } => {
+                tracked(profile, config, false, || approve(profile, &token, config)).map(|()| done)
+            }
+        }
+    })
+}
+
+fn show_config(show: bool, config: &Config) -> Result<()> {
+    if show {
+        return effective::show(config);
+    }
+    println!("{}", config::path().display());
+    Ok(())
+}
+
+/// Whether each installed profile is locked, and until when a lock-now
+/// or pause lasts as unix timestamp
+fn api_status() -> Result<serde_json::Value> {
+    let now = OffsetDateTime::now_utc();
+    let mut profiles = Vec::new();
+    for profile in Profile::installed()? {
+        let timestamp = |at: Option<OffsetDateTime>| at.map(OffsetDateTime::unix_timestamp);
+        profiles.push(serde_json::json!({
+            "profile": profile.to_string(),
+            "locked": locked_files(&profile)?,
+            "lock_now_until": timestamp(adhoc::until(&profile, now)?),
+            "paused_until": timestamp(pause::until(&profile, now)?),
+        }));
+    }
+    Ok(serde_json::json!({ "profiles": profiles }))
+}
+
+/// What `install` does instead of installing
+#[derive(Clone, Copy)]
+enum Preview<'a> {
+    Print,
+    DryRun,
+    Bundle(&'a Path),
+}
+
+impl<'a> Preview<'a> {
+    fn new(print: bool, dry_run: bool, bundle: Option<&'a Path>) -> Option<Self> {
+        bundle
+            .map(Preview::Bundle)
+            .or(print.then_some(Preview::Print))
+            .or(dry_run.then_some(Preview::DryRun))
+    }
+}
+
+/// Settings for `reconfigure` to change
+#[derive(clap::Args, Debug)]
+struct Changes {
+    /// Setting to go back to its default, or to remove all of for
+    /// one that can be passed multiple times, such as `path`
+    #[clap(long, value_name = "SETTING")]
+    unset: Vec<String>,
+    /// Do not ask before installing with --lock-all, or with
+    /// --allow-sync on a device paired with the cloud
+    #[clap(short, long, action = ArgAction::SetTrue)]
+    yes: bool,
+    /// The settings to change, as passed to install. Pass them after
+    /// --unset and --yes.
+    #[clap(
+        value_name = "SETTINGS",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    settings: Vec<String>,
+}
+
+/// What was confirmed on the command line, so install does not ask
+#[derive(clap::Args, Debug, Clone, Copy)]
+struct Confirmed {
+    /// Do not ask before installing with --lock-all
+    #[clap(short, long, action = ArgAction::SetTrue)]
+    yes: bool,
+    /// Do not ask before installing with --allow-sync on a device
+    /// paired with the cloud
+    #[clap(long, action = ArgAction::SetTrue, requires = "allow_sync")]
+    yes_i_understand: bool,
+}
+
+fn install(
+    profile: &Profile,
+    log: simplelog::Level,
+    mut args: Args,
+    preview: Option<Preview>,
+    confirmed: Confirmed,
+    kind: scheduler::Kind,
+    config: &Config,
+) -> Result<()> {
+    if args.fuzzy_paths {
+        let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+        match_loosely(&mut args, &tree);
+    }
+    util::check_folders(&args.all_paths()).wrap_err(exit::Failure::Missing)?;
+    util::check_folders(&args.except).wrap_err(exit::Failure::Missing)?;
+    args.filter()?;
+    args.resolve_times(config)?;
+    if let Schedule::Window { start, end } = args.schedule()? {
+        group::from_tiers(
+            &args.unlock_at,
+            &args.paths(),
+            &args.except,
+            args.lock_pinned,
+            start,
+            end,
+        )?;
+    }
+    let installed = systemd::Installed {
+        args: args.to_install_args(profile, log),
+        scheduler: kind,
+        folders: folder_ids(&args)?,
+    };
+    let scheduler = scheduler::new(kind);
+    match preview {
+        Some(Preview::DryRun) => return dry_run(&args),
+        Some(Preview::Print) => {
+            scheduler.print(profile, &installed.run_args(), &args, config)?;
+            let record = serde_json::to_string_pretty(&installed)?;
+            println!("# {}\n{record}", profile.record().display());
+            return Ok(());
+        }
+        // importing it asks before locking everything
+        Some(Preview::Bundle(path)) => {
+            let timezone = install_timezone(&args, config)?;
+            util::time::find_timezone(&timezone)?;
+            let bundle = bundle::Bundle::single(profile, installed, &timezone)?;
+            return bundle.write(path);
+        }
+        None => (),
+    }
+    if args.lock_all && !confirmed.yes {
+        confirm_lock_all(&args.except)?;
+    }
+    if args.allow_sync && !confirmed.yes_i_understand && device::current().cloud_paired() {
+        confirm_cloud_churn(&args)?;
+    }
+
+    let timezone = install_timezone(&args, config)?;
+    util::time::find_timezone(&timezone)?;
+    if config.set_os_timezone {
+        set_os_timezone(&timezone).wrap_err("Could not change os time zone")?;
+    }
+    Config::save_timezone(&timezone).wrap_err("Could not store the timezone")?;
+    args.timezone = Some(timezone);
+    if let Some(earlier) = systemd::Installed::load(profile)?.filter(|i| i.scheduler != kind) {
+        let earlier = scheduler::new(earlier.scheduler);
+        log::info!("replacing the {} of the earlier install", earlier.name());
+        earlier
+            .disable(profile)
+            .wrap_err("Error disabling earlier install")?;
+        earlier
+            .remove(profile)
+            .wrap_err("Error removing earlier install")?;
+    }
+    installed.save(profile)?;
+    scheduler.write(profile, &installed.run_args(), &args, config)?;
+    scheduler.verify(profile)?;
+    Config::restrict_permissions().wrap_err("Could not secure the config file")?;
+    scheduler
+        .enable(profile)
+        .wrap_err("Error enabling the scheduler")?;
+    // run will not re-lock if already locked, the folders might have changed
+    unlock(profile, config).wrap_err("Could not undo lock from previous install")?;
+    run(profile, &args, config).wrap_err("Failed first run after install")
+}
+
+/// The uuid of every folder in `args`, to follow them if renamed
+fn folder_ids(args: &Args) -> Result<BTreeMap<String, String>> {
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    Ok(args
+        .all_paths()
+        .into_iter()
+        .chain(args.except.iter().cloned())
+        .filter_map(|path| {
+            let uuid = tree.folder_id(&path)?;
+            Some((path, uuid.to_string()))
+        })
+        .collect())
+}
+
+/// Locking everything by mistake leaves nothing to read, ask first
+fn confirm_lock_all(except: &[String]) -> Result<()> {
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let keep: HashSet<_> = except
+        .iter()
+        .filter_map(|path| tree.node_for(path).ok())
+        .flat_map(|node| tree.descendant_files(node))
+        .collect();
+    let all = tree.descendant_files(*tree.root());
+    let locked = all.iter().filter(|uuid| !keep.contains(uuid)).count();
+    let question = format!(
+        "This locks {locked} of the {} documents, continue?",
+        all.len()
+    );
+    if ask(&question, "pass --yes to install without asking")? {
+        Ok(())
+    } else {
+        Err(eyre::eyre!("Not installed, --lock-all was not confirmed"))
+    }
+}
+
+/// With sync allowed the cloud sees the locked documents deleted at every
+/// lock and uploaded again at every unlock
+fn confirm_cloud_churn(args: &Args) -> Result<()> {
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let filter = args.filter()?;
+    let keep: HashSet<_> = args
+        .except
+        .iter()
+        .filter_map(|path| tree.node_for(path).ok())
+        .flat_map(|node| tree.descendant_files(node))
+        .collect();
+    let churned: HashSet<_> = args
+        .all_paths()
+        .iter()
+        .filter_map(|path| tree.node_for(path).ok())
+        .flat_map(|node| tree.descendant_files(node))
+        .filter(|uuid| !keep.contains(uuid) && filter.locks(*uuid))
+        .collect();
+    let bytes = churned.iter().map(|uuid| directory::doc_size(*uuid)).sum();
+    warn!(
+        "sync stays on and this device is paired with the cloud: every lock \
+        deletes {} documents ({}) from the cloud and every unlock uploads \
+        them again",
+        churned.len(),
+        util::progress::mb(bytes)
+    );
+    if ask(
+        "Install with sync allowed anyway?",
+        "pass --yes-i-understand to install without asking",
+    )? {
+        Ok(())
+    } else {
+        Err(eyre::eyre!("Not installed, --allow-sync was not confirmed"))
+            .suggestion("leave out --allow-sync to block sync while locked")
+    }
+}
+
+/// The timezone passed on the command line, the one stored by an earlier
+/// install or if neither exist the current os timezone
+fn install_timezone(args: &Args, config: &Config) -> Result<String> {
+    if let Some(timezone) = args.timezone.as_ref().or(config.timezone.as_ref()) {
+        return Ok(timezone.clone());
+    }
+
+    let timezone = util::time::os_timezone()
+        .wrap_err("Could not detect the timezone")
+        .suggestion("pass it using --timezone")?;
+    if timezone == "UTC" || timezone == "Etc/UTC" {
+        warn!("using timezone UTC, if that is not yours reinstall passing --timezone");
+    } else {
+        log::info!("using the os timezone: {timezone}");
+    }
+    Ok(timezone)
+}
+
+fn self_test(profile: &Profile, action: &TestAction, config: &Config) -> Result<()> {
+    match action {
+        TestAction::Notify => notify::test(config, profile).wrap_err("Notification test failed"),
+        TestAction::Lock { length } => {
+            let length = util::time::parse_age(length).wrap_err("Invalid --for")?;
+            tracked(profile, config, false, || {
+                test_lock(profile, length, config).wrap_err("Lock test failed")
+            })
+        }
+    }
+}
+
+/// Added to a test lock, the unlock scheduled at its end is only needed
+/// if `test lock` is stopped before it unlocks
+const TEST_LOCK_MARGIN: time::Duration = time::Duration::minutes(1);
+
+/// Locks for `length` then unlocks, saying what happened at every step
+fn test_lock(profile: &Profile, length: time::Duration, config: &Config) -> Result<()> {
+    if !length.is_positive() {
+        return Err(eyre::eyre!("The test lock has to last a while"))
+            .suggestion("pass a duration such as `--for 2m`");
+    }
+    if locked_files(profile)? {
+        return Err(eyre::eyre!("Profile {profile} is already locked"))
+            .wrap_err(exit::Failure::Refused)
+            .suggestion("test while unlocked, or run `unlock` first");
+    }
+
+    println!("locking the installed folders, the ui restarts");
+    lock_for(profile, length + TEST_LOCK_MARGIN, config)?;
+    let documents = metrics::locked_documents(profile)?;
+    println!("locked {documents} documents, the report lists them");
+    if report::sync_allowed(profile) {
+        println!("sync stays on, installed with --allow-sync");
+    } else {
+        println!("sync is blocked");
+    }
+
+    println!("unlocking in {length}, ctrl-c unlocks now");
+    let end = Instant::now() + length.unsigned_abs();
+    while Instant::now() < end && interrupt::check().is_ok() {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+    unlock(profile, config)?;
+    systemd::cancel_unlock(profile);
+    if locked_files(profile)? {
+        return Err(eyre::eyre!("Documents were left in the safe"))
+            .suggestion("run `unlock`, if they stay there please open an issue");
+    }
+    println!("unlocked, all {documents} documents are back");
+    Ok(())
+}
+
+fn cache(profile: &Profile, action: &CacheAction) -> Result<()> {
+    match action {
+        CacheAction::Show => sync::show_cache(),
+        CacheAction::Clear if locked_files(profile)? || others_locked(profile)? => Err(
+            eyre::eyre!("Can not clear the route cache while files are locked"),
+        )
+        .wrap_err(exit::Failure::Refused)
+        .suggestion("run `unlock` first"),
+        CacheAction::Clear => sync::clear_cache(),
+    }
+}
+
+fn routes(profile: &Profile, action: &RoutesAction, config: &Config) -> Result<()> {
+    let locked = || -> Result<bool> { Ok(locked_files(profile)? || others_locked(profile)?) };
+    match action {
+        RoutesAction::List => sync::list_routes(),
+        RoutesAction::Clear if locked()? => Err(eyre::eyre!(
+            "Can not clear the routes while files are locked"
+        ))
+        .wrap_err(exit::Failure::Refused)
+        .suggestion("run `unlock` first"),
+        RoutesAction::Clear => sync::clear_routes(config),
+        RoutesAction::Add { ip } => sync::add_route(*ip, config, locked()?),
+        RoutesAction::Remove { ip } => sync::remove_route(*ip, config),
+    }
+}
+
+/// The install command line recorded in `installed`
+fn recorded_cli(installed: &systemd::Installed) -> Result<Cli> {
+    // older records hold the same arguments passed to run
+    let recorded = installed.args.iter().map(|arg| match arg.as_str() {
+        "run" => "install",
+        arg => arg,
+    });
+    Cli::try_parse_from(std::iter::once(env!("CARGO_PKG_NAME")).chain(recorded))
+        .wrap_err("Could not parse the recorded install arguments")
+}
+
+fn recorded_args(installed: &systemd::Installed) -> Result<Args> {
+    match recorded_cli(installed)?.command {
+        Commands::Install { args, .. } => Ok(*args),
+        _ => Err(eyre::eyre!("Recorded arguments are not for install")),
+    }
+}
+
+/// The arguments `profile` was installed with
+fn installed_args(profile: &Profile) -> Result<Args> {
+    let installed = systemd::Installed::load(profile)?
+        .ok_or_else(|| eyre::eyre!("Profile {profile} is not installed"))
+        .suggestion("run uses the schedule passed to install, install first")?;
+    recorded_args(&installed)
+}
+
+fn export(path: &Path) -> Result<()> {
+    bundle::Bundle::collect()
+        .and_then(|bundle| bundle.write(path))
+        .wrap_err("Error exporting")
+}
+
+/// Replaces the config with the one exported to `path` and installs the
+/// profiles exported with it, profiles not in it are left alone
+fn import(path: &Path, yes: bool) -> Result<()> {
+    let bundle = bundle::Bundle::read(path)?;
+    // check every profile before changing anything
+    let mut profiles = Vec::new();
+    for (name, installed) in &bundle.profiles {
+        let profile = Profile::new(name)?;
+        let log = recorded_cli(installed)
+            .wrap_err_with(|| format!("Invalid install record for profile {name}"))?
+            .log;
+        let args = recorded_args(installed)?;
+        profiles.push((profile, log, args, installed.scheduler));
+    }
+    Config::save_json(&bundle.config).wrap_err("Could not import the config")?;
+    let config = Config::load().wrap_err(exit::Failure::Config)?;
+    for (profile, log, args, kind) in profiles {
+        log::info!("installing profile {profile}");
+        ensure_safe_dir(&profile)?;
+        let confirmed = Confirmed {
+            yes,
+            yes_i_understand: yes,
+        };
+        install(&profile, log, args, None, confirmed, kind, &config)
+            .wrap_err_with(|| format!("Could not install profile {profile}"))?;
+    }
+    Ok(())
+}
+
+/// Installs `profile` again with the recorded settings, except those in
+/// `settings` and `unset` and those conflicting with the `settings`
+fn reconfigure(profile: &Profile, changes: &Changes, config: &Config) -> Result<()> {
+    let installed = systemd::Installed::load(profile)?
+        .ok_or_else(|| eyre::eyre!("Profile {profile} is not installed"))
+        .suggestion("there is nothing to reconfigure, install first")?;
+    let log = recorded_cli(&installed)?.log;
+    let recorded = recorded_args(&installed)?.to_install_args(profile, log);
+    let flags = recorded.iter().skip_while(|arg| *arg != "install").skip(1);
+    let replaced = replaced_settings(&changes.unset, &changes.settings)?;
+    let kept = flags.filter(|flag| setting_id(flag).is_none_or(|id| !replaced.contains(&id)));
+
+    let merged = std::iter::once(env!("CARGO_PKG_NAME"))
+        .chain(kept.map(String::as_str))
+        .chain(changes.settings.iter().map(String::as_str));
+    let args = Args::try_parse_from(merged).wrap_err("Invalid settings")?;
+    let confirmed = Confirmed {
+        yes: changes.yes,
+        yes_i_understand: changes.yes,
+    };
+    install(
+        profile,
+        log,
+        args,
+        None,
+        confirmed,
+        installed.scheduler,
+        config,
+    )
+}
+
+/// Ids of the settings to drop from the install record: those `unset`,
+/// those in `settings` and those that conflict with them
+fn replaced_settings(unset: &[String], settings: &[String]) -> Result<HashSet<String>> {
+    let command = Args::command();
+    let mut replaced = HashSet::new();
+    for name in unset {
+        let id = setting_id(name)
+            .ok_or_else(|| eyre::eyre!("Unknown setting: {name}"))
+            .suggestion("pass the name of an install option, for example: allow-sync")?;
+        replaced.insert(id);
+    }
+    // errors are reported when parsing the merged settings
+    let matches = command
+        .clone()
+        .no_binary_name(true)
+        .ignore_errors(true)
+        .try_get_matches_from(settings)
+        .wrap_err("Invalid settings")?;
+    let given: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| {
+            matches.value_source(arg.get_id().as_str())
+                == Some(clap::parser::ValueSource::CommandLine)
+        })
+        .collect();
+    // a conflict is declared on one of the two args
+    let conflicts = |a, b| command.get_arg_conflicts_with(a).contains(&b);
+    for arg in command.get_arguments() {
+        if given
+            .iter()
+            .any(|given| *given == arg || conflicts(given, arg) || conflicts(arg, given))
+        {
+            replaced.insert(arg.get_id().to_string());
+        }
+    }
+    Ok(replaced)
+}
+
+/// The id of the install option `flag` sets, with or without dashes
+/// and value, for example `--end=7:00`
+fn setting_id(flag: &str) -> Option<String> {
+    let long = flag.trim_start_matches('-').split('=').next()?;
+    Args::command()
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long))
+        .map(|arg| arg.get_id().to_string())
+}
+
+/// Firmware updates wipe our units from `/etc`, this restores them using
+/// the arguments recorded during install
+fn ensure_installed(config: &Config) -> Result<()> {
+    if !device::current().is_remarkable() {
+        return Ok(());
+    }
+
+    for profile in Profile::installed()? {
+        let Some(installed) = systemd::Installed::load(&profile)? else {
+            continue;
+        };
+        let scheduler = scheduler::new(installed.scheduler);
+        if update_units(&profile, &installed, config)? {
+            warn!("{} of profile {profile} was missing or outdated (after a firmware update?), reinstalled it", scheduler.name());
+        }
+    }
+    Ok(())
+}
+
+/// Writes and enables the units, or cron entries, of `installed` if
+/// they are missing or outdated. Returns whether they were.
+fn update_units(
+    profile: &Profile,
+    installed: &systemd::Installed,
+    config: &Config,
+) -> Result<bool> {
+    let args = recorded_args(installed)?;
+    let scheduler = scheduler::new(installed.scheduler);
+    if scheduler.up_to_date(profile, &installed.run_args(), &args, config)? {
+        return Ok(false);
+    }
+    scheduler.write(profile, &installed.run_args(), &args, config)?;
+    scheduler
+        .enable(profile)
+        .wrap_err("Error enabling the scheduler")?;
+    Ok(true)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FolderChange {
+    Add,
+    Remove,
+}
+
+fn add_folder(profile: &Profile, path: &str, config: &Config) -> Result<()> {
+    tracked(profile, config, false, || {
+        change_folder(profile, path, FolderChange::Add, config).wrap_err("Error adding folder")
+    })
+}
+
+fn remove_folder(profile: &Profile, path: &str, config: &Config) -> Result<()> {
+    tracked(profile, config, false, || {
+        change_folder(profile, path, FolderChange::Remove, config).wrap_err("Error removing folder")
+    })
+}
+
+/// Adds `path` to or removes it from the folders `profile` locks, with
+/// --lock-all it is removed from or added to the exceptions instead.
+/// While locked the lock is redone with the new folders.
+fn change_folder(
+    profile: &Profile,
+    path: &str,
+    change: FolderChange,
+    config: &Config,
+) -> Result<()> {
+    let mut installed = systemd::Installed::load(profile)?
+        .ok_or_else(|| eyre::eyre!("Profile {profile} is not installed"))
+        .suggestion("install first, passing the folder with --path")?;
+    let log = recorded_cli(&installed)?.log;
+    let mut args = recorded_args(&installed)?;
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let folder_id = change_folder_args(&mut args, &tree, path, change)?;
+
+    match folder_id {
+        Some(uuid) => installed.folders.insert(path.to_owned(), uuid.to_string()),
+        None => installed.folders.remove(path),
+    };
+    installed.args = args.to_install_args(profile, log);
+    installed.save(profile)?;
+    // removing a folder that unlocks early removes its timer edge
+    if device::current().is_remarkable() && update_units(profile, &installed, config)? {
+        log::info!("updated the schedule");
+    }
+
+    if !locked_files(profile)? {
+        log::info!("the folders change from the next lock on");
+        return Ok(());
+    }
+    relock(profile, &args, config)
+}
+
+/// Changes the folders in `args` as `change_folder` describes, returns
+/// the id of the folder if it is now recorded in the install record
+fn change_folder_args(
+    args: &mut Args,
+    tree: &directory::Tree,
+    path: &str,
+    change: FolderChange,
+) -> Result<Option<Uuid>> {
+    let listed = if args.lock_all {
+        &mut args.except
+    } else {
+        &mut args.path
+    };
+    // with --lock-all the exceptions are the folders not locked
+    let to_list = (change == FolderChange::Add) != args.lock_all;
+    let tiers: Vec<_> = args
+        .unlock_at
+        .iter()
+        .filter_map(|tier| tier.split_once('=').map(|(_, p)| p))
+        .collect();
+    let is_listed = listed.iter().any(|p| p == path) || tiers.contains(&path);
+
+    if to_list {
+        if is_listed {
+            let state = if args.lock_all {
+                "not locked"
+            } else {
+                "locked"
+            };
+            return Err(eyre::eyre!("{path} is already {state}"));
+        }
+        util::check_folders(&[path.to_owned()]).wrap_err(exit::Failure::Missing)?;
+        listed.push(path.to_owned());
+        return Ok(tree.folder_id(path));
+    }
+
+    if !is_listed {
+        return Err(if args.lock_all {
+            eyre::eyre!("{path} is already locked by --lock-all")
+        } else {
+            eyre::eyre!("{path} is not locked")
+        })
+        .suggestion("`book-safe config --show` lists the locked folders");
+    }
+    let others = args.all_paths().into_iter().filter(|p| p != path).count();
+    if others == 0 && !args.lock_pinned {
+        return Err(eyre::eyre!("That would leave no folder to lock"))
+            .suggestion("use uninstall to stop locking");
+    }
+    let listed = if args.lock_all {
+        &mut args.except
+    } else {
+        &mut args.path
+    };
+    listed.retain(|p| p != path);
+    args.unlock_at
+        .retain(|tier| tier.split_once('=').is_none_or(|(_, p)| p != path));
+    Ok(None)
+}
+
+/// Undoes the lock of `profile` then runs, locking the folders in
+/// `args`. Unlike `unlock` this keeps sync blocked and a lock-now.
+fn relock(profile: &Profile, args: &Args, config: &Config) -> Result<()> {
+    let ui = systemd::Ui::detect(config).wrap_err(exit::Failure::Device)?;
+    ui.stop().wrap_err("Could not stop gui")?;
+    let res = try_unlock(profile);
+    ui.reset_failed()?;
+    ui.start().wrap_err("Could not start gui")?;
+    res.wrap_err("Could not undo the lock")?;
+    run(profile, args, config).wrap_err("Could not lock again")
+}
+
+fn remove(profile: &Profile, keep_locked: bool, purge: bool, config: &Config) -> Result<()> {
+    let installed = systemd::Installed::load(profile)?;
+    let scheduler = scheduler::new(installed.as_ref().map(|i| i.scheduler).unwrap_or_default());
+    scheduler
+        .disable(profile)
+        .wrap_err("Error disabling service")?;
+    scheduler
+        .remove(profile)
+        .wrap_err("Error removing service files")?;
+    systemd::Installed::remove(profile)?;
+
+    if keep_locked && locked_files(profile)? {
+        let installed = installed.ok_or_else(|| eyre::eyre!("No install record for: {profile}"))?;
+        let args = recorded_args(&installed)?;
+        let end = match args.schedule()? {
+            Schedule::Window { end, .. } => end,
+            Schedule::Quota(_) => Time::MIDNIGHT,
+        };
+        systemd::schedule_unlock(profile, end, args.timezone(config))
+            .wrap_err("Could not schedule unlock")?;
+        return Ok(());
+    }
+
+    unlock(profile, config).wrap_err("Error unlocking any locked documents")?;
+    quota::clear(profile)?;
+    pause::clear(profile)?;
+    request::Request::clear(profile)?;
+    metrics::clear(profile)?;
+    health::clear(profile)?;
+    if purge {
+        self::purge(profile)?;
+    }
+    Ok(())
+}
+
+/// Removes everything book-safe stored for `profile`, and if no other
+/// profiles are installed the shared route cache, state and config
+fn purge(profile: &Profile) -> Result<()> {
+    fs::remove_dir(profile.safe_dir())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove the books safe")?;
+
+    history::clear(profile)?;
+    if !Profile::installed()?.is_empty() {
+        log::info!("other profiles are installed, keeping the shared state and config");
+        return Ok(());
+    }
+    log::info!("removing state and config");
+    fs::remove_dir_all(state_dir())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove the state dir")?;
+    fs::remove_file(config::path())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove the config file")
+}
+
+fn stats(profile: &Profile, config: &Config) -> Result<()> {
+    let installed = systemd::Installed::load(profile)?
+        .ok_or_else(|| eyre::eyre!("Profile {profile} is not installed"))
+        .suggestion("stats are for the folders passed to install, install first")?;
+    let args = recorded_args(&installed)?;
+    // rewritten on every install
+    let installed_at = fs::metadata(profile.record())
+        .and_then(|meta| meta.modified())
+        .map(OffsetDateTime::from)
+        .ok();
+    let tz = util::time::zone(args.timezone(config)).wrap_err("Could not get timezone")?;
+    stats::print(profile, &args.all_paths(), installed_at, tz)
+}
+
+fn show_history(profile: &Profile, days: u16, config: &Config) -> Result<()> {
+    let tz = util::time::zone(config.timezone.as_deref()).wrap_err("Could not get timezone")?;
+    history::print(profile, days, tz)
+}
+
+/// Prints what a lock with `args` would hide
+fn dry_run(args: &Args) -> Result<()> {
+    let groups = match args.schedule()? {
+        Schedule::Window { start, end } => group::from_tiers(
+            &args.unlock_at,
+            &args.paths(),
+            &args.except,
+            args.lock_pinned,
+            start,
+            end,
+        )?,
+        Schedule::Quota(_) => args.single_group(Time::MIDNIGHT),
+    };
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let was_in = if args.include_trash {
+        trash::remembered()?
+    } else {
+        HashMap::new()
+    };
+    let selection = select(&tree, groups, &was_in, &HashSet::new(), &args.filter()?);
+    for path in &selection.missing {
+        warn!("could not find: {path}");
+    }
+    let paths = tree.doc_paths();
+    let locked: Vec<_> = selection
+        .to_lock
+        .iter()
+        .filter_map(|uuid| paths.get(uuid))
+        .sorted()
+        .collect();
+    println!("{} documents would be locked", locked.len());
+    for path in locked {
+        println!("  {}", path.display());
+    }
+    Ok(())
+}
+
+fn print_tree() -> Result<()> {
+    let (mut tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    tree.measure(device::current().documents());
+    print!("{tree}");
+    print!("{}", tree.trash());
+    if let Some(orphaned) = tree.orphaned() {
+        print!("{orphaned}");
+    }
+    Ok(())
+}
+
+/// When the service last ran and how that went, warns if that was
+/// too long ago
+fn print_last_run(profile: &Profile, timezone: Option<&str>) -> Result<()> {
+    let Some(run) = health::last_run(profile)? else {
+        println!("\tnot run by the service yet");
+        return Ok(());
+    };
+    let tz = util::time::zone(timezone)?;
+    let at = OffsetDateTime::from_unix_timestamp(run.at)
+        .wrap_err("Invalid time in health file")?
+        .to_timezone(tz);
+    let result = match &run.error {
+        None => "ok".to_owned(),
+        Some(error) => format!("failed: {error}"),
+    };
+    println!(
+        "\tlast run {} {}:{:02}, {result}",
+        at.date(),
+        at.hour(),
+        at.minute()
+    );
+    if run.stale(OffsetDateTime::now_utc()) {
+        println!(
+            "\tstale: no run for over {} hours, is the service still installed?",
+            health::STALE.whole_hours()
+        );
+    }
+    Ok(())
+}
+
+fn status(config: &Config) -> Result<()> {
+    let profiles = Profile::installed()?;
+    if profiles.is_empty() {
+        println!("no profiles installed");
+    }
+
+    for profile in profiles {
+        let Some(installed) = systemd::Installed::load(&profile)? else {
+            continue;
+        };
+        let args = recorded_args(&installed)?;
+        let locked = if locked_files(&profile)? {
+            "locked"
+        } else {
+            "unlocked"
+        };
+        let scheduler = scheduler::new(installed.scheduler);
+        let state = scheduler
+            .state(&profile)
+            .unwrap_or_else(|e| format!("unknown ({e})"));
+        println!("{profile}: {locked}, {} {state}", scheduler.name());
+        print_last_run(&profile, args.timezone(config))?;
+        if let Some(until) = adhoc::until(&profile, OffsetDateTime::now_utc())? {
+            let tz = util::time::zone(args.timezone(config))?;
+            println!("\tlocked by lock-now until {}", until.to_timezone(tz));
+        }
+        if let Some(until) = pause::until(&profile, OffsetDateTime::now_utc())? {
+            let tz = util::time::zone(args.timezone(config))?;
+            println!("\tpaused until {}", until.to_timezone(tz));
+        }
+        let timezone = args.timezone(config).unwrap_or("os timezone");
+        match args.schedule()? {
+            Schedule::Window { start, end } => println!(
+                "\t{} - {} ({timezone})",
+                util::time::clock(start),
+                util::time::clock(end)
+            ),
+            Schedule::Quota(quota) => {
+                let tz = util::time::zone(args.timezone(config))?;
+                let now = OffsetDateTime::now_utc().to_timezone(tz);
+                let used = quota::used(&profile, now)?;
+                println!(
+                    "\tquota {} per day, {} used today ({timezone})",
+                    hm(quota),
+                    hm(used)
+                );
+            }
+        }
+        if args.mode == locker::Mode::Readonly {
+            println!("\treadonly, locked documents stay in the ui");
+        }
+        if args.mode == locker::Mode::Stub {
+            println!("\tstub, locked documents are replaced by a page");
+        }
+        if args.lock_all {
+            println!("\tall documents");
+        }
+        for path in &args.path {
+            println!("\t{path}");
+        }
+        for path in &args.except {
+            println!("\texcept {path}");
+        }
+        for tier in &args.unlock_at {
+            let (at, path) = group::parse_tier(tier)?;
+            println!("\t{path} (unlocks at {})", util::time::clock(at));
+        }
+    }
+
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    if let Some(orphaned) = tree.orphaned() {
+        println!("in folders that no longer exist, these can not be locked:");
+        print!("{orphaned}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Completions and man pages are generated from this
+    #[test]
+    fn cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    /// Adds a document or folder with a uuid made from its `name`
+    fn add_doc(name: &str, parent: Option<&str>, folder: bool) {
+        let kind = if folder {
+            "CollectionType"
+        } else {
+            "DocumentType"
+        };
+        let uuid = directory::test::id(name).to_string();
+        let path = device::current().documents().join(uuid);
+        let parent = parent.map(directory::test::id);
+        let parent = parent.map(|uuid| uuid.to_string()).unwrap_or_default();
+        let metadata =
+            format!(r#"{{"parent": "{parent}", "type": "{kind}", "visibleName": "{name}"}}"#);
+        fs::write(path.with_extension("metadata"), metadata).unwrap();
+        if !folder {
+            fs::write(path.with_extension("pdf"), "%PDF-1.4").unwrap();
+            fs::create_dir(path).unwrap();
+        }
+    }
+
+    fn listing(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The default profile with its safe, and the ui a lock stops
+    fn lock_setup() -> (Profile, systemd::Ui) {
+        let profile = Profile::new(profile::DEFAULT).unwrap();
+        ensure_safe_dir(&profile).unwrap();
+        let ui = systemd::Ui::detect(&Config::default()).unwrap();
+        (profile, ui)
+    }
+
+    fn seven() -> Time {
+        Time::from_hms(7, 0, 0).unwrap()
+    }
+
+    /// Locks the `Books` folder until 7:00
+    fn books_group() -> group::Group {
+        group::Group {
+            paths: vec!["Books".to_owned()],
+            except: Vec::new(),
+            pinned: false,
+            unlock_at: seven(),
+        }
+    }
+
+    /// A plain lock until `unlock_at`, tests change what they are about
+    fn options(unlock_at: Time) -> LockOptions<'static> {
+        static CONFIG: std::sync::LazyLock<Config> = std::sync::LazyLock::new(Config::default);
+        LockOptions {
+            unlock_at,
+            quota: None,
+            block: None,
+            allow_sync: false,
+            block_policy: sync::Policy::Required,
+            include_trash: false,
+            mode: locker::Mode::Hide,
+            grace: None,
+            filter: directory::Filter::default(),
+            config: &CONFIG,
+        }
+    }
+
+    #[test]
+    fn lock_report_unlock() {
+        let root = device::simulate_in_temp_dir("roundtrip");
+        let documents = device::current().documents();
+        fs::create_dir(documents).unwrap();
+        add_doc("Books", None, true);
+        add_doc("Sci-fi", Some("Books"), true);
+        add_doc("Dune", Some("Sci-fi"), false);
+        add_doc("Emma", Some("Books"), false);
+        add_doc("Notes", None, false);
+        let before = listing(documents);
+
+        let (profile, ui) = lock_setup();
+        lock(&profile, &ui, vec![books_group()], &options(seven())).unwrap();
+
+        assert!(locked_files(&profile).unwrap());
+        let safe = listing(&profile.safe_dir());
+        let [dune, emma, notes] = ["Dune", "Emma", "Notes"].map(directory::test::id);
+        for locked in [
+            dune.to_string(),
+            format!("{dune}.metadata"),
+            format!("{dune}.pdf"),
+            format!("{emma}.metadata"),
+        ] {
+            assert!(safe.contains(&locked), "{locked} not locked");
+        }
+        let visible = listing(documents);
+        assert!(visible.contains(&format!("{notes}.metadata")));
+        let report_added = visible
+            .iter()
+            .filter(|name| !before.contains(name))
+            .any(|name| Path::new(name).extension().is_some_and(|ext| ext == "pdf"));
+        assert!(report_added);
+
+        try_unlock(&profile).unwrap();
+        assert!(!locked_files(&profile).unwrap());
+        assert_eq!(listing(documents), before);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn nothing_to_lock() {
+        let root = device::simulate_in_temp_dir("nothing-to-lock");
+        let documents = device::current().documents();
+        fs::create_dir(documents).unwrap();
+        add_doc("Books", None, true);
+        add_doc("Notes", None, false);
+
+        let (profile, ui) = lock_setup();
+        let groups = vec![books_group()];
+        let mut options = options(seven());
+        lock(&profile, &ui, groups.clone(), &options).unwrap();
+
+        let config = Config {
+            nothing_locked: config::NothingLocked::Fail,
+            ..Config::default()
+        };
+        options.config = &config;
+        let err = lock(&profile, &ui, groups, &options).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&exit::Failure::Empty));
+        assert!(format!("{err:#}").contains("found nothing to lock in: Books"));
+        assert!(!locked_files(&profile).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    struct Unreachable;
+
+    impl sync::SyncBlocker for Unreachable {
+        fn block(&self) -> Result<sync::Pending> {
+            Err(eyre::eyre!("no routes resolved in time"))
+        }
+
+        fn unblock(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn block_policy() {
+        let root = device::simulate_in_temp_dir("block-policy");
+        let documents = device::current().documents();
+        fs::create_dir(documents).unwrap();
+        add_doc("Books", None, true);
+        add_doc("Dune", Some("Books"), false);
+
+        let (profile, ui) = lock_setup();
+        let groups = vec![books_group()];
+        let mut options = options(seven());
+        options.block = Some(&Unreachable);
+        let err = lock(&profile, &ui, groups.clone(), &options).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&exit::Failure::Block));
+        assert!(!locked_files(&profile).unwrap());
+
+        options.block_policy = sync::Policy::BestEffort;
+        lock(&profile, &ui, groups, &options).unwrap();
+        assert!(locked_files(&profile).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unsynchronized_clock_keeps_locks() {
+        let root = device::simulate_in_temp_dir("unsynced-clock");
+        let device = device::Profile::remarkable_in(&root);
+        fs::write(root.join("xochitl.conf"), "[General]\ndevicetoken=abc\n").unwrap();
+        let now = OffsetDateTime::from_unix_timestamp(1_790_000_000).unwrap();
+        let unsynced = || Ok(false);
+        assert!(
+            check_clock(now, false, &device, unsynced).is_err(),
+            "no new lock"
+        );
+        check_clock(now, true, &device, unsynced).unwrap();
+        let reset = OffsetDateTime::UNIX_EPOCH;
+        assert!(check_clock(reset, true, &device, || Ok(true)).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// A device that is never online can not synchronize its clock nor
+    /// resolve the sync domains, neither may stop it from locking
+    #[test]
+    fn offline_device_locks() {
+        let root = device::simulate_in_temp_dir("offline-device");
+        let device = device::Profile::remarkable_in(&root);
+        fs::write(root.join("xochitl.conf"), "[General]\ndevicetoken=\n").unwrap();
+        let now = OffsetDateTime::from_unix_timestamp(1_790_000_000).unwrap();
+        check_clock(now, false, &device, || Ok(false)).unwrap();
+        assert!(!sync::needs_blocking(&device));
+        let reset = OffsetDateTime::UNIX_EPOCH;
+        assert!(check_clock(reset, false, &device, || Ok(false)).is_err());
+
+        fs::write(root.join("xochitl.conf"), "[General]\ndevicetoken=abc\n").unwrap();
+        assert!(check_clock(now, false, &device, || Ok(false)).is_err());
+        assert!(sync::needs_blocking(&device));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn folder_changes() {
+        use FolderChange::{Add, Remove};
+        let root = device::simulate_in_temp_dir("folder-changes");
+        fs::create_dir(device::current().documents()).unwrap();
+        add_doc("Books", None, true);
+        add_doc("Comics", None, true);
+        let (tree, _) = directory::map().unwrap();
+        let parse = |cli: &[&str]| {
+            let cli = ["book-safe", "install", "-s", "22:00", "-e", "7:00"]
+                .iter()
+                .chain(cli);
+            let Commands::Install { args, .. } = Cli::try_parse_from(cli).unwrap().command else {
+                panic!("not install")
+            };
+            *args
+        };
+
+        let mut args = parse(&["-p", "Books", "--unlock-at", "6:00=Comics"]);
+        assert!(change_folder_args(&mut args, &tree, "Comics", Add).is_err());
+        assert!(change_folder_args(&mut args, &tree, "Missing", Add).is_err());
+        assert_eq!(
+            change_folder_args(&mut args, &tree, "Comics", Remove).unwrap(),
+            None
+        );
+        assert!(args.unlock_at.is_empty());
+        assert!(change_folder_args(&mut args, &tree, "Books", Remove).is_err());
+        let comics = change_folder_args(&mut args, &tree, "Comics", Add).unwrap();
+        assert_eq!(comics, Some(directory::test::id("Comics")));
+        assert_eq!(args.path, ["Books", "Comics"]);
+
+        let mut args = parse(&["--lock-all"]);
+        assert!(change_folder_args(&mut args, &tree, "Books", Add).is_err());
+        assert!(change_folder_args(&mut args, &tree, "Books", Remove)
+            .unwrap()
+            .is_some());
+        assert_eq!(args.except, ["Books"]);
+        assert_eq!(
+            change_folder_args(&mut args, &tree, "Books", Add).unwrap(),
+            None
+        );
+        assert!(args.except.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn lock_all_except() {
+        let root = device::simulate_in_temp_dir("lock-all");
+        let documents = device::current().documents();
+        fs::create_dir(documents).unwrap();
+        add_doc("Books", None, true);
+        add_doc("Dune", Some("Books"), false);
+        add_doc("School", None, true);
+        add_doc("Maths", Some("School"), false);
+        add_doc("Notes", None, false);
+        let before = listing(documents);
+
+        let (profile, ui) = lock_setup();
+        let everything = group::Group {
+            paths: vec![String::new()],
+            except: vec!["School".to_owned()],
+            ..books_group()
+        };
+        lock(&profile, &ui, vec![everything], &options(seven())).unwrap();
+
+        let safe = listing(&profile.safe_dir());
+        let [dune, maths, notes] = ["Dune", "Maths", "Notes"].map(directory::test::id);
+        assert!(safe.contains(&format!("{dune}.metadata")));
+        assert!(safe.contains(&format!("{notes}.metadata")));
+        assert!(!safe.contains(&format!("{maths}.metadata")));
+        // folders and the report stay
+        assert_eq!(safe.len(), 2 * 3);
+
+        try_unlock(&profile).unwrap();
+        assert_eq!(listing(documents), before);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn folder_paths() {
+        let parse = |path: &str| {
+            let cli = [
+                "book-safe",
+                "install",
+                "-s",
+                "22:00",
+                "-e",
+                "06:00",
+                "--path",
+                path,
+            ];
+            match Cli::try_parse_from(cli).map(|cli| cli.command) {
+                Ok(Commands::Install { args, .. }) => Ok(args.path),
+                Ok(_) => panic!("not install"),
+                Err(e) => Err(e.to_string()),
+            }
+        };
+        assert_eq!(parse("/Books/Sci-fi/").unwrap(), ["Books/Sci-fi"]);
+        assert_eq!(parse("Books//Sci-fi").unwrap(), ["Books/Sci-fi"]);
+        assert!(parse("").unwrap_err().contains("the path is empty"));
+        assert!(parse("/").unwrap_err().contains("--lock-all"));
+        assert_eq!(tier_arg("7:00=/Comics/").unwrap(), "7:00=Comics");
+        assert!(tier_arg("7:00=").is_err());
+    }
+
+    #[test]
+    fn install_args_roundtrip() {
+        let nasty = [
+            "install",
+            "my install",
+            r#"say "hi""#,
+            r"back\slash",
+            "50% done",
+            "$HOME",
+            "--start",
+            ";",
+        ];
+        let mut cli: Vec<String> = ["book-safe", "--profile", "exams", "install"]
+            .map(String::from)
+            .to_vec();
+        cli.extend(nasty.iter().map(|path| format!("--path={path}")));
+        cli.extend(
+            ["-s", "22:00", "-e", "06:00", "-z", "Europe/Amsterdam"]
+                .into_iter()
+                .chain([
+                    "--block-scope",
+                    "sync-only",
+                    "--offline-ok",
+                    "--include-trash",
+                    "--fuzzy-paths",
+                    "--warn-minutes",
+                    "15",
+                    "--grace-minutes",
+                    "5",
+                    "--lock-pinned",
+                    "--sync-block-policy",
+                    "best-effort",
+                    "--skip-types",
+                    "notebook,epub",
+                    "--min-size",
+                    "5MB",
+                ])
+                .map(String::from),
+        );
+
+        let parsed = Cli::try_parse_from(&cli).unwrap();
+        let Commands::Install { args, .. } = parsed.command else {
+            panic!("not install")
+        };
+        let profile = Profile::new(&parsed.profile).unwrap();
+        let installed = systemd::Installed {
+            args: args.to_install_args(&profile, parsed.log),
+            scheduler: scheduler::Kind::Systemd,
+            folders: BTreeMap::new(),
+        };
+        assert_eq!(
+            installed.run_args(),
+            ["--log=info", "--profile=exams", "run"]
+        );
+
+        assert_eq!(recorded_cli(&installed).unwrap().profile, "exams");
+        let reparsed = recorded_args(&installed).unwrap();
+        assert_eq!(reparsed.path, nasty);
+        assert_eq!(reparsed.start.as_deref(), Some("22:00"));
+        assert_eq!(reparsed.block_scope, sync::Scope::SyncOnly);
+        assert_eq!(reparsed.sync_block_policy, sync::Policy::BestEffort);
+        assert!(reparsed.offline_ok);
+        assert!(reparsed.include_trash);
+        assert!(reparsed.fuzzy_paths);
+        assert_eq!(reparsed.warn_minutes, Some(15));
+        assert_eq!(reparsed.grace_minutes, Some(5));
+        assert!(reparsed.lock_pinned);
+        assert_eq!(reparsed.min_size.as_deref(), Some("5MB"));
+        assert_eq!(
+            reparsed.skip_types,
+            [directory::FileType::Notebook, directory::FileType::Epub]
+        );
+        assert!(!reparsed.allow_sync);
+        assert!(!reparsed.force_time);
+        assert_eq!(reparsed.timezone, None);
+    }
+
+    #[test]
+    fn legacy_record() {
+        let installed = systemd::Installed {
+            args: [
+                "--profile=kids",
+                "run",
+                "--path=Books",
+                "--start=22:00",
+                "--end=7:00",
+            ]
+            .map(String::from)
+            .to_vec(),
+            scheduler: scheduler::Kind::Systemd,
+            folders: BTreeMap::new(),
+        };
+        assert_eq!(recorded_args(&installed).unwrap().path, ["Books"]);
+        assert_eq!(installed.run_args(), ["--profile=kids", "run"]);
+
+        let cli = [
+            "book-safe",
+            "run",
+            "--path=Books",
+            "--start=22:00",
+            "--dry-run",
+        ];
+        let Commands::Run { dry_run, legacy } = Cli::try_parse_from(cli).unwrap().command else {
+            panic!("not run")
+        };
+        assert!(!dry_run);
+        assert_eq!(legacy.len(), 3);
+    }
+
+    #[test]
+    fn reconfigured_settings() {
+        let replaced = replaced_settings(
+            &["allow-sync".to_owned()],
+            &["--quota=2:00", "-p", "Comics"].map(String::from),
+        )
+        .unwrap();
+        for id in ["allow_sync", "quota", "start", "end", "path", "lock_all"] {
+            assert!(replaced.contains(id), "{id} not replaced");
+        }
+        assert!(!replaced.contains("mode"));
+        assert_eq!(setting_id("--end=7:00").as_deref(), Some("end"));
+        assert!(replaced_settings(&["colour".to_owned()], &[]).is_err());
+    }
+
+    #[test]
+    fn folders_by_uuid() {
+        use directory::{test::id, Parent};
+
+        let cli = [
+            "book-safe",
+            "install",
+            "--path=A0/A1",
+            "--path=B0",
+            "--start=22:00",
+            "--end=06:00",
+        ];
+        let Commands::Install { mut args, .. } = Cli::try_parse_from(cli).unwrap().command else {
+            panic!("not install")
+        };
+        let mut folders = BTreeMap::from([
+            ("A0/A1".to_owned(), id("A1").to_string()),
+            ("B0".to_owned(), id("Gone").to_string()),
+        ]);
+        let mut tree = directory::test::test_tree();
+        // moved into B0, with a new folder taking its place
+        tree.add_folder(id("A1"), Parent::Folder(id("B0")), "A1".into());
+        tree.add_folder(id("New"), Parent::Folder(id("A0")), "A1".into());
+
+        assert!(resolve_folders(&mut args, &mut folders, &tree));
+        assert_eq!(args.path, ["B0/A1", "B0"]);
+        assert_eq!(folders["B0"], id("B0").to_string());
+        assert!(!resolve_folders(&mut args, &mut folders, &tree));
+    }
+
+    #[test]
+    fn moves_legacy_safe() {
+        let root = device::simulate_in_temp_dir("legacy-safe");
+        let kids = Profile::new("kids").unwrap();
+        let legacy = kids.legacy_safe_dir();
+        fs::create_dir_all(legacy.join("doc")).unwrap();
+        fs::write(legacy.join("doc.metadata"), "{}").unwrap();
+
+        ensure_safe_dir(&kids).unwrap();
+        let safe_dir = kids.safe_dir();
+        assert_eq!(listing(&safe_dir), ["doc", "doc.metadata"]);
+        assert!(!legacy.exists());
+        let mode = fs::metadata(&safe_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir_all(root).unwrap();
+    }
 }
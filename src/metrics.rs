@@ -0,0 +1,206 @@
+//! Writes a [node exporter](https://github.com/prometheus/node_exporter)
+//! textfile so someone looking after several devices can monitor them.
+//! The counters and timestamps are kept per profile in the state dir.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+use crate::util::AcceptErr;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Record {
+    /// unix timestamps
+    last_lock: Option<i64>,
+    last_unlock: Option<i64>,
+    last_run: Option<i64>,
+    last_run_secs: Option<f64>,
+    failures: u64,
+}
+
+impl Record {
+    fn load(profile: &Profile) -> Result<Self> {
+        match fs::read_to_string(profile.metrics_record()) {
+            Ok(json) => serde_json::from_str(&json).wrap_err("Could not parse metrics record"),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).wrap_err("Could not read metrics record"),
+        }
+    }
+
+    fn save(&self, profile: &Profile) -> Result<()> {
+        fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(profile.metrics_record(), json).wrap_err("Could not write metrics record")
+    }
+}
+
+/// What a command did, compared to before it ran
+pub struct Outcome {
+    pub was_locked: bool,
+    pub is_locked: bool,
+    pub failed: bool,
+    /// Only set for scheduled runs
+    pub run_took: Option<Duration>,
+}
+
+/// Updates the record of `profile` with `outcome`
+pub fn record(profile: &Profile, outcome: &Outcome) -> Result<()> {
+    let mut record = Record::load(profile)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    match (outcome.was_locked, outcome.is_locked) {
+        (false, true) => record.last_lock = Some(now),
+        (true, false) => record.last_unlock = Some(now),
+        _ => (),
+    }
+    if outcome.failed {
+        record.failures += 1;
+    }
+    if let Some(took) = outcome.run_took {
+        record.last_run = Some(now);
+        record.last_run_secs = Some(took.as_secs_f64());
+    }
+    record.save(profile)
+}
+
+pub fn clear(profile: &Profile) -> Result<()> {
+    fs::remove_file(profile.metrics_record())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove metrics record")
+}
+
+/// Writes the metrics of `profiles` to `path`. The file is replaced in
+/// one go so the exporter never reads half of it.
+pub fn write(path: &Path, profiles: &[Profile], blocked_routes: Option<usize>) -> Result<()> {
+    let mut records = Vec::new();
+    for profile in profiles {
+        let record = Record::load(profile)?;
+        let locked = locked_documents(profile)?;
+        records.push((profile.to_string(), record, locked));
+    }
+    let text = format(&records, blocked_routes);
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, text).wrap_err("Could not write metrics file")?;
+    fs::rename(&tmp, path)
+        .wrap_err_with(|| format!("Could not move metrics file to: {}", path.display()))
+}
+
+/// Every document has a `.metadata` file
+pub fn locked_documents(profile: &Profile) -> Result<usize> {
+    let entries = match fs::read_dir(profile.safe_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).wrap_err("Could not read books safe"),
+    };
+    let mut count = 0;
+    for entry in entries {
+        let path = entry.wrap_err("Could not read books safe")?.path();
+        if path.extension().is_some_and(|ext| ext == "metadata") {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn format(records: &[(String, Record, usize)], blocked_routes: Option<usize>) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(&str, String)>| {
+        if values.is_empty() {
+            return;
+        }
+        let _ = writeln!(text, "# HELP book_safe_{name} {help}");
+        let _ = writeln!(text, "# TYPE book_safe_{name} {kind}");
+        for (profile, value) in values {
+            let _ = writeln!(text, "book_safe_{name}{{profile=\"{profile}\"}} {value}");
+        }
+    };
+    let each = |value: fn(&Record, usize) -> Option<String>| {
+        records
+            .iter()
+            .filter_map(|(profile, record, locked)| {
+                Some((profile.as_str(), value(record, *locked)?))
+            })
+            .collect()
+    };
+
+    metric(
+        "last_lock_timestamp_seconds",
+        "gauge",
+        "When folders were last locked",
+        each(|r, _| r.last_lock.map(|t| t.to_string())),
+    );
+    metric(
+        "last_unlock_timestamp_seconds",
+        "gauge",
+        "When folders were last unlocked",
+        each(|r, _| r.last_unlock.map(|t| t.to_string())),
+    );
+    metric(
+        "locked_documents",
+        "gauge",
+        "Number of documents locked",
+        each(|_, locked| Some(locked.to_string())),
+    );
+    metric(
+        "failures_total",
+        "counter",
+        "Number of runs, unlocks, pauses and lock-nows that failed",
+        each(|r, _| Some(r.failures.to_string())),
+    );
+    metric(
+        "last_run_timestamp_seconds",
+        "gauge",
+        "When the service last ran",
+        each(|r, _| r.last_run.map(|t| t.to_string())),
+    );
+    metric(
+        "last_run_duration_seconds",
+        "gauge",
+        "How long the last run of the service took",
+        each(|r, _| r.last_run_secs.map(|secs| format!("{secs:.3}"))),
+    );
+
+    if let Some(routes) = blocked_routes {
+        text.push_str("# HELP book_safe_blocked_routes Number of reject routes blocking sync\n");
+        text.push_str("# TYPE book_safe_blocked_routes gauge\n");
+        let _ = writeln!(text, "book_safe_blocked_routes {routes}");
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn textfile() {
+        let record = Record {
+            last_lock: Some(1_700_000_000),
+            last_run_secs: Some(1.5),
+            failures: 2,
+            ..Record::default()
+        };
+        let text = format(&[("default".to_owned(), record, 3)], Some(12));
+        let lines: Vec<_> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            [
+                "book_safe_last_lock_timestamp_seconds{profile=\"default\"} 1700000000",
+                "book_safe_locked_documents{profile=\"default\"} 3",
+                "book_safe_failures_total{profile=\"default\"} 2",
+                "book_safe_last_run_duration_seconds{profile=\"default\"} 1.500",
+                "book_safe_blocked_routes 12",
+            ]
+        );
+        // every metric has help and a type
+        assert_eq!(text.lines().filter(|l| l.starts_with("# TYPE")).count(), 5);
+    }
+}
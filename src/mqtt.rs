@@ -0,0 +1,342 @@
+//! A minimal mqtt 3.1.1 client. Lock events and whether a profile is
+//! locked are published so home automation can react to them, and `serve`
+//! can subscribe to commands to pause or extend the lock.
+//!
+//! Topics, with `book-safe` replaced by the `topic` from the config:
+//! - `book-safe/<profile>/state`: `locked` or `unlocked`, retained
+//! - `book-safe/<profile>/event`: json with the `event` and a `message`
+//! - `book-safe/<profile>/command`: `pause <hours>` or `lock <hours>`
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::{Help, Result};
+
+use crate::config::Mqtt;
+use crate::profile::Profile;
+use crate::serve::Action;
+use crate::util::net::{self, Stream};
+
+/// How long to wait for the broker to connect and answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The broker drops us if we are silent for one and a half times this
+const KEEP_ALIVE: Duration = Duration::from_mins(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+pub struct Client {
+    stream: Box<dyn Stream>,
+}
+
+impl Client {
+    pub fn connect(config: &Mqtt, client_id: &str) -> Result<Self> {
+        let url = &config.url;
+        let parsed = url::Url::parse(url).wrap_err_with(|| format!("Invalid mqtt url: {url}"))?;
+        let (tls, default_port) = match parsed.scheme() {
+            "mqtt" => (false, 1883),
+            "mqtts" => (true, 8883),
+            other => {
+                return Err(eyre!("Unsupported mqtt url scheme: {other}"))
+                    .suggestion("use a mqtt:// or mqtts:// url")
+            }
+        };
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| eyre!("Mqtt url has no host: {url}"))?;
+        let port = parsed.port().unwrap_or(default_port);
+
+        let mut stream = net::connect(host, port, tls, TIMEOUT)?;
+        let connect = connect_packet(
+            client_id,
+            config.username.as_deref(),
+            config.password.as_deref(),
+        );
+        stream
+            .write_all(&connect)
+            .wrap_err("Could not send connect")?;
+        let mut client = Self { stream };
+        let (header, body) = client.read_packet().wrap_err("No answer to connect")?;
+        if header >> 4 != CONNACK {
+            return Err(eyre!("Broker did not acknowledge the connection"));
+        }
+        match body.get(1) {
+            Some(0) => Ok(client),
+            Some(4 | 5) => Err(eyre!("Broker refused the connection"))
+                .suggestion("check the mqtt username and password in the config"),
+            Some(code) => Err(eyre!("Broker refused the connection, code: {code}")),
+            None => Err(eyre!("Invalid connect acknowledgement")),
+        }
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+        self.stream
+            .write_all(&publish_packet(topic, payload, retain))
+            .wrap_err("Could not publish")
+    }
+
+    pub fn subscribe(&mut self, filter: &str) -> Result<()> {
+        let mut body = 1u16.to_be_bytes().to_vec(); // packet id
+        push_str(&mut body, filter);
+        body.push(0); // at most once
+        self.stream
+            .write_all(&packet(SUBSCRIBE << 4 | 0b0010, &body))
+            .wrap_err("Could not subscribe")?;
+        let (header, body) = self.read_packet()?;
+        match (header >> 4, body.get(2)) {
+            (SUBACK, Some(0..=2)) => Ok(()),
+            _ => Err(eyre!("Broker refused subscription to: {filter}")),
+        }
+    }
+
+    pub fn disconnect(mut self) -> Result<()> {
+        self.stream
+            .write_all(&packet(DISCONNECT << 4, &[]))
+            .and_then(|()| self.stream.flush())
+            .wrap_err("Could not disconnect")
+    }
+
+    fn read_packet(&mut self) -> Result<(u8, Vec<u8>)> {
+        let mut header = [0u8];
+        self.stream
+            .read_exact(&mut header)
+            .wrap_err("Could not read from broker")?;
+        self.read_rest(header[0])
+    }
+
+    fn read_rest(&mut self, header: u8) -> Result<(u8, Vec<u8>)> {
+        let mut len = 0usize;
+        for shift in [0, 7, 14, 21] {
+            let mut byte = [0u8];
+            self.stream.read_exact(&mut byte)?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                let mut body = vec![0; len];
+                self.stream.read_exact(&mut body)?;
+                return Ok((header, body));
+            }
+        }
+        Err(eyre!("Invalid packet length"))
+    }
+
+    /// The next message on a subscribed topic, pinging the broker while
+    /// it is quiet
+    fn next_message(&mut self) -> Result<(String, Vec<u8>)> {
+        loop {
+            let mut header = [0u8];
+            match self.stream.read(&mut header) {
+                Ok(0) => return Err(eyre!("Broker closed the connection")),
+                Ok(_) => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    self.stream
+                        .write_all(&packet(PINGREQ << 4, &[]))
+                        .wrap_err("Could not ping broker")?;
+                    continue;
+                }
+                Err(e) => return Err(e).wrap_err("Could not read from broker"),
+            }
+            let (header, body) = self.read_rest(header[0])?;
+            if header >> 4 == PUBLISH {
+                return parse_publish(header, &body);
+            }
+        }
+    }
+}
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const DISCONNECT: u8 = 14;
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        #[allow(clippy::cast_possible_truncation)] // masked to 7 bits
+        let mut byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    let len = u16::try_from(s.len()).unwrap_or(u16::MAX);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(&s.as_bytes()[..usize::from(len)]);
+}
+
+fn connect_packet(client_id: &str, username: Option<&str>, password: Option<&str>) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_str(&mut body, "MQTT");
+    body.push(4); // protocol level of mqtt 3.1.1
+    let mut flags = 0b0000_0010; // clean session
+    if username.is_some() {
+        flags |= 0b1000_0000;
+    }
+    if password.is_some() {
+        flags |= 0b0100_0000;
+    }
+    body.push(flags);
+    let keep_alive = u16::try_from(KEEP_ALIVE.as_secs()).expect("fits");
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    push_str(&mut body, client_id);
+    for field in [username, password].into_iter().flatten() {
+        push_str(&mut body, field);
+    }
+    packet(CONNECT << 4, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_str(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH << 4 | u8::from(retain), &body)
+}
+
+fn parse_publish(header: u8, body: &[u8]) -> Result<(String, Vec<u8>)> {
+    let invalid = || eyre!("Invalid publish packet");
+    let len = body.get(..2).ok_or_else(invalid)?;
+    let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+    let topic = body.get(2..2 + len).ok_or_else(invalid)?;
+    let topic = String::from_utf8(topic.to_vec()).wrap_err("Topic is not utf8")?;
+    // messages sent with a higher qos carry a packet id
+    let qos = (header >> 1) & 0b11;
+    let payload_start = if qos > 0 { 4 + len } else { 2 + len };
+    let payload = body.get(payload_start..).ok_or_else(invalid)?;
+    Ok((topic, payload.to_vec()))
+}
+
+fn topic(config: &Mqtt, profile: &Profile, leaf: &str) -> String {
+    format!("{}/{profile}/{leaf}", config.topic)
+}
+
+/// Publishes `event` and, if known, whether `profile` is `locked`
+pub fn publish(
+    config: &Mqtt,
+    profile: &Profile,
+    event: &str,
+    message: &str,
+    locked: Option<bool>,
+) -> Result<()> {
+    let client_id = format!("book-safe-{profile}-{}", std::process::id());
+    let mut client = Client::connect(config, &client_id)?;
+    let payload = serde_json::json!({ "event": event, "message": message });
+    client.publish(
+        &topic(config, profile, "event"),
+        payload.to_string().as_bytes(),
+        false,
+    )?;
+    if let Some(locked) = locked {
+        let state = if locked { "locked" } else { "unlocked" };
+        client.publish(&topic(config, profile, "state"), state.as_bytes(), true)?;
+    }
+    client.disconnect()
+}
+
+/// Subscribes to the command topic of every profile in the background,
+/// reconnecting if the connection is lost
+pub fn listen(config: Mqtt) -> mpsc::Receiver<(Profile, Action)> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || loop {
+        match forward_commands(&config, &tx) {
+            Ok(()) => return, // serve stopped
+            Err(e) => log::warn!(
+                "mqtt connection lost, reconnecting in {}s: {e:?}",
+                RECONNECT_DELAY.as_secs()
+            ),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+    rx
+}
+
+fn forward_commands(config: &Mqtt, tx: &mpsc::Sender<(Profile, Action)>) -> Result<()> {
+    let client_id = format!("book-safe-serve-{}", std::process::id());
+    let mut client = Client::connect(config, &client_id)?;
+    let filter = format!("{}/+/command", config.topic);
+    client.subscribe(&filter)?;
+    log::info!("listening for commands on mqtt topic {filter}");
+
+    loop {
+        let (topic, payload) = client.next_message()?;
+        let payload = String::from_utf8_lossy(&payload);
+        match parse_command(&config.topic, &topic, &payload) {
+            Ok(command) => {
+                if tx.send(command).is_err() {
+                    return client.disconnect();
+                }
+            }
+            Err(e) => log::warn!("ignoring mqtt command {payload:?} on {topic}: {e}"),
+        }
+    }
+}
+
+fn parse_command(prefix: &str, topic: &str, payload: &str) -> Result<(Profile, Action)> {
+    let profile = topic
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|rest| rest.strip_suffix("/command"))
+        .ok_or_else(|| eyre!("Not a command topic"))?;
+    let profile = Profile::new(profile)?;
+
+    let (command, hours) = payload
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| eyre!("Expected a command and a number of hours"))?;
+    let hours = hours
+        .trim()
+        .parse()
+        .wrap_err_with(|| format!("Invalid number of hours: {hours}"))?;
+    let action = match command {
+        "pause" => Action::Pause { hours },
+        "lock" => Action::Lock { hours },
+        other => return Err(eyre!("Unknown command: {other}")),
+    };
+    Ok((profile, action))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let publish = publish_packet("a/b", b"locked", true);
+        assert_eq!(publish[..2], [0x31, 11]);
+        let (topic, payload) = parse_publish(publish[0], &publish[2..]).unwrap();
+        assert_eq!(
+            (topic.as_str(), payload.as_slice()),
+            ("a/b", &b"locked"[..])
+        );
+
+        // lengths over 127 take more than one byte
+        let long = packet(PUBLISH << 4, &[0; 200]);
+        assert_eq!(long[1..3], [200 - 128 + 0x80, 1]);
+
+        let connect = connect_packet("id", Some("user"), Some("pass"));
+        assert_eq!(connect[2..9], [0, 4, b'M', b'Q', b'T', b'T', 4]);
+        assert_eq!(connect[9], 0b1100_0010);
+    }
+
+    #[test]
+    fn commands() {
+        let (profile, action) =
+            parse_command("book-safe", "book-safe/evening/command", "pause 1.5").unwrap();
+        assert_eq!(profile.to_string(), "evening");
+        assert_eq!(action, Action::Pause { hours: 1.5 });
+        assert!(parse_command("book-safe", "book-safe/evening/command", "unlock").is_err());
+        assert!(parse_command("book-safe", "other/evening/command", "lock 2").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Sends a message to a webhook (for example [ntfy](https://ntfy.sh)) and
+//! the mqtt broker when folders are locked or unlocked, locking fails or
+//! someone unlocks by hand.
+//! Notifications are best effort: a failure is logged and never stops
+//! book-safe from (un)locking.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::{Help, Result};
+
+use crate::config::Config;
+use crate::profile::Profile;
+use crate::util::net;
+
+/// How long to wait for the server to connect and answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Lock,
+    Unlock,
+    Failure,
+    /// Someone unlocked or uninstalled outside the schedule
+    Tamper,
+    /// Someone asked for the folders to be unlocked
+    Request,
+    /// The lock starts soon
+    Upcoming,
+    /// Sent by `book-safe test notify`
+    Test,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Lock => "lock",
+            Event::Unlock => "unlock",
+            Event::Failure => "failure",
+            Event::Tamper => "tamper",
+            Event::Request => "request",
+            Event::Upcoming => "upcoming",
+            Event::Test => "test",
+        }
+    }
+}
+
+/// Whether book-safe was started from a terminal instead of by one of
+/// its systemd units
+pub fn started_by_hand() -> bool {
+    std::env::var_os("INVOCATION_ID").is_none()
+}
+
+/// Whether there is anywhere to send notifications to
+pub fn configured(config: &Config) -> bool {
+    config.notify_url.is_some() || config.mqtt.is_some()
+}
+
+/// Sends `message` to the configured `notify_url` and mqtt broker if
+/// there are any, and records it for the digest
+pub fn send(config: &Config, profile: &Profile, event: Event, message: &str) {
+    crate::activity::record(profile, event.name(), message);
+    if !configured(config) {
+        return;
+    }
+    if !crate::device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, not sending notification: {message}");
+        return;
+    }
+
+    if let Some(url) = &config.notify_url {
+        if let Err(e) = post_event(url, &config.notify_template, event, profile, message) {
+            log::warn!("Could not send notification: {e:?}");
+        }
+    }
+
+    if let Some(mqtt) = &config.mqtt {
+        let locked = match event {
+            Event::Lock | Event::Unlock => crate::locked_files(profile).ok(),
+            Event::Failure | Event::Tamper | Event::Request | Event::Upcoming | Event::Test => None,
+        };
+        if let Err(e) = crate::mqtt::publish(mqtt, profile, event.name(), message, locked) {
+            log::warn!("Could not publish to mqtt: {e:?}");
+        }
+    }
+}
+
+/// Sends a test message everywhere `send` would. Unlike `send` this
+/// fails if there is nowhere to send to or one of them can not be reached.
+pub fn test(config: &Config, profile: &Profile) -> Result<()> {
+    if !configured(config) {
+        return Err(eyre!("There is nowhere to send notifications to"))
+            .suggestion("set `notify_url` or `mqtt` in the config");
+    }
+    if !crate::device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, not sending test notification");
+        return Ok(());
+    }
+
+    let message = "test notification, book-safe can reach you";
+    if let Some(url) = &config.notify_url {
+        post_event(url, &config.notify_template, Event::Test, profile, message)
+            .wrap_err("Could not send to the notify_url")?;
+        println!("sent a test notification to the notify_url");
+    }
+    if let Some(mqtt) = &config.mqtt {
+        crate::mqtt::publish(mqtt, profile, Event::Test.name(), message, None)
+            .wrap_err("Could not publish to the mqtt broker")?;
+        println!("published a test message to the mqtt broker");
+    }
+    Ok(())
+}
+
+fn post_event(
+    url: &str,
+    template: &str,
+    event: Event,
+    profile: &Profile,
+    message: &str,
+) -> Result<()> {
+    let content_type = if is_json(template) {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    post(
+        url,
+        content_type,
+        &render(template, event, profile, message),
+    )
+}
+
+/// Fills in `{event}`, `{profile}` and `{message}`. In a json template
+/// the values are escaped so it stays valid json.
+fn render(template: &str, event: Event, profile: &Profile, message: &str) -> String {
+    let escape = |value: &str| {
+        if is_json(template) {
+            let quoted = serde_json::to_string(value).expect("strings always serialize");
+            quoted[1..quoted.len() - 1].to_owned()
+        } else {
+            value.to_owned()
+        }
+    };
+    template
+        .replace("{event}", event.name())
+        .replace("{profile}", &escape(&profile.to_string()))
+        .replace("{message}", &escape(message))
+}
+
+fn is_json(template: &str) -> bool {
+    let blank = ["{event}", "{profile}", "{message}"]
+        .into_iter()
+        .fold(template.to_owned(), |text, placeholder| {
+            text.replace(placeholder, "")
+        });
+    serde_json::from_str::<serde_json::Value>(&blank).is_ok()
+}
+
+/// A minimal http/1.1 POST, the response body is ignored
+pub fn post(url: &str, content_type: &str, body: &str) -> Result<()> {
+    let url = url::Url::parse(url).wrap_err_with(|| format!("Invalid notify url: {url}"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| eyre!("Notify url has no host: {url}"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| eyre!("Notify url has no port: {url}"))?;
+
+    let request = request(&url, content_type, body);
+
+    let tls = match url.scheme() {
+        "http" => false,
+        "https" => true,
+        other => {
+            return Err(eyre!("Unsupported notify url scheme: {other}"))
+                .suggestion("use an http:// or https:// url")
+        }
+    };
+    let stream = net::connect(host, port, tls, TIMEOUT)?;
+    let response = exchange(stream, &request)?;
+    check_status(&response)
+}
+
+fn request(url: &url::Url, content_type: &str, body: &str) -> String {
+    let host = url.host_str().unwrap_or_default();
+    let host = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    };
+    let target = &url[url::Position::BeforePath..url::Position::AfterQuery];
+    let length = body.len();
+    let version = env!("CARGO_PKG_VERSION");
+    format!(
+        "POST {target} HTTP/1.1\r\n\
+        Host: {host}\r\n\
+        User-Agent: book-safe/{version}\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {length}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}"
+    )
+}
+
+/// Sends `request` and returns the response, cut off after a few kB
+fn exchange(mut stream: impl Read + Write, request: &str) -> Result<String> {
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|()| stream.flush())
+        .wrap_err("Could not send request")?;
+    let mut response = Vec::new();
+    match stream.take(4096).read_to_end(&mut response) {
+        Ok(_) => (),
+        // we have what we need if the server keeps the connection open
+        Err(e) if !response.is_empty() && is_timeout(&e) => (),
+        Err(e) => return Err(e).wrap_err("Could not read response"),
+    }
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Errors with the status line and body if the response is not a success
+fn check_status(response: &str) -> Result<()> {
+    let status_line = response.lines().next().unwrap_or_default();
+    let code = status_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| eyre!("Invalid response: {status_line}"))?;
+    if code.starts_with('2') {
+        return Ok(());
+    }
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.trim())
+        .unwrap_or_default();
+    let status = format!("Server responded with: {}", status_line.trim());
+    if body.is_empty() {
+        Err(eyre!(status))
+    } else {
+        Err(eyre!("{body}").wrap_err(status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn templates() {
+        let profile = Profile::new("default").unwrap();
+        let text = render("{profile}: {message}", Event::Lock, &profile, "say \"hi\"");
+        assert_eq!(text, "default: say \"hi\"");
+
+        let template = r#"{"event": "{event}", "text": "{message}"}"#;
+        let json = render(template, Event::Tamper, &profile, "say \"hi\"");
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["event"], "tamper");
+        assert_eq!(parsed["text"], "say \"hi\"");
+    }
+
+    #[test]
+    fn test_needs_somewhere_to_send() {
+        let profile = Profile::new("default").unwrap();
+        let config = Config::default();
+        let err = test(&config, &profile).unwrap_err();
+        assert!(err.to_string().contains("nowhere to send"));
+    }
+
+    #[test]
+    fn post_to_local_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while !request.ends_with("\r\n\r\n") {
+                reader.read_line(&mut request).unwrap();
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            request
+        });
+
+        let url = format!("http://127.0.0.1:{port}/topic?x=1");
+        post(&url, "text/plain", "locked").unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /topic?x=1 HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("Host: 127.0.0.1:{port}\r\n")));
+        assert!(request.contains("Content-Length: 6\r\n"));
+
+        assert!(check_status("HTTP/1.1 404 Not Found\r\n").is_err());
+        let refused = check_status("HTTP/1.1 500 Oops\r\nA: b\r\n\r\nWrong token").unwrap_err();
+        assert!(format!("{refused:#}").contains("Wrong token"));
+    }
+}
@@ -0,0 +1,46 @@
+//! Pauses started with `pause`. Until the pause ends runs of the service
+//! keep the folders unlocked, regardless of the schedule.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+use crate::util::AcceptErr;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// unix timestamp of when the pause ends
+    until: i64,
+}
+
+pub fn save(profile: &Profile, until: OffsetDateTime) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let record = Record {
+        until: until.unix_timestamp(),
+    };
+    let json = serde_json::to_string_pretty(&record)?;
+    fs::write(profile.pause_record(), json).wrap_err("Could not write pause record")
+}
+
+/// When the current pause ends, if there is one that has not ended yet
+pub fn until(profile: &Profile, now: OffsetDateTime) -> Result<Option<OffsetDateTime>> {
+    let json = match fs::read_to_string(profile.pause_record()) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err("Could not read pause record"),
+    };
+    let record: Record = serde_json::from_str(&json).wrap_err("Could not parse pause record")?;
+    let until = OffsetDateTime::from_unix_timestamp(record.until)
+        .wrap_err("Invalid end in pause record")?;
+    Ok(Some(until).filter(|until| *until > now))
+}
+
+pub fn clear(profile: &Profile) -> Result<()> {
+    fs::remove_file(profile.pause_record())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove pause record")
+}
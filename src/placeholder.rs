@@ -0,0 +1,220 @@
+//! A note left in every locked folder saying when it unlocks, so an
+//! emptied folder does not look like its documents were lost, and with
+//! `--mode stub` a page in the place of every locked document. They are
+//! written like the lock report and removed when their folder unlocks.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{eyre::WrapErr, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use time::Time;
+
+use crate::directory::{Parent, Tree, Uuid};
+use crate::profile::Profile;
+use crate::report;
+use crate::util::time::{clock, ParseHourMinute};
+use crate::util::AcceptErr;
+
+/// The notes are derived from this by replacing the last part with a
+/// hash of the profile and folder
+const PLACEHOLDER_UUID: &str = "5be1c0d2-4f7a-4c39-9e61-2d8a0b6f3c47";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Placeholder {
+    uuid: String,
+    /// format: 23:59, or 23:59:30 if there are seconds
+    unlock_at: String,
+}
+
+/// Whether `uuid` is a note of any profile, these are never locked
+pub fn is_placeholder(uuid: &Uuid) -> bool {
+    report::is_derived(PLACEHOLDER_UUID, uuid)
+}
+
+fn load(profile: &Profile) -> Result<Vec<Placeholder>> {
+    match fs::read_to_string(profile.placeholders_record()) {
+        Ok(json) => serde_json::from_str(&json).wrap_err("Could not parse placeholders record"),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).wrap_err("Could not read placeholders record"),
+    }
+}
+
+fn save(profile: &Profile, placeholders: &[Placeholder]) -> Result<()> {
+    if placeholders.is_empty() {
+        return fs::remove_file(profile.placeholders_record())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not remove placeholders record");
+    }
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let json = serde_json::to_string_pretty(placeholders)?;
+    fs::write(profile.placeholders_record(), json).wrap_err("Could not write placeholders record")
+}
+
+/// A document written by `add`
+struct Note {
+    uuid: String,
+    name: String,
+    /// as in the metadata files
+    parent: String,
+    unlock_at: Time,
+}
+
+/// Writes `notes`, with the page `doc` makes for their unlock time
+fn add(profile: &Profile, notes: Vec<Note>, doc: impl Fn(Time) -> report::Doc) -> Result<()> {
+    let mut placeholders = load(profile)?;
+    // recorded first so a note that is only partly written is removed too
+    placeholders.retain(|placeholder| notes.iter().all(|note| note.uuid != placeholder.uuid));
+    placeholders.extend(notes.iter().map(|note| Placeholder {
+        uuid: note.uuid.clone(),
+        unlock_at: clock(note.unlock_at),
+    }));
+    save(profile, &placeholders)?;
+
+    // there are only a few unlock times, render each once
+    let mut rendered = HashMap::new();
+    for note in notes {
+        let pdf = match rendered.entry(note.unlock_at) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(doc(note.unlock_at).render()?),
+        };
+        report::write(&note.uuid, &note.name, &note.parent, pdf)
+            .wrap_err_with(|| format!("Could not write placeholder: {}", note.name))?;
+    }
+    Ok(())
+}
+
+fn uuid(profile: &Profile, of: Uuid) -> String {
+    report::derived_uuid(PLACEHOLDER_UUID, &format!("{profile}/{of}"))
+}
+
+/// Puts a note in each of `folders` saying when it unlocks
+pub fn add_folders(profile: &Profile, folders: &[(Uuid, Time)]) -> Result<()> {
+    // a folder in two groups is locked with the first, like its documents
+    let notes = folders
+        .iter()
+        .unique_by(|(folder, _)| folder)
+        .map(|(folder, unlock_at)| Note {
+            uuid: uuid(profile, *folder),
+            name: format!("Locked until {}", clock(*unlock_at)),
+            parent: folder.to_string(),
+            unlock_at: *unlock_at,
+        })
+        .collect();
+    add(profile, notes, report::note)
+}
+
+/// Puts a stub with the same name in the folder of each of `docs`, saying
+/// when it unlocks. Documents in the trash get none.
+pub fn add_stubs(profile: &Profile, tree: &Tree, docs: &[(Uuid, Time)]) -> Result<()> {
+    let parents = tree.parents();
+    let paths = tree.doc_paths();
+    let notes = docs
+        .iter()
+        .filter_map(|(doc, unlock_at)| {
+            let parent = parents.get(doc).filter(|p| **p != Parent::Trash)?;
+            let name = paths.get(doc)?.file_name()?.to_string_lossy();
+            Some(Note {
+                uuid: uuid(profile, *doc),
+                name: name.into_owned(),
+                parent: parent.to_string(),
+                unlock_at: *unlock_at,
+            })
+        })
+        .collect();
+    add(profile, notes, report::stub)
+}
+
+/// Removes the notes of the folders that do not unlock at one of the
+/// `due` times, see `group::Locked::take_ended`
+pub fn remove_ended(profile: &Profile, due: &[Time]) -> Result<()> {
+    let uploaded = report::sync_allowed(profile);
+    let mut kept = Vec::new();
+    for placeholder in load(profile)? {
+        let unlock_at = Time::try_parse(&placeholder.unlock_at)
+            .wrap_err("Invalid unlock time in placeholders record")?;
+        if due.contains(&unlock_at) {
+            kept.push(placeholder);
+        } else {
+            report::delete(&placeholder.uuid, uploaded).wrap_err("Could not remove placeholder")?;
+        }
+    }
+    save(profile, &kept)
+}
+
+/// Removes every note of `profile`
+pub fn remove(profile: &Profile) -> Result<()> {
+    remove_ended(profile, &[])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device;
+    use crate::directory::test::id;
+
+    #[test]
+    fn notes_follow_the_unlock() {
+        let root = device::simulate_in_temp_dir("placeholder");
+        let documents = device::current().documents();
+        fs::create_dir(documents).unwrap();
+        let profile = Profile::new("kids").unwrap();
+        let hm = |h, m| Time::from_hms(h, m, 0).unwrap();
+        let (books, comics) = (id("books"), id("comics"));
+
+        let folders = [(books, hm(8, 0)), (comics, hm(7, 0)), (books, hm(7, 0))];
+        add_folders(&profile, &folders).unwrap();
+        let notes = load(&profile).unwrap();
+        assert_eq!(notes.len(), 2);
+        let metadata = documents.join(&notes[0].uuid).with_extension("metadata");
+        let metadata = fs::read_to_string(metadata).unwrap();
+        assert!(metadata.contains(&format!("\"parent\": \"{books}\"")));
+        assert!(metadata.contains("\"visibleName\": \"Locked until 8:00\""));
+        assert!(is_placeholder(&Uuid::parse(&notes[1].uuid).unwrap()));
+
+        remove_ended(&profile, &[hm(8, 0)]).unwrap();
+        assert_eq!(load(&profile).unwrap().len(), 1);
+        assert!(!documents
+            .join(&notes[1].uuid)
+            .with_extension("pdf")
+            .exists());
+        assert!(documents
+            .join(&notes[0].uuid)
+            .with_extension("pdf")
+            .exists());
+
+        remove(&profile).unwrap();
+        assert_eq!(fs::read_dir(documents).unwrap().count(), 0);
+        assert!(!profile.placeholders_record().exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn stubs_keep_the_name() {
+        let root = device::simulate_in_temp_dir("stubs");
+        let documents = device::current().documents();
+        fs::create_dir(documents).unwrap();
+        let profile = Profile::new(crate::profile::DEFAULT).unwrap();
+        let tree = crate::directory::test::test_tree();
+        let at = Time::from_hms(7, 0, 0).unwrap();
+
+        add_stubs(&profile, &tree, &[(id("a2"), at), (id("b0"), at)]).unwrap();
+        let read = |doc| {
+            let path = documents
+                .join(uuid(&profile, doc))
+                .with_extension("metadata");
+            fs::read_to_string(path).unwrap()
+        };
+        let a2 = read(id("a2"));
+        assert!(a2.contains("\"visibleName\": \"a2\""));
+        assert!(a2.contains(&format!("\"parent\": \"{}\"", id("A1"))));
+        assert!(read(id("b0")).contains("\"parent\": \"\""));
+
+        remove(&profile).unwrap();
+        assert_eq!(fs::read_dir(documents).unwrap().count(), 0);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Independent schedules, each with their own folders, times, units and
+//! safe. The default profile uses the same names as before profiles
+//! existed so older installs keep working.
+
+use std::fmt::Display;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+
+use crate::device;
+
+pub const DEFAULT: &str = "default";
+const RECORD_PREFIX: &str = "install";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    name: String,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Result<Self> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(eyre!("Invalid profile name: {name:?}"))
+                .suggestion("use only letters, digits, '-' and '_'");
+        }
+        Ok(Self {
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT
+    }
+
+    /// Where this profile moves the locked documents
+    pub fn safe_dir(&self) -> PathBuf {
+        self.own(device::current().safe_dir())
+    }
+
+    /// Where older versions moved this profile's locked documents
+    pub fn legacy_safe_dir(&self) -> PathBuf {
+        self.own(device::current().legacy_safe_dir())
+    }
+
+    /// `dir` with this profile's name appended, unless it is the default
+    fn own(&self, dir: &Path) -> PathBuf {
+        if self.is_default() {
+            dir.to_path_buf()
+        } else {
+            let mut dir = dir.as_os_str().to_owned();
+            dir.push(format!("-{}", self.name));
+            PathBuf::from(dir)
+        }
+    }
+
+    /// Name of this profiles systemd unit with extension `ext`
+    pub fn unit(&self, ext: &str) -> String {
+        let pkg = env!("CARGO_PKG_NAME");
+        if self.is_default() {
+            format!("{pkg}.{ext}")
+        } else {
+            format!("{pkg}@{}.{ext}", self.name)
+        }
+    }
+
+    /// Where the arguments this profile was installed with are kept
+    pub fn record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            format!("{RECORD_PREFIX}.json")
+        } else {
+            format!("{RECORD_PREFIX}-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Where the end of a `lock-now` lock is kept
+    pub fn lock_now_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "lock-now.json".to_owned()
+        } else {
+            format!("lock-now-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Where the end of a pause is kept
+    pub fn pause_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "pause.json".to_owned()
+        } else {
+            format!("pause-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Where a pending unlock request is kept
+    pub fn request_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "unlock-request.json".to_owned()
+        } else {
+            format!("unlock-request-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Where the time unlocked today is kept in quota mode
+    pub fn quota_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "quota.json".to_owned()
+        } else {
+            format!("quota-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Which documents are locked until when
+    pub fn groups_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "locked-groups.json".to_owned()
+        } else {
+            format!("locked-groups-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Marks a lock during which sync was allowed
+    pub fn sync_allowed_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "sync-allowed.json".to_owned()
+        } else {
+            format!("sync-allowed-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Marks a lock that postponed recently opened documents
+    pub fn grace_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "grace.json".to_owned()
+        } else {
+            format!("grace-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Counters and timestamps exported as metrics
+    pub fn metrics_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "metrics.json".to_owned()
+        } else {
+            format!("metrics-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// Past and current lock sessions
+    pub fn history_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "history.json".to_owned()
+        } else {
+            format!("history-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// The notes left in the locked folders
+    pub fn placeholders_record(&self) -> PathBuf {
+        let file = if self.is_default() {
+            "placeholders.json".to_owned()
+        } else {
+            format!("placeholders-{}.json", self.name)
+        };
+        crate::state_dir().join(file)
+    }
+
+    /// All profiles that have been installed
+    pub fn installed() -> Result<Vec<Self>> {
+        let entries = match fs::read_dir(crate::state_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).wrap_err("Could not read state dir"),
+        };
+
+        let mut profiles = Vec::new();
+        for entry in entries {
+            let file = entry?.file_name();
+            if let Some(profile) = file.to_str().and_then(Self::from_record) {
+                profiles.push(profile);
+            }
+        }
+        profiles.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(profiles)
+    }
+
+    fn from_record(file: &str) -> Option<Self> {
+        let name = file.strip_prefix(RECORD_PREFIX)?.strip_suffix(".json")?;
+        match name.strip_prefix('-') {
+            Some(name) => Self::new(name).ok(),
+            None if name.is_empty() => Some(Self::new(DEFAULT).expect("default is valid")),
+            None => None,
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(Profile::new("exam-week").is_ok());
+        assert!(Profile::new("").is_err());
+        assert!(Profile::new("../etc").is_err());
+        assert!(Profile::new("week night").is_err());
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        for name in [DEFAULT, "weeknight"] {
+            let profile = Profile::new(name).unwrap();
+            let record = profile.record();
+            let file = record.file_name().unwrap().to_str().unwrap();
+            assert_eq!(Profile::from_record(file), Some(profile));
+        }
+        assert_eq!(Profile::from_record("routes.json"), None);
+    }
+
+    #[test]
+    fn default_units_unchanged() {
+        let profile = Profile::new(DEFAULT).unwrap();
+        assert_eq!(profile.unit("timer"), "book-safe.timer");
+        let profile = Profile::new("weeknight").unwrap();
+        assert_eq!(profile.unit("service"), "book-safe@weeknight.service");
+    }
+}
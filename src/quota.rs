@@ -0,0 +1,120 @@
+//! Daily quota mode, the folders are unlocked until they have been
+//! unlocked for the quota that day. The time used is counted by the
+//! frequent runs of the service and kept in the state dir.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::profile::Profile;
+use crate::util::AcceptErr;
+
+/// Longer gaps between runs are the device sleeping, the folders can
+/// not be used then so that time is not counted.
+const MAX_GAP: Duration = Duration::minutes(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Usage {
+    /// julian day the usage is for
+    day: i32,
+    /// seconds unlocked on `day`
+    used: i64,
+    /// unix timestamp of the last run
+    last_seen: i64,
+}
+
+impl Usage {
+    fn load(profile: &Profile) -> Result<Option<Self>> {
+        let json = match fs::read_to_string(profile.quota_record()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).wrap_err("Could not read quota record"),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .wrap_err("Could not parse quota record")
+    }
+
+    fn save(&self, profile: &Profile) -> Result<()> {
+        fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(profile.quota_record(), json).wrap_err("Could not write quota record")
+    }
+
+    /// Adds the time since the last run if the folders were unlocked
+    /// during it. `now` must be in the local timezone as the count
+    /// resets at midnight.
+    fn tally(previous: Option<Self>, now: OffsetDateTime, unlocked: bool) -> Self {
+        let day = now.date().to_julian_day();
+        let now_ts = now.unix_timestamp();
+        let Some(previous) = previous.filter(|usage| usage.day == day) else {
+            return Self {
+                day,
+                used: 0,
+                last_seen: now_ts,
+            };
+        };
+
+        let gap = Duration::seconds(now_ts - previous.last_seen);
+        let used = if unlocked && gap.is_positive() && gap <= MAX_GAP {
+            previous.used + gap.whole_seconds()
+        } else {
+            previous.used
+        };
+        Self {
+            day,
+            used,
+            last_seen: now_ts,
+        }
+    }
+}
+
+/// Counts the time since the last run if `profile` was unlocked, returns
+/// how long it has been unlocked today
+pub fn update(profile: &Profile, now: OffsetDateTime, unlocked: bool) -> Result<Duration> {
+    let usage = Usage::tally(Usage::load(profile)?, now, unlocked);
+    usage.save(profile)?;
+    Ok(Duration::seconds(usage.used))
+}
+
+/// How long `profile` has been unlocked today, without counting
+pub fn used(profile: &Profile, now: OffsetDateTime) -> Result<Duration> {
+    let day = now.date().to_julian_day();
+    Ok(Usage::load(profile)?
+        .filter(|usage| usage.day == day)
+        .map_or(Duration::ZERO, |usage| Duration::seconds(usage.used)))
+}
+
+pub fn clear(profile: &Profile) -> Result<()> {
+    fs::remove_file(profile.quota_record())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove quota record")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let date = time::Date::from_calendar_date(2024, time::Month::June, 1).unwrap();
+        let morning = date.with_hms(9, 0, 0).unwrap().assume_utc();
+
+        let usage = Usage::tally(None, morning, true);
+        assert_eq!(usage.used, 0);
+        let usage = Usage::tally(Some(usage), morning + Duration::minutes(1), true);
+        assert_eq!(usage.used, 60);
+        // locked time does not count
+        let usage = Usage::tally(Some(usage), morning + Duration::minutes(2), false);
+        assert_eq!(usage.used, 60);
+        // nor does time asleep
+        let usage = Usage::tally(Some(usage), morning + Duration::hours(2), true);
+        assert_eq!(usage.used, 60);
+        // the next day starts over
+        let usage = Usage::tally(Some(usage), morning + Duration::days(1), true);
+        assert_eq!(usage.used, 0);
+    }
+}
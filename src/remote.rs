@@ -0,0 +1,173 @@
+//! Running book-safe on one or more devices over ssh from a desktop, so
+//! one command manages every tablet in a family. Uses the `ssh` and `scp`
+//! of the desktop, so its keys and ssh config apply.
+
+use std::io::Write;
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+use itertools::Itertools;
+
+use crate::util::cmd::{quote, SysCmd};
+
+/// Where book-safe lives on the devices
+pub struct Install<'a> {
+    pub path: &'a str,
+    /// Build for the devices, copied over if they have another version
+    pub binary: Option<&'a Path>,
+}
+
+/// Runs book-safe with `args` on every host, going on if one fails
+pub fn run(cmd: &impl SysCmd, hosts: &[String], install: &Install, args: &[String]) -> Result<()> {
+    let mut failed = Vec::new();
+    for host in hosts {
+        println!("== {host}");
+        if let Err(e) = run_on(cmd, host, install, args) {
+            log::error!("{host}: {e:#}");
+            failed.push(host.as_str());
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!("Failed on: {}", failed.join(", ")))
+    }
+}
+
+fn run_on(cmd: &impl SysCmd, host: &str, install: &Install, args: &[String]) -> Result<()> {
+    ensure_binary(cmd, host, install)?;
+    let line = std::iter::once(install.path)
+        .chain(args.iter().map(String::as_str))
+        .map(quote)
+        .join(" ");
+    let output = cmd
+        .output("ssh", &[host, &line])
+        .wrap_err("Could not run ssh")?;
+    std::io::stdout().write_all(&output.stdout)?;
+    std::io::stderr().write_all(&output.stderr)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("book-safe failed ({})", output.status))
+    }
+}
+
+/// Copies `install.binary` over if the host has no book-safe or another
+/// version then this one
+fn ensure_binary(cmd: &impl SysCmd, host: &str, install: &Install) -> Result<()> {
+    let output = cmd
+        .output(
+            "ssh",
+            &[host, &format!("{} --version", quote(install.path))],
+        )
+        .wrap_err("Could not run ssh")?;
+    // ssh exits with 255 if it could not connect or log in
+    if output.status.code() == Some(255) {
+        let reason = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("{}", reason.trim()).wrap_err("Could not connect"));
+    }
+    let ours = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let theirs = String::from_utf8_lossy(&output.stdout);
+    let theirs = theirs.trim();
+    if output.status.success() && theirs == ours {
+        return Ok(());
+    }
+    let Some(binary) = install.binary else {
+        let found = if output.status.success() {
+            theirs
+        } else {
+            "no book-safe"
+        };
+        return Err(eyre!("Found {found} at {}, need {ours}", install.path))
+            .suggestion("pass --binary with book-safe built for the device to copy it over");
+    };
+    let binary = binary
+        .to_str()
+        .ok_or_else(|| eyre!("Binary path is not utf-8"))?;
+    log::info!("copying {binary} to {host}:{}", install.path);
+    // the running service keeps the old binary, a rename does not disturb it
+    let new = format!("{}.new", install.path);
+    cmd.run("scp", &["-q", binary, &format!("{host}:{new}")])?;
+    cmd.run(
+        "ssh",
+        &[
+            host,
+            &format!(
+                "chmod 755 {0} && mv {0} {1}",
+                quote(&new),
+                quote(install.path)
+            ),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::cmd::Scripted;
+
+    const PATH: &str = "/home/root/book-safe";
+
+    fn ours() -> String {
+        format!("{} {}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    }
+
+    #[test]
+    fn runs_on_every_host() {
+        let cmd = Scripted::default()
+            .expect(
+                "ssh root@rm1 /home/root/book-safe --version",
+                0,
+                &ours(),
+                "",
+            )
+            .expect(
+                "ssh root@rm1 /home/root/book-safe '--path=My Books' status",
+                0,
+                "",
+                "",
+            )
+            .expect(
+                "ssh root@rm2 /home/root/book-safe --version",
+                255,
+                "",
+                "no route to host",
+            );
+        let install = Install {
+            path: PATH,
+            binary: None,
+        };
+        let hosts = ["root@rm1", "root@rm2"].map(String::from);
+        let args = ["--path=My Books", "status"].map(String::from);
+        let err = run(&cmd, &hosts, &install, &args).unwrap_err();
+        assert_eq!(err.to_string(), "Failed on: root@rm2");
+    }
+
+    #[test]
+    fn copies_binary_if_outdated() {
+        let cmd = Scripted::default()
+            .expect("ssh rm /home/root/book-safe --version", 0, "book-safe 0.0.1\n", "")
+            .expect("scp -q target/book-safe rm:/home/root/book-safe.new", 0, "", "")
+            .expect(
+                "ssh rm chmod 755 /home/root/book-safe.new && mv /home/root/book-safe.new /home/root/book-safe",
+                0,
+                "",
+                "",
+            );
+        let install = Install {
+            path: PATH,
+            binary: Some(Path::new("target/book-safe")),
+        };
+        ensure_binary(&cmd, "rm", &install).unwrap();
+
+        let cmd = Scripted::default().expect("ssh rm /home/root/book-safe --version", 127, "", "");
+        let install = Install {
+            path: PATH,
+            binary: None,
+        };
+        assert!(ensure_binary(&cmd, "rm", &install).is_err());
+    }
+}
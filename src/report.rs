@@ -1,16 +1,19 @@
 use color_eyre::eyre::Context;
 use color_eyre::Result;
 use indextree::NodeId;
+use itertools::Itertools;
 use printpdf::{
     BuiltinFont, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
     PdfPageIndex, Point, Pt,
 };
-use std::fs::{self, File};
-use std::io::{BufWriter, ErrorKind};
+use std::fs;
+use std::io::ErrorKind;
 use std::path::Path;
-use time::Time;
+use time::{Date, Duration, Time};
 
-use crate::directory::{self, Tree};
+use crate::device;
+use crate::directory::{Tree, Uuid};
+use crate::profile::Profile;
 use crate::util::AcceptErr;
 
 pub struct Doc {
@@ -26,7 +29,20 @@ pub struct Doc {
     n_pages: usize,
 }
 
+/// A `Doc` turned into pdf, to write it more than once
+pub struct Rendered {
+    n_pages: usize,
+    pdf: Vec<u8>,
+}
+
 impl Doc {
+    pub fn render(self) -> Result<Rendered> {
+        Ok(Rendered {
+            n_pages: self.n_pages,
+            pdf: self.pdf.save_to_bytes()?,
+        })
+    }
+
     fn hline(&mut self) {
         let points = vec![
             (Point::new(self.w_margin, self.y), false),
@@ -103,6 +119,43 @@ impl Doc {
         self.layer.end_text_section();
     }
 
+    /// A page with a bar for every day in `days` as long as it was locked
+    pub fn add_history(&mut self, days: &[(Date, Duration)]) {
+        self.next_page();
+        self.add_header(&format!("Locked in the last {} days", days.len()));
+        self.vspace(5.);
+        let (size, row) = (10.0, Mm(7.));
+        let bar_start = self.w_margin + Mm(30.);
+        let full = self.w - self.w_margin - bar_start - Mm(15.);
+        for (date, locked) in days {
+            let label = format!("{} {}", &date.weekday().to_string()[..3], date);
+            self.layer
+                .use_text(label, size, self.w_margin, self.y, &self.font);
+            let fraction = locked.as_seconds_f64() / Duration::DAY.as_seconds_f64();
+            let end = bar_start + Mm(full.0 * fraction.min(1.));
+            let (top, bottom) = (self.y + Mm(3.), self.y);
+            self.layer.add_shape(Line {
+                points: [
+                    (bar_start, bottom),
+                    (bar_start, top),
+                    (end, top),
+                    (end, bottom),
+                ]
+                .map(|(x, y)| (Point::new(x, y), false))
+                .to_vec(),
+                is_closed: true,
+                has_fill: true,
+                has_stroke: false,
+                is_clipping_path: false,
+            });
+            let minutes = locked.whole_minutes();
+            let hours = format!("{}:{:02}", minutes / 60, minutes % 60);
+            self.layer
+                .use_text(hours, size, end + Mm(2.), self.y, &self.font);
+            self.y -= row;
+        }
+    }
+
     fn next_page(&mut self) {
         let (page, layer) = self
             .pdf
@@ -114,13 +167,13 @@ impl Doc {
     }
 }
 
-pub fn build(tree: &Tree, roots: Vec<NodeId>, missing: Vec<String>, unlock: Time) -> Doc {
+fn new_doc() -> Doc {
     let (w, h) = (Mm(210.), Mm(297.));
     let (pdf, page, layer1) = PdfDocument::new("Book-locker", w, h, "Layer 1");
     let layer = pdf.get_page(page).get_layer(layer1);
     let font = pdf.add_builtin_font(BuiltinFont::TimesRoman).unwrap();
 
-    let mut doc = Doc {
+    Doc {
         w,
         h,
         y: h - Mm(30.),
@@ -131,8 +184,41 @@ pub fn build(tree: &Tree, roots: Vec<NodeId>, missing: Vec<String>, unlock: Time
         w_margin: Mm(30.),
         h_margin: Mm(30.),
         n_pages: 0,
-    };
+    }
+}
+
+/// A page saying the folder it is in unlocks at `unlock`
+pub fn note(unlock: Time) -> Doc {
+    let mut doc = new_doc();
+    doc.add_title("Folder is locked");
+    doc.add_header(&format!(
+        "Its documents are back at: {}",
+        crate::util::time::clock(unlock)
+    ));
+    doc
+}
+
+/// A page standing in for a locked document until `unlock`
+pub fn stub(unlock: Time) -> Doc {
+    let mut doc = new_doc();
+    doc.add_title("Locked");
+    doc.add_header(&format!(
+        "This book is locked until {}",
+        crate::util::time::clock(unlock)
+    ));
+    doc
+}
 
+pub fn build(
+    tree: &Tree,
+    roots: Vec<(NodeId, Time)>,
+    except: &[NodeId],
+    pinned: &[Uuid],
+    missing: Vec<String>,
+    unlock: Time,
+    quota: Option<Duration>,
+) -> Doc {
+    let mut doc = new_doc();
     doc.add_title("Folders are locked");
     if !missing.is_empty() {
         doc.add_header("Missing paths:");
@@ -142,26 +228,67 @@ pub fn build(tree: &Tree, roots: Vec<NodeId>, missing: Vec<String>, unlock: Time
         }
     }
     doc.vspace(10.);
+    if let Some(quota) = quota {
+        let minutes = quota.whole_minutes();
+        doc.add_header(&format!(
+            "Used up today's {}:{:02} hours",
+            minutes / 60,
+            minutes % 60
+        ));
+    }
     doc.add_header(&format!(
-        "Will unlock at: {}:{:02}",
-        unlock.hour(),
-        unlock.minute()
+        "Will unlock at: {}",
+        crate::util::time::clock(unlock)
     ));
     doc.hline();
     doc.vspace(8.);
     doc.add_header("Locked files:");
-    for root in roots {
+    let staggered = roots.iter().any(|(_, at)| *at != unlock);
+    for (root, at) in roots {
         doc.vspace(8.);
-        let subtree = tree.subtree(root);
-        doc.add_subheader(&format!("path: {:?}", subtree.path));
+        let subtree = tree.subtree(root).without(except);
+        let path = if root == *tree.root() {
+            "all documents".to_owned()
+        } else {
+            subtree.path.display().to_string()
+        };
+        if staggered {
+            doc.add_subheader(&format!(
+                "path: {path}, unlocks at {}",
+                crate::util::time::clock(at)
+            ));
+        } else {
+            doc.add_subheader(&format!("path: {path}"));
+        }
         let subtree = format!("{subtree}");
         doc.add_text(&subtree);
     }
+    if !pinned.is_empty() {
+        doc.vspace(8.);
+        doc.add_subheader("pinned documents:");
+        let paths = tree.doc_paths();
+        for path in pinned.iter().filter_map(|uuid| paths.get(uuid)).sorted() {
+            doc.add_text(&format!("- {}", path.display()));
+        }
+    }
+    if !except.is_empty() {
+        doc.vspace(8.);
+        doc.add_header("Not locked:");
+        for folder in except {
+            let path = tree.subtree(*folder).path;
+            doc.add_subheader(&format!("- {}", path.display()));
+        }
+    }
+    if let Some(orphaned) = tree.orphaned() {
+        doc.vspace(8.);
+        doc.add_header("Not locked, in folders that no longer exist:");
+        doc.add_text(&format!("{orphaned}"));
+    }
 
     doc
 }
 
-fn metadata() -> String {
+fn metadata(name: &str, parent: &str) -> String {
     let unix_ts = time::OffsetDateTime::now_utc().unix_timestamp();
     format!(
         "{{
@@ -169,12 +296,12 @@ fn metadata() -> String {
     \"lastModified\": \"{unix_ts}000\",
     \"metadatamodified\": false,
     \"modified\": false,
-    \"parent\": \"\",
+    \"parent\": \"{parent}\",
     \"pinned\": false,
     \"synced\": true,
     \"type\": \"DocumentType\",
     \"version\": 1,
-    \"visibleName\": \"Locked Books\"
+    \"visibleName\": \"{name}\"
 }}"
     )
 }
@@ -210,12 +337,61 @@ fn content(pages: usize) -> String {
 }
 
 const REPORT_UUID: &str = "64a3befb-b815-47e8-bf74-996bb6a76a5d";
-pub fn save(doc: Doc) -> Result<()> {
-    log::info!("report uuid: {REPORT_UUID} (constant)");
-    let path = Path::new(directory::DIR).join(REPORT_UUID);
+
+/// Every profile needs its own report, they are derived from the default
+/// one by replacing the last part of the uuid with a hash of the name.
+fn uuid(profile: &Profile) -> String {
+    if profile.is_default() {
+        return REPORT_UUID.to_owned();
+    }
+    derived_uuid(REPORT_UUID, &profile.to_string())
+}
+
+/// `base` with the last part replaced by a hash of `key`
+pub fn derived_uuid(base: &str, key: &str) -> String {
+    // fnv-1a, unlike std's hasher guaranteed to be stable between releases
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    let (prefix, _) = base.rsplit_once('-').expect("uuid has parts");
+    format!("{prefix}-{:012x}", hash & 0xffff_ffff_ffff)
+}
+
+/// Whether `uuid` was derived from `base`
+pub fn is_derived(base: &str, uuid: &Uuid) -> bool {
+    let (prefix, _) = base.rsplit_once('-').expect("uuid has parts");
+    uuid.to_string().starts_with(prefix)
+}
+
+/// Whether `uuid` is the report of any profile, these are never locked
+pub fn is_report(uuid: &Uuid) -> bool {
+    is_derived(REPORT_UUID, uuid)
+}
+
+pub fn save(profile: &Profile, doc: Doc) -> Result<()> {
+    let uuid = uuid(profile);
+    log::info!("report uuid: {uuid} (constant)");
+    let name = if profile.is_default() {
+        "Locked Books".to_owned()
+    } else {
+        format!("Locked Books ({profile})")
+    };
+    write(&uuid, &name, "", &doc.render()?)?;
+    log::info!("added report on locked files (pdf)");
+    Ok(())
+}
+
+/// Adds `doc` to the documents as `name` in folder `parent`, the empty
+/// string for the root
+pub fn write(uuid: &str, name: &str, parent: &str, doc: &Rendered) -> Result<()> {
+    let path = device::current().documents().join(uuid);
+    // left by `delete`, would have sync delete it again
+    fs::remove_file(path.with_extension("tombstone"))
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Failed to remove tombstone")?;
 
     fs::write(path.with_extension("content"), content(doc.n_pages))?;
-    fs::write(path.with_extension("metadata"), metadata())?;
+    fs::write(path.with_extension("metadata"), metadata(name, parent))?;
     fs::write(path.with_extension("pagedata"), "")?;
     for dir_ext in &["", "cache", "highlights", "thumbnails", "textconversion"] {
         fs::create_dir(path.with_extension(dir_ext))
@@ -225,34 +401,84 @@ pub fn save(doc: Doc) -> Result<()> {
             .wrap_err_with(|| format!("Failed to create {dir_ext} dir"))?;
     }
 
-    let mut writer = BufWriter::new(File::create(path.with_extension("pdf"))?);
-    doc.pdf.save(&mut writer)?;
-    log::info!("added report on locked files (pdf)");
+    fs::write(path.with_extension("pdf"), &doc.pdf)?;
     Ok(())
 }
 
-pub fn remove() -> Result<()> {
-    let path = Path::new(directory::DIR).join(REPORT_UUID);
-    assert!(!REPORT_UUID.is_empty(), "report uuid is empty str");
-    let files = ["content", "metadata", "pagedata", "pdf"];
-    let dirs = ["", "cache", "highlights", "thumbnails", "textconversion"];
+/// Records whether sync runs during the lock of `profile`. Then what we
+/// add to the documents may be uploaded and must be deleted from the
+/// cloud as well.
+pub fn set_sync_allowed(profile: &Profile, allowed: bool) -> Result<()> {
+    let record = profile.sync_allowed_record();
+    if allowed {
+        fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+        fs::write(record, "").wrap_err("Could not write sync allowed record")
+    } else {
+        fs::remove_file(record)
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not remove sync allowed record")
+    }
+}
 
-    // check for the last dir we remove, if its not here neither will
-    // the other files be so nothing can be removed
-    if !path.with_extension(dirs.last().unwrap()).is_dir() {
+/// Whether what we added to the documents of `profile` may have been
+/// uploaded, see `set_sync_allowed`
+pub fn sync_allowed(profile: &Profile) -> bool {
+    profile.sync_allowed_record().exists()
+}
+
+pub fn remove(profile: &Profile) -> Result<()> {
+    if !delete(&uuid(profile), sync_allowed(profile))? {
         log::warn!("no lock report to remove: was not locked or report got corrupted");
-        return Ok(());
     }
+    Ok(())
+}
+
+/// Removes what `write` added, returns whether there was anything. If
+/// it may have been `uploaded` the firmware is left what it needs to
+/// delete it from the cloud too.
+pub fn delete(uuid: &str, uploaded: bool) -> Result<bool> {
+    assert!(!uuid.is_empty(), "document uuid is empty str");
+    let path = device::current().documents().join(uuid);
+    let metadata = fs::read_to_string(path.with_extension("metadata")).ok();
+    let files = ["content", "metadata", "pagedata", "pdf"];
+    let dirs = ["", "cache", "highlights", "thumbnails", "textconversion"];
 
+    // a failed write can leave only some of these, remove what is there
+    let mut removed_any = false;
     for file_ext in &files {
-        fs::remove_file(path.with_extension(file_ext))
-            .wrap_err_with(|| format!("Failed to remove file: {file_ext}"))?;
+        let path = path.with_extension(file_ext);
+        if path.is_file() {
+            fs::remove_file(path).wrap_err_with(|| format!("Failed to remove file: {file_ext}"))?;
+            removed_any = true;
+        }
     }
     for dir_ext in &dirs {
-        fs::remove_dir_all(path.with_extension(dir_ext))
-            .wrap_err_with(|| format!("Failed to remove dir: {dir_ext}"))?;
+        let path = path.with_extension(dir_ext);
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+                .wrap_err_with(|| format!("Failed to remove dir: {dir_ext}"))?;
+            removed_any = true;
+        }
     }
-    Ok(())
+    if let Some(metadata) = metadata.filter(|_| uploaded) {
+        mark_deleted(&path, &metadata).wrap_err("Could not mark document deleted for sync")?;
+    }
+    Ok(removed_any)
+}
+
+/// Has the next sync delete the document at `path` from the cloud. The
+/// firmware removes what we leave once it did.
+fn mark_deleted(path: &Path, metadata: &str) -> Result<()> {
+    if device::current().layout.tombstones {
+        return fs::write(path.with_extension("tombstone"), "")
+            .wrap_err("Could not write tombstone");
+    }
+    let mut metadata: serde_json::Value =
+        serde_json::from_str(metadata).wrap_err("Could not parse metadata")?;
+    metadata["deleted"] = true.into();
+    metadata["metadatamodified"] = true.into();
+    let metadata = serde_json::to_string_pretty(&metadata)?;
+    fs::write(path.with_extension("metadata"), metadata).wrap_err("Could not write metadata")
 }
 
 #[cfg(test)]
@@ -265,24 +491,77 @@ mod test {
         simplelog::SimpleLogger::init(log::LevelFilter::Warn, simplelog::Config::default())
             .unwrap();
         let tree = test_tree();
-        let roots = vec![*tree.root()];
+        let unlock = time::Time::from_hms(12, 42, 59).unwrap();
+        let roots = vec![(*tree.root(), unlock)];
         let missing = vec![
             "missing_path".to_owned(),
             "another missing path.pdf".to_owned(),
         ];
-        let doc = build(
-            &tree,
-            roots,
-            missing,
-            time::Time::from_hms(12, 42, 59).unwrap(),
-        );
-
-        if built::util::detect_ci().is_some() {
-            log::warn!("skipping doc save as it fails on CI");
-            return Ok(());
-        }
+        let except = [tree.node_for("B0").unwrap()];
+        let quota = Some(Duration::minutes(150));
+        let pinned = [crate::directory::test::id("b0")];
+        let mut doc = build(&tree, roots, &except, &pinned, missing, unlock, quota);
+        let pages = doc.n_pages;
+        let day = time::Date::from_calendar_date(2024, time::Month::June, 10).unwrap();
+        doc.add_history(&[
+            (day, Duration::hours(9)),
+            (day.next_day().unwrap(), Duration::ZERO),
+        ]);
+        assert_eq!(doc.n_pages, pages + 1);
 
-        save(doc)?; // this fails on many CI platforms
+        let root = crate::device::simulate_in_temp_dir("report");
+        let documents = device::current().documents();
+        fs::create_dir(documents)?;
+        let profile = Profile::new(crate::profile::DEFAULT)?;
+        save(&profile, doc)?;
+        let pdf = documents.join(REPORT_UUID).with_extension("pdf");
+        assert!(fs::read(&pdf)?.starts_with(b"%PDF"));
+
+        remove(&profile)?;
+        assert_eq!(fs::read_dir(documents)?.count(), 0);
+        fs::remove_dir_all(root)?;
         Ok(())
     }
+
+    #[test]
+    fn deleted_from_the_cloud() -> Result<()> {
+        let root = crate::device::simulate_in_temp_dir("report-synced");
+        let documents = device::current().documents();
+        fs::create_dir(documents)?;
+        let path = documents.join(REPORT_UUID);
+
+        write(
+            REPORT_UUID,
+            "Locked Books",
+            "",
+            &note(Time::MIDNIGHT).render()?,
+        )?;
+        assert!(delete(REPORT_UUID, true)?);
+        // the simulated device is laid out like 3.x
+        assert!(path.with_extension("tombstone").exists());
+        write(
+            REPORT_UUID,
+            "Locked Books",
+            "",
+            &note(Time::MIDNIGHT).render()?,
+        )?;
+        assert!(!path.with_extension("tombstone").exists());
+        assert!(delete(REPORT_UUID, false)?);
+        assert_eq!(fs::read_dir(documents)?.count(), 0);
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn uuid_per_profile() {
+        let default = Profile::new(crate::profile::DEFAULT).unwrap();
+        assert_eq!(uuid(&default), REPORT_UUID);
+        let exams = uuid(&Profile::new("exam-week").unwrap());
+        assert_ne!(exams, REPORT_UUID);
+        assert_eq!(exams.len(), REPORT_UUID.len());
+        assert_eq!(exams, uuid(&Profile::new("exam-week").unwrap()));
+        assert!(is_report(&Uuid::parse(&exams).unwrap()));
+        let book = Uuid::parse("95318cc7-f844-416f-963a-cf277c83f10c").unwrap();
+        assert!(!is_report(&book));
+    }
 }
@@ -0,0 +1,134 @@
+//! Unlock requests. `request-unlock` stores a pending request and sends its
+//! token to the parent, who approves it with that token. An approved
+//! request pauses the lock for a set time. The token is only in the
+//! notification, which is why it does not need the `api_token`.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{ErrorKind, Read};
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::{Help, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::profile::Profile;
+use crate::util::AcceptErr;
+
+/// How long a request can be approved
+pub const TTL: time::Duration = time::Duration::minutes(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Pending,
+    Approved,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    token: String,
+    pub reason: String,
+    /// How long an approval unlocks
+    pub hours: f32,
+    /// unix timestamp after which the request can not be approved
+    expires: i64,
+    pub state: State,
+}
+
+impl Request {
+    pub fn new(reason: String, hours: f32, now: OffsetDateTime) -> Result<Self> {
+        Ok(Self {
+            token: new_token()?,
+            reason,
+            hours,
+            expires: (now + TTL).unix_timestamp(),
+            state: State::Pending,
+        })
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn expired(&self, now: OffsetDateTime) -> bool {
+        now.unix_timestamp() > self.expires
+    }
+
+    /// Marks the request approved if `token` is right and it is still
+    /// pending
+    pub fn approve(&mut self, token: &str, now: OffsetDateTime) -> Result<()> {
+        if !crate::util::same_secret(token, &self.token) {
+            return Err(eyre!("Wrong token"))
+                .suggestion("use the token from the latest unlock request");
+        }
+        if self.state == State::Approved {
+            return Err(eyre!("Request was already approved"));
+        }
+        if self.expired(now) {
+            return Err(eyre!("Request expired"))
+                .suggestion("ask for a new one with request-unlock");
+        }
+        self.state = State::Approved;
+        Ok(())
+    }
+
+    pub fn load(profile: &Profile) -> Result<Option<Self>> {
+        let json = match fs::read_to_string(profile.request_record()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).wrap_err("Could not read unlock request"),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .wrap_err("Could not parse unlock request")
+    }
+
+    pub fn save(&self, profile: &Profile) -> Result<()> {
+        fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+        let path = profile.request_record();
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json).wrap_err("Could not write unlock request")?;
+        // the token approves the request
+        crate::util::restrict_to_root(&path)
+    }
+
+    pub fn clear(profile: &Profile) -> Result<()> {
+        fs::remove_file(profile.request_record())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not remove unlock request")
+    }
+}
+
+fn new_token() -> Result<String> {
+    let mut bytes = [0u8; 8];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .wrap_err("Could not read /dev/urandom")?;
+    Ok(bytes.iter().fold(String::new(), |mut token, byte| {
+        let _ = write!(token, "{byte:02x}");
+        token
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn approving() {
+        let now = OffsetDateTime::UNIX_EPOCH;
+        let mut request = Request::new("homework".to_owned(), 1.0, now).unwrap();
+        assert_eq!(request.token().len(), 16);
+        let token = request.token().to_owned();
+
+        assert!(request.approve("0000000000000000", now).is_err());
+        let late = now + TTL + time::Duration::SECOND;
+        assert!(request.approve(&token, late).is_err());
+        assert_eq!(request.state, State::Pending);
+
+        request.approve(&token, now).unwrap();
+        assert_eq!(request.state, State::Approved);
+        assert!(request.approve(&token, now).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! What starts `run` at the times in the schedule. Systemd timers by
+//! default, cron for setups that prefer it. Both share the install,
+//! restore and uninstall flow through the `Scheduler` trait.
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::profile::Profile;
+use crate::systemd;
+
+mod cron;
+pub use cron::Cron;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// A systemd service started by a timer
+    #[default]
+    Systemd,
+    /// Entries in the crontab of root
+    Cron,
+}
+
+pub trait Scheduler {
+    /// Shown in the status, for example "timer"
+    fn name(&self) -> &'static str;
+    /// Writes what starts `run` with `run_args` for `profile`
+    fn write(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<()>;
+    /// Checks what `write` wrote
+    fn verify(&self, profile: &Profile) -> Result<()>;
+    fn enable(&self, profile: &Profile) -> Result<()>;
+    fn disable(&self, profile: &Profile) -> Result<()>;
+    fn remove(&self, profile: &Profile) -> Result<()>;
+    /// Shows what `write` would write, without writing anything
+    fn print(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<()>;
+    /// False if missing or different from what `write` would write now,
+    /// for example because the binary moved or the config changed
+    fn up_to_date(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<bool>;
+    /// For example `active` or `inactive`
+    fn state(&self, profile: &Profile) -> Result<String>;
+}
+
+pub fn new(kind: Kind) -> Box<dyn Scheduler> {
+    match kind {
+        Kind::Systemd => Box::new(systemd::Timer),
+        Kind::Cron => Box::new(Cron),
+    }
+}
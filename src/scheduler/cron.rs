@@ -0,0 +1,311 @@
+//! Entries in the crontab of root, installed through `crontab` so both
+//! busybox crond and other crons pick up the change. Every entry is tagged
+//! with its profile, entries of others are left alone. Cron only knows
+//! the os timezone, the times are converted when written. A run rewrites
+//! the entries once the offset changes, it still decides on the actual
+//! window so a run at the old time does no harm.
+
+use std::env::current_exe;
+use std::fs;
+use std::io::ErrorKind;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Help, Result,
+};
+use itertools::Itertools;
+use time::{Date, OffsetDateTime, Time};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use super::Scheduler;
+use crate::config::Config;
+use crate::device;
+use crate::profile::Profile;
+use crate::util::cmd::{self, SysCmd};
+use crate::util::time::{self as util_time, Schedule};
+use crate::util::AcceptErr;
+
+pub struct Cron;
+
+impl Scheduler for Cron {
+    fn name(&self) -> &'static str {
+        "cron"
+    }
+
+    fn write(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<()> {
+        let entries = entries(profile, run_args, args, config)?;
+        update(profile, &entries)
+    }
+
+    fn verify(&self, _: &Profile) -> Result<()> {
+        if device::current().is_remarkable() && !crond_running() {
+            log::warn!("no cron daemon is running, the entries will not run until one is");
+        }
+        Ok(())
+    }
+
+    /// The entries are active once written
+    fn enable(&self, _: &Profile) -> Result<()> {
+        Ok(())
+    }
+
+    fn disable(&self, profile: &Profile) -> Result<()> {
+        update(profile, &[])
+    }
+
+    fn remove(&self, profile: &Profile) -> Result<()> {
+        update(profile, &[])
+    }
+
+    fn print(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<()> {
+        let entries = entries(profile, run_args, args, config)?;
+        println!("# crontab of root\n{}\n", entries.join("\n"));
+        Ok(())
+    }
+
+    fn up_to_date(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<bool> {
+        let expected = entries(profile, run_args, args, config)?;
+        let tag = tag(profile);
+        let current = read(&cmd::System)?;
+        let current: Vec<_> = current.lines().filter(|l| l.ends_with(&tag)).collect();
+        if current != expected {
+            log::debug!("cron entries of {profile} are missing or outdated");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn state(&self, profile: &Profile) -> Result<String> {
+        if !device::current().is_remarkable() {
+            return Ok("unknown (not on a remarkable)".to_owned());
+        }
+        let tag = tag(profile);
+        Ok(if !read(&cmd::System)?.lines().any(|l| l.ends_with(&tag)) {
+            "missing".to_owned()
+        } else if crond_running() {
+            "active".to_owned()
+        } else {
+            "inactive (no cron daemon running)".to_owned()
+        })
+    }
+}
+
+/// Marks the entries of `profile`, a shell comment at the end of the line
+fn tag(profile: &Profile) -> String {
+    format!("# {}:{profile}", env!("CARGO_PKG_NAME"))
+}
+
+fn entries(
+    profile: &Profile,
+    run_args: &[String],
+    args: &crate::Args,
+    config: &Config,
+) -> Result<Vec<String>> {
+    let bin = current_exe().wrap_err("Could not get binary location")?;
+    let bin = bin
+        .to_str()
+        .ok_or_else(|| eyre!("Binary path is not utf-8"))?;
+    let command = std::iter::once(bin)
+        .chain(run_args.iter().map(String::as_str))
+        // cron runs the entries with sh
+        .map(cmd::quote)
+        .join(" ");
+    // some crons turn a '%' into a newline, no quoting prevents that
+    if command.contains('%') {
+        return Err(eyre!("Can not put a '%' in a cron entry: {command}"))
+            .suggestion("rename the folder or install with --scheduler systemd");
+    }
+    let tag = tag(profile);
+    let entry = |when: &str| format!("{when} {command} >/dev/null 2>&1 {tag}");
+
+    let whens = match args.schedule()? {
+        Schedule::Window { start, end } => {
+            let tz = util_time::zone(args.timezone(config)).wrap_err("Could not get timezone")?;
+            let os = util_time::zone(None)?;
+            let today = OffsetDateTime::now_utc().to_timezone(os).date();
+            let mut edges = vec![start, end];
+            if let Some(minutes) = args.warn_minutes {
+                edges.push(start - time::Duration::minutes(minutes.into()));
+            }
+            for tier in &args.unlock_at {
+                edges.push(crate::group::parse_tier(tier)?.0);
+            }
+            let mut whens: Vec<_> = edges
+                .into_iter()
+                .map(|at| os_time(at, tz, os, today))
+                .unique()
+                .map(|at| format!("{} {} * * *", at.minute(), at.hour()))
+                .collect();
+            // the run after it sends the weekly digest, near midnight the
+            // day can be off but then the next run sends it
+            if let Some((day, at)) = crate::digest::due_at(config)? {
+                let at = os_time(at, tz, os, today);
+                let day = day.number_days_from_sunday();
+                whens.push(format!("{} {} * * {day}", at.minute(), at.hour()));
+            }
+            // while locked the run re-resolves the sync ips, blocking any new ones
+            whens.extend(refresh(config.sync_refresh_minutes));
+            // powered off across an edge nothing locks until the next one
+            whens.push("@reboot sleep 120 &&".to_owned());
+            whens
+        }
+        // every run counts the time unlocked since the previous one
+        Schedule::Quota(_) => vec!["* * * * *".to_owned()],
+    };
+    Ok(whens.iter().map(|when| entry(when)).collect())
+}
+
+/// `at` on the clock of `tz` as the os clock shows it on `today`, a
+/// minute late like the timers so the run is sure to be past the edge
+fn os_time(at: Time, tz: &Tz, os: &Tz, today: Date) -> Time {
+    let at = util_time::resolve(today, at, tz) + time::Duration::MINUTE;
+    at.to_timezone(os).time()
+}
+
+fn refresh(minutes: u32) -> Option<String> {
+    match minutes {
+        0 => None,
+        1..=59 => Some(format!("*/{minutes} * * * *")),
+        _ => Some(format!("0 */{} * * *", (minutes / 60).min(23))),
+    }
+}
+
+/// Replaces the entries of `profile` in the crontab of root
+fn update(profile: &Profile, entries: &[String]) -> Result<()> {
+    if !device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, not changing the crontab");
+        return Ok(());
+    }
+    update_with(&cmd::System, profile, entries)
+}
+
+fn update_with(cmd: &impl SysCmd, profile: &Profile, entries: &[String]) -> Result<()> {
+    let crontab = merged(&read(cmd)?, &tag(profile), entries);
+    // crontab installs a file as the whole new table
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let path = crate::state_dir().join("crontab.new");
+    fs::write(&path, crontab).wrap_err("Could not write the new crontab")?;
+    let res = cmd.run("crontab", &[&path.to_string_lossy()]);
+    fs::remove_file(&path)
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not remove the new crontab")?;
+    res
+}
+
+/// The crontab of root, empty if there is none
+fn read(cmd: &impl SysCmd) -> Result<String> {
+    let output = match cmd.output("crontab", &["-l"]) {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(eyre!("crontab is not installed"))
+                .suggestion("install cron, for example from toltec, or use --scheduler systemd")
+        }
+        Err(e) => return Err(e).wrap_err("Could not run crontab"),
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else if stderr.contains("no crontab") {
+        Ok(String::new())
+    } else {
+        Err(eyre!("{}", stderr.trim()).wrap_err("crontab returned an error"))
+    }
+}
+
+/// `crontab` without the lines ending in `tag`, with `entries` added
+fn merged(crontab: &str, tag: &str, entries: &[String]) -> String {
+    crontab
+        .lines()
+        .filter(|line| !line.ends_with(tag))
+        .map(str::to_owned)
+        .chain(entries.iter().cloned())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Whether a process named cron or crond runs
+fn crond_running() -> bool {
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+    procs.flatten().any(|proc| {
+        std::fs::read_to_string(proc.path().join("comm"))
+            .is_ok_and(|comm| matches!(comm.trim(), "cron" | "crond"))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merges_entries() {
+        let crontab = "MAILTO=\"\"\n\
+            0 3 * * * /opt/backup\n\
+            1 22 * * * /old/book-safe run # book-safe:default\n\
+            1 7 * * * /bin/book-safe run # book-safe:kids\n";
+        let tag = "# book-safe:default";
+        let new = "1 23 * * * /bin/book-safe run # book-safe:default".to_owned();
+        let crontab = merged(crontab, tag, &[new]);
+        assert_eq!(
+            crontab,
+            "MAILTO=\"\"\n\
+            0 3 * * * /opt/backup\n\
+            1 7 * * * /bin/book-safe run # book-safe:kids\n\
+            1 23 * * * /bin/book-safe run # book-safe:default\n"
+        );
+        assert!(!merged(&crontab, tag, &[]).contains(tag));
+    }
+
+    #[test]
+    fn installs_through_crontab() {
+        let root = device::simulate_in_temp_dir("crontab");
+        let profile = Profile::new("kids").unwrap();
+        let new = crate::state_dir().join("crontab.new");
+        let entry = "1 22 * * * /bin/book-safe run # book-safe:kids".to_owned();
+        let cmd = cmd::Scripted::default()
+            .expect("crontab -l", 1, "", "no crontab for root\n")
+            .expect(&format!("crontab {}", new.display()), 0, "", "")
+            .missing("crontab -l");
+        update_with(&cmd, &profile, &[entry]).unwrap();
+        assert!(!new.exists());
+        let err = read(&cmd).unwrap_err();
+        assert_eq!(err.to_string(), "crontab is not installed");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn converts_to_os_time() {
+        let amsterdam = util_time::find_timezone("Europe/Amsterdam").unwrap();
+        let utc = util_time::find_timezone("UTC").unwrap();
+        let summer = Date::from_calendar_date(2024, time::Month::July, 1).unwrap();
+        let winter = Date::from_calendar_date(2024, time::Month::January, 1).unwrap();
+        let at = Time::from_hms(22, 0, 0).unwrap();
+        assert_eq!(
+            os_time(at, amsterdam, utc, summer),
+            Time::from_hms(20, 1, 0).unwrap()
+        );
+        assert_eq!(
+            os_time(at, amsterdam, utc, winter),
+            Time::from_hms(21, 1, 0).unwrap()
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! A small http api so a companion app or home automation can check on
+//! and control book-safe without running commands over ssh. Every request
+//! needs the `api_token` from the config as bearer token. Requests, and
+//! commands received over mqtt, are handled one at a time as locking and
+//! unlocking can not run in parallel.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use serde_json::json;
+
+use crate::profile::Profile;
+use crate::util::{self, interrupt};
+
+/// How long a client gets to send its request
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Larger requests are rejected, ours are a few hundred bytes
+const MAX_REQUEST: u64 = 16 * 1024;
+/// How often to check for connections, commands and interrupts
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Status,
+    Lock {
+        hours: f32,
+    },
+    Unlock,
+    Pause {
+        hours: f32,
+    },
+    /// Approve an unlock request, needs the token of the request instead
+    /// of the api token
+    Approve {
+        token: String,
+    },
+}
+
+/// What a request asks for
+#[derive(Debug, PartialEq)]
+enum Request {
+    Act(Profile, Action),
+    /// A html page to show, changes nothing
+    Page(String),
+}
+
+/// An error with the http status to answer it with
+#[derive(Debug)]
+struct Rejected {
+    status: u16,
+    reason: String,
+}
+
+impl Rejected {
+    fn new(status: u16, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Serves the api on `http`, a listen address and token, and handles
+/// the `commands` from mqtt until interrupted. Requests without a
+/// `profile` parameter act on `profile`.
+pub fn run(
+    http: Option<(SocketAddr, &str)>,
+    commands: Option<&Receiver<(Profile, Action)>>,
+    profile: &Profile,
+    mut handle: impl FnMut(&Profile, Action) -> Result<serde_json::Value>,
+) -> Result<()> {
+    let listener = match http {
+        Some((listen, _)) => {
+            let listener = TcpListener::bind(listen)
+                .wrap_err_with(|| format!("Could not listen on {listen}"))?;
+            // polled so an interrupt stops us
+            listener.set_nonblocking(true)?;
+            log::info!("serving api on {listen}");
+            Some(listener)
+        }
+        None => None,
+    };
+
+    loop {
+        if interrupt::check().is_err() {
+            log::info!("interrupted, no longer serving");
+            return Ok(());
+        }
+        if let Some((profile, action)) = commands.and_then(|rx| rx.try_recv().ok()) {
+            log::info!("mqtt command: {action:?} for profile {profile}");
+            if let Err(e) = handle(&profile, action) {
+                log::error!("mqtt command failed: {e:?}");
+            }
+            continue;
+        }
+        let Some((listener, (_, token))) = listener.as_ref().zip(http) else {
+            std::thread::sleep(POLL);
+            continue;
+        };
+        let stream = match listener.accept() {
+            Ok((stream, peer)) => {
+                log::debug!("connection from {peer}");
+                stream
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL);
+                continue;
+            }
+            Err(e) => return Err(e).wrap_err("Could not accept connection"),
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        if let Err(e) = respond(stream, token, profile, &mut handle) {
+            log::warn!("Could not answer request: {e:?}");
+        }
+    }
+}
+
+fn respond(
+    mut stream: impl Read + Write,
+    token: &str,
+    profile: &Profile,
+    handle: &mut impl FnMut(&Profile, Action) -> Result<serde_json::Value>,
+) -> Result<()> {
+    let json = "application/json";
+    let answer = parse(&mut stream, token, profile).and_then(|request| match request {
+        Request::Page(html) => Ok(("text/html; charset=utf-8", html)),
+        Request::Act(profile, action) => {
+            log::info!("api request: {action:?} for profile {profile}");
+            handle(&profile, action)
+                .map(|body| (json, body.to_string()))
+                .map_err(|e| {
+                    log::error!("api request failed: {e:?}");
+                    Rejected::new(500, format!("{e:#}"))
+                })
+        }
+    });
+    let (status, content_type, body) = match answer {
+        Ok((content_type, body)) => (200, content_type, body),
+        Err(Rejected { status, reason }) => (status, json, json!({ "error": reason }).to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status} {}\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        status_text(status),
+        body.len()
+    );
+    stream
+        .write_all(response.as_bytes())
+        .and_then(|()| stream.flush())
+        .wrap_err("Could not send response")
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Reads the request, checks the token and works out what to do
+fn parse(stream: impl Read, token: &str, default: &Profile) -> Result<Request, Rejected> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST));
+    let mut line = String::new();
+    let invalid = |_| Rejected::new(400, "invalid request");
+    reader.read_line(&mut line).map_err(invalid)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Rejected::new(400, "invalid request line"));
+    };
+    let (method, target) = (method.to_owned(), target.to_owned());
+
+    let mut authorized = false;
+    let mut body_len = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(invalid)? == 0 {
+            return Err(Rejected::new(413, "request too large or cut off"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(Rejected::new(400, "invalid header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            authorized = value
+                .strip_prefix("Bearer ")
+                .is_some_and(|given| util::same_secret(given, token));
+        } else if name.eq_ignore_ascii_case("content-length") {
+            body_len = value
+                .parse()
+                .map_err(|_| Rejected::new(400, "invalid length"))?;
+        }
+    }
+    // the body is not used, but the client expects it to be read
+    let mut body = Vec::new();
+    reader
+        .take(body_len)
+        .read_to_end(&mut body)
+        .map_err(invalid)?;
+
+    let request = route(&method, &target, default)?;
+    let needs_token = match &request {
+        Request::Act(_, action) => !matches!(action, Action::Approve { .. }),
+        Request::Page(_) => false,
+    };
+    if needs_token && !authorized {
+        return Err(Rejected::new(401, "missing or wrong api token"));
+    }
+    Ok(request)
+}
+
+fn route(method: &str, target: &str, default: &Profile) -> Result<Request, Rejected> {
+    let url = url::Url::parse(&format!("http://localhost{target}"))
+        .map_err(|_| Rejected::new(400, "invalid url"))?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let hours = || {
+        let hours = param("hours").ok_or_else(|| Rejected::new(400, "missing hours"))?;
+        hours
+            .parse()
+            .map_err(|_| Rejected::new(400, format!("invalid hours: {hours}")))
+    };
+
+    let token = || param("token").ok_or_else(|| Rejected::new(400, "missing token"));
+    let profile = match param("profile") {
+        Some(name) => Profile::new(&name).map_err(|e| Rejected::new(400, format!("{e}")))?,
+        None => default.clone(),
+    };
+
+    let action = match (method, url.path()) {
+        ("GET", "/status") => Action::Status,
+        ("POST", "/lock") => Action::Lock { hours: hours()? },
+        ("POST", "/unlock") => Action::Unlock,
+        ("POST", "/pause") => Action::Pause { hours: hours()? },
+        // the link in a notification only shows a page, so a link preview
+        // can not approve
+        ("GET", "/approve") => return Ok(Request::Page(approve_page(&token()?, &profile))),
+        ("POST", "/approve") => Action::Approve { token: token()? },
+        (_, "/status" | "/lock" | "/unlock" | "/pause" | "/approve") => {
+            return Err(Rejected::new(405, format!("{method} not allowed")))
+        }
+        (_, path) => return Err(Rejected::new(404, format!("no such endpoint: {path}"))),
+    };
+    Ok(Request::Act(profile, action))
+}
+
+/// Asks to confirm the approval, the button posts the request back
+fn approve_page(token: &str, profile: &Profile) -> String {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", token)
+        .append_pair("profile", &profile.to_string())
+        .finish()
+        .replace('&', "&amp;");
+    format!(
+        "<!DOCTYPE html>\n\
+        <html><head><meta name=\"viewport\" content=\"width=device-width\">\
+        <title>book-safe</title></head><body>\n\
+        <form method=\"post\" action=\"/approve?{query}\">\
+        <button type=\"submit\">Approve unlock request</button></form>\n\
+        </body></html>\n"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(text: &str) -> Result<(Profile, Action), Rejected> {
+        match page_or_request(text)? {
+            Request::Act(profile, action) => Ok((profile, action)),
+            Request::Page(_) => panic!("expected an action, got a page"),
+        }
+    }
+
+    fn page_or_request(text: &str) -> Result<Request, Rejected> {
+        let default = Profile::new("default").unwrap();
+        parse(text.as_bytes(), "secret", &default)
+    }
+
+    #[test]
+    fn requests() {
+        let (profile, action) = request(
+            "POST /lock?hours=1.5&profile=evening HTTP/1.1\r\n\
+            Authorization: Bearer secret\r\n\
+            Content-Length: 2\r\n\r\n{}",
+        )
+        .unwrap();
+        assert_eq!(profile.to_string(), "evening");
+        assert_eq!(action, Action::Lock { hours: 1.5 });
+
+        let (profile, action) =
+            request("GET /status HTTP/1.1\r\nauthorization: Bearer secret\r\n\r\n").unwrap();
+        assert_eq!(profile.to_string(), "default");
+        assert_eq!(action, Action::Status);
+
+        // the request token is all that is needed to approve
+        let (_, action) = request("POST /approve?token=abc HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            action,
+            Action::Approve {
+                token: "abc".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn opening_approve_link_only_shows_page() {
+        let page = page_or_request("GET /approve?token=a+b&profile=kids HTTP/1.1\r\n\r\n");
+        let Ok(Request::Page(html)) = page else {
+            panic!("expected a page, got: {page:?}");
+        };
+        assert!(html.contains("method=\"post\" action=\"/approve?token=a+b&amp;profile=kids\""));
+    }
+
+    #[test]
+    fn rejected() {
+        let status = |text: &str| request(text).unwrap_err().status;
+        assert_eq!(status("POST /unlock HTTP/1.1\r\n\r\n"), 401);
+        assert_eq!(
+            status("POST /unlock HTTP/1.1\r\nAuthorization: Bearer secreT\r\n\r\n"),
+            401
+        );
+        let auth = "Authorization: Bearer secret\r\n\r\n";
+        assert_eq!(status(&format!("GET /unlock HTTP/1.1\r\n{auth}")), 405);
+        assert_eq!(status(&format!("GET /nope HTTP/1.1\r\n{auth}")), 404);
+        assert_eq!(status("PUT /approve?token=abc HTTP/1.1\r\n\r\n"), 405);
+        assert_eq!(status(&format!("POST /pause HTTP/1.1\r\n{auth}")), 400);
+        assert_eq!(
+            status(&format!("POST /lock?hours=x HTTP/1.1\r\n{auth}")),
+            400
+        );
+        assert_eq!(
+            status("POST /unlock HTTP/1.1\r\nAuthorization\r\n\r\n"),
+            400
+        );
+        let cut_off = "POST /unlock HTTP/1.1\r\nAuthorization: Bearer secret\r\n";
+        assert_eq!(status(cut_off), 413);
+    }
+}
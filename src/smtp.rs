@@ -0,0 +1,248 @@
+//! A minimal smtp client to mail the weekly digest. Speaks tls from the
+//! start (`smtps://`, port 465) or plain smtp (`smtp://`, port 25) for a
+//! relay on the local network, STARTTLS is not supported. The login is
+//! taken from the url.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::{Help, Result};
+
+use crate::util::net;
+
+/// How long to wait for the server to connect and answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Mail<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub body: &'a str,
+}
+
+pub fn send(url: &str, mail: &Mail) -> Result<()> {
+    let parsed = url::Url::parse(url).wrap_err("Invalid smtp url")?;
+    let (tls, default_port) = match parsed.scheme() {
+        "smtp" => (false, 25),
+        "smtps" => (true, 465),
+        other => {
+            return Err(eyre!("Unsupported smtp url scheme: {other}"))
+                .suggestion("use a smtp:// or smtps:// url")
+        }
+    };
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| eyre!("Smtp url has no host"))?;
+    let port = parsed.port().unwrap_or(default_port);
+    let username = percent_decode(parsed.username());
+    let password = parsed.password().map(percent_decode);
+    let login = password
+        .as_deref()
+        .filter(|_| !username.is_empty())
+        .map(|password| (username.as_str(), password));
+
+    let stream = net::connect(host, port, tls, TIMEOUT)?;
+    session(stream, login, mail)
+}
+
+fn session(mut stream: impl Read + Write, login: Option<(&str, &str)>, mail: &Mail) -> Result<()> {
+    expect(&mut stream, 220).wrap_err("Server did not greet us")?;
+    command(&mut stream, "EHLO book-safe", 250)?;
+    if let Some((username, password)) = login {
+        let token = base64(format!("\0{username}\0{password}").as_bytes());
+        command(&mut stream, &format!("AUTH PLAIN {token}"), 235)
+            .suggestion("check the username and password in the digest url")?;
+    }
+    command(&mut stream, &format!("MAIL FROM:<{}>", mail.from), 250)?;
+    command(&mut stream, &format!("RCPT TO:<{}>", mail.to), 250)?;
+    command(&mut stream, "DATA", 354)?;
+    stream
+        .write_all(message(mail).as_bytes())
+        .wrap_err("Could not send the mail")?;
+    expect(&mut stream, 250).wrap_err("Server did not accept the mail")?;
+    // the mail is sent, a server hanging up early does no harm
+    let _ = command(&mut stream, "QUIT", 221);
+    Ok(())
+}
+
+fn command(stream: &mut (impl Read + Write), line: &str, code: u16) -> Result<()> {
+    stream
+        .write_all(format!("{line}\r\n").as_bytes())
+        .and_then(|()| stream.flush())
+        .wrap_err("Could not send to the smtp server")?;
+    let verb = line.split_whitespace().next().unwrap_or_default();
+    expect(stream, code).wrap_err_with(|| format!("{verb} failed"))
+}
+
+/// Reads a reply, which can span multiple lines, and errors with it if it
+/// does not have `code`
+fn expect(stream: &mut impl Read, code: u16) -> Result<()> {
+    loop {
+        let line = read_line(stream)?;
+        let got = line.get(..3).and_then(|c| c.parse::<u16>().ok());
+        if got != Some(code) {
+            return Err(eyre!("{}", line.trim()));
+        }
+        // `250-` is followed by more lines, `250 ` is the last
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Byte by byte so nothing after the line is consumed
+fn read_line(stream: &mut impl Read) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\n") {
+        match stream.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) => return Err(e).wrap_err("Could not read from the smtp server"),
+        }
+        if line.len() > 1000 {
+            return Err(eyre!("Reply from the smtp server is too long"));
+        }
+    }
+    if line.is_empty() {
+        return Err(eyre!("Smtp server closed the connection"));
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Headers and body with crlf line ends, ending in the line with a single
+/// `.` that ends the data
+fn message(mail: &Mail) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\r\n",
+        mail.from, mail.to, mail.subject
+    );
+    for line in mail.body.lines() {
+        // a line with only a `.` would end the data early
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    message
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (n >> (18 - 6 * i)) & 0x3f;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Undoes the `%xx` escapes the url keeps in its username and password
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(decoded) if byte == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Answers with `replies` and keeps what was sent
+    struct Server {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Read for Server {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Server {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const MAIL: Mail = Mail {
+        from: "book-safe@remarkable",
+        to: "parent@example.com",
+        subject: "digest",
+        body: "locked 3 times\n.\n",
+    };
+
+    #[test]
+    fn sends_mail() {
+        let replies = "220 mail.example.com ESMTP\r\n\
+            250-mail.example.com\r\n250 AUTH PLAIN\r\n\
+            235 ok\r\n250 ok\r\n250 ok\r\n354 go ahead\r\n250 queued\r\n221 bye\r\n";
+        let mut server = Server {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            sent: Vec::new(),
+        };
+        session(&mut server, Some(("me", "secret")), &MAIL).unwrap();
+        let sent = String::from_utf8(server.sent).unwrap();
+        assert!(sent.starts_with("EHLO book-safe\r\nAUTH PLAIN AG1lAHNlY3JldA==\r\n"));
+        assert!(sent.contains("RCPT TO:<parent@example.com>\r\nDATA\r\n"));
+        assert!(sent.ends_with("locked 3 times\r\n..\r\n.\r\nQUIT\r\n"));
+    }
+
+    #[test]
+    fn reports_refusal() {
+        let replies = "220 hi\r\n250 hi\r\n535 5.7.8 bad credentials\r\n";
+        let mut server = Server {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            sent: Vec::new(),
+        };
+        let err = session(&mut server, Some(("me", "wrong")), &MAIL).unwrap_err();
+        assert_eq!(format!("{err:#}"), "AUTH failed: 535 5.7.8 bad credentials");
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(base64(b"\0me\0secret"), "AG1lAHNlY3JldA==");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(percent_decode("p%40ss%2Bword"), "p@ss+word");
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
@@ -0,0 +1,139 @@
+//! Reading statistics for the locked folders, from when xochitl last
+//! opened each document. A document opened again only counts once so
+//! these are rough.
+
+use color_eyre::{eyre::WrapErr, Result};
+use time::{Duration, OffsetDateTime};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use crate::directory::{self, Doc};
+use crate::profile::Profile;
+
+/// Number of recently opened documents to list
+const RECENT: usize = 10;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Summary {
+    total: usize,
+    last_week: usize,
+    last_month: usize,
+    since_install: usize,
+    before_install: usize,
+    never: usize,
+}
+
+fn summarize(docs: &[Doc], now: OffsetDateTime, installed: Option<OffsetDateTime>) -> Summary {
+    let mut summary = Summary {
+        total: docs.len(),
+        ..Summary::default()
+    };
+    for at in docs.iter().map(|doc| doc.opened.at) {
+        let Some(at) = at else {
+            summary.never += 1;
+            continue;
+        };
+        if now - at <= Duration::weeks(1) {
+            summary.last_week += 1;
+        }
+        if now - at <= Duration::days(30) {
+            summary.last_month += 1;
+        }
+        match installed {
+            Some(installed) if at >= installed => summary.since_install += 1,
+            Some(_) => summary.before_install += 1,
+            None => (),
+        }
+    }
+    summary
+}
+
+fn ago(now: OffsetDateTime, at: OffsetDateTime) -> String {
+    let since = now - at;
+    match since.whole_days() {
+        0 => format!("{} hours ago", since.whole_hours()),
+        1 => "yesterday".to_owned(),
+        days => format!("{days} days ago"),
+    }
+}
+
+pub fn print(
+    profile: &Profile,
+    paths: &[String],
+    installed: Option<OffsetDateTime>,
+    tz: &Tz,
+) -> Result<()> {
+    // while locked the documents are in the safe
+    let safe_dir = profile.safe_dir();
+    let dirs = [crate::device::current().documents(), safe_dir.as_path()];
+    let (tree, _) = directory::map_dirs(&dirs).wrap_err("Could not build document tree")?;
+    let mut docs = Vec::new();
+    for path in paths {
+        match tree.node_for(path) {
+            Ok(node) => docs.extend(tree.descendant_docs(node)),
+            Err(_) => log::warn!("could not find: {path}"),
+        }
+    }
+
+    let now = OffsetDateTime::now_utc().to_timezone(tz);
+    let summary = summarize(&docs, now, installed);
+    println!("{} documents in the locked folders", summary.total);
+    println!("opened in the last 7 days: {}", summary.last_week);
+    println!("opened in the last 30 days: {}", summary.last_month);
+    if let Some(installed) = installed {
+        println!(
+            "last opened since installing on {}: {}, before that: {}",
+            installed.to_timezone(tz).date(),
+            summary.since_install,
+            summary.before_install
+        );
+    }
+    println!("never opened: {}", summary.never);
+
+    docs.retain(|doc| doc.opened.at.is_some());
+    docs.sort_unstable_by_key(|doc| std::cmp::Reverse(doc.opened.at));
+    if !docs.is_empty() {
+        println!("\nrecently opened:");
+    }
+    for doc in docs.iter().take(RECENT) {
+        let at = doc.opened.at.expect("retained only opened docs");
+        let page = doc
+            .opened
+            .page
+            .map(|page| format!(" (page {})", page + 1))
+            .unwrap_or_default();
+        println!("\t{}: {}{page}", ago(now, at), doc.path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::directory::Opened;
+
+    #[test]
+    fn summary() {
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(100);
+        let doc = |days_ago: Option<i64>| Doc {
+            path: "Books/doc".into(),
+            opened: Opened {
+                at: days_ago.map(|days| now - Duration::days(days)),
+                page: None,
+            },
+        };
+        let docs = [doc(Some(1)), doc(Some(10)), doc(Some(60)), doc(None)];
+        let installed = Some(now - Duration::days(20));
+
+        assert_eq!(
+            summarize(&docs, now, installed),
+            Summary {
+                total: 4,
+                last_week: 1,
+                last_month: 2,
+                since_install: 2,
+                before_install: 1,
+                never: 1,
+            }
+        );
+    }
+}
@@ -1,41 +1,196 @@
 use color_eyre::{
     eyre::{eyre, WrapErr},
-    Result,
+    Help, Result,
 };
 use itertools::{Either, Itertools};
 use std::{
+    collections::BTreeSet,
     net::IpAddr,
     thread,
     time::{Duration, Instant},
 };
 use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::Resolver;
 
 mod cache;
+mod discover;
 mod route;
+mod services;
+mod wifi;
 
 use cache::Cached;
+use route::RouteTable;
+pub use services::StopServices;
+pub use wifi::Wifi;
 
+use crate::config::{Config, DnsProtocol, DnsServer};
+use crate::device;
+use crate::util::cmd;
+
+/// Endpoints used for syncing documents
 const SYNC_BACKENDS: [&str; 9] = [
-    "hwr-production-dot-remarkable-production.appspot.com",
     "service-manager-production-dot-remarkable-production.appspot.com",
     "local.appspot.com",
     "my.remarkable.com",
-    "ping.remarkable.com",
     "internal.cloud.remarkable.com",
+    "eu.tectonic.remarkable.com",
+    "eu.internal.tctn.cloud.remarkable.com",
     "ams15s41-in-f20.1e100.net",
     "ams15s48-in-f20.1e100.net",
     "206.137.117.34.bc.googleusercontent.com",
 ];
 
-fn resolve_sync_routes() -> (Vec<IpAddr>, Vec<ResolveError>) {
-    use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
-    use trust_dns_resolver::Resolver;
+/// Endpoints for features unrelated to syncing: handwriting
+/// conversion and the connectivity check
+const SERVICE_BACKENDS: [&str; 2] = [
+    "hwr-production-dot-remarkable-production.appspot.com",
+    "ping.remarkable.com",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Scope {
+    /// Only block document sync, keeping handwriting conversion
+    /// and screen sharing working
+    SyncOnly,
+    /// Block all of the reMarkable cloud
+    #[default]
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Backend {
+    /// Reject traffic to the sync servers
+    #[default]
+    Routes,
+    /// Turn wifi off entirely
+    Wifi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Policy {
+    /// Lock only if sync could be blocked
+    #[default]
+    Required,
+    /// Lock even if blocking sync failed, with a warning and notification
+    BestEffort,
+}
+
+pub trait SyncBlocker {
+    /// Starts blocking sync, part of the work may continue in the background
+    fn block(&self) -> Result<Pending>;
+    fn unblock(&self) -> Result<()>;
+}
+
+/// What to block, needs to be created while xochitl is still running
+#[derive(Clone)]
+pub struct Blocklist {
+    domains: Vec<String>,
+    discovered: Vec<IpAddr>,
+    cache_limits: cache::Limits,
+    retry: Retry,
+    dns_servers: Vec<DnsServer>,
+    resolve_timeout: Duration,
+    /// block recently cached ips right away and resolve in the background
+    offline_ok: bool,
+}
+
+impl Blocklist {
+    pub fn new(config: &Config, extra: &[String], scope: Scope, offline_ok: bool) -> Self {
+        let domains = backends(config, extra, scope);
+        let discovered = if config.discover_endpoints {
+            // we can not tell what a live connection is used for
+            let include_live = scope == Scope::All;
+            discover::endpoints(&domains, include_live)
+        } else {
+            Vec::new()
+        };
+        Self {
+            domains,
+            discovered,
+            cache_limits: config.into(),
+            retry: config.into(),
+            dns_servers: config.dns_servers.clone(),
+            resolve_timeout: Duration::from_secs(config.resolve_timeout_secs),
+            offline_ok,
+        }
+    }
+}
 
-    let resolver = Resolver::new(ResolverConfig::default(), ResolverOpts::default()).unwrap();
+/// The domains to block: the builtin list (unless the config replaces it)
+/// extended with those from the config and the command line
+fn backends(config: &Config, extra: &[String], scope: Scope) -> Vec<String> {
+    let builtin = match (config.replace_default_domains, scope) {
+        (true, _) => Vec::new(),
+        (false, Scope::SyncOnly) => SYNC_BACKENDS.to_vec(),
+        (false, Scope::All) => SYNC_BACKENDS.into_iter().chain(SERVICE_BACKENDS).collect(),
+    };
 
-    let (err, res): (Vec<_>, Vec<_>) = SYNC_BACKENDS
+    let mut domains: Vec<_> = builtin
         .into_iter()
-        .map(|domain| resolver.lookup_ip(domain))
+        .map(str::to_owned)
+        .chain(config.block_domains.iter().cloned())
+        .chain(extra.iter().cloned())
+        .collect();
+    domains.sort_unstable();
+    domains.dedup();
+    domains
+}
+
+fn resolver(servers: &[DnsServer]) -> Result<Resolver> {
+    use trust_dns_resolver::config::{
+        LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts,
+    };
+    use trust_dns_resolver::config::{NameServerConfigGroup, Protocol};
+
+    let config = if servers.is_empty() {
+        ResolverConfig::default()
+    } else {
+        let mut group = NameServerConfigGroup::new();
+        for server in servers {
+            let protocol = match server.protocol {
+                DnsProtocol::Udp => Protocol::Udp,
+                DnsProtocol::Tcp => Protocol::Tcp,
+                #[cfg(feature = "dns-over-https")]
+                DnsProtocol::Https => Protocol::Https,
+                #[cfg(not(feature = "dns-over-https"))]
+                DnsProtocol::Https => {
+                    return Err(eyre!("book-safe was build without dns over https support"))
+                }
+            };
+            if server.protocol == DnsProtocol::Https && server.tls_name.is_none() {
+                return Err(eyre!(
+                    "dns over https server {} needs a `tls_name`",
+                    server.address
+                ));
+            }
+            let mut name_server = NameServerConfig::new(server.address, protocol);
+            name_server.tls_dns_name.clone_from(&server.tls_name);
+            group.push(name_server);
+        }
+        ResolverConfig::from_parts(None, Vec::new(), group)
+    };
+
+    // the default strategy only returns ipv6 addresses if there are no
+    // ipv4 ones, the device will happily sync over either
+    let mut opts = ResolverOpts::default();
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    Resolver::new(config, opts).wrap_err("Could not create dns resolver")
+}
+
+fn resolve_sync_routes(
+    resolver: &Resolver,
+    domains: &[String],
+) -> (Vec<IpAddr>, Vec<ResolveError>) {
+    let (err, res): (Vec<_>, Vec<_>) = domains
+        .iter()
+        .map(|domain| {
+            let lookup = resolver.lookup_ip(domain.as_str());
+            match &lookup {
+                Ok(ips) => log::debug!("{domain} resolved to: {:?}", ips.iter().collect_vec()),
+                Err(e) => log::debug!("could not resolve {domain}: {e}"),
+            }
+            lookup
+        })
         .partition_map(Either::from);
 
     // there can be duplicate ips, collecting to hashset deduplicates them
@@ -47,15 +202,13 @@ fn resolve_sync_routes() -> (Vec<IpAddr>, Vec<ResolveError>) {
     (res, err)
 }
 
-fn update_and_get_sync_routes() -> Result<Vec<IpAddr>> {
-    // wifi can take a long time to get up and running
-    const TIMEOUT: Duration = Duration::from_secs(30);
-
+fn update_and_get_sync_routes(list: &Blocklist) -> Result<Vec<IpAddr>> {
+    let dns = resolver(&list.dns_servers).wrap_err("Invalid dns server config")?;
     let cache = Cached::load().wrap_err("Could not load files from cache file")?;
 
     let start = Instant::now();
     let resolved = loop {
-        let (resolved, err) = resolve_sync_routes();
+        let (resolved, err) = resolve_sync_routes(&dns, &list.domains);
         let conn_errs = err
             .iter()
             .map(ResolveError::kind)
@@ -66,96 +219,443 @@ fn update_and_get_sync_routes() -> Result<Vec<IpAddr>> {
             break resolved;
         }
 
-        if start.elapsed() > TIMEOUT {
-            log::warn!("Could not resolve routes within timeout: {TIMEOUT:?}");
+        // wifi can take a long time to get up and running
+        if start.elapsed() > list.resolve_timeout {
+            log::warn!(
+                "Could not resolve routes within timeout: {:?}",
+                list.resolve_timeout
+            );
             break Vec::new();
         }
 
         log::debug!("Could not resolve sync adresses, retrying...");
+        crate::util::interrupt::check()?;
         thread::sleep(Duration::from_millis(200));
     };
 
+    let resolved = resolved
+        .into_iter()
+        .chain(list.discovered.iter().copied())
+        .collect();
     let routes = cache
-        .update(resolved)
+        .update(resolved, &list.cache_limits)
         .ok_or_else(|| eyre!("cache empty and no routes resolved in time"))?;
     routes
         .cache()
         .wrap_err("Could not cache syn routes to durable storage")?;
+    unblock_ips(routes.evicted(), list.retry)
+        .wrap_err("Could not unblock ips dropped from the cache")?;
 
     Ok(routes.into_ips())
 }
 
-// directly after resuming from sleep the `route` tool does not seem to work
-// therefore this retries `route` a few times
-pub fn block() -> Result<()> {
+pub fn show_cache() -> Result<()> {
+    let cache = Cached::load().wrap_err("Could not load route cache")?;
+    println!("route cache: {}", cache::path().display());
+    for entry in cache.entries() {
+        println!("{:<40} updated {}", entry.ip, age(entry));
+    }
+    Ok(())
+}
+
+fn age(entry: &cache::Entry) -> String {
+    let age = entry.last_updated.elapsed().unwrap_or_default();
+    let days = age.as_secs() / (24 * 60 * 60);
+    let hours = age.as_secs() / (60 * 60) % 24;
+    format!("{days}d {hours}h ago")
+}
+
+/// Prints the ips in the route cache and those rejected by the routing
+/// table. Unlock only removes the routes of cached ips.
+pub fn list_routes() -> Result<()> {
+    let cache = Cached::load().wrap_err("Could not load route cache")?;
+    let rejected = if device::current().is_remarkable() {
+        Some(route::rejected(&cmd::System).wrap_err("Could not read the routing table")?)
+    } else {
+        log::warn!("not running on a remarkable, not reading the routing table");
+        None
+    };
+
+    let mut ips: BTreeSet<_> = cache.entries().iter().map(|e| e.ip).collect();
+    ips.extend(rejected.iter().flatten());
+    for ip in ips {
+        let cached = match cache.entries().iter().find(|e| e.ip == ip) {
+            Some(entry) => format!("cached, updated {}", age(entry)),
+            None => "not cached".to_owned(),
+        };
+        let blocked = match &rejected {
+            Some(rejected) if rejected.contains(&ip) => "blocked",
+            Some(_) => "not blocked",
+            None => "",
+        };
+        println!("{ip:<40} {cached:<30} {blocked}");
+    }
+    Ok(())
+}
+
+/// Removes every reject route, also those that are not cached, and
+/// empties the route cache
+pub fn clear_routes(config: &Config) -> Result<()> {
+    let mut ips: BTreeSet<_> = Cached::load()?.blocked_ips().into_iter().collect();
+    if device::current().is_remarkable() {
+        ips.extend(route::rejected(&cmd::System).wrap_err("Could not read the routing table")?);
+    }
+    unblock_ips(&ips.into_iter().collect_vec(), config.into())?;
+    Cached::clear()
+}
+
+/// Caches `ip` so it is blocked while locked, `block_now` blocks it
+/// right away
+pub fn add_route(ip: IpAddr, config: &Config, block_now: bool) -> Result<()> {
+    let routes = Cached::load()?
+        .update(vec![ip], &cache::Limits::from(config))
+        .ok_or_else(|| eyre!("The route cache can not hold any ips"))
+        .suggestion("raise route_cache_max_entries in the config")?;
+    routes.cache()?;
+    unblock_ips(routes.evicted(), config.into())
+        .wrap_err("Could not unblock ips dropped from the cache")?;
+    if block_now {
+        block_ips(&[ip], config.into())?;
+    }
+    Ok(())
+}
+
+/// Forgets `ip` and removes its route if there is one
+pub fn remove_route(ip: IpAddr, config: &Config) -> Result<()> {
+    Cached::load()?.without(ip).cache()?;
+    unblock_ips(&[ip], config.into())
+}
+
+/// Number of ips in the route cache
+pub fn cached_ips() -> Result<usize> {
+    Ok(Cached::load()?.entries().len())
+}
+
+/// Number of reject routes in the routing table, whoever added them
+pub fn blocked_routes() -> Result<usize> {
+    blocked_routes_in(&cmd::System)
+}
+
+fn blocked_routes_in(cmd: &impl cmd::SysCmd) -> Result<usize> {
+    Ok(route::rejected(cmd)?.len())
+}
+
+pub fn clear_cache() -> Result<()> {
+    Cached::clear()
+}
+
+/// Blocking of newly resolved ips that is still running in the background
+#[must_use]
+pub struct Pending(Option<thread::JoinHandle<Result<()>>>);
+
+impl Pending {
+    pub fn done() -> Self {
+        Self(None)
+    }
+
+    pub fn wait(self) -> Result<()> {
+        let Some(handle) = self.0 else {
+            return Ok(());
+        };
+        log::info!("waiting for sync domains to be resolved and blocked");
+        handle
+            .join()
+            .map_err(|_| eyre!("Background sync blocking panicked"))?
+            .wrap_err("Could not block newly resolved sync ips")
+    }
+}
+
+impl SyncBlocker for Blocklist {
+    fn block(&self) -> Result<Pending> {
+        block(self)
+    }
+
+    fn unblock(&self) -> Result<()> {
+        unblock_routes(self.retry)
+    }
+}
+
+/// An unpaired device never syncs, it often never was online either so
+/// the sync domains would not resolve
+pub fn needs_blocking(device: &device::Profile) -> bool {
+    !device.is_remarkable() || device.cloud_paired()
+}
+
+/// Resolving the sync domains can take long when offline. If allowed by the
+/// list this blocks the recently cached ips then resolves in the background.
+fn block(list: &Blocklist) -> Result<Pending> {
+    if !needs_blocking(device::current()) {
+        log::info!("device is not paired with the cloud, no need to block sync");
+        return Ok(Pending::done());
+    }
     log::info!("blocking sync");
-    let to_block = update_and_get_sync_routes().wrap_err("Could not determine ip's used by sync")?;
+    if list.offline_ok {
+        let recent = Cached::load()
+            .wrap_err("Could not load files from cache file")?
+            .recent_ips(list.cache_limits.ttl);
+        if !recent.is_empty() {
+            log::info!("blocking cached ips, resolving sync domains in the background");
+            block_ips(&recent, list.retry)?;
+            let list = list.clone();
+            let handle = thread::spawn(move || block_resolved(&list));
+            return Ok(Pending(Some(handle)));
+        }
+        log::info!("no recently cached ips, resolving sync domains first");
+    }
 
-    #[cfg(target_arch = "arm")]
-    let mut attempt = 1;
-    let routes = route::table().wrap_err("Error parsing routing table")?;
-    for addr in &to_block {
-        if routes.contains(addr) {
-            continue;
+    block_resolved(list)?;
+    Ok(Pending(None))
+}
+
+fn block_resolved(list: &Blocklist) -> Result<()> {
+    let to_block =
+        update_and_get_sync_routes(list).wrap_err("Could not determine ip's used by sync")?;
+    block_ips(&to_block, list.retry)
+}
+
+/// How `route` is retried when it has no effect, as happens directly
+/// after resuming from sleep
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub attempts: u32,
+    /// wait before the first retry, doubles every retry
+    pub backoff: Duration,
+}
+
+impl From<&Config> for Retry {
+    fn from(config: &Config) -> Self {
+        Self {
+            attempts: config.route_retry_attempts.max(1),
+            backoff: Duration::from_millis(config.route_retry_backoff_ms),
         }
+    }
+}
 
-        #[cfg(not(target_arch = "arm"))]
-        log::warn!("not running on a remarkable, skipping block");
-        #[cfg(target_arch = "arm")]
-        loop {
-            match route::block(addr) {
-                Ok(()) | Err(route::Error::Exists) => break,
-                Err(route::Error::NoEffect) if attempt > 4 => {
-                    return Err(eyre!("Timed out blocking"))
-                }
-                Err(route::Error::NoEffect) => {
-                    attempt += 1;
-                    thread::sleep(Duration::from_millis(200))
-                }
-                // route was added on the previous attempt
-                Err(other) => return Err(other).wrap_err("could not block route"),
+/// Runs `op` until it succeeds or fails with an error `should_retry`
+/// rejects, at most `policy.attempts` times
+fn retry<T, E>(
+    policy: Retry,
+    should_retry: impl Fn(&E) -> bool,
+    mut op: impl FnMut() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    let mut backoff = policy.backoff;
+    for attempt in 1.. {
+        match op() {
+            Err(e) if attempt < policy.attempts && should_retry(&e) => {
+                log::debug!("attempt {attempt} had no effect, retrying in {backoff:?}");
+                thread::sleep(backoff);
+                backoff *= 2;
             }
+            res => return res,
         }
     }
+    unreachable!("returns once out of attempts")
+}
 
-    #[cfg(target_arch = "arm")]
-    log::debug!("blocked successfull in {attempt} attemp(s)",);
+fn block_ips(to_block: &[IpAddr], policy: Retry) -> Result<()> {
+    if !device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, skipping block");
+        return Ok(());
+    }
+    block_in(&route::System(cmd::System), to_block, policy)
+}
+
+fn block_in(table: &impl RouteTable, to_block: &[IpAddr], policy: Retry) -> Result<()> {
+    let routes = table.routes().wrap_err("Error parsing routing table")?;
+    for addr in to_block {
+        if routes.contains(addr) {
+            continue;
+        }
+        let res = retry(
+            policy,
+            |e| matches!(e, route::Error::NoEffect),
+            || route::block(table, addr),
+        );
+        match res {
+            Ok(()) => (),
+            Err(route::Error::NoEffect) => return Err(eyre!("Timed out blocking: {addr}")),
+            Err(other) => return Err(other).wrap_err("could not block route"),
+        }
+    }
     Ok(())
 }
 
-/// directly after resuming from sleep the `route` tool does not seem to work
-/// therefore this retries `route` a few times
-pub fn unblock() -> Result<()> {
+/// Whether `unblock` has anything to undo
+pub fn blocked() -> Result<bool> {
+    if wifi::turned_off() || services::any_stopped()? {
+        return Ok(true);
+    }
+    if !device::current().is_remarkable() {
+        return Ok(false);
+    }
+    blocked_in(&route::System(cmd::System))
+}
+
+/// Whether one of the cached ips has a route in `table`
+fn blocked_in(table: &impl RouteTable) -> Result<bool> {
+    let cached = Cached::load().wrap_err("Could not retrieve blocked routes from file")?;
+    let routes = table.routes().wrap_err("Error parsing routing table")?;
+    Ok(cached.blocked_ips().iter().any(|ip| routes.contains(ip)))
+}
+
+/// Undoes blocking by any of the backends
+pub fn unblock(config: &Config) -> Result<()> {
     log::info!("unblocking sync");
+    unblock_routes(config.into())?;
+    Wifi::new(&config.wifi_interface).unblock()?;
+    services::restore()
+}
+
+fn unblock_routes(policy: Retry) -> Result<()> {
     let to_unblock = Cached::load().wrap_err("Could not retrieve blocked routes from file")?;
+    unblock_ips(&to_unblock.blocked_ips(), policy)
+}
 
-    #[cfg(target_arch = "arm")]
-    let mut attempt = 1;
-    let routes = route::table().wrap_err("Error parsing routing table")?;
-    for addr in &to_unblock.blocked_ips() {
+fn unblock_ips(to_unblock: &[IpAddr], policy: Retry) -> Result<()> {
+    if !device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, skipping unblock");
+        return Ok(());
+    }
+    unblock_in(&route::System(cmd::System), to_unblock, policy)
+}
+
+fn unblock_in(table: &impl RouteTable, to_unblock: &[IpAddr], policy: Retry) -> Result<()> {
+    let routes = table.routes().wrap_err("Error parsing routing table")?;
+    for addr in to_unblock {
         if !routes.contains(addr) {
             continue;
         }
-
-        #[cfg(not(target_arch = "arm"))]
-        log::warn!("not running on a remarkable, skipping unblock");
-        #[cfg(target_arch = "arm")]
-        loop {
-            match route::unblock(addr) {
-                Ok(()) | Err(route::Error::NotFound) => break,
-                Err(route::Error::NoEffect) if attempt > 4 => {
-                    return Err(eyre!("Timed out unblocking"))
-                }
-                Err(route::Error::NoEffect) => {
-                    attempt += 1;
-                    thread::sleep(Duration::from_millis(200))
-                }
-                Err(other) => return Err(other).wrap_err("could not unblock route"),
-            }
+        let res = retry(
+            policy,
+            |e| matches!(e, route::Error::NoEffect),
+            || route::unblock(table, addr),
+        );
+        match res {
+            Ok(()) => (),
+            Err(route::Error::NoEffect) => return Err(eyre!("Timed out unblocking: {addr}")),
+            Err(other) => return Err(other).wrap_err("could not unblock route"),
         }
     }
-
-    #[cfg(target_arch = "arm")]
-    log::debug!("unblocked successfull in {attempt} attemp(s)",);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    const RETRY: Retry = Retry {
+        attempts: 3,
+        backoff: Duration::ZERO,
+    };
+
+    #[test]
+    fn retries_until_done() {
+        let mut results = vec![Ok(()), Err(route::Error::NoEffect)];
+        let res = retry(
+            RETRY,
+            |e| matches!(e, route::Error::NoEffect),
+            || results.pop().unwrap(),
+        );
+        assert!(res.is_ok() && results.is_empty());
+    }
+
+    #[test]
+    fn blocked_by_cached_routes() {
+        let root = device::simulate_in_temp_dir("blocked-routes");
+        let table = |route_n: &str| {
+            let cmd = cmd::Scripted::default()
+                .expect("route -n", 0, route_n, "")
+                .expect("ip -6 route show", 0, "", "");
+            blocked_in(&route::System(cmd))
+        };
+        let route_n = "Kernel IP routing table
+Destination     Gateway         Genmask         Flags Metric Ref    Use Iface
+0.0.0.0         10.0.0.1        0.0.0.0         UG    0      0        0 wlan0
+34.117.137.206  -               255.255.255.255 !H    0      -        0 -
+";
+        assert!(!table(route_n).unwrap(), "nothing cached");
+
+        let limits = cache::Limits::from(&Config::default());
+        let ip = IpAddr::from([34, 117, 137, 206]);
+        Cached::load()
+            .unwrap()
+            .update(vec![ip], &limits)
+            .unwrap()
+            .cache()
+            .unwrap();
+        assert!(table(route_n).unwrap());
+        let default_only = route_n.lines().take(3).join("\n");
+        assert!(!table(&default_only).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn only_reject_routes_count() {
+        let route_n = "Kernel IP routing table
+Destination     Gateway         Genmask         Flags Metric Ref    Use Iface
+0.0.0.0         10.0.0.1        0.0.0.0         UG    0      0        0 wlan0
+";
+        let cmd = cmd::Scripted::default()
+            .expect("route -n", 0, route_n, "")
+            .expect("ip -6 route show", 0, "default via fe80::1 dev wlan0\n", "");
+        assert_eq!(blocked_routes_in(&cmd).unwrap(), 0);
+    }
+
+    #[test]
+    fn blocking_is_idempotent() {
+        let ips = [IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])];
+        let table = route::MockTable::default();
+        table.routes.borrow_mut().insert(ips[0]);
+        table.ignored.set(2);
+        block_in(&table, &ips, RETRY).unwrap();
+        block_in(&table, &ips, RETRY).unwrap();
+        assert_eq!(table.routes.borrow().len(), 2);
+
+        table.ignored.set(1);
+        unblock_in(&table, &ips, RETRY).unwrap();
+        unblock_in(&table, &ips, RETRY).unwrap();
+        assert!(table.routes.borrow().is_empty());
+
+        table.ignored.set(3);
+        assert!(block_in(&table, &ips, RETRY).is_err(), "out of attempts");
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut calls = 0;
+        let res: std::result::Result<(), _> = retry(
+            RETRY,
+            |e| matches!(e, route::Error::NoEffect),
+            || {
+                calls += 1;
+                Err(route::Error::NoEffect)
+            },
+        );
+        assert!(matches!(res, Err(route::Error::NoEffect)));
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        let res: std::result::Result<(), _> = retry(
+            RETRY,
+            |e| matches!(e, route::Error::NoEffect),
+            || {
+                calls += 1;
+                Err(route::Error::Exists)
+            },
+        );
+        assert!(matches!(res, Err(route::Error::Exists)));
+        assert_eq!(calls, 1, "only retries errors it is told to");
+    }
+
+    #[test]
+    fn sync_only_keeps_hwr() {
+        let config = Config::default();
+        let domains = backends(&config, &[], Scope::SyncOnly);
+        assert!(!domains.iter().any(|d| d.starts_with("hwr-production")));
+        assert!(domains.iter().any(|d| d == "my.remarkable.com"));
+
+        let domains = backends(&config, &[], Scope::All);
+        assert!(domains.iter().any(|d| d.starts_with("hwr-production")));
+    }
+}
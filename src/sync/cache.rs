@@ -1,17 +1,97 @@
 use color_eyre::Help;
-use color_eyre::{eyre::WrapErr, Result};
-use std::io::{BufReader, BufWriter};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use std::{fs, net::IpAddr};
 
 use serde::{Deserialize, Serialize};
 
-const EXPIRATION: Duration = Duration::from_secs(60 * 60 * 24 * 7 * 4 * 2);
+use crate::config::Config;
+use crate::util::AcceptErr;
+
+/// older versions kept the cache in the working directory
+const LEGACY_PATH: &str = "routes.json";
+/// increment on incompatible changes to the file format
+const VERSION: u32 = 1;
+
+pub fn path() -> PathBuf {
+    crate::state_dir().join("routes.json")
+}
+
+fn migrate_legacy(path: &Path) {
+    let legacy = Path::new(LEGACY_PATH);
+    if path.exists() || !legacy.is_file() {
+        return;
+    }
+    log::info!("moving route cache to: {}", path.display());
+    if let Err(e) = fs::copy(legacy, path).and_then(|_| fs::remove_file(legacy)) {
+        log::warn!("could not move route cache from working directory: {e}");
+    }
+}
+
+#[derive(Clone)]
+pub struct Limits {
+    /// entries older then this are removed if there are enough recent ones
+    pub ttl: Duration,
+    /// the oldest entries are removed if there are more then this
+    pub max_entries: usize,
+}
+
+impl From<&Config> for Limits {
+    fn from(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_hours(24 * config.route_cache_ttl_days),
+            max_entries: config.route_cache_max_entries,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Entry {
-    ip: IpAddr,
-    last_updated: SystemTime,
+    pub ip: IpAddr,
+    pub last_updated: SystemTime,
+}
+
+#[derive(Serialize)]
+struct OnDisk<'a> {
+    version: u32,
+    entries: &'a [Entry],
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Versioned {
+        version: u32,
+        entries: Vec<Entry>,
+    },
+    /// before versioning the file was a bare list of entries
+    Legacy(Vec<Entry>),
+}
+
+/// Only parses the first json value as files written before versioning
+/// could have trailing garbage: they were not truncated before writing.
+fn parse(json: &str) -> Result<Vec<Entry>> {
+    let stored = serde_json::Deserializer::from_str(json)
+        .into_iter::<Stored>()
+        .next()
+        .ok_or_else(|| eyre!("file is empty"))??;
+
+    match stored {
+        Stored::Versioned { version, entries } if version == VERSION => Ok(entries),
+        Stored::Versioned { version, .. } => Err(eyre!(
+            "unsupported route cache version: {version}, expected: {VERSION}"
+        ))
+        .note("the cache was probably written by a newer version of book-safe"),
+        Stored::Legacy(entries) => {
+            log::info!("migrating route cache to versioned format");
+            Ok(entries)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -21,39 +101,72 @@ pub struct Cached(Vec<Entry>);
 
 impl Cached {
     pub fn load() -> Result<Self> {
-        let f = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("routes.json")?;
+        let path = path();
+        fs::create_dir_all(path.parent().expect("path is in the state dir"))
+            .wrap_err("Could not create state directory")?;
+        migrate_legacy(&path);
+
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Cached(Vec::new())),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Could not read: {}", path.display()))
+            }
+        };
 
-        if f.metadata()?.len() == 0 {
+        if json.trim().is_empty() {
             return Ok(Cached(Vec::new()));
         }
 
-        let r = BufReader::new(f);
-        let entries = serde_json::from_reader(r)
+        let entries = parse(&json)
             .wrap_err("could not parse adress in file")
-            .suggestion("try removing `routes.json`")
+            .with_suggestion(|| format!("try removing `{}`", path.display()))
             .note("if that does not work please open an issue")?;
         Ok(Cached(entries))
     }
 
+    pub fn clear() -> Result<()> {
+        fs::remove_file(path())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not remove route cache")
+    }
+
+    #[must_use]
+    pub fn entries(&self) -> &[Entry] {
+        &self.0
+    }
+
     #[must_use]
-    fn n_recent(&self) -> usize {
+    fn n_recent(&self, ttl: Duration) -> usize {
         self.0
             .iter()
-            .filter(|e| e.last_updated.elapsed().unwrap_or(Duration::ZERO) < EXPIRATION)
+            .filter(|e| e.last_updated.elapsed().unwrap_or(Duration::ZERO) < ttl)
             .count()
     }
 
+    #[must_use]
+    pub fn recent_ips(&self, ttl: Duration) -> Vec<IpAddr> {
+        self.0
+            .iter()
+            .filter(|e| e.last_updated.elapsed().unwrap_or(Duration::ZERO) < ttl)
+            .map(|e| e.ip)
+            .collect()
+    }
+
     #[must_use]
     pub fn blocked_ips(self) -> Vec<IpAddr> {
         self.0.into_iter().map(|e| e.ip).collect()
     }
 
+    /// Forgets `ip`, for example as it is no longer used by sync
     #[must_use]
-    pub fn update(mut self, new: Vec<IpAddr>) -> Option<UpToDateRoutes> {
+    pub fn without(mut self, ip: IpAddr) -> UpToDateRoutes {
+        self.0.retain(|e| e.ip != ip);
+        UpToDateRoutes(self.0, Vec::new())
+    }
+
+    #[must_use]
+    pub fn update(mut self, new: Vec<IpAddr>, limits: &Limits) -> Option<UpToDateRoutes> {
         self.0.extend(new.into_iter().map(|ip| Entry {
             ip,
             last_updated: SystemTime::now(),
@@ -63,38 +176,59 @@ impl Cached {
         if self.0.is_empty() {
             return None;
         }
-
-        if self.n_recent() < 2 {
-            return Some(UpToDateRoutes(self.0));
+        let before: Vec<_> = self.0.iter().map(|e| e.ip).collect();
+
+        // only expire entries if there are enough recent ones to replace them
+        if self.n_recent(limits.ttl) >= 2 {
+            self.0.retain(|e| {
+                e.last_updated
+                    .elapsed()
+                    .map_or(true, |age| age <= limits.ttl)
+            });
         }
 
-        /* TODO: replace with drain_filter once stabelized <16-02-23, dvdsk> */
-        let mut i = 0;
-        while i < self.0.len() {
-            if let Ok(age) = self.0[i].last_updated.elapsed() {
-                if age > EXPIRATION {
-                    self.0.remove(i);
-                    continue;
-                }
-            }
-            i += 1;
+        if self.0.len() > limits.max_entries {
+            self.0.sort_unstable_by_key(|e| e.last_updated);
+            let n_removed = self.0.len() - limits.max_entries;
+            self.0.drain(..n_removed);
         }
 
-        Some(UpToDateRoutes(self.0))
+        let evicted = before
+            .into_iter()
+            .filter(|ip| self.0.iter().all(|e| e.ip != *ip))
+            .collect();
+        Some(UpToDateRoutes(self.0, evicted))
     }
 }
 
-pub struct UpToDateRoutes(Vec<Entry>);
+/// The cache entries and the ips that expired or were evicted from it
+pub struct UpToDateRoutes(Vec<Entry>, Vec<IpAddr>);
 
 impl UpToDateRoutes {
+    /// Ips no longer cached, their routes are not removed by unblocking
+    /// so they should be removed now
+    #[must_use]
+    pub fn evicted(&self) -> &[IpAddr] {
+        &self.1
+    }
+
+    /// writes to a temporary file first then moves that over
+    /// the cache, this way the cache is never partially written
     pub fn cache(&self) -> Result<()> {
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open("routes.json")?;
-        let w = BufWriter::new(f);
-        serde_json::to_writer_pretty(w, &self.0)?;
-        Ok(())
+        let path = path();
+        let tmp = path.with_extension("json.tmp");
+
+        let f = fs::File::create(&tmp).wrap_err("Could not create temporary file")?;
+        let mut w = BufWriter::new(f);
+        let on_disk = OnDisk {
+            version: VERSION,
+            entries: &self.0,
+        };
+        serde_json::to_writer_pretty(&mut w, &on_disk)?;
+        w.flush()?;
+        w.get_ref().sync_all()?;
+
+        fs::rename(&tmp, &path).wrap_err("Could not move temporary file over cache")
     }
 
     #[must_use]
@@ -119,6 +253,11 @@ mod tests {
 
     use super::*;
 
+    const LIMITS: Limits = Limits {
+        ttl: Duration::from_hours(24 * 7 * 8),
+        max_entries: 100,
+    };
+
     fn old_entry(ip: u8, age: u64) -> Entry {
         Entry {
             ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, ip)),
@@ -138,8 +277,8 @@ mod tests {
 
         #[test]
         fn oldest_removed() {
-            let mut list: Vec<_> = (0..10).into_iter().map(|i| old_entry(i, 0)).collect();
-            let new = (0..10).into_iter().map(|i| old_entry(i, i as u64 + 100));
+            let mut list: Vec<_> = (0..10).map(|i| old_entry(i, 0)).collect();
+            let new = (0..10).map(|i| old_entry(i, u64::from(i) + 100));
             list.extend(new);
 
             dedup_keep_newest(&mut list);
@@ -165,10 +304,10 @@ mod tests {
 
         #[test]
         fn do_not_remove_recent() {
-            let list: Vec<_> = (0..10).into_iter().map(|i| recent_entry(i)).collect();
+            let list: Vec<_> = (0..10).map(recent_entry).collect();
             let cache = Cached(list);
 
-            let cache = cache.update(Vec::new()).unwrap();
+            let cache = cache.update(Vec::new(), &LIMITS).unwrap();
             assert_eq!(cache.0.len(), 10);
         }
     }
@@ -179,14 +318,15 @@ mod tests {
         #[test]
         fn remove_old() {
             let list: Vec<_> = (0..5)
-                .map(|i| recent_entry(i))
+                .map(recent_entry)
                 .chain((5..10).map(|i| old_entry(i, 0)))
                 .collect();
 
             let cache = Cached(list);
 
-            let cache = cache.update(Vec::new()).unwrap();
+            let cache = cache.update(Vec::new(), &LIMITS).unwrap();
             assert_eq!(cache.0.len(), 5);
+            assert_eq!(cache.evicted().len(), 5);
         }
     }
 
@@ -196,14 +336,102 @@ mod tests {
         #[test]
         fn keep_old() {
             let list: Vec<_> = (0..5)
-                .map(|i| recent_entry(i))
+                .map(recent_entry)
                 .chain((5..10).map(|i| old_entry(i, 0)))
                 .collect();
 
             let cache = Cached(list);
 
-            let cache = cache.update(Vec::new()).unwrap();
+            let cache = cache.update(Vec::new(), &LIMITS).unwrap();
             assert_eq!(cache.0.len(), 5);
         }
     }
+
+    mod too_many_entries {
+        use super::*;
+
+        #[test]
+        fn oldest_removed() {
+            let list: Vec<_> = (0..10).map(|i| old_entry(i, u64::from(i))).collect();
+            let cache = Cached(list);
+
+            let limits = Limits {
+                max_entries: 4,
+                ..LIMITS
+            };
+            let cache = cache.update(Vec::new(), &limits).unwrap();
+            let mut evicted = cache.evicted().to_vec();
+            evicted.sort();
+            assert_eq!(
+                evicted,
+                (0..6)
+                    .map(|i| IpAddr::V4(Ipv4Addr::new(0, 0, 0, i)))
+                    .collect::<Vec<_>>()
+            );
+            let ips: Vec<_> = cache.into_ips();
+            assert_eq!(
+                ips,
+                (6..10)
+                    .map(|i| IpAddr::V4(Ipv4Addr::new(0, 0, 0, i)))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    mod format {
+        use super::*;
+
+        #[test]
+        fn roundtrip() {
+            let entries = vec![recent_entry(1), old_entry(2, 5)];
+            let on_disk = OnDisk {
+                version: VERSION,
+                entries: &entries,
+            };
+            let json = serde_json::to_string(&on_disk).unwrap();
+            let parsed = parse(&json).unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed[1].last_updated, entries[1].last_updated);
+        }
+
+        #[test]
+        fn legacy() {
+            let entries = vec![old_entry(1, 5)];
+            let json = serde_json::to_string(&entries).unwrap();
+            assert_eq!(parse(&json).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn legacy_with_trailing_garbage() {
+            let entries = vec![old_entry(1, 5)];
+            let json = serde_json::to_string_pretty(&entries).unwrap();
+            let json = format!("{json}\n  }},\n  {{\n    \"ip\": \"0.0.0.9\"");
+            assert_eq!(parse(&json).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn newer_version() {
+            let json = r#"{"version": 9000, "entries": []}"#;
+            assert!(parse(json).is_err());
+        }
+    }
+
+    #[test]
+    fn stored_in_state_dir() {
+        let root = crate::device::simulate_in_temp_dir("route-cache");
+        let ips = vec![recent_entry(1).ip, recent_entry(2).ip];
+        let routes = Cached::load()
+            .unwrap()
+            .update(ips.clone(), &LIMITS)
+            .unwrap();
+        routes.cache().unwrap();
+        assert!(path().starts_with(&root));
+
+        let mut loaded = Cached::load().unwrap().blocked_ips();
+        loaded.sort();
+        assert_eq!(loaded, ips);
+        Cached::clear().unwrap();
+        assert!(Cached::load().unwrap().entries().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
 }
@@ -0,0 +1,182 @@
+//! Find the addresses xochitl actually uses for the cloud. The static list
+//! of domains lags behind firmware updates and does not cover self hosted
+//! clouds (rmfakecloud), which are set up by overriding `/etc/hosts`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use color_eyre::{eyre::WrapErr, Result};
+
+/// Addresses that `/etc/hosts` maps any of the domains to and, if
+/// `include_live` is set, those xochitl currently has a connection open
+/// to. Must be called while xochitl is running.
+pub fn endpoints(domains: &[String], include_live: bool) -> Vec<IpAddr> {
+    let mut found = Vec::new();
+
+    match fs::read_to_string("/etc/hosts") {
+        Ok(hosts) => found.extend(hosts_overrides(&hosts, domains)),
+        Err(e) => log::debug!("could not read /etc/hosts: {e}"),
+    }
+
+    if include_live {
+        match xochitl_connections() {
+            Ok(ips) => found.extend(ips),
+            Err(e) => log::warn!("could not discover xochitl connections: {e:?}"),
+        }
+    }
+
+    found.sort_unstable();
+    found.dedup();
+    log::debug!("discovered sync endpoints: {found:?}");
+    found
+}
+
+fn hosts_overrides(hosts: &str, domains: &[String]) -> Vec<IpAddr> {
+    hosts
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let ip = fields.next()?.parse().ok()?;
+            fields
+                .any(|name| domains.iter().any(|d| d == name))
+                .then_some(ip)
+        })
+        .collect()
+}
+
+fn xochitl_connections() -> Result<Vec<IpAddr>> {
+    let mut inodes = HashSet::new();
+    for pid in pids_named("xochitl").wrap_err("Could not list processes")? {
+        let fd_dir = Path::new("/proc").join(&pid).join("fd");
+        let Ok(fds) = fs::read_dir(&fd_dir) else {
+            continue; // process exited
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            if let Some(inode) = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+            {
+                inodes.insert(inode.to_owned());
+            }
+        }
+    }
+
+    let mut ips = Vec::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(table) = fs::read_to_string(table) else {
+            continue; // no ipv6 support
+        };
+        ips.extend(
+            table
+                .lines()
+                .skip(1)
+                .filter_map(parse_tcp_line)
+                .filter(|conn| conn.established && inodes.contains(conn.inode))
+                .map(|conn| conn.remote)
+                .filter(|ip| !ip.is_loopback() && !ip.is_unspecified()),
+        );
+    }
+    Ok(ips)
+}
+
+fn pids_named(name: &str) -> Result<Vec<String>> {
+    let mut pids = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(comm) = fs::read_to_string(entry.path().join("comm")) else {
+            continue;
+        };
+        if comm.trim_end() == name {
+            pids.push(pid);
+        }
+    }
+    Ok(pids)
+}
+
+struct Connection<'a> {
+    remote: IpAddr,
+    established: bool,
+    inode: &'a str,
+}
+
+/// parse a line from `/proc/net/tcp` or `/proc/net/tcp6`
+fn parse_tcp_line(line: &str) -> Option<Connection<'_>> {
+    const ESTABLISHED: &str = "01";
+
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let (remote, _port) = fields.get(2)?.split_once(':')?;
+    Some(Connection {
+        remote: parse_proc_addr(remote)?,
+        established: *fields.get(3)? == ESTABLISHED,
+        inode: fields.get(9)?,
+    })
+}
+
+/// the kernel prints addresses as 32 bit words in host byte order
+fn parse_proc_addr(hex: &str) -> Option<IpAddr> {
+    let word = |i: usize| -> Option<[u8; 4]> {
+        let word = hex.get(i * 8..(i + 1) * 8)?;
+        Some(u32::from_str_radix(word, 16).ok()?.to_ne_bytes())
+    };
+
+    match hex.len() {
+        8 => Some(IpAddr::V4(Ipv4Addr::from(word(0)?))),
+        32 => {
+            let mut octets = [0u8; 16];
+            for i in 0..4 {
+                octets[i * 4..(i + 1) * 4].copy_from_slice(&word(i)?);
+            }
+            let ip = Ipv6Addr::from(octets);
+            Some(match ip.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(ip),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hosts_file() {
+        let hosts = "127.0.0.1 localhost\n\
+            # 10.0.0.1 my.remarkable.com\n\
+            192.168.1.5 my.remarkable.com internal.cloud.remarkable.com # rmfakecloud\n";
+        let domains = vec!["my.remarkable.com".to_owned()];
+        assert_eq!(
+            hosts_overrides(hosts, &domains),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5))]
+        );
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn tcp_table_line() {
+        let line = "   3: 0B00A8C0:D2F4 2275C922:01BB 01 00000000:00000000 02:000A2E5F 00000000     0        0 21436 2 00000000 20 4 30 10 -1";
+        let conn = parse_tcp_line(line).unwrap();
+        assert_eq!(conn.remote, IpAddr::V4(Ipv4Addr::new(34, 201, 117, 34)));
+        assert!(conn.established);
+        assert_eq!(conn.inode, "21436");
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn tcp6_mapped_v4() {
+        let addr = parse_proc_addr("0000000000000000FFFF00002275C922").unwrap();
+        assert_eq!(addr, IpAddr::V4(Ipv4Addr::new(34, 201, 117, 34)));
+    }
+}
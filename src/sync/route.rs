@@ -1,16 +1,14 @@
-#[cfg(target_arch = "arm")]
 use color_eyre::{eyre, Help, SectionExt};
 use color_eyre::{eyre::WrapErr, Result};
 
-use std::process::Command;
-#[cfg(target_arch = "arm")]
 use std::process::Output;
 
 use std::{collections::HashSet, net::IpAddr, str::FromStr};
 
-#[cfg(target_arch = "arm")]
+use crate::util::cmd::SysCmd;
+
 fn handle_any_error(
-    output: Output,
+    output: &Output,
     address: &IpAddr,
     text: &'static str,
 ) -> std::result::Result<(), Error> {
@@ -20,11 +18,13 @@ fn handle_any_error(
     let stderr = String::from_utf8_lossy(&output.stderr);
     let stdout = String::from_utf8_lossy(&output.stdout);
 
-    if stderr == "route: SIOCADDRT: File exists\n" {
+    if stderr == "route: SIOCADDRT: File exists\n" || stderr == "RTNETLINK answers: File exists\n" {
         return Err(Error::Exists);
     }
 
-    if stderr == "route: SIOCDELRT: No such process\n" {
+    if stderr == "route: SIOCDELRT: No such process\n"
+        || stderr == "RTNETLINK answers: No such process\n"
+    {
         return Err(Error::NotFound);
     }
 
@@ -36,23 +36,53 @@ fn handle_any_error(
     ))
 }
 
-#[cfg(target_arch = "arm")]
-pub fn block(address: &IpAddr) -> std::result::Result<(), Error> {
-    log::debug!("blocking: {address}");
-    let output = Command::new("route")
-        .arg("add")
-        .arg("-host")
-        .arg(address.to_string())
-        .arg("reject")
-        .output()
-        .map_err(Error::Start)?;
-    handle_any_error(output, address, "Command route add returned an error")
+/// `route` only handles ipv4 on the remarkable, ipv6 routes
+/// are managed using `ip`
+fn route_cmd(
+    cmd: &impl SysCmd,
+    action: &str,
+    address: &IpAddr,
+) -> std::result::Result<Output, Error> {
+    let output = match address {
+        IpAddr::V4(_) => cmd.output("route", &[action, "-host", &address.to_string(), "reject"]),
+        IpAddr::V6(_) => cmd.output(
+            "ip",
+            &["-6", "route", action, "prohibit", &format!("{address}/128")],
+        ),
+    };
+    output.map_err(Error::Start)
+}
+
+/// Where routes are added and removed, the system's routing table
+/// or a mock in tests
+pub trait RouteTable {
+    fn add(&self, address: &IpAddr) -> std::result::Result<(), Error>;
+    fn delete(&self, address: &IpAddr) -> std::result::Result<(), Error>;
+    fn routes(&self) -> Result<HashSet<IpAddr>>;
+}
+
+/// Changes routes using `route` and `ip`
+pub struct System<C>(pub C);
+
+impl<C: SysCmd> RouteTable for System<C> {
+    fn add(&self, address: &IpAddr) -> std::result::Result<(), Error> {
+        let output = route_cmd(&self.0, "add", address)?;
+        handle_any_error(&output, address, "Command route add returned an error")
+    }
+
+    fn delete(&self, address: &IpAddr) -> std::result::Result<(), Error> {
+        let output = route_cmd(&self.0, "delete", address)?;
+        handle_any_error(&output, address, "Command route delete returned an error")
+    }
+
+    fn routes(&self) -> Result<HashSet<IpAddr>> {
+        table(&self.0)
+    }
 }
 
-#[cfg(target_arch = "arm")]
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("could not run route program")]
+    #[error("could not run route or ip program")]
     Start(std::io::Error),
     #[error("route run into an error while running: {0:?}")]
     Run(eyre::Report),
@@ -66,19 +96,36 @@ pub enum Error {
     NotFound,
 }
 
-#[cfg(target_arch = "arm")]
-pub fn unblock(address: &IpAddr) -> std::result::Result<(), Error> {
+/// Adds a reject route for `address`, a route that is already there is
+/// fine
+pub fn block(table: &impl RouteTable, address: &IpAddr) -> std::result::Result<(), Error> {
+    log::debug!("blocking: {address}");
+    match table.add(address) {
+        Ok(()) | Err(Error::Exists) => (),
+        Err(e) => return Err(e),
+    }
+
+    let routes = table
+        .routes()
+        .wrap_err("Error parsing routing table")
+        .map_err(Error::Verifying)?;
+    if routes.contains(address) {
+        Ok(())
+    } else {
+        Err(Error::NoEffect)
+    }
+}
+
+/// Removes the route for `address`, a route that is already gone is fine
+pub fn unblock(table: &impl RouteTable, address: &IpAddr) -> std::result::Result<(), Error> {
     log::debug!("unblocking: {address}");
-    let output = Command::new("route")
-        .arg("delete")
-        .arg("-host")
-        .arg(address.to_string())
-        .arg("reject")
-        .output()
-        .map_err(Error::Start)?;
-    handle_any_error(output, address, "Command route delete returned an error")?;
-
-    let routes = table()
+    match table.delete(address) {
+        Ok(()) | Err(Error::NotFound) => (),
+        Err(e) => return Err(e),
+    }
+
+    let routes = table
+        .routes()
         .wrap_err("Error parsing routing table")
         .map_err(Error::Verifying)?;
     if routes.contains(address) {
@@ -88,19 +135,231 @@ pub fn unblock(address: &IpAddr) -> std::result::Result<(), Error> {
     }
 }
 
-pub fn table() -> Result<HashSet<IpAddr>> {
-    let output = Command::new("route")
-        .arg("-n")
-        .output()
+pub fn table(cmd: &impl SysCmd) -> Result<HashSet<IpAddr>> {
+    let output = cmd
+        .output("route", &["-n"])
         .wrap_err("Could not run route")?;
-
     let output = String::from_utf8_lossy(&output.stdout);
-    let routes: Result<HashSet<IpAddr>, _> = output
+    let mut routes = parse_v4_table(&output).wrap_err("Could not parse routing table entries")?;
+
+    match cmd.output("ip", &["-6", "route", "show"]) {
+        Ok(output) if output.status.success() => {
+            let output = String::from_utf8_lossy(&output.stdout);
+            routes.extend(parse_v6_table(&output));
+        }
+        Ok(output) => log::warn!(
+            "could not list ipv6 routes: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => log::warn!("could not run ip to list ipv6 routes: {e}"),
+    }
+
+    log::debug!("parsed routes: {routes:?}");
+    Ok(routes)
+}
+
+/// Destinations of reject routes, whoever added them
+pub fn rejected(cmd: &impl SysCmd) -> Result<HashSet<IpAddr>> {
+    let output = cmd
+        .output("route", &["-n"])
+        .wrap_err("Could not run route")?;
+    let mut routes = rejected_v4(&String::from_utf8_lossy(&output.stdout));
+    let output = cmd
+        .output("ip", &["-6", "route", "show"])
+        .wrap_err("Could not run ip to list ipv6 routes")?;
+    routes.extend(rejected_v6(&String::from_utf8_lossy(&output.stdout)));
+    Ok(routes)
+}
+
+/// parses the output of `route -n`, reject routes have a `!` flag
+fn rejected_v4(output: &str) -> HashSet<IpAddr> {
+    output
         .lines()
         .skip(2)
-        .map(|f| f.split_once(' ').unwrap().0)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let flags = fields.get(3)?;
+            flags
+                .contains('!')
+                .then(|| IpAddr::from_str(fields[0]).ok())?
+        })
+        .collect()
+}
+
+/// parses the output of `route -n`
+fn parse_v4_table(output: &str) -> Result<HashSet<IpAddr>, std::net::AddrParseError> {
+    output
+        .lines()
+        .skip(2)
+        .filter_map(|f| f.split_whitespace().next())
         .map(IpAddr::from_str)
-        .collect();
-    log::debug!("parsed routes: {routes:?}");
-    routes.wrap_err("Could not parse routing table entries")
+        .collect()
+}
+
+/// `ip` route types that drop traffic
+const ROUTE_TYPES: [&str; 4] = ["unreachable", "prohibit", "blackhole", "throw"];
+
+/// parses the output of `ip -6 route show`, entries that are not
+/// for a single host are skipped
+fn parse_v6_table(output: &str) -> HashSet<IpAddr> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let first = fields.next()?;
+            let dest = if ROUTE_TYPES.contains(&first) {
+                fields.next()?
+            } else {
+                first
+            };
+            let dest = dest.strip_suffix("/128").unwrap_or(dest);
+            IpAddr::from_str(dest).ok()
+        })
+        .collect()
+}
+
+/// parses the output of `ip -6 route show`, only host routes that
+/// drop traffic
+fn rejected_v6(output: &str) -> HashSet<IpAddr> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            if !ROUTE_TYPES.contains(&fields.next()?) {
+                return None;
+            }
+            let dest = fields.next()?;
+            IpAddr::from_str(dest.strip_suffix("/128").unwrap_or(dest)).ok()
+        })
+        .collect()
+}
+
+/// Routing table in memory, changes only stick once `ignored` calls
+/// had no effect, like `route` right after resume
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockTable {
+    pub routes: std::cell::RefCell<HashSet<IpAddr>>,
+    pub ignored: std::cell::Cell<u32>,
+}
+
+#[cfg(test)]
+impl MockTable {
+    fn ignore(&self) -> bool {
+        let ignored = self.ignored.get();
+        self.ignored.set(ignored.saturating_sub(1));
+        ignored > 0
+    }
+}
+
+#[cfg(test)]
+impl RouteTable for MockTable {
+    fn add(&self, address: &IpAddr) -> std::result::Result<(), Error> {
+        if self.ignore() {
+            return Ok(());
+        }
+        if self.routes.borrow_mut().insert(*address) {
+            Ok(())
+        } else {
+            Err(Error::Exists)
+        }
+    }
+
+    fn delete(&self, address: &IpAddr) -> std::result::Result<(), Error> {
+        if self.ignore() {
+            return Ok(());
+        }
+        if self.routes.borrow_mut().remove(address) {
+            Ok(())
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    fn routes(&self) -> Result<HashSet<IpAddr>> {
+        Ok(self.routes.borrow().clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::cmd::Scripted;
+
+    const ROUTE_N: &str = "Kernel IP routing table
+Destination     Gateway         Genmask         Flags Metric Ref    Use Iface
+34.117.137.206  -               255.255.255.255 !H    0      -        0 -
+";
+
+    #[test]
+    fn blocks_with_route() {
+        let ip = IpAddr::from([34, 117, 137, 206]);
+        let add = "route add -host 34.117.137.206 reject";
+        let cmd = Scripted::default()
+            .expect(add, 7, "", "route: SIOCADDRT: File exists\n")
+            .expect("route -n", 0, ROUTE_N, "")
+            .expect("ip -6 route show", 0, "", "")
+            .expect(add, 7, "", "route: SIOCADDRT: Operation not permitted\n");
+        let table = System(cmd);
+        block(&table, &ip).unwrap();
+        assert!(matches!(block(&table, &ip), Err(Error::Run(_))));
+    }
+
+    #[test]
+    fn unblocks_v6_with_ip() {
+        let ip: IpAddr = "2a00:1450:400e:80c::2014".parse().unwrap();
+        let cmd = Scripted::default()
+            .expect(
+                "ip -6 route delete prohibit 2a00:1450:400e:80c::2014/128",
+                2,
+                "",
+                "RTNETLINK answers: No such process\n",
+            )
+            .expect("route -n", 0, ROUTE_N, "")
+            .missing("ip -6 route show");
+        unblock(&System(cmd), &ip).unwrap();
+    }
+
+    #[test]
+    fn idempotent() {
+        let table = MockTable::default();
+        let ip = IpAddr::from([34, 117, 137, 206]);
+        block(&table, &ip).unwrap();
+        block(&table, &ip).unwrap();
+        assert!(table.routes.borrow().contains(&ip));
+        unblock(&table, &ip).unwrap();
+        unblock(&table, &ip).unwrap();
+        assert!(table.routes.borrow().is_empty());
+
+        table.ignored.set(1);
+        assert!(matches!(block(&table, &ip), Err(Error::NoEffect)));
+    }
+
+    #[test]
+    fn v4_table() {
+        let output = "Kernel IP routing table
+Destination     Gateway         Genmask         Flags Metric Ref    Use Iface
+0.0.0.0         10.0.0.1        0.0.0.0         UG    0      0        0 wlan0
+34.117.137.206  -               255.255.255.255 !H    0      -        0 -
+";
+        let routes = parse_v4_table(output).unwrap();
+        assert!(routes.contains(&IpAddr::from([34, 117, 137, 206])));
+        assert_eq!(routes.len(), 2);
+        let rejected = rejected_v4(output);
+        assert_eq!(rejected, HashSet::from([IpAddr::from([34, 117, 137, 206])]));
+    }
+
+    #[test]
+    fn v6_table() {
+        let output = "prohibit 2a00:1450:400e:80c::2014 dev lo metric 1024 pref medium
+fe80::/64 dev wlan0 proto kernel metric 256 pref medium
+default via fe80::1 dev wlan0 proto ra metric 1024 pref medium
+";
+        let routes = parse_v6_table(output);
+        let blocked: IpAddr = "2a00:1450:400e:80c::2014".parse().unwrap();
+        assert_eq!(routes, HashSet::from([blocked]));
+        let output = format!("{output}2a00:1450::1 via fe80::1 dev wlan0 metric 1024\n");
+        assert_eq!(parse_v6_table(&output).len(), 2);
+        assert_eq!(rejected_v6(&output), HashSet::from([blocked]));
+    }
 }
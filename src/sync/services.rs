@@ -0,0 +1,148 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+
+use super::{Pending, SyncBlocker};
+use crate::config::Config;
+use crate::systemd;
+use crate::util::AcceptErr;
+
+/// Services that sync on their own on newer firmware, rejected routes
+/// only make them retry
+const SYNC_SERVICES: [&str; 1] = ["rm-sync.service"];
+
+/// Records the services we stopped, so we only start those again
+fn marker() -> PathBuf {
+    crate::state_dir().join("sync_services_stopped")
+}
+
+fn stopped() -> Result<Vec<String>> {
+    match fs::read_to_string(marker()) {
+        Ok(list) => Ok(list.lines().map(str::to_owned).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).wrap_err("Could not read which sync services were stopped"),
+    }
+}
+
+fn record(services: &[String]) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    fs::write(marker(), services.join("\n"))
+        .wrap_err("Could not record which sync services were stopped")
+}
+
+/// The services from the config, or the known sync services
+fn candidates(config: &Config) -> Vec<String> {
+    if config.sync_services.is_empty() {
+        SYNC_SERVICES.map(str::to_owned).to_vec()
+    } else {
+        config.sync_services.clone()
+    }
+}
+
+/// Also stops the sync services while `inner` blocks sync
+pub struct StopServices {
+    inner: Box<dyn SyncBlocker>,
+    candidates: Vec<String>,
+}
+
+impl StopServices {
+    pub fn new(inner: Box<dyn SyncBlocker>, config: &Config) -> Self {
+        Self {
+            inner,
+            candidates: candidates(config),
+        }
+    }
+
+    fn stop(&self) -> Result<()> {
+        let mut stopped = stopped()?;
+        for service in &self.candidates {
+            if stopped.contains(service) || !systemd::is_active(service)? {
+                continue;
+            }
+            // recorded first so a crash while stopping still restarts it
+            stopped.push(service.clone());
+            record(&stopped)?;
+            log::info!("stopping sync service: {service}");
+            systemd::stop_masked(service)?;
+        }
+        Ok(())
+    }
+}
+
+impl SyncBlocker for StopServices {
+    fn block(&self) -> Result<Pending> {
+        if crate::device::current().is_remarkable() {
+            // blocking the routes is enough to stop sync
+            if let Err(e) = self.stop() {
+                log::warn!("could not stop the sync services: {e:#}");
+            }
+        } else {
+            log::warn!("not running on a remarkable, skipping stopping sync services");
+        }
+        self.inner.block()
+    }
+
+    fn unblock(&self) -> Result<()> {
+        self.inner.unblock()?;
+        restore()
+    }
+}
+
+/// Whether blocking stopped sync services that are not started again yet
+pub fn any_stopped() -> Result<bool> {
+    Ok(!stopped()?.is_empty())
+}
+
+/// Starts the sync services stopped while blocking
+pub fn restore() -> Result<()> {
+    let stopped = stopped()?;
+    if stopped.is_empty() {
+        return Ok(());
+    }
+    // one failing should not keep the others stopped
+    let mut failed = Vec::new();
+    for service in stopped {
+        log::info!("starting sync service: {service}");
+        if let Err(e) = systemd::unmask_and_start(&service) {
+            log::error!("Could not restart sync service {service}: {e:?}");
+            failed.push(service);
+        }
+    }
+    if !failed.is_empty() {
+        record(&failed)?;
+        return Err(eyre!(
+            "Could not restart sync services: {}",
+            failed.join(", ")
+        ));
+    }
+    fs::remove_file(marker())
+        .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+        .wrap_err("Could not clear which sync services were stopped")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_stopped() {
+        let root = crate::device::simulate_in_temp_dir("sync-services");
+        assert!(stopped().unwrap().is_empty());
+        let services = vec!["rm-sync.service".to_owned(), "other.service".to_owned()];
+        record(&services).unwrap();
+        assert_eq!(stopped().unwrap(), services);
+
+        let config = Config {
+            sync_services: vec!["other.service".to_owned()],
+            ..Config::default()
+        };
+        assert_eq!(candidates(&config), ["other.service"]);
+        assert_eq!(candidates(&Config::default()), SYNC_SERVICES);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+
+use super::{Pending, SyncBlocker};
+use crate::util::cmd::{self, SysCmd};
+use crate::util::AcceptErr;
+
+/// Records how wifi was turned off, so we only turn it back
+/// on if we were the ones to turn it off
+fn marker() -> PathBuf {
+    crate::state_dir().join("wifi_disabled")
+}
+
+/// Whether wifi is off because we turned it off
+pub fn turned_off() -> bool {
+    marker().exists()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Rfkill,
+    Ifconfig,
+}
+
+impl Method {
+    fn as_str(self) -> &'static str {
+        match self {
+            Method::Rfkill => "rfkill",
+            Method::Ifconfig => "ifconfig",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "rfkill" => Some(Method::Rfkill),
+            "ifconfig" => Some(Method::Ifconfig),
+            _ => None,
+        }
+    }
+}
+
+/// Turns wifi off entirely while locked
+pub struct Wifi<C = cmd::System> {
+    interface: String,
+    cmd: C,
+}
+
+impl Wifi {
+    pub fn new(interface: impl Into<String>) -> Self {
+        Self::with_cmd(cmd::System, interface)
+    }
+}
+
+impl<C: SysCmd> Wifi<C> {
+    fn with_cmd(cmd: C, interface: impl Into<String>) -> Self {
+        Self {
+            interface: interface.into(),
+            cmd,
+        }
+    }
+
+    fn set(&self, method: Method, enabled: bool) -> Result<()> {
+        match method {
+            Method::Rfkill => {
+                let action = if enabled { "unblock" } else { "block" };
+                self.cmd.run("rfkill", &[action, "wifi"])
+            }
+            Method::Ifconfig => {
+                let state = if enabled { "up" } else { "down" };
+                self.cmd.run("ifconfig", &[&self.interface, state])
+            }
+        }
+    }
+
+    /// Whether wifi is off, asks rfkill and falls back to the interface
+    /// flags like `disable` does
+    fn is_off(&self) -> Result<bool> {
+        match self.cmd.output("rfkill", &["list", "wifi"]) {
+            Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+                let list = String::from_utf8_lossy(&output.stdout);
+                return Ok(list
+                    .lines()
+                    .filter_map(|line| line.trim().split_once(':'))
+                    .any(|(key, value)| key.ends_with("blocked") && value.trim() == "yes"));
+            }
+            Ok(_) => log::debug!("rfkill lists no wifi, falling back to ifconfig"),
+            Err(e) => log::debug!("could not use rfkill, falling back to ifconfig: {e}"),
+        }
+        let output = self
+            .cmd
+            .output("ifconfig", &[&self.interface])
+            .wrap_err("Could not run ifconfig")?;
+        if !output.status.success() {
+            let reason = String::from_utf8_lossy(&output.stderr);
+            return Err(eyre!("{}", reason.trim())).wrap_err("ifconfig returned an error");
+        }
+        // busybox prints `UP BROADCAST ...`, net-tools `flags=4163<UP,...>`
+        let up = String::from_utf8_lossy(&output.stdout)
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .any(|flag| flag == "UP");
+        Ok(!up)
+    }
+
+    fn disable(&self) -> Result<Method> {
+        match self.set(Method::Rfkill, false) {
+            Ok(()) => return Ok(Method::Rfkill),
+            Err(e) => log::debug!("could not use rfkill, falling back to ifconfig: {e}"),
+        }
+        self.set(Method::Ifconfig, false)?;
+        Ok(Method::Ifconfig)
+    }
+
+    /// Turns wifi off, recording how only if it was on
+    fn turn_off(&self) -> Result<()> {
+        if !marker().exists() && self.is_off().wrap_err("Could not check if wifi is on")? {
+            log::info!("wifi is already off, leaving it off after unlocking");
+            return Ok(());
+        }
+        let method = self.disable().wrap_err("Could not turn off wifi")?;
+        if !marker().exists() {
+            fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+            fs::write(marker(), method.as_str()).wrap_err("Could not record wifi state")?;
+        }
+        Ok(())
+    }
+}
+
+impl<C: SysCmd> SyncBlocker for Wifi<C> {
+    fn block(&self) -> Result<Pending> {
+        log::info!("turning off wifi");
+        if !crate::device::current().is_remarkable() {
+            log::warn!("not running on a remarkable, skipping turning off wifi");
+            return Ok(Pending::done());
+        }
+        self.turn_off()?;
+        Ok(Pending::done())
+    }
+
+    fn unblock(&self) -> Result<()> {
+        let method = match fs::read_to_string(marker()) {
+            Ok(method) => method,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::debug!("wifi was not turned off by book-safe");
+                return Ok(());
+            }
+            Err(e) => return Err(e).wrap_err("Could not read wifi state"),
+        };
+        let method = Method::parse(&method).unwrap_or(Method::Ifconfig);
+
+        log::info!("turning wifi back on");
+        self.set(method, true)
+            .wrap_err("Could not turn wifi back on")?;
+        fs::remove_file(marker())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not clear wifi state")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::cmd::Scripted;
+
+    #[test]
+    fn method_roundtrip() {
+        for method in [Method::Rfkill, Method::Ifconfig] {
+            assert_eq!(Method::parse(method.as_str()), Some(method));
+        }
+        assert_eq!(Method::parse("rfkill\n"), Some(Method::Rfkill));
+    }
+
+    #[test]
+    fn already_off_stays_off() {
+        let root = crate::device::simulate_in_temp_dir("wifi-off");
+        let list = "0: phy0: Wireless LAN\n\tSoft blocked: yes\n\tHard blocked: no\n";
+        let wifi = Wifi::with_cmd(
+            Scripted::default().expect("rfkill list wifi", 0, list, ""),
+            "wlan0",
+        );
+        wifi.turn_off().unwrap();
+        assert!(!marker().exists());
+        // turning it on would be an unexpected command
+        wifi.unblock().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_ifconfig() {
+        let root = crate::device::simulate_in_temp_dir("wifi-ifconfig");
+        let flags = "wlan0     Link encap:Ethernet\n          UP BROADCAST RUNNING  MTU:1500\n";
+        let wifi = Wifi::with_cmd(
+            Scripted::default()
+                .missing("rfkill list wifi")
+                .expect("ifconfig wlan0", 0, flags, "")
+                .missing("rfkill block wifi")
+                .expect("ifconfig wlan0 down", 0, "", "")
+                .expect("ifconfig wlan0 up", 0, "", ""),
+            "wlan0",
+        );
+        wifi.turn_off().unwrap();
+        assert_eq!(fs::read_to_string(marker()).unwrap(), "ifconfig");
+        wifi.unblock().unwrap();
+        assert!(!marker().exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
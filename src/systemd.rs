@@ -1,82 +1,318 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::env::current_exe;
-use std::process::Command;
-use std::time::Duration;
-use std::{fs, thread};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use color_eyre::eyre;
-use eyre::{eyre, Result, WrapErr};
+use eyre::{Result, WrapErr};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
-use crate::util::time::ParseHourMinute;
+use crate::config::Config;
+use crate::device;
+use crate::exit;
+use crate::profile::Profile;
+use crate::scheduler::{self, Scheduler};
+use crate::util::cmd::{self, SysCmd};
+use crate::util::time::Schedule;
+use crate::util::{self, AcceptErr};
 use time::Time;
 
-#[cfg(not(target_arch = "arm"))]
-#[allow(clippy::unnecessary_wraps)]
-pub fn reset_failed() -> Result<()> {
-    Ok(())
-}
-#[cfg(target_arch = "arm")]
-#[allow(clippy::unnecessary_wraps)]
-pub fn reset_failed() -> Result<()> {
-    systemctl(&["reset-failed"], "xochitl")?;
-    Ok(())
+mod dbus;
+
+/// Toltec launchers, these start xochitl themselves (when they are
+/// active xochitl.service is not) and would restart it if we stopped it.
+const LAUNCHERS: [&str; 3] = ["tarnish.service", "remux.service", "draft.service"];
+/// how long to wait for a unit to (de)activate
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The services running the user interface
+pub struct Ui {
+    services: Vec<String>,
+    /// set while we have the ui stopped
+    stopped: RefCell<Option<Stopped>>,
+    /// in a `batch` the ui is started once, at its end
+    batched: Cell<bool>,
+    /// how long to wait for each service to stop or start
+    timeout: Duration,
+    /// the ui has this open once it is drawing
+    ready_file: Option<PathBuf>,
 }
 
-#[cfg(not(target_arch = "arm"))]
-#[allow(clippy::unnecessary_wraps)]
-pub fn ui_action(_operation: &'static str) -> Result<()> {
-    Ok(())
+struct Stopped {
+    /// the services that were running, only these are started again
+    running: Vec<String>,
+    /// the device can suspend again once this is closed
+    _no_sleep: Option<OwnedFd>,
 }
 
-#[cfg(target_arch = "arm")]
-pub fn ui_action(operation: &'static str) -> Result<()> {
-    systemctl(&[operation], "xochitl")?;
+impl Ui {
+    /// Uses the services from the config or if there are none there
+    /// looks for active launchers. Must be called while the ui is running.
+    pub fn detect(config: &Config) -> Result<Self> {
+        if !config.ui_services.is_empty() {
+            return Ok(Self::new(config.ui_services.clone(), config));
+        }
+
+        let mut services = Vec::new();
+        if device::current().is_remarkable() {
+            for launcher in LAUNCHERS {
+                if dbus::active_state(launcher)? == "active" {
+                    log::info!("ui is managed by launcher: {launcher}");
+                    services.push(launcher.to_owned());
+                }
+            }
+        }
+        if services.is_empty() {
+            // an earlier run could have left a launcher stopped
+            services = left_stopped();
+        }
+        if services.is_empty() {
+            services.push(device::current().ui_service.to_owned());
+        }
+        Ok(Self::new(services, config))
+    }
 
-    let target_activity = match operation {
-        "start" => {
-            log::info!("starting ui");
-            true
+    fn new(services: Vec<String>, config: &Config) -> Self {
+        Self {
+            services,
+            stopped: RefCell::new(None),
+            batched: Cell::new(false),
+            timeout: Duration::from_secs(config.ui_timeout_secs),
+            ready_file: config.ui_ready_file.clone(),
         }
-        "stop" => {
-            log::info!("stopping ui");
-            false
+    }
+
+    /// Runs `f` stopping the ui at most once: a stop inside it while the
+    /// ui is already stopped does nothing and starting is left until
+    /// `f` is done
+    pub fn batch<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.batched.set(true);
+        let res = f();
+        self.batched.set(false);
+        if self.stopped.borrow().is_some() {
+            self.reset_failed()?;
+            self.start().wrap_err("Could not start gui")?;
         }
-        _ => unreachable!(),
+        res
+    }
+
+    /// Stops the services that are running and holds off suspend until
+    /// they are started again. Stopping the ui while the power manager
+    /// suspends the device can leave the screen black.
+    pub fn stop(&self) -> Result<()> {
+        if self.batched.get() && self.stopped.borrow().is_some() {
+            return Ok(());
+        }
+        log::info!("stopping ui: {:?}", self.services);
+        if !device::current().is_remarkable() {
+            *self.stopped.borrow_mut() = Some(Stopped {
+                running: self.services.clone(),
+                _no_sleep: None,
+            });
+            return Ok(());
+        }
+        let no_sleep = dbus::inhibit_sleep("moving documents while the ui is stopped")
+            .inspect_err(|e| log::warn!("could not hold off suspend: {e:#}"))
+            .ok();
+        let left_stopped = left_stopped();
+        let mut running = Vec::new();
+        for service in &self.services {
+            let state = dbus::active_state(service)?;
+            if was_running(service, &state, &left_stopped) {
+                running.push(service.clone());
+            } else {
+                log::info!("{service} is not running, leaving it stopped");
+            }
+        }
+        // recorded first so a run after a crash still starts them again
+        record_stopped(&running)?;
+        *self.stopped.borrow_mut() = Some(Stopped {
+            running: running.clone(),
+            _no_sleep: no_sleep,
+        });
+        for service in &running {
+            dbus::stop(service)?;
+            wait_for_within(service, false, self.timeout)
+                .wrap_err("operation did not complete in time")?;
+        }
+        Ok(())
+    }
+
+    /// Starts the services `stop` stopped, then allows suspend again
+    pub fn start(&self) -> Result<()> {
+        if self.batched.get() {
+            return Ok(());
+        }
+        let running = match self.stopped.borrow().as_ref() {
+            Some(stopped) => stopped.running.clone(),
+            None => self.services.clone(),
+        };
+        log::info!("starting ui: {running:?}");
+        if !device::current().is_remarkable() {
+            self.stopped.take();
+            return Ok(());
+        }
+        for service in &running {
+            dbus::start(service)?;
+            wait_for_within(service, true, self.timeout)
+                .wrap_err("operation did not complete in time")?;
+            if let Some(file) = &self.ready_file {
+                wait_for_open(service, file, self.timeout)
+                    .wrap_err("ui did not get ready in time")?;
+            }
+        }
+        self.stopped.take();
+        fs::remove_file(ui_marker())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not clear which ui services were stopped")
+    }
+
+    pub fn reset_failed(&self) -> Result<()> {
+        if self.batched.get() || !device::current().is_remarkable() {
+            return Ok(());
+        }
+        for service in &self.services {
+            dbus::reset_failed(service)?;
+        }
+        Ok(())
+    }
+}
+
+/// Waits until the main process of `service` has `file` open. The
+/// service is active before the ui has drawn anything.
+fn wait_for_open(service: &str, file: &Path, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pid = dbus::main_pid(service)?;
+        if pid != 0 && has_open(pid, file) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(eyre::eyre!("{service} did not open {}", file.display()));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn has_open(pid: u32, file: &Path) -> bool {
+    let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+        return false;
     };
-    wait_for("xochitl", target_activity).wrap_err("operation did not complete in time")?;
-    Ok(())
+    fds.filter_map(Result::ok)
+        .any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target == file))
+}
+
+/// Records which ui services we stopped, if we crash before starting
+/// them again the next stop knows they were running
+fn ui_marker() -> PathBuf {
+    crate::state_dir().join("ui_stopped")
+}
+
+fn record_stopped(services: &[String]) -> Result<()> {
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    fs::write(ui_marker(), services.join("\n"))
+        .wrap_err("Could not record which ui services are stopped")
+}
+
+/// The ui services an earlier run stopped and did not start again
+fn left_stopped() -> Vec<String> {
+    match fs::read_to_string(ui_marker()) {
+        Ok(list) => list.lines().map(str::to_owned).collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            log::warn!("could not read which ui services were stopped: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Whether `service` in `state` should be started after we are done
+fn was_running(service: &str, state: &str, left_stopped: &[String]) -> bool {
+    if !matches!(state, "inactive" | "failed") {
+        return true;
+    }
+    let ours = left_stopped.iter().any(|s| s == service);
+    if ours {
+        log::warn!("{service} was left stopped by an earlier run, starting it after");
+    }
+    ours
 }
 
-fn is_active(service: &str) -> Result<bool> {
-    let output = Command::new("systemctl")
-        .arg("is-active")
-        .arg(service)
-        .output()
-        .wrap_err("Could not run systemctl")?;
+fn wait_for(unit: &str, wanted_active: bool) -> Result<()> {
+    wait_for_within(unit, wanted_active, TIMEOUT)
+}
 
-    Ok(output.status.code().unwrap() == 0)
+fn wait_for_within(unit: &str, wanted_active: bool, timeout: Duration) -> Result<()> {
+    let wanted: &[&str] = if wanted_active {
+        &["active"]
+    } else {
+        &["inactive", "failed"]
+    };
+    dbus::wait_for(unit, wanted, timeout)
 }
 
-fn wait_for(service: &str, wanted_active: bool) -> Result<()> {
-    for _ in 0..20 {
-        match (wanted_active, is_active(service)?) {
-            (true, true) | (false, false) => return Ok(()),
-            (true, false) | (false, true) => (),
+/// The arguments book-safe was installed with. Kept in the state dir
+/// as firmware updates wipe the units in `/etc/systemd/system`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Installed {
+    /// see `Args::to_install_args`, records from before `run` read them
+    /// hold the arguments for `run` instead
+    #[serde(alias = "run_args")]
+    pub args: Vec<String>,
+    /// records from before cron was supported lack this
+    #[serde(default)]
+    pub scheduler: scheduler::Kind,
+    /// Uuid of every folder passed to install by its path, to follow
+    /// them when renamed or moved
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub folders: BTreeMap<String, String>,
+}
+
+impl Installed {
+    pub fn load(profile: &Profile) -> Result<Option<Self>> {
+        let json = match fs::read_to_string(profile.record()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).wrap_err("Could not read install record"),
         };
-        thread::sleep(Duration::from_millis(50));
+        serde_json::from_str(&json)
+            .map(Some)
+            .wrap_err("Could not parse install record")
     }
 
-    Err(eyre!(
-        "Time out waiting for {}",
-        if wanted_active {
-            "activation"
-        } else {
-            "deactivation"
-        }
-    ))
+    /// Arguments for the service: the global options install was
+    /// given followed by `run`, which reads the rest from this record
+    pub fn run_args(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .take_while(|arg| arg.starts_with('-'))
+            .cloned()
+            .chain(std::iter::once("run".to_owned()))
+            .collect()
+    }
+
+    pub fn save(&self, profile: &Profile) -> Result<()> {
+        fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(profile.record(), json).wrap_err("Could not write install record")
+    }
+
+    pub fn remove(profile: &Profile) -> Result<()> {
+        fs::remove_file(profile.record())
+            .accept_fn(|e| e.kind() == ErrorKind::NotFound)
+            .wrap_err("Could not remove install record")
+    }
 }
 
 // String should be written to a .service file
-fn service_str() -> Result<String> {
+fn service_str(profile: &Profile, run_args: &[String], config: &Config) -> Result<String> {
     let path = current_exe().wrap_err(concat!(
         "Could not get ",
         env!("CARGO_PKG_NAME"),
@@ -85,109 +321,589 @@ fn service_str() -> Result<String> {
 
     let working_dir = path.parent().unwrap().to_str().unwrap();
     let bin_path = path.to_str().unwrap();
-    let args: String = std::env::args()
-        .skip(1) // skip binary name
-        .map(|s| {
-            if s.contains(' ') {
-                format!("\"{s}\" ")
-            } else {
-                format!("{s} ")
-            }
-        })
-        .collect();
-    let args = args.replace("install", "run");
+    let device = device::current();
+    let safe_dir = profile.safe_dir();
+    // the unit dir is needed to restore the units after a firmware update
+    let writable = [
+        device.documents(),
+        &safe_dir,
+        device.state_dir(),
+        Path::new(UNIT_DIR),
+    ]
+    .map(|p| p.display().to_string())
+    .join(" ");
+    // the '-' keeps the service working if the dir does not exist (yet)
+    let writable = format!("{writable} -{}", profile.legacy_safe_dir().display());
+    let writable = match config.metrics_file.as_deref().and_then(Path::parent) {
+        Some(dir) => format!("{writable} -{}", dir.display()),
+        None => writable,
+    };
+    let args = run_args.iter().map(|arg| quote(arg)).join(" ");
+    let bin = quote(bin_path);
+    let (limit, restart) = restart_str(config);
 
     Ok(format!(
         "[Unit]
-Description=Makes folders in ui inaccesible for given period
-
+Description=Makes folders in ui inaccesible for given period ({profile})
+{limit}
 [Service]
 Type=oneshot
+User=root
+ProtectSystem=strict
+ReadWritePaths={writable}
 WorkingDirectory={working_dir}
-ExecStart={bin_path} {args}
-
+ExecStart={bin} {args}
+{restart}
 [Install]
 WantedBy=multi-user.target
 ",
     ))
 }
 
-macro_rules! unit_path {
-    ($ext:literal) => {
-        concat!("/etc/systemd/system/", env!("CARGO_PKG_NAME"), ".", $ext)
-    };
+/// Starts a failed run again a bit later, a lock can fail right after
+/// resume when the network is not up yet. Returns the lines for the
+/// unit and the service section. Failures that do not go away by
+/// themselves are not retried: invalid arguments or config, missing
+/// folders, a refusal and nothing to lock.
+fn restart_str(config: &Config) -> (String, String) {
+    let attempts = config.run_retry_attempts;
+    if attempts == 0 {
+        return (String::new(), String::new());
+    }
+    let delay = config.run_retry_delay_secs;
+    // the limit counts the timer's starts too, the window is kept short
+    // so these do not use it up
+    let limit = format!(
+        "StartLimitIntervalSec={}\nStartLimitBurst={}\n",
+        u64::from(delay) * u64::from(attempts) + 1,
+        attempts + 1
+    );
+    let permanent = [
+        // clap's invalid arguments
+        2,
+        exit::Failure::Config.code(),
+        exit::Failure::Missing.code(),
+        exit::Failure::Refused.code(),
+        exit::Failure::Empty.code(),
+    ]
+    .iter()
+    .join(" ");
+    let restart =
+        format!("Restart=on-failure\nRestartSec={delay}\nRestartPreventExitStatus={permanent}\n");
+    (limit, restart)
+}
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+fn unit_path(profile: &Profile, ext: &str) -> PathBuf {
+    Path::new(UNIT_DIR).join(profile.unit(ext))
 }
 
-pub fn write_service() -> Result<()> {
-    let service = service_str().wrap_err("Could not construct service")?;
-    let path = unit_path!("service");
-    fs::write(path, service).wrap_err_with(|| format!("could not write file to: {path}"))?;
-    Ok(())
+/// Only root may read or change the units, otherwise other apps on
+/// the device could change the schedule
+fn write_unit(path: &Path, content: &str) -> Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .wrap_err_with(|| format!("could not write file to: {}", path.display()))?;
+    util::restrict_to_root(path)
+}
+
+fn write_service(profile: &Profile, run_args: &[String], config: &Config) -> Result<()> {
+    let service = service_str(profile, run_args, config).wrap_err("Could not construct service")?;
+    write_unit(&unit_path(profile, "service"), &service)
+}
+
+/// Checks the units using `systemd-analyze`, if it is installed
+fn verify_units(cmd: &impl SysCmd, profile: &Profile) -> Result<()> {
+    let [service, timer] = ["service", "timer"].map(|ext| unit_path(profile, ext));
+    let (service, timer) = (service.display().to_string(), timer.display().to_string());
+    let output = match cmd.output("systemd-analyze", &["verify", &service, &timer]) {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::warn!("systemd-analyze is not installed, not verifying units");
+            return Ok(());
+        }
+        Err(e) => return Err(e).wrap_err("Could not run systemd-analyze"),
+    };
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let reason = String::from_utf8_lossy(&output.stderr);
+        Err(eyre::eyre!("{}", reason.trim()).wrap_err("Generated units are invalid"))
+    }
 }
 
 // String should be written to a .timer file
-fn timer_str(args: &crate::Args) -> Result<String> {
-    let start = Time::try_parse(&args.start).wrap_err("Invalid start time")?;
-    let end = Time::try_parse(&args.end).wrap_err("Invalid end time")?;
-    // default systemd accuracy is 1 minute for power consumption reasons
-    // therefore we add one minute and some seconds to both times to ensure
-    // hiding or unhiding happens
-    let run_hide = format!("*-*-* {}:{}:10", start.hour(), start.minute() + 1);
-    let run_unhide = format!("*-*-* {}:{}:10", end.hour(), end.minute() + 1);
+fn timer_str(profile: &Profile, args: &crate::Args, config: &Config) -> Result<String> {
+    // the default accuracy of 1 minute saves power, therefore we then add
+    // one minute and some seconds to both times to ensure hiding or
+    // unhiding happens. A precise timer fires a second after, the window
+    // includes its end. Run decides using the actual window in the
+    // timezone, so these only need to be roughly right around DST.
+    // with a timezone the timers do not depend on the os timezone
+    let zone = args
+        .timezone(config)
+        .map(|tz| format!(" {tz}"))
+        .unwrap_or_default();
+    let after_edge = if config.timer_accuracy_secs >= 60 {
+        time::Duration::seconds(70)
+    } else {
+        time::Duration::SECOND
+    };
+    let on_calendar = |at: Time| {
+        let at = at + after_edge;
+        format!(
+            "*-*-* {}:{:02}:{:02}{zone}",
+            at.hour(),
+            at.minute(),
+            at.second()
+        )
+    };
+    let triggers = match args.schedule()? {
+        Schedule::Window { start, end } => {
+            // while locked the run re-resolves the sync ips, blocking any new ones
+            let refresh = match config.sync_refresh_minutes {
+                0 => String::new(),
+                n => format!("OnUnitActiveSec={n}min\n"),
+            };
+            let mut edges = vec![start, end];
+            if let Some(minutes) = args.warn_minutes {
+                edges.push(start - time::Duration::minutes(minutes.into()));
+            }
+            for tier in &args.unlock_at {
+                edges.push(crate::group::parse_tier(tier)?.0);
+            }
+            let mut edges: Vec<_> = edges
+                .into_iter()
+                .unique()
+                .map(|at| format!("OnCalendar={}", on_calendar(at)))
+                .collect();
+            // the run after it sends the weekly digest
+            if let Some((day, at)) = crate::digest::due_at(config)? {
+                edges.push(format!("OnCalendar={day} {}", on_calendar(at)));
+            }
+            let edges = edges.join("\n");
+            // powered off across an edge nothing locks until the next one
+            format!("{edges}\nOnBootSec=2min\n{refresh}")
+        }
+        // every run counts the time unlocked since the previous one
+        Schedule::Quota(_) => format!(
+            "OnCalendar={}\nOnActiveSec=1min\nOnUnitActiveSec=1min\n",
+            on_calendar(Time::MIDNIGHT)
+        ),
+    };
 
+    let accuracy = config.timer_accuracy_secs.max(1);
+    let delay = config
+        .timer_random_delay_secs
+        .map(|secs| format!("RandomizedDelaySec={secs}\n"))
+        .unwrap_or_default();
     Ok(format!(
         "[Unit]
-Description=Hide folders in ui at certain times
+Description=Hide folders in ui at certain times ({profile})
 
 [Timer]
-OnCalendar={run_hide}
-OnCalendar={run_unhide}
-AccuracySec=60
-
+{triggers}AccuracySec={accuracy}
+{delay}
 [Install]
 WantedBy=timers.target
 "
     ))
 }
 
-pub fn write_timer(args: &crate::Args) -> Result<()> {
-    let timer = timer_str(args).wrap_err("Could not construct timer")?;
-    let path = unit_path!("timer");
-    fs::write(path, timer).wrap_err_with(|| format!("could not write file to: {path}"))
+fn write_timer(profile: &Profile, args: &crate::Args, config: &Config) -> Result<()> {
+    let timer = timer_str(profile, args, config).wrap_err("Could not construct timer")?;
+    write_unit(&unit_path(profile, "timer"), &timer)
 }
 
-pub fn remove_units() -> Result<()> {
-    fs::remove_file(unit_path!("timer")).wrap_err("Error removing timer")?;
-    fs::remove_file(unit_path!("service")).wrap_err("Error removing service")
-}
+/// The service started by a timer
+pub struct Timer;
 
-fn systemctl(args: &[&'static str], service: &str) -> Result<()> {
-    let output = Command::new("systemctl")
-        .args(args)
-        .arg(service)
-        .output()
-        .wrap_err("Could not run systemctl")?;
+impl Scheduler for Timer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
 
-    if output.status.success() {
+    fn write(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<()> {
+        write_service(profile, run_args, config).wrap_err("Error creating service")?;
+        write_timer(profile, args, config).wrap_err("Error creating timer")
+    }
+
+    fn verify(&self, profile: &Profile) -> Result<()> {
+        verify_units(&cmd::System, profile)
+    }
+
+    fn enable(&self, profile: &Profile) -> Result<()> {
+        let timer = profile.unit("timer");
+        dbus::enable(&timer)?;
+        dbus::start(&timer)?;
+        wait_for(&timer, true).wrap_err("Timer was not activated")?;
         Ok(())
-    } else {
-        let reason = String::from_utf8(output.stderr).unwrap();
-        Err(eyre!("{reason}").wrap_err("Systemctl returned an error"))
     }
+
+    fn disable(&self, profile: &Profile) -> Result<()> {
+        let timer = profile.unit("timer");
+        dbus::disable(&timer)?;
+        dbus::stop(&timer)?;
+        wait_for(&timer, false).wrap_err("Timer was not deactivated")?;
+        Ok(())
+    }
+
+    fn remove(&self, profile: &Profile) -> Result<()> {
+        fs::remove_file(unit_path(profile, "timer")).wrap_err("Error removing timer")?;
+        fs::remove_file(unit_path(profile, "service")).wrap_err("Error removing service")
+    }
+
+    fn print(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<()> {
+        let service = service_str(profile, run_args, config)?;
+        let timer = timer_str(profile, args, config)?;
+        println!("# {}\n{service}", unit_path(profile, "service").display());
+        println!("# {}\n{timer}", unit_path(profile, "timer").display());
+        Ok(())
+    }
+
+    fn up_to_date(
+        &self,
+        profile: &Profile,
+        run_args: &[String],
+        args: &crate::Args,
+        config: &Config,
+    ) -> Result<bool> {
+        let expected = [
+            (
+                unit_path(profile, "service"),
+                service_str(profile, run_args, config)?,
+            ),
+            (
+                unit_path(profile, "timer"),
+                timer_str(profile, args, config)?,
+            ),
+        ];
+        for (path, expected) in expected {
+            let display = path.display();
+            match fs::read_to_string(&path) {
+                Ok(current) if current == expected => continue,
+                Ok(_) => log::debug!("{display} is outdated"),
+                Err(e) if e.kind() == ErrorKind::NotFound => log::debug!("{display} is missing"),
+                Err(e) => return Err(e).wrap_err_with(|| format!("Could not read: {display}")),
+            }
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn state(&self, profile: &Profile) -> Result<String> {
+        if !device::current().is_remarkable() {
+            return Ok("unknown (not on a remarkable)".to_owned());
+        }
+        dbus::active_state(&profile.unit("timer"))
+    }
+}
+
+/// Unlocks `profile` at `at` using a transient timer, these are
+/// lost on reboot
+pub fn schedule_unlock(profile: &Profile, at: Time, timezone: Option<&str>) -> Result<()> {
+    let zone = timezone.map(|tz| format!(" {tz}")).unwrap_or_default();
+    let at = at + time::Duration::seconds(10);
+    let on_calendar = format!(
+        "*-*-* {}:{:02}:{:02}{zone}",
+        at.hour(),
+        at.minute(),
+        at.second()
+    );
+    transient_unlock(profile, &format!("--on-calendar={on_calendar}"))
+}
+
+/// Unlocks `profile` once `after` has passed using a transient timer,
+/// these are lost on reboot
+pub fn schedule_unlock_after(profile: &Profile, after: time::Duration) -> Result<()> {
+    let secs = after.whole_seconds().max(1);
+    transient_unlock(profile, &format!("--on-active={secs}s"))
+}
+
+/// Starts the service of `profile`, or without one `run`, once `after`
+/// has passed using a transient timer, these are lost on reboot
+pub fn schedule_run_after(
+    profile: &Profile,
+    installed: &Installed,
+    after: time::Duration,
+) -> Result<()> {
+    let secs = after.whole_seconds().max(1);
+    let command: Vec<String> = match installed.scheduler {
+        scheduler::Kind::Systemd => vec![
+            "systemctl".to_owned(),
+            "start".to_owned(),
+            profile.unit("service"),
+        ],
+        scheduler::Kind::Cron => std::iter::once(bin()?)
+            .chain(installed.run_args())
+            .collect(),
+    };
+    transient(profile, "run", &format!("--on-active={secs}s"), &command)
+}
+
+fn bin() -> Result<String> {
+    let bin = current_exe().wrap_err("Could not get binary location")?;
+    bin.into_os_string()
+        .into_string()
+        .map_err(|bin| eyre::eyre!("Binary location is not utf-8: {bin:?}"))
+}
+
+fn transient_unlock(profile: &Profile, when: &str) -> Result<()> {
+    let command = [
+        bin()?,
+        format!("--profile={profile}"),
+        "unlock".to_owned(),
+        "--yes".to_owned(),
+    ];
+    transient(profile, "unlock", when, &command)
+}
+
+/// Stops the unlock scheduled by `schedule_unlock_after`, if it did not
+/// run yet
+pub fn cancel_unlock(profile: &Profile) {
+    if !device::current().is_remarkable() {
+        return;
+    }
+    let unit = transient_unit(profile, "unlock");
+    if let Err(e) = dbus::stop(&format!("{unit}.timer")) {
+        log::debug!("no scheduled unlock to cancel: {e:#}");
+    }
+}
+
+fn transient_unit(profile: &Profile, action: &str) -> String {
+    format!("{}-{action}-{profile}", env!("CARGO_PKG_NAME"))
+}
+
+fn transient(profile: &Profile, action: &str, when: &str, command: &[String]) -> Result<()> {
+    let unit = transient_unit(profile, action);
+    log::info!("scheduling {action} {when} using {unit}.timer");
+    if !device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, not scheduling {action}");
+        return Ok(());
+    }
+
+    // an earlier scheduled one would make systemd-run fail
+    if let Err(e) = dbus::stop(&format!("{unit}.timer")) {
+        log::debug!("no earlier {action} to replace: {e:#}");
+    }
+    systemd_run(&cmd::System, &unit, when, command)
+}
+
+fn systemd_run(cmd: &impl SysCmd, unit: &str, when: &str, command: &[String]) -> Result<()> {
+    let unit = format!("--unit={unit}");
+    let args: Vec<&str> = [unit.as_str(), when, "--timer-property=AccuracySec=60"]
+        .into_iter()
+        .chain(command.iter().map(String::as_str))
+        .collect();
+    cmd.run("systemd-run", &args)
 }
 
-fn timer() -> &'static str {
-    concat!(env!("CARGO_PKG_NAME"), ".timer")
+/// Version of the running systemd
+pub fn version() -> Result<String> {
+    dbus::version()
 }
 
-pub fn enable() -> Result<()> {
-    systemctl(&["enable", "--now"], timer())?;
-    wait_for(timer(), true).wrap_err("Timer was not activated")?;
-    Ok(())
+pub fn is_active(service: &str) -> Result<bool> {
+    Ok(dbus::active_state(service)? == "active")
+}
+
+/// Stops `service` and masks it until the next boot, so nothing starts
+/// it again before [`unmask_and_start`]
+pub fn stop_masked(service: &str) -> Result<()> {
+    dbus::mask_runtime(service)?;
+    dbus::stop(service)?;
+    wait_for(service, false).wrap_err("operation did not complete in time")
+}
+
+pub fn unmask_and_start(service: &str) -> Result<()> {
+    dbus::unmask_runtime(service)?;
+    dbus::start(service)
+}
+
+/// Quote an argument for `ExecStart`, see the "command lines"
+/// section of `man systemd.service`
+fn quote(arg: &str) -> String {
+    if arg == ";" {
+        return "\\;".to_owned(); // a lone semicolon separates commands
+    }
+    // specifiers and environment variables are expanded even in quotes
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_.,:/+=@%$".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg;
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
 
-pub fn disable() -> Result<()> {
-    systemctl(&["disable", "--now"], timer())?;
-    wait_for(timer(), false).wrap_err("Timer was not deactivated")?;
-    Ok(())
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer_accuracy() {
+        use clap::Parser;
+        let cli = [
+            "book-safe",
+            "install",
+            "-s",
+            "22:00",
+            "-e",
+            "7:00",
+            "-z",
+            "UTC",
+            "-p",
+            "Books",
+        ];
+        let crate::Commands::Install { args, .. } = crate::Cli::parse_from(cli).command else {
+            panic!("not install")
+        };
+        let profile = Profile::new(crate::profile::DEFAULT).unwrap();
+        let timer = timer_str(&profile, &args, &Config::default()).unwrap();
+        assert!(timer.contains("OnCalendar=*-*-* 22:01:10 UTC\nOnCalendar=*-*-* 7:01:10 UTC\n"));
+        assert!(timer.contains("AccuracySec=60\n\n[Install]"));
+
+        let precise = Config {
+            timer_accuracy_secs: 1,
+            timer_random_delay_secs: Some(300),
+            ..Config::default()
+        };
+        let timer = timer_str(&profile, &args, &precise).unwrap();
+        assert!(timer.contains("OnCalendar=*-*-* 22:00:01 UTC\nOnCalendar=*-*-* 7:00:01 UTC\n"));
+        assert!(timer.contains("AccuracySec=1\nRandomizedDelaySec=300\n"));
+    }
+
+    #[test]
+    fn restarts_failed_runs() {
+        let (limit, restart) = restart_str(&Config::default());
+        assert_eq!(limit, "StartLimitIntervalSec=181\nStartLimitBurst=4\n");
+        assert_eq!(
+            restart,
+            "Restart=on-failure\nRestartSec=60\nRestartPreventExitStatus=2 3 6 7 8\n"
+        );
+
+        let never = Config {
+            run_retry_attempts: 0,
+            ..Config::default()
+        };
+        assert_eq!(restart_str(&never), (String::new(), String::new()));
+    }
+
+    #[test]
+    fn batch_starts_ui_once() {
+        let ui = Ui::new(vec!["xochitl.service".to_owned()], &Config::default());
+        ui.batch(|| {
+            ui.stop()?;
+            ui.start()?;
+            assert!(ui.stopped.borrow().is_some(), "started inside the batch");
+            ui.stop()?;
+            ui.start()
+        })
+        .unwrap();
+        assert!(ui.stopped.borrow().is_none());
+    }
+
+    #[test]
+    fn open_files() {
+        let path = std::env::temp_dir().join(format!("book-safe-open-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        assert!(has_open(std::process::id(), &path));
+        drop(file);
+        assert!(!has_open(std::process::id(), &path));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn restarts_only_running_ui() {
+        let left = vec!["xochitl.service".to_owned()];
+        assert!(was_running("xochitl.service", "active", &[]));
+        assert!(was_running("xochitl.service", "activating", &[]));
+        assert!(!was_running("xochitl.service", "inactive", &[]));
+        assert!(!was_running("remux.service", "failed", &left));
+        assert!(was_running("xochitl.service", "inactive", &left));
+    }
+
+    #[test]
+    fn verifies_units_if_possible() {
+        let profile = Profile::new("kids").unwrap();
+        let verify = "systemd-analyze verify \
+            /etc/systemd/system/book-safe@kids.service \
+            /etc/systemd/system/book-safe@kids.timer";
+        let cmd = cmd::Scripted::default()
+            .expect(verify, 0, "", "")
+            .expect(verify, 1, "", "Unknown key name 'OnBoot'\n")
+            .missing(verify);
+        verify_units(&cmd, &profile).unwrap();
+        let err = verify_units(&cmd, &profile).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Generated units are invalid: Unknown key name 'OnBoot'"
+        );
+        verify_units(&cmd, &profile).unwrap();
+    }
+
+    #[test]
+    fn schedules_with_systemd_run() {
+        let cmd = cmd::Scripted::default().expect(
+            "systemd-run --unit=book-safe-unlock-default --on-active=60s \
+            --timer-property=AccuracySec=60 /bin/book-safe unlock",
+            0,
+            "",
+            "",
+        );
+        let command = ["/bin/book-safe".to_owned(), "unlock".to_owned()];
+        systemd_run(
+            &cmd,
+            "book-safe-unlock-default",
+            "--on-active=60s",
+            &command,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("Books"), "Books");
+        assert_eq!(quote("22:00"), "22:00");
+        assert_eq!(quote("Europe/Amsterdam"), "Europe/Amsterdam");
+        assert_eq!(quote("my install"), "\"my install\"");
+        assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote(r"back\slash"), r#""back\\slash""#);
+        assert_eq!(quote("50%"), "50%%");
+        assert_eq!(quote("$HOME dir"), "\"$$HOME dir\"");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote(";"), "\\;");
+        assert_eq!(quote("it's"), "\"it's\"");
+    }
 }
@@ -0,0 +1,229 @@
+//! Talks to the systemd manager and logind over the system bus instead
+//! of running `systemctl` and parsing its output.
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use zbus::blocking::Connection;
+use zbus::proxy;
+use zbus::zvariant::{OwnedFd, OwnedObjectPath};
+
+/// change made to a unit file: (type, file name, destination)
+type Change = (String, String, String);
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1",
+    gen_async = false,
+    blocking_name = "ManagerProxy"
+)]
+trait Manager {
+    fn start_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn reset_failed_unit(&self, name: &str) -> zbus::Result<()>;
+    fn load_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+    fn reload(&self) -> zbus::Result<()>;
+    fn enable_unit_files(
+        &self,
+        files: &[&str],
+        runtime: bool,
+        force: bool,
+    ) -> zbus::Result<(bool, Vec<Change>)>;
+    fn disable_unit_files(&self, files: &[&str], runtime: bool) -> zbus::Result<Vec<Change>>;
+    fn mask_unit_files(
+        &self,
+        files: &[&str],
+        runtime: bool,
+        force: bool,
+    ) -> zbus::Result<Vec<Change>>;
+    fn unmask_unit_files(&self, files: &[&str], runtime: bool) -> zbus::Result<Vec<Change>>;
+    #[zbus(property)]
+    fn version(&self) -> zbus::Result<String>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1",
+    gen_async = false,
+    blocking_name = "UnitProxy"
+)]
+trait Unit {
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1",
+    gen_async = false,
+    blocking_name = "LoginProxy"
+)]
+trait Login {
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Service",
+    default_service = "org.freedesktop.systemd1",
+    gen_async = false,
+    blocking_name = "ServiceProxy"
+)]
+trait Service {
+    #[zbus(property, name = "MainPID")]
+    fn main_pid(&self) -> zbus::Result<u32>;
+}
+
+fn manager() -> Result<ManagerProxy<'static>> {
+    let conn = Connection::system().wrap_err("Could not connect to the system bus")?;
+    ManagerProxy::new(&conn).wrap_err("Could not reach systemd")
+}
+
+fn unit(name: &str) -> Result<UnitProxy<'static>> {
+    let manager = manager()?;
+    let path = manager
+        .load_unit(name)
+        .wrap_err_with(|| format!("Could not load unit: {name}"))?;
+    UnitProxy::builder(manager.inner().connection())
+        .path(path)?
+        .build()
+        .wrap_err("Could not create unit proxy")
+}
+
+/// The pid of the main process of service `name`, 0 if it has none
+pub fn main_pid(name: &str) -> Result<u32> {
+    let manager = manager()?;
+    let path = manager
+        .load_unit(name)
+        .wrap_err_with(|| format!("Could not load unit: {name}"))?;
+    ServiceProxy::builder(manager.inner().connection())
+        .path(path)?
+        .build()
+        .wrap_err("Could not create service proxy")?
+        .main_pid()
+        .wrap_err_with(|| format!("Could not get main pid of: {name}"))
+}
+
+pub fn version() -> Result<String> {
+    manager()?
+        .version()
+        .wrap_err("Could not get systemd version")
+}
+
+pub fn start(name: &str) -> Result<()> {
+    manager()?
+        .start_unit(name, "replace")
+        .wrap_err_with(|| format!("Could not start: {name}"))?;
+    Ok(())
+}
+
+pub fn stop(name: &str) -> Result<()> {
+    manager()?
+        .stop_unit(name, "replace")
+        .wrap_err_with(|| format!("Could not stop: {name}"))?;
+    Ok(())
+}
+
+pub fn reset_failed(name: &str) -> Result<()> {
+    manager()?
+        .reset_failed_unit(name)
+        .wrap_err_with(|| format!("Could not reset failed state of: {name}"))
+}
+
+pub fn enable(name: &str) -> Result<()> {
+    let manager = manager()?;
+    manager.reload().wrap_err("Could not reload systemd")?;
+    let (_, changes) = manager
+        .enable_unit_files(&[name], false, true)
+        .wrap_err_with(|| format!("Could not enable: {name}"))?;
+    log::debug!("enabled {name}, changes: {changes:?}");
+    manager.reload().wrap_err("Could not reload systemd")
+}
+
+pub fn disable(name: &str) -> Result<()> {
+    let manager = manager()?;
+    let changes = manager
+        .disable_unit_files(&[name], false)
+        .wrap_err_with(|| format!("Could not disable: {name}"))?;
+    log::debug!("disabled {name}, changes: {changes:?}");
+    manager.reload().wrap_err("Could not reload systemd")
+}
+
+/// Until unmasked or the next boot, nothing can start the unit
+pub fn mask_runtime(name: &str) -> Result<()> {
+    let manager = manager()?;
+    let changes = manager
+        .mask_unit_files(&[name], true, true)
+        .wrap_err_with(|| format!("Could not mask: {name}"))?;
+    log::debug!("masked {name}, changes: {changes:?}");
+    manager.reload().wrap_err("Could not reload systemd")
+}
+
+pub fn unmask_runtime(name: &str) -> Result<()> {
+    let manager = manager()?;
+    let changes = manager
+        .unmask_unit_files(&[name], true)
+        .wrap_err_with(|| format!("Could not unmask: {name}"))?;
+    log::debug!("unmasked {name}, changes: {changes:?}");
+    manager.reload().wrap_err("Could not reload systemd")
+}
+
+/// Blocks suspend until the returned fd is closed
+pub fn inhibit_sleep(why: &str) -> Result<std::os::fd::OwnedFd> {
+    let conn = Connection::system().wrap_err("Could not connect to the system bus")?;
+    LoginProxy::new(&conn)
+        .wrap_err("Could not reach logind")?
+        .inhibit("sleep", env!("CARGO_PKG_NAME"), why, "block")
+        .map(Into::into)
+        .wrap_err("Could not take an inhibitor lock")
+}
+
+pub fn active_state(name: &str) -> Result<String> {
+    unit(name)?
+        .active_state()
+        .wrap_err_with(|| format!("Could not get state of: {name}"))
+}
+
+/// Waits until the unit reaches one of the `wanted` states. Returns
+/// an error if the unit fails or if it takes longer then `timeout`.
+pub fn wait_for(name: &str, wanted: &'static [&'static str], timeout: Duration) -> Result<()> {
+    let unit = unit(name)?;
+    let (tx, rx) = mpsc::channel();
+    // the property iterator blocks without timeout, therefore it runs on
+    // its own thread. It is left behind if the state never changes.
+    thread::spawn(move || {
+        // subscribe before the first read so we do not miss a change
+        let changes = unit.receive_active_state_changed();
+        if tx.send(unit.active_state()).is_err() {
+            return;
+        }
+        for change in changes {
+            if tx.send(change.get()).is_err() {
+                return;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let state = rx
+            .recv_timeout(left)
+            .map_err(|_| eyre!("Time out waiting for {name} to become: {wanted:?}"))?
+            .wrap_err("Could not get unit state")?;
+        log::trace!("{name} is {state}");
+
+        if wanted.contains(&state.as_str()) {
+            return Ok(());
+        }
+        if state == "failed" {
+            return Err(eyre!("{name} failed"));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Trashing a document sets its parent to "trash", where it was is lost.
+//! We remember the parent of every document and folder each time we lock
+//! so `--include-trash` can also lock what was trashed from a locked
+//! folder. Documents trashed before we ever saw them can not be traced.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use color_eyre::{eyre::WrapErr, Result};
+
+use crate::directory::{Parent, Tree, Uuid};
+
+fn path() -> PathBuf {
+    crate::state_dir().join("parents.json")
+}
+
+/// Where documents and folders were the last time we saw them outside
+/// the trash
+pub fn remembered() -> Result<HashMap<Uuid, Parent>> {
+    let json = match fs::read_to_string(path()) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e).wrap_err("Could not read parents record"),
+    };
+    let record: HashMap<String, String> =
+        serde_json::from_str(&json).wrap_err("Could not parse parents record")?;
+    let mut parents = HashMap::new();
+    for (uuid, parent) in record {
+        match Uuid::parse(&uuid).and_then(|uuid| Ok((uuid, Parent::parse(&parent)?))) {
+            Ok((uuid, parent)) => {
+                parents.insert(uuid, parent);
+            }
+            Err(e) => log::warn!("ignoring entry in parents record: {e}"),
+        }
+    }
+    Ok(parents)
+}
+
+/// Updates the record with where everything in `tree` is now
+pub fn remember(tree: &Tree) -> Result<()> {
+    let mut parents = remembered()?;
+    update(&mut parents, tree.parents());
+    let record: HashMap<String, String> = parents
+        .into_iter()
+        .map(|(uuid, parent)| (uuid.to_string(), parent.to_string()))
+        .collect();
+    fs::create_dir_all(crate::state_dir()).wrap_err("Could not create state dir")?;
+    let json = serde_json::to_string_pretty(&record)?;
+    fs::write(path(), json).wrap_err("Could not write parents record")
+}
+
+/// Keeps where trashed documents were, forgets those that are gone
+fn update(known: &mut HashMap<Uuid, Parent>, now: HashMap<Uuid, Parent>) {
+    known.retain(|uuid, _| now.get(uuid) == Some(&Parent::Trash));
+    known.extend(now.into_iter().filter(|(_, p)| *p != Parent::Trash));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::directory::test::id;
+
+    #[test]
+    fn keeps_parent_of_trashed() {
+        let books = Parent::Folder(id("Books"));
+        let mut known = HashMap::from([(id("dune"), books), (id("gone"), books)]);
+        let now = HashMap::from([
+            (id("dune"), Parent::Trash),
+            (id("emma"), books),
+            (id("notes"), Parent::Trash),
+        ]);
+        update(&mut known, now);
+        assert_eq!(
+            known,
+            HashMap::from([(id("dune"), books), (id("emma"), books)])
+        );
+    }
+}
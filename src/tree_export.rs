@@ -0,0 +1,125 @@
+//! Writes every folder and document on the device as json or csv, for
+//! spreadsheets or backup tools that want to know what is there.
+
+use std::fmt::Write;
+
+use clap::ValueEnum;
+use color_eyre::{eyre::WrapErr, Result};
+use itertools::Itertools;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::directory::{self, FileType, Item};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+    path: String,
+    uuid: String,
+    /// `folder`, the document type or `unknown`
+    #[serde(rename = "type")]
+    kind: String,
+    /// in bytes, of a document and its pages
+    size: Option<u64>,
+    /// UTC, in ISO 8601
+    last_opened: Option<String>,
+    last_opened_page: Option<u32>,
+}
+
+impl Row {
+    fn of(item: &Item) -> Self {
+        let kind = if item.folder {
+            "folder".to_owned()
+        } else {
+            FileType::of(item.uuid)
+                .and_then(|kind| kind.to_possible_value())
+                .map_or("unknown".to_owned(), |kind| kind.get_name().to_owned())
+        };
+        Self {
+            path: item.path.display().to_string(),
+            uuid: item.uuid.to_string(),
+            kind,
+            size: (!item.folder).then(|| directory::doc_size(item.uuid)),
+            last_opened: item.opened.at.map(iso),
+            last_opened_page: item.opened.page,
+        }
+    }
+}
+
+fn iso(at: OffsetDateTime) -> String {
+    let at = at.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        at.date(),
+        at.hour(),
+        at.minute(),
+        at.second()
+    )
+}
+
+/// Quoted if needed, with quotes doubled
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn csv(rows: &[Row]) -> String {
+    let mut text = "path,uuid,type,size,last_opened,last_opened_page\n".to_owned();
+    for row in rows {
+        let fields = [
+            csv_field(&row.path),
+            row.uuid.clone(),
+            row.kind.clone(),
+            row.size.map(|size| size.to_string()).unwrap_or_default(),
+            row.last_opened.clone().unwrap_or_default(),
+            row.last_opened_page
+                .map(|page| page.to_string())
+                .unwrap_or_default(),
+        ];
+        let _ = writeln!(text, "{}", fields.iter().join(","));
+    }
+    text
+}
+
+/// Prints the document tree in `format`
+pub fn print(format: Format) -> Result<()> {
+    let (tree, _) = directory::map().wrap_err("Could not build document tree")?;
+    let rows: Vec<_> = tree.items().iter().map(Row::of).collect();
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+        Format::Csv => print!("{}", csv(&rows)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_rows() {
+        let row = Row {
+            path: r#"Books/Dune, "the" saga"#.to_owned(),
+            uuid: "95318cc7-f844-416f-963a-cf277c83f10c".to_owned(),
+            kind: "epub".to_owned(),
+            size: Some(1024),
+            last_opened: Some(iso(OffsetDateTime::UNIX_EPOCH)),
+            last_opened_page: None,
+        };
+        assert_eq!(
+            csv(&[row]).lines().nth(1),
+            Some(
+                r#""Books/Dune, ""the"" saga",95318cc7-f844-416f-963a-cf277c83f10c,epub,1024,1970-01-01T00:00:00Z,"#
+            )
+        );
+    }
+}
@@ -1,9 +1,17 @@
 use color_eyre::{eyre, Help};
 use eyre::{eyre, Result, WrapErr};
 use rust_fuzzy_search::fuzzy_search_best_n;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 use crate::directory;
 
+pub mod cmd;
+pub mod interrupt;
+pub mod logfile;
+pub mod net;
+pub mod progress;
 pub mod time;
 
 pub trait AcceptErr {
@@ -15,13 +23,51 @@ impl<E> AcceptErr for Result<(), E> {
     type Error = E;
     fn accept_fn<P: FnMut(&Self::Error) -> bool>(self, mut predicate: P) -> Self {
         match self {
-            Ok(_) => Ok(()),
+            Ok(()) => Ok(()),
             Err(e) if predicate(&e) => Ok(()),
             Err(e) => Err(e),
         }
     }
 }
 
+/// Compares every byte so the time taken does not give the secret away
+pub fn same_secret(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Make a file readable and writable only by root
+pub fn restrict_to_root(path: &Path) -> Result<()> {
+    std::os::unix::fs::chown(path, Some(0), Some(0))
+        .wrap_err_with(|| format!("Could not make root the owner of: {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .wrap_err_with(|| format!("Could not restrict permissions of: {}", path.display()))
+}
+
+/// Parses a size such as `5MB` or `300kB` to bytes, a kB is 1000 bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .wrap_err_with(|| format!("Invalid size: {s}"))?;
+    let unit = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        _ => {
+            return Err(eyre!("Invalid size unit: {unit}"))
+                .suggestion("use B, kB, MB or GB, for example: 5MB")
+        }
+    };
+    Ok(number * unit)
+}
+
 pub fn without_overlapping(mut list: Vec<String>) -> Vec<String> {
     let mut result = Vec::new();
     list.sort_unstable_by_key(String::len);
@@ -102,7 +148,16 @@ mod test {
     }
 
     fn vec(list: &[&str]) -> Vec<String> {
-        list.into_iter().map(|s| s.to_string()).collect()
+        list.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("5MB").unwrap(), 5_000_000);
+        assert_eq!(parse_size("300 kB").unwrap(), 300_000);
+        assert_eq!(parse_size("12").unwrap(), 12);
+        assert!(parse_size("5 MiB").is_err());
+        assert!(parse_size("MB").is_err());
     }
 
     #[test]
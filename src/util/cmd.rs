@@ -0,0 +1,134 @@
+//! Running system programs such as `route` and `timedatectl`. Code
+//! taking a `SysCmd` can be tested off the device with `Scripted`.
+
+use std::io;
+use std::process::{Command, Output};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+pub trait SysCmd {
+    /// Runs `program` with `args` and collects what it printed
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+
+    /// Runs `program` with `args`, an error with what it printed to
+    /// stderr if it fails
+    fn run(&self, program: &str, args: &[&str]) -> Result<()> {
+        let output = self
+            .output(program, args)
+            .wrap_err_with(|| format!("Could not run {program}"))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            let reason = String::from_utf8_lossy(&output.stderr);
+            Err(eyre!("{}", reason.trim()).wrap_err(format!("{program} returned an error")))
+        }
+    }
+}
+
+/// Runs the real programs
+pub struct System;
+
+impl SysCmd for System {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+}
+
+/// Quotes `arg` for `sh`, for command lines run by cron or over ssh
+pub fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_.,:/+=@".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_owned();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Expects commands in order and answers each with the given output.
+/// Panics on an unexpected command or if not every command ran.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Scripted {
+    script: std::cell::RefCell<std::collections::VecDeque<(String, io::Result<Output>)>>,
+}
+
+#[cfg(test)]
+impl Scripted {
+    /// `command` is the program and its arguments separated by spaces
+    pub fn expect(self, command: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+
+        let output = Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        };
+        self.script
+            .borrow_mut()
+            .push_back((command.to_owned(), Ok(output)));
+        self
+    }
+
+    /// `command` is answered as if its program is not installed
+    pub fn missing(self, command: &str) -> Self {
+        let err = io::Error::from(io::ErrorKind::NotFound);
+        self.script
+            .borrow_mut()
+            .push_back((command.to_owned(), Err(err)));
+        self
+    }
+}
+
+#[cfg(test)]
+impl SysCmd for Scripted {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        let command = std::iter::once(program)
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let (expected, output) = self
+            .script
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or_else(|| panic!("unexpected command: {command}"));
+        assert_eq!(command, expected);
+        output
+    }
+}
+
+#[cfg(test)]
+impl Drop for Scripted {
+    fn drop(&mut self) {
+        let left = self.script.get_mut();
+        assert!(
+            std::thread::panicking() || left.is_empty(),
+            "commands did not run: {:?}",
+            left.iter().map(|(c, _)| c).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_reports_stderr() {
+        let cmd = Scripted::default().expect("true", 0, "", "").expect(
+            "false --now",
+            1,
+            "",
+            "it failed\n",
+        );
+        cmd.run("true", &[]).unwrap();
+        let err = cmd.run("false", &["--now"]).unwrap_err();
+        assert_eq!(format!("{err:#}"), "false returned an error: it failed");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("--path=Books"), "--path=Books");
+        assert_eq!(quote("--path=My Books"), "'--path=My Books'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+    }
+}
@@ -0,0 +1,29 @@
+//! Lets a SIGINT or SIGTERM (systemd stopping us) abort a lock between
+//! steps, so the work is rolled back and the ui is started again instead
+//! of being left stopped.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use color_eyre::{eyre::WrapErr, Result};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            log::error!("interrupted twice, exiting without cleaning up");
+            std::process::exit(130);
+        }
+        log::warn!("interrupted, stopping after the current step");
+    })
+    .wrap_err("Could not install signal handler")
+}
+
+/// Errors if we got interrupted, call between steps that can be undone
+pub fn check() -> Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Err(crate::exit::Failure::Interrupted.into())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! A log file next to the terminal output. The service has no journal to
+//! keep its output, so without this a failed run at night leaves no trace.
+//! The file is rotated once it grows too large so it never fills the disk.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+
+/// Messages logged with this target only end up in the log file
+pub const FILE_ONLY: &str = "book_safe::file_only";
+/// Size at which the log is rotated
+pub const MAX_SIZE: u64 = 512 * 1024;
+/// Number of rotated logs kept: `<log>.1` (newest) up to `<log>.3`
+pub const KEEP: usize = 3;
+
+pub fn default_path() -> PathBuf {
+    crate::state_dir().join("log").join("book-safe.log")
+}
+
+/// Appends to a file, moving it to `<path>.1` once it reaches `max_size`
+pub struct Rotating {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl Rotating {
+    pub fn open(path: &Path, max_size: u64) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Could not create log dir: {}", dir.display()))?;
+        }
+        let file = append(path)
+            .wrap_err_with(|| format!("Could not open log file: {}", path.display()))?;
+        let size = file.metadata().map_or(0, |m| m.len());
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for Rotating {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotates() {
+        let dir = std::env::temp_dir().join(format!("book-safe-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("test.log");
+
+        let mut log = Rotating::open(&path, 10).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n", "fifth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        // reopening continues where we left off
+        let mut log = Rotating::open(&path, 10).unwrap();
+        log.write_all(b"six\n").unwrap();
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "fifth\nsix\n");
+        assert_eq!(read(&log.rotated(1)), "fourth\n");
+        assert_eq!(read(&log.rotated(3)), "second\n");
+        assert!(!log.rotated(4).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Connections to servers we talk to ourselves, such as the notify
+//! webhook and the mqtt broker
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+
+pub trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Connects to `host`, over tls if `tls` is set. Reads and writes time
+/// out after `timeout`.
+pub fn connect(host: &str, port: u16, tls: bool, timeout: Duration) -> Result<Box<dyn Stream>> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .wrap_err_with(|| format!("Could not resolve {host}"))?
+        .next()
+        .ok_or_else(|| eyre!("No address found for {host}"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)
+        .wrap_err_with(|| format!("Could not connect to {host}"))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    if tls {
+        wrap_tls(stream, host)
+    } else {
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(feature = "tls")]
+fn wrap_tls(stream: TcpStream, host: &str) -> Result<Box<dyn Stream>> {
+    use std::sync::Arc;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = rustls::ServerName::try_from(host)
+        .wrap_err_with(|| format!("Invalid server name: {host}"))?;
+    let connection =
+        rustls::ClientConnection::new(Arc::new(config), name).wrap_err("Could not set up tls")?;
+    Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+}
+
+#[cfg(not(feature = "tls"))]
+fn wrap_tls(_: TcpStream, _: &str) -> Result<Box<dyn Stream>> {
+    use color_eyre::Help;
+    Err(eyre!("book-safe was built without tls support"))
+        .suggestion("use an unencrypted url or build with the tls feature")
+}
@@ -0,0 +1,141 @@
+//! Progress of moving documents. Locking thousands takes a while with the
+//! ui down, so every few seconds we log how far we are. In a terminal a
+//! progress bar is shown as well, the log lines then only go to the log
+//! file.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::logfile;
+
+const LOG_EVERY: Duration = Duration::from_secs(5);
+
+pub struct Progress {
+    /// What is counted, such as "documents"
+    what: &'static str,
+    total: usize,
+    done: usize,
+    bytes: u64,
+    started: Instant,
+    logged: Instant,
+    #[cfg(feature = "progress-bar")]
+    bar: Option<indicatif::ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(what: &'static str, total: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            what,
+            total,
+            done: 0,
+            bytes: 0,
+            started: now,
+            logged: now,
+            #[cfg(feature = "progress-bar")]
+            bar: bar(what, total),
+        }
+    }
+
+    /// One more done, it was `bytes` large
+    pub fn add(&mut self, bytes: u64) {
+        self.done += 1;
+        self.bytes += bytes;
+        #[cfg(feature = "progress-bar")]
+        let has_bar = self.bar.as_ref().inspect(|bar| bar.inc(1)).is_some();
+        #[cfg(not(feature = "progress-bar"))]
+        let has_bar = false;
+        if self.logged.elapsed() >= LOG_EVERY {
+            self.logged = Instant::now();
+            let message = self.message(self.started.elapsed());
+            if has_bar {
+                log::info!(target: logfile::FILE_ONLY, "{message}");
+            } else {
+                log::info!("{message}");
+            }
+        }
+    }
+
+    fn message(&self, elapsed: Duration) -> String {
+        let Progress {
+            what, total, done, ..
+        } = self;
+        let mut message = format!("moved {done}/{total} {what}, {}", mb(self.bytes));
+        if *done > 0 && done < total {
+            let left_ms = elapsed.as_millis() * (total - done) as u128 / *done as u128;
+            let _ = write!(message, ", about {}s left", left_ms.div_ceil(1000));
+        }
+        message
+    }
+
+    /// Logs how many were moved and how long that took
+    pub fn finish(self) {
+        #[cfg(feature = "progress-bar")]
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+        if self.total == 0 {
+            return;
+        }
+        log::info!(
+            "moved {} {} ({}) in {:.1?}",
+            self.done,
+            self.what,
+            mb(self.bytes),
+            self.started.elapsed()
+        );
+    }
+}
+
+#[cfg(feature = "progress-bar")]
+fn bar(what: &str, total: usize) -> Option<indicatif::ProgressBar> {
+    use std::io::IsTerminal;
+
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+    let style = indicatif::ProgressStyle::with_template(&format!(
+        "{{bar:40}} {{pos}}/{{len}} {what}, {{eta}} left"
+    ))
+    .expect("template is valid");
+    Some(indicatif::ProgressBar::new(total as u64).with_style(style))
+}
+
+pub fn mb(bytes: u64) -> String {
+    format!("{}.{} MB", bytes / 1_000_000, bytes / 100_000 % 10)
+}
+
+/// Size of a file, or of everything in a dir. Zero if it can not be read
+pub fn size(path: &Path) -> u64 {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| size(&entry.path()))
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimates_time_left() {
+        let mut progress = Progress::new("documents", 400);
+        progress.done = 100;
+        progress.bytes = 2_500_000;
+        let message = progress.message(Duration::from_secs(10));
+        assert_eq!(message, "moved 100/400 documents, 2.5 MB, about 30s left");
+        progress.done = 400;
+        let message = progress.message(Duration::from_secs(40));
+        assert_eq!(message, "moved 400/400 documents, 2.5 MB");
+    }
+}
@@ -1,67 +1,406 @@
+use super::cmd::{self, SysCmd};
+use crate::config::Location;
 use color_eyre::{
     eyre::{eyre, WrapErr},
     Help, Result,
 };
 use itertools::Itertools;
 use rust_fuzzy_search::fuzzy_search_best_n;
-use std::{io::BufRead, process::Command};
-use time::Time;
+use std::fs;
+use std::path::Path;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time};
+use time_tz::{
+    timezones, Offset, OffsetDateTimeExt, OffsetResult, PrimitiveDateTimeExt, TimeZone, Tz,
+};
 
 pub trait ParseHourMinute {
     fn try_parse(s: &str) -> Result<time::Time>;
 }
 
 impl ParseHourMinute for time::Time {
+    /// Accepts `22:30`, `22:30:15` and a 12 hour clock such as `10pm`,
+    /// `10:30pm` or `10:30:15 pm`
     fn try_parse(s: &str) -> Result<time::Time> {
-        let (h, m) = s
-            .split_once(':')
-            .ok_or_else(|| eyre!("Hours and minutes must be separated by :"))?;
-        let h = h.parse().wrap_err("Could not parse hour")?;
-        let m = m.parse().wrap_err("Could not parse minute")?;
-        time::Time::from_hms(h, m, 0).wrap_err("Hour or minute not possible")
+        let s = s.trim().to_lowercase();
+        let (clock, half) = match s.strip_suffix("am").or_else(|| s.strip_suffix("pm")) {
+            Some(clock) => (clock.trim_end(), Some(s.ends_with("pm"))),
+            None => (s.as_str(), None),
+        };
+        let mut parts = clock.split(':');
+        let h: u8 = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .wrap_err("Could not parse hour")?;
+        let m = match parts.next() {
+            Some(m) => m.parse().wrap_err("Could not parse minute")?,
+            None if half.is_some() => 0,
+            None => {
+                return Err(eyre!("Hours and minutes must be separated by :"))
+                    .suggestion("use 23:59, 23:59:59 or 11pm")
+            }
+        };
+        let sec = match parts.next() {
+            Some(sec) => sec.parse().wrap_err("Could not parse second")?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return Err(eyre!("Too many : in {s}")).suggestion("use 23:59, 23:59:59 or 11pm");
+        }
+        let h = match half {
+            Some(_) if !(1..=12).contains(&h) => {
+                return Err(eyre!("Hour must be 1 to 12 with am or pm"));
+            }
+            Some(pm) => h % 12 + if pm { 12 } else { 0 },
+            None => h,
+        };
+        time::Time::from_hms(h, m, sec).wrap_err("Hour, minute or second not possible")
     }
 }
 
-pub fn should_lock(now: Time, start: Time, end: Time) -> bool {
-    if start <= end {
-        now >= start && now <= end
-    } else {
-        now >= start || now <= end
+/// `at` as `try_parse` reads it back, with the seconds only if there are
+/// any: 7:00 or 7:00:30
+pub fn clock(at: Time) -> String {
+    match at.second() {
+        0 => format!("{}:{:02}", at.hour(), at.minute()),
+        sec => format!("{}:{:02}:{sec:02}", at.hour(), at.minute()),
     }
 }
 
-pub fn set_os_timezone(timezone: &str) -> Result<()> {
-    let output = Command::new("timedatectl")
-        .arg("set-timezone")
-        .arg(timezone)
-        .output()
-        .wrap_err("Could not run timedatectl")?;
+/// Parses how long ago such as `30d`, in minutes (`m`), hours (`h`),
+/// days (`d`) or weeks (`w`)
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: i64 = number
+        .parse()
+        .wrap_err_with(|| format!("Invalid duration: {s}"))?;
+    match unit {
+        "m" => Ok(Duration::minutes(number)),
+        "h" => Ok(Duration::hours(number)),
+        "d" => Ok(Duration::days(number)),
+        "w" => Ok(Duration::weeks(number)),
+        _ => Err(eyre!("Invalid duration unit: {unit:?}"))
+            .suggestion("use m, h, d or w, for example: 30d"),
+    }
+}
 
-    if output.status.success() {
-        Ok(())
-    } else {
-        let reason = String::from_utf8(output.stderr).unwrap();
-        let timezones = get_timezones().wrap_err("Could not get time zones for suggestion")?;
-        let report = eyre!("{reason}");
-        Err(match list_fuzzy(&timezones, timezone, 1).get(0) {
-            Some(sugg) => report.suggestion(format!("did you mean: \"{sugg}\"",)),
-            None => report,
+/// A start or end as given on the command line: a clock time, an offset
+/// from the other end of the window (`+9h`, `-30m`) or from the sun
+/// (`sunrise+1h`, `sunset-30m`). Install works out the clock time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expr {
+    At(Time),
+    FromOther(Duration),
+    Sun(SunEvent, Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SunEvent {
+    Rise,
+    Set,
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        for (name, event) in [("sunrise", SunEvent::Rise), ("sunset", SunEvent::Set)] {
+            if let Some(offset) = s.strip_prefix(name) {
+                let offset = match offset.trim() {
+                    "" => Duration::ZERO,
+                    offset => parse_offset(offset)?,
+                };
+                return Ok(Self::Sun(event, offset));
+            }
+        }
+        if s.starts_with(['+', '-']) {
+            return parse_offset(&s).map(Self::FromOther);
+        }
+        Time::try_parse(&s)
+            .map(Self::At)
+            .suggestion("use a time like 22:00, an offset like +9h or sunrise+1h")
+    }
+}
+
+/// A signed offset made of numbers with a unit, hours (`h`), minutes
+/// (`m`) or seconds (`s`), for example: `+9h`, `-30m` or `+1h30m`
+fn parse_offset(s: &str) -> Result<Duration> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let (negative, mut rest) = match s.split_at_checked(1) {
+        Some(("+", rest)) => (false, rest),
+        Some(("-", rest)) => (true, rest),
+        _ => return Err(eyre!("Offset must start with + or -: {s:?}")),
+    };
+    if rest.is_empty() {
+        return Err(eyre!("Offset is missing a duration: {s:?}"));
+    }
+    let mut offset = Duration::ZERO;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: i64 = number
+            .parse()
+            .wrap_err_with(|| format!("Invalid offset: {s}"))?;
+        let mut tail = tail.chars();
+        offset += match tail.next() {
+            Some('h') => Duration::hours(number),
+            Some('m') => Duration::minutes(number),
+            Some('s') => Duration::seconds(number),
+            unit => {
+                return Err(eyre!("Invalid offset unit: {unit:?}"))
+                    .suggestion("use h, m or s, for example: +1h30m")
+            }
+        };
+        rest = tail.as_str();
+    }
+    Ok(if negative { -offset } else { offset })
+}
+
+/// The clock times a window from `start` to `end` stands for on `date`
+/// in `tz`. An offset is from the other end, which must then be a time
+/// of its own. Times relative to the sun need the `location`.
+pub fn resolve_window<T: TimeZone>(
+    start: Expr,
+    end: Expr,
+    date: Date,
+    tz: &T,
+    location: Option<&Location>,
+) -> Result<(Time, Time)> {
+    let fixed = |expr| -> Result<Option<Time>> {
+        match expr {
+            Expr::At(at) => Ok(Some(at)),
+            Expr::FromOther(_) => Ok(None),
+            Expr::Sun(event, offset) => {
+                let location = location
+                    .ok_or_else(|| eyre!("Times relative to the sun need a location"))
+                    .suggestion("set location in the config")?;
+                Ok(Some(sun(event, date, location, tz)? + offset))
+            }
+        }
+    };
+    match (fixed(start)?, fixed(end)?, start, end) {
+        (Some(start), Some(end), ..) => Ok((start, end)),
+        (Some(start), None, _, Expr::FromOther(offset)) => Ok((start, start + offset)),
+        (None, Some(end), Expr::FromOther(offset), _) => Ok((end + offset, end)),
+        _ => Err(eyre!("Start and end can not both be offsets"))
+            .suggestion("make one of them a time, for example: --start 22:00 --end +9h"),
+    }
+}
+
+/// When the sun rises or sets on `date` at `location`, on the clock of
+/// `tz`, to the minute. Uses the sunrise equation which is good to about
+/// a minute.
+#[allow(clippy::cast_possible_truncation)] // unix timestamps fit in i64
+pub fn sun<T: TimeZone>(event: SunEvent, date: Date, location: &Location, tz: &T) -> Result<Time> {
+    const J2000: f64 = 2_451_545.0;
+    let day = f64::from(date.to_julian_day()) - J2000 + 0.0008;
+    let mean_noon = day - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour) {
+        return Err(eyre!(
+            "The sun does not rise or set on {date} at the location"
+        ));
+    }
+    let half_day = cos_hour.acos().to_degrees() / 360.0;
+    let julian = match event {
+        SunEvent::Rise => transit - half_day,
+        SunEvent::Set => transit + half_day,
+    };
+    // rounded to the minute, more precision would be false
+    let unix = ((julian - 2_440_587.5) * 1440.0).round() as i64 * 60;
+    let at = OffsetDateTime::from_unix_timestamp(unix).wrap_err("Sun time out of range")?;
+    Ok(at.to_timezone(tz).time())
+}
+
+/// When to lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// every day from `start` to `end`
+    Window { start: Time, end: Time },
+    /// once unlocked this long in a day, until midnight
+    Quota(Duration),
+}
+
+/// Whether `now` falls in the window from `start` to `end` on the wall
+/// clock of `tz`. The window is worked out as actual instants so a DST
+/// change during it does not move the lock or the release.
+pub fn should_lock<T: TimeZone>(now: OffsetDateTime, start: Time, end: Time, tz: &T) -> bool {
+    let today = now.to_timezone(tz).date();
+    [today.previous_day(), Some(today)]
+        .into_iter()
+        .flatten()
+        .any(|date| {
+            let end_date = if start <= end {
+                Some(date)
+            } else {
+                date.next_day()
+            };
+            end_date.is_some_and(|end_date| {
+                resolve(date, start, tz) <= now && now <= resolve(end_date, end, tz)
+            })
         })
+}
+
+/// Whether the window from `start` to `end` starts within `within` from
+/// `now`, false once it started
+pub fn starts_within<T: TimeZone>(
+    now: OffsetDateTime,
+    within: Duration,
+    start: Time,
+    end: Time,
+    tz: &T,
+) -> bool {
+    !should_lock(now, start, end, tz) && should_lock(now + within, start, end, tz)
+}
+
+/// The instant the clock in `tz` shows `time` on `date`. A time skipped
+/// by DST resolves to as long after the jump as it was after the last
+/// valid time before it, a time that occurs twice to the first occurrence.
+pub fn resolve<T: TimeZone>(date: Date, time: Time, tz: &T) -> OffsetDateTime {
+    let wall = PrimitiveDateTime::new(date, time);
+    match wall.assume_timezone(tz) {
+        OffsetResult::Some(instant) => instant,
+        OffsetResult::Ambiguous(a, b) => a.min(b),
+        OffsetResult::None => {
+            let before_jump = wall.assume_utc() - Duration::hours(12);
+            wall.assume_offset(tz.get_offset_utc(&before_jump).to_utc())
+        }
+    }
+}
+
+/// The reMarkable's clock falls back to 1970 when the battery runs dry,
+/// no date before this release can be the actual time.
+const EARLIEST_PLAUSIBLE_YEAR: i32 = 2024;
+
+/// Whether `now` can be trusted to decide if we should lock
+pub fn check_clock(now: OffsetDateTime, ntp_synchronized: bool) -> Result<()> {
+    check_plausible(now)?;
+    if !ntp_synchronized {
+        return Err(eyre!("System clock is not synchronized"))
+            .suggestion("connect to wifi so the clock is set, or pass --force-time");
     }
+    Ok(())
 }
 
-fn get_timezones() -> Result<Vec<String>> {
-    let output = Command::new("timedatectl")
-        .arg("list-timezones")
-        .output()
+/// Whether `now` could be the actual date, without network this is all
+/// we can check
+pub fn check_plausible(now: OffsetDateTime) -> Result<()> {
+    if now.year() < EARLIEST_PLAUSIBLE_YEAR {
+        return Err(eyre!("System date {} is implausible", now.date()))
+            .note("the clock resets when the battery runs out")
+            .suggestion("connect to wifi so the clock is set");
+    }
+    Ok(())
+}
+
+/// Asks systemd whether the clock has been set over the network
+pub fn ntp_synchronized() -> Result<bool> {
+    if !crate::device::current().is_remarkable() {
+        return Ok(true);
+    }
+    ntp_synchronized_with(&cmd::System)
+}
+
+fn ntp_synchronized_with(cmd: &impl SysCmd) -> Result<bool> {
+    let output = cmd
+        .output(
+            "timedatectl",
+            &["show", "--property=NTPSynchronized", "--value"],
+        )
         .wrap_err("Could not run timedatectl")?;
+    if !output.status.success() {
+        let reason = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("{reason}").wrap_err("timedatectl returned an error"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "yes")
+}
 
-    if output.status.success() {
-        Ok(output.stdout.lines().map(Result::unwrap).collect_vec())
+/// Looks `name` up in the timezone database compiled into book-safe
+pub fn find_timezone(name: &str) -> Result<&'static Tz> {
+    if let Some(tz) = timezones::get_by_name(name) {
+        return Ok(tz);
+    }
+    let report = eyre!("Unknown timezone: {name}");
+    Err(match list_fuzzy(&get_timezones(), name, 1).first() {
+        Some(sugg) => report.suggestion(format!("did you mean: \"{sugg}\"")),
+        None => report.suggestion("use `list-tz` to see all timezones"),
+    })
+}
+
+/// The zone named `timezone`, or the os timezone if there is none
+/// configured
+pub fn zone(timezone: Option<&str>) -> Result<&'static Tz> {
+    if let Some(name) = timezone {
+        find_timezone(name)
     } else {
-        let reason = String::from_utf8(output.stderr).unwrap();
-        Err(eyre!("{reason}").wrap_err("datetimectl returned an error"))
+        let name = os_timezone().wrap_err("Could not get the os timezone")?;
+        find_timezone(&name).wrap_err("The os timezone is not in our database")
+    }
+}
+
+/// Only called if the user asked for it, book-safe itself does not
+/// need the os timezone to be correct.
+pub fn set_os_timezone(timezone: &str) -> Result<()> {
+    find_timezone(timezone)?;
+    if !crate::device::current().is_remarkable() {
+        log::warn!("not running on a remarkable, not changing the time zone");
+        return Ok(());
+    }
+
+    cmd::System.run("timedatectl", &["set-timezone", timezone])
+}
+
+/// The timezone the os is set to, read from the `/etc/localtime` link
+pub fn os_timezone() -> Result<String> {
+    let target =
+        fs::read_link(LOCALTIME).wrap_err_with(|| format!("Could not read link {LOCALTIME}"))?;
+    zone_name(&target)
+        .ok_or_else(|| eyre!("{LOCALTIME} does not point into a zoneinfo dir"))
+        .with_note(|| format!("it points to: {}", target.display()))
+}
+
+const LOCALTIME: &str = "/etc/localtime";
+
+fn zone_name(localtime_target: &Path) -> Option<String> {
+    let target = localtime_target.to_str()?;
+    let (_, name) = target.split_once("zoneinfo/")?;
+    Some(name.to_owned())
+}
+
+/// Sets the os timezone back to `timezone` if it changed, as happens
+/// on every firmware update
+pub fn ensure_os_timezone(timezone: &str) -> Result<()> {
+    if !crate::device::current().is_remarkable() {
+        return Ok(());
+    }
+
+    let current = os_timezone().wrap_err("Could not get the os timezone")?;
+    if current == timezone {
+        return Ok(());
     }
+    log::warn!("os timezone is {current}, setting it back to {timezone}");
+    set_os_timezone(timezone)
+}
+
+fn get_timezones() -> Vec<String> {
+    timezones::iter()
+        .map(|tz| tz.name().to_owned())
+        .sorted_unstable()
+        .collect_vec()
 }
 
 fn list_fuzzy<'a>(timezones: &'a [String], term: &'a str, n: usize) -> Vec<String> {
@@ -81,36 +420,233 @@ fn list_fuzzy<'a>(timezones: &'a [String], term: &'a str, n: usize) -> Vec<Strin
         .collect_vec()
 }
 
-pub(crate) fn list_tz(search: Option<String>) -> Result<(), color_eyre::Report> {
-    let mut timezones = get_timezones().wrap_err("Could not get timezones")?;
+pub(crate) fn list_tz(search: Option<String>) {
+    let mut timezones = get_timezones();
     if let Some(term) = search {
         timezones = list_fuzzy(&timezones, &term, 10);
     }
     for name in timezones {
         println!("{name}");
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn asks_timedatectl_about_ntp() {
+        let show = "timedatectl show --property=NTPSynchronized --value";
+        let cmd = cmd::Scripted::default()
+            .expect(show, 0, "yes\n", "")
+            .expect(show, 0, "no\n", "")
+            .expect(show, 1, "", "Failed to connect to bus");
+        assert!(ntp_synchronized_with(&cmd).unwrap());
+        assert!(!ntp_synchronized_with(&cmd).unwrap());
+        assert!(ntp_synchronized_with(&cmd).is_err());
+    }
+
+    #[test]
+    fn clock_times() {
+        let parse = |s| Time::try_parse(s).ok();
+        let hms = |h, m, s| Some(Time::from_hms(h, m, s).unwrap());
+        assert_eq!(parse("22:30"), hms(22, 30, 0));
+        assert_eq!(parse("22:30:15"), hms(22, 30, 15));
+        assert_eq!(parse("10pm"), hms(22, 0, 0));
+        assert_eq!(parse("10:30 PM"), hms(22, 30, 0));
+        assert_eq!(parse("12am"), hms(0, 0, 0));
+        assert_eq!(parse("12:05pm"), hms(12, 5, 0));
+        assert_eq!(parse("7:00:30am"), hms(7, 0, 30));
+        for invalid in ["22", "13pm", "0am", "22:61", "1:2:3:4", "x:30"] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+        assert_eq!(clock(Time::from_hms(7, 0, 0).unwrap()), "7:00");
+        assert_eq!(clock(Time::from_hms(7, 0, 30).unwrap()), "7:00:30");
+    }
+
+    #[test]
+    fn expressions() {
+        let parse = |s| Expr::parse(s).ok();
+        assert_eq!(parse("22:00"), Some(Expr::At(hm(22, 0))));
+        assert_eq!(parse("10pm"), Some(Expr::At(hm(22, 0))));
+        assert_eq!(parse("+9h"), Some(Expr::FromOther(Duration::hours(9))));
+        assert_eq!(
+            parse("+1h30m"),
+            Some(Expr::FromOther(Duration::minutes(90)))
+        );
+        assert_eq!(parse("-45s"), Some(Expr::FromOther(Duration::seconds(-45))));
+        assert_eq!(
+            parse("Sunrise + 1h"),
+            Some(Expr::Sun(SunEvent::Rise, Duration::hours(1)))
+        );
+        assert_eq!(
+            parse("sunset-30m"),
+            Some(Expr::Sun(SunEvent::Set, Duration::minutes(-30)))
+        );
+        assert_eq!(
+            parse("sunset"),
+            Some(Expr::Sun(SunEvent::Set, Duration::ZERO))
+        );
+        for invalid in ["+", "+9", "+9d", "9h", "sunrise1h", "noon", "+h"] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let tz = find_timezone("Europe/Amsterdam").unwrap();
+        let date = Date::from_calendar_date(2024, time::Month::June, 21).unwrap();
+        let amsterdam = Location {
+            latitude: 52.37,
+            longitude: 4.90,
+        };
+        let window = |start, end, location| {
+            let start = Expr::parse(start).unwrap();
+            let end = Expr::parse(end).unwrap();
+            resolve_window(start, end, date, tz, location).ok()
+        };
+        assert_eq!(window("22:00", "+9h", None), Some((hm(22, 0), hm(7, 0))));
+        assert_eq!(window("-30m", "1:00", None), Some((hm(0, 30), hm(1, 0))));
+        assert_eq!(window("+1h", "-1h", None), None);
+        assert_eq!(window("22:00", "sunrise", None), None);
+
+        let (start, end) = window("sunset", "sunrise+1h", Some(&amsterdam)).unwrap();
+        // 22:06 and 5:18 according to the KNMI
+        let close = |at: Time, h, m| (at - hm(h, m)).whole_minutes().abs() <= 2;
+        assert!(close(start, 22, 6), "{start}");
+        assert!(close(end, 6, 18), "{end}");
+        assert_eq!(end.second(), 0);
+
+        let north_cape = Location {
+            latitude: 71.17,
+            longitude: 25.78,
+        };
+        assert!(sun(SunEvent::Rise, date, &north_cape, tz).is_err());
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("30d").unwrap(), Duration::days(30));
+        assert_eq!(parse_age("2w").unwrap(), Duration::weeks(2));
+        assert_eq!(parse_age("90m").unwrap(), Duration::minutes(90));
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("d").is_err());
+    }
+
+    fn hm(h: u8, m: u8) -> Time {
+        Time::from_hms(h, m, 0).unwrap()
+    }
+
+    /// The instant the clock in Amsterdam shows `h:m` on `day` `month` 2024
+    fn amsterdam(month: time::Month, day: u8, h: u8, m: u8) -> OffsetDateTime {
+        let tz = find_timezone("Europe/Amsterdam").unwrap();
+        let date = Date::from_calendar_date(2024, month, day).unwrap();
+        resolve(date, hm(h, m), tz)
+    }
+
     #[test]
     fn time_compare() {
-        let start = Time::from_hms(23, 10, 0).unwrap();
-        let end = Time::from_hms(8, 5, 0).unwrap();
+        let tz = find_timezone("Europe/Amsterdam").unwrap();
+        let (start, end) = (hm(23, 10), hm(8, 5));
+        let at = |h, m| amsterdam(time::Month::June, 12, h, m);
+
+        assert!(!should_lock(at(8, 10), start, end, tz));
+        assert!(should_lock(at(8, 4), start, end, tz));
+        assert!(should_lock(at(23, 11), start, end, tz));
+        assert!(!should_lock(at(23, 9), start, end, tz));
+
+        let end = Time::from_hms(8, 5, 30).unwrap();
+        assert!(should_lock(
+            at(8, 5) + Duration::seconds(30),
+            start,
+            end,
+            tz
+        ));
+        assert!(!should_lock(
+            at(8, 5) + Duration::seconds(31),
+            start,
+            end,
+            tz
+        ));
+    }
 
-        let now = Time::from_hms(8, 10, 0).unwrap();
-        assert!(!should_lock(now, start, end));
+    #[test]
+    fn starts_soon() {
+        let tz = find_timezone("Europe/Amsterdam").unwrap();
+        let (start, end) = (hm(22, 0), hm(7, 0));
+        let at = |h, m| amsterdam(time::Month::June, 12, h, m);
+        let quarter = Duration::minutes(15);
 
-        let now = Time::from_hms(8, 4, 0).unwrap();
-        assert!(should_lock(now, start, end));
+        assert!(!starts_within(at(21, 40), quarter, start, end, tz));
+        assert!(starts_within(at(21, 46), quarter, start, end, tz));
+        assert!(!starts_within(at(22, 1), quarter, start, end, tz));
+    }
 
-        let now = Time::from_hms(23, 11, 0).unwrap();
-        assert!(should_lock(now, start, end));
+    #[test]
+    fn dst_start() {
+        // on 31 March 2024 the clock in Amsterdam jumps from 2:00 to 3:00
+        let tz = find_timezone("Europe/Amsterdam").unwrap();
+        let at = |h, m| amsterdam(time::Month::March, 31, h, m);
+        assert_eq!(at(2, 30), at(3, 30));
+
+        // a lock starting in the skipped hour starts an hour late
+        let (start, end) = (hm(2, 30), hm(8, 0));
+        assert!(!should_lock(at(1, 59), start, end, tz));
+        assert!(!should_lock(at(3, 29), start, end, tz));
+        assert!(should_lock(at(3, 30), start, end, tz));
+
+        // but a lock spanning the jump releases on time
+        let (start, end) = (hm(23, 0), hm(8, 0));
+        let night = amsterdam(time::Month::March, 30, 23, 30);
+        assert!(should_lock(night, start, end, tz));
+        assert!(should_lock(at(7, 59), start, end, tz));
+        assert!(!should_lock(at(8, 1), start, end, tz));
+    }
 
-        let now = Time::from_hms(23, 09, 0).unwrap();
-        assert!(!should_lock(now, start, end));
+    #[test]
+    fn dst_end() {
+        // on 27 October 2024 the clock in Amsterdam goes from 3:00 back to 2:00
+        let tz = find_timezone("Europe/Amsterdam").unwrap();
+        let first = amsterdam(time::Month::October, 27, 2, 30);
+        let second = first + Duration::hours(1);
+        assert_eq!(second.to_timezone(tz).time(), hm(2, 30));
+
+        // a lock ending in the repeated hour releases the first time
+        let (start, end) = (hm(23, 0), hm(2, 45));
+        assert!(should_lock(first, start, end, tz));
+        assert!(!should_lock(first + Duration::minutes(20), start, end, tz));
+        assert!(!should_lock(second, start, end, tz));
+
+        // a lock starting in it locks the first time and stays locked
+        let (start, end) = (hm(2, 15), hm(8, 0));
+        assert!(should_lock(first, start, end, tz));
+        assert!(should_lock(second, start, end, tz));
+    }
+
+    #[test]
+    fn implausible_clock() {
+        let reset = OffsetDateTime::UNIX_EPOCH;
+        assert!(check_clock(reset, true).is_err());
+
+        let date = time::Date::from_calendar_date(2024, time::Month::June, 1).unwrap();
+        let now = date.midnight().assume_utc();
+        assert!(check_clock(now, true).is_ok());
+        assert!(check_clock(now, false).is_err());
+    }
+
+    #[test]
+    fn timezones() {
+        assert!(find_timezone("Europe/Amsterdam").is_ok());
+        assert!(find_timezone("Europe/Amsterdan").is_err());
+        let utc = OffsetDateTime::UNIX_EPOCH;
+        let local = utc.to_timezone(find_timezone("Asia/Kolkata").unwrap());
+        assert_eq!(local.time(), Time::from_hms(5, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn localtime_link() {
+        let target = Path::new("/usr/share/zoneinfo/Europe/Amsterdam");
+        assert_eq!(zone_name(target).as_deref(), Some("Europe/Amsterdam"));
+        assert_eq!(zone_name(Path::new("../UTC")), None);
     }
 }
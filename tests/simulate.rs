@@ -0,0 +1,104 @@
+//! Locks and unlocks a made up library using the binary in simulation,
+//! needs the `dev-fixture` feature: `cargo test --features dev-fixture`
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn book_safe(dir: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_book-safe"))
+        .arg("--simulate")
+        .args(args)
+        .current_dir(dir)
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "book-safe {args:?} failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Every file and dir below `dir`, relative to it
+fn listing(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut todo = vec![dir.to_owned()];
+    while let Some(next) = todo.pop() {
+        for entry in fs::read_dir(next).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                todo.push(path.clone());
+            }
+            found.push(path.strip_prefix(dir).unwrap().to_owned());
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Number of documents, folders are not counted
+fn documents_in(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "metadata"))
+        .filter(|path| fs::read_to_string(path).unwrap().contains("DocumentType"))
+        .count()
+}
+
+#[test]
+fn lock_and_unlock() {
+    let dir = std::env::temp_dir().join(format!("book-safe-simulate-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let documents = dir.join("data/xochitl");
+    let safe = dir.join("data/.book-safe/vault");
+
+    book_safe(&dir, &["dev-fixture", "60"]);
+    let before = listing(&documents);
+    assert_eq!(documents_in(&documents), 60);
+
+    // a window around now so the folders get locked
+    let hour = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 3600
+        % 24;
+    let start = format!("{}:00", (hour + 23) % 24);
+    let end = format!("{}:00", (hour + 2) % 24);
+    // as install records it, installing would touch the system
+    let record = serde_json::json!({
+        "args": [
+            "install",
+            format!("--start={start}"),
+            format!("--end={end}"),
+            "--timezone=UTC",
+            "--force-time",
+            "--allow-sync",
+            "--path=Books",
+            "--path=Articles/hobby",
+        ]
+    });
+    fs::create_dir_all(dir.join("data/state")).unwrap();
+    fs::write(dir.join("data/state/install.json"), record.to_string()).unwrap();
+    book_safe(&dir, &["run"]);
+    let locked = documents_in(&safe);
+    assert!(locked > 0, "nothing was locked");
+    // the report was added to the visible documents
+    assert_eq!(documents_in(&documents) + locked, 60 + 1);
+    book_safe(&dir, &["status"]);
+
+    book_safe(&dir, &["unlock", "--yes"]);
+    assert!(listing(&safe).is_empty());
+    // sync was allowed, the report is left a tombstone so it is deleted
+    // from the cloud too
+    let (tombstones, after): (Vec<_>, Vec<_>) = listing(&documents)
+        .into_iter()
+        .partition(|path| path.extension().is_some_and(|ext| ext == "tombstone"));
+    assert_eq!(after, before);
+    assert_eq!(tombstones.len(), 1);
+    fs::remove_dir_all(dir).unwrap();
+}